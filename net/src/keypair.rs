@@ -1,4 +1,4 @@
-use std::io::{Read, Write};
+use std::io::Read;
 use std::path::Path;
 
 use openssl::asn1::Asn1Time;
@@ -8,7 +8,7 @@ use openssl::nid::Nid;
 use openssl::pkey::{PKey, Private};
 use openssl::x509::{X509Name, X509};
 
-use crate::{create_or_truncate, ErrorIdentity};
+use crate::{write_atomic, ErrorIdentity};
 
 pub struct Cert {
     x509: X509
@@ -108,21 +108,11 @@ impl KeyPair {
     }
 
     pub fn save(&self, certdir: &Path) -> Result<(), ErrorIdentity> {
-        let mut oo = std::fs::OpenOptions::new();
         let keypath = certdir.join(Self::KEY_FILE);
-        let mut keyfile = create_or_truncate(&keypath)?;
-        keyfile.write_all(&self.key.private_key_to_pem()?)?;
+        write_atomic(&keypath, &self.key.private_key_to_pem()?)?;
 
         let certpath = certdir.join(Self::CERT_FILE);
-
-        let mut certfile = if std::fs::exists(&certpath)? {
-            let f = oo.write(true).open(&certpath)?;
-            f.set_len(0)?;
-            f
-        } else {
-            std::fs::File::create(&certpath)?
-        };
-        certfile.write_all(&self.cert.to_bytes()?)?;
+        write_atomic(&certpath, &self.cert.to_bytes()?)?;
         Ok(())
     }
 
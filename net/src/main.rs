@@ -1,4 +1,4 @@
-use std::{fs::File, path::Path};
+use std::{fs::File, io::Write, path::Path};
 
 
 use openssl::{error::ErrorStack, x509::X509};
@@ -38,15 +38,30 @@ impl discovery::IpEvent for IpLogger {
     }
 }
 
-pub fn create_or_truncate(p: &Path) -> Result<File, ErrorIdentity> {
-    let mut oo = std::fs::OpenOptions::new();
-    Ok(if std::fs::exists(p)? {
-        let f = oo.write(true).open(&p)?;
-        f.set_len(0)?;
-        f
-    } else {
-        std::fs::File::create(&p)?
-    })
+/// Replace the contents of p so a crash leaves either the old or the new file.
+/// Writes to a temp file next to p, syncs it, renames it over p and syncs the directory.
+pub fn write_atomic(p: &Path, data: &[u8]) -> Result<(), ErrorIdentity> {
+    let dir = match p.parent() {
+        Some(d) if d != Path::new("") => d,
+        _ => Path::new(".")
+    };
+    let fname = p.file_name()
+        .ok_or(std::io::Error::from(std::io::ErrorKind::InvalidInput))?;
+    let tmppath = dir.join(format!(".tmp-{}.{}", fname.to_string_lossy(), std::process::id()));
+
+    let res = (|| -> Result<(), std::io::Error> {
+        let mut f = File::create(&tmppath)?;
+        f.write_all(data)?;
+        f.sync_all()?;
+        std::fs::rename(&tmppath, p)
+    })();
+    if res.is_err() {
+        let _ = std::fs::remove_file(&tmppath);
+    }
+    res?;
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+    Ok(())
 }
 
 fn main() -> Result<(), ErrorIdentity> {
@@ -1,8 +1,8 @@
-use std::{io::{Read, Write}, path::Path};
+use std::{io::Read, path::Path};
 
 use serde::{Deserialize, Serialize};
 
-use crate::{create_or_truncate, ErrorIdentity};
+use crate::{write_atomic, ErrorIdentity};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct IdentityList(Vec<Identity>);
@@ -22,8 +22,7 @@ impl IdentityList {
     pub fn save(&self, certdir: &Path) -> Result<(), ErrorIdentity> {
         let trustpath = certdir.join(Self::IDLIST_FILE);
         let buf = bincode::serialize(self)?;
-        let mut file = create_or_truncate(&trustpath)?;
-        file.write_all(&buf)?;
+        write_atomic(&trustpath, &buf)?;
         Ok(())
     }
 
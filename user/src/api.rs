@@ -1,14 +1,27 @@
 use std::collections::HashSet;
-use std::fs::{File, read_dir, remove_file};
+use std::fs::{create_dir_all, read_dir, remove_dir_all, remove_file, rename, File};
+use std::io::{Read, Write};
+use std::path::PathBuf;
 
 use sha2::{Digest, Sha256};
 use thiserror::Error;
 
 use crate::spec::service_v2::ServiceEntryV2;
-use crate::spec::{base_path, identity_path, load, load_header, save, APKey, Encryptor, EncryptorType, IdentityType, Serializable, ServiceType, SpecType};
+use crate::spec::{audit_path, backup_path, base_path, config_path, hooks_path, check_file, identity_path, legacy_path, load, lock_path, quarantine_path, snapshots_path, FileStatus, trash_info_path, trash_path, trash_retention, TrashType, load_attachment, load_attachment_data, save_attachment, save_file, write_atomic, APKey, AttachmentType, Encryptor, EncryptorType, IdentityType, Serializable, ServiceType, SpecType, Visibility, AuditOp};
 use crate::hash::{bin_to_str, TextMode};
-use crate::upgrade::check_upgrade;
+use crate::lock;
+use crate::hooks::{self, HookOp};
+use crate::git::git_path;
+use crate::audit;
+use crate::snapshot;
+use crate::search::SearchDoc;
+use crate::tagquery::{TagIndex, TagQuery};
+use crate::tagtree::{self, TagNode};
+use crate::storage::DirStorage;
+use crate::vault::Vault;
+use crate::warnings;
 
+const SECONDS_PER_DAY: u64 = 60 * 60 * 24;
 
 
 #[derive(Error, Debug)]
@@ -34,18 +47,45 @@ pub enum APError {
     #[error("Wrong spec type, wanted {0:?} but got {1:?}")]
     WrongSpecType(SpecType, SpecType),
     #[error("Spec version too old")]
-    VersionTooOld
+    VersionTooOld,
+    #[error("Corrupt file header")]
+    CorruptHeader,
+    #[error("Corrupt ciphertext")]
+    CorruptCiphertext,
+    #[error("Vault is locked by another process")]
+    Locked,
+    #[error("Can't change the vault while holding a read lock on it")]
+    LockUpgrade,
+    #[error("Entry {0} was changed by another process, reload and try again")]
+    Modified(String),
+    #[error("Hook {0} failed: {1}")]
+    Hook(String, String),
+    #[error("{0}")]
+    Git(String),
+    #[error("{0}")]
+    Config(String),
+    #[error("{0}")]
+    Backup(String),
+    #[error("{0}")]
+    Import(String),
+    #[error("{0}")]
+    Kdbx(String),
+    #[error("{0}")]
+    Export(String)
 }
 
 
+fn vault() -> Vault<DirStorage> {
+    Vault::new(DirStorage::new(base_path()))
+}
+
 fn exists_int(key: &[u8], name: &str) -> bool {
-    EncryptorType::full_path(key, name).exists()
+    vault().exists(key, name).unwrap_or(false)
 }
 
-pub fn exists(pass: &str, name: &str) -> bool {
-    load_id(pass)
-        .map(|id| exists_int(&id.key(), name))
-        .unwrap_or(false)
+pub fn exists(pass: &str, name: &str) -> Result<bool, APError> {
+    let _lock = lock::shared()?;
+    Ok(exists_int(&load_id(pass)?.key(), name))
 }
 
 pub fn generate_pass(name: &str,
@@ -66,21 +106,8 @@ pub fn generate_pass(name: &str,
     bin_to_str(&pwbin, text_mode, len)
 }
 
-fn load_id(pass: &str) -> Result<IdentityType, APError> {
-    let key = EncryptorType::genkey(pass);
-    let idpath = identity_path(base_path());
-    if !idpath.exists() {
-        return Err(APError::NotInited);
-    }
-
-    let mut file = File::open(&idpath)?;
-    let header = load_header(&mut file)?;
-    if header.spec_type != IdentityType::spec_type() {
-        return Err(APError::WrongSpecType(IdentityType::spec_type(), header.spec_type));
-    }
-    check_upgrade::<EncryptorType>(&idpath, &key)?;
-    let mut file = File::open(&idpath)?;
-    let id = load::<IdentityType, EncryptorType>(&mut file, &key)?;
+pub(crate) fn load_id(pass: &str) -> Result<IdentityType, APError> {
+    let id = vault().load_id(pass)?;
     if !id.sanity_check() {
         return Err(APError::PasswordIncorrect);
     }
@@ -89,23 +116,15 @@ fn load_id(pass: &str) -> Result<IdentityType, APError> {
 
 fn load_entry(name: &str, pass: &str) -> Result<(ServiceType, APKey), APError> {
     let key = load_id(pass)?.key();
-    if !exists_int(&key, name) {
-        return Err(APError::NotExist(name.to_owned()));
-    }
+    Ok((load_entry_key(&key, name)?, key))
+}
 
-    let filename = EncryptorType::full_path(&key, name);
-    let mut file = File::open(&filename)?;
-    let header = load_header(&mut file)?;
-    if header.spec_type != ServiceType::spec_type() {
-        return Err(APError::WrongSpecType(ServiceType::spec_type(), header.spec_type));
-    }
-    check_upgrade::<EncryptorType>(&filename, &key)?;
-    let mut file = File::open(&filename)?;
-    let entry = load::<ServiceType, EncryptorType>(&mut file, &key)?;
+fn load_entry_key(key: &[u8], name: &str) -> Result<ServiceType, APError> {
+    let entry = vault().load_service(key, name)?;
     if !entry.sanity_check() {
         return Err(APError::PasswordIncorrect);
     }
-    Ok((entry, key))
+    Ok(entry)
 }
 
 pub fn init<T: AsRef<str>>(
@@ -120,10 +139,13 @@ pub fn init<T: AsRef<str>>(
     }
 
     std::fs::create_dir_all(base_path())?;
+    let _lock = lock::exclusive()?;
+    if idpath.exists() {
+        return Err(APError::AlreadyInited);
+    }
 
     let id = IdentityType::new(name, &key, kvs);
-    let mut file = File::create(idpath)?;
-    save(&mut file, &key, &id)?;
+    vault().save_id(pass, &id)?;
     Ok(id)
 }
 
@@ -136,6 +158,40 @@ pub fn new<T: AsRef<str>>(
     tags: &[T],
     service_pass: Option<&str>) -> Result<ServiceType, APError>
 {
+    let kvs: Vec<(&str, &str, Visibility)> = kvs.iter().map(|(k, v)| (k.as_ref(), v.as_ref(), Visibility::Plain)).collect();
+    let tags: Vec<&str> = tags.iter().map(|t| t.as_ref()).collect();
+    hooks::wrap(HookOp::New, name, || new_int(name, pass, text_mode, len, &kvs, &tags, service_pass, &[]))
+}
+
+/// A new service with each kv at its own visibility and its attachments, the way an
+/// import brings one in. The attachments are written first and the service last, so
+/// if anything fails nothing of it is left behind.
+#[allow(clippy::too_many_arguments)]
+pub fn new_with_attachments(
+    name: &str,
+    pass: &str,
+    text_mode: &TextMode,
+    len: u8,
+    kvs: &[(&str, &str, Visibility)],
+    tags: &[&str],
+    service_pass: Option<&str>,
+    attachments: &[(String, Vec<u8>)]) -> Result<ServiceType, APError>
+{
+    hooks::wrap(HookOp::New, name, || new_int(name, pass, text_mode, len, kvs, tags, service_pass, attachments))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn new_int(
+    name: &str,
+    pass: &str,
+    text_mode: &TextMode,
+    len: u8,
+    kvs: &[(&str, &str, Visibility)],
+    tags: &[&str],
+    service_pass: Option<&str>,
+    attachments: &[(String, Vec<u8>)]) -> Result<ServiceType, APError>
+{
+    let _lock = lock::exclusive()?;
     let key = load_id(pass)?.key();
 
     if exists_int(&key, name) {
@@ -147,27 +203,48 @@ pub fn new<T: AsRef<str>>(
         Some(s) => s.to_string()
     };
 
-    let entry = ServiceType::new(
+    let mut entry = ServiceType::new::<&str>(
         name,
         &password,
         0u8,
-        kvs,
+        &[],
         tags,
         len,
         text_mode
     );
-    let path = base_path();
-    std::fs::create_dir_all(path)?;
-    let full_path = EncryptorType::full_path(&key, entry.get_name());
-    let mut file = File::create(full_path)?;
-    save(&mut file, &key, &entry)?;
+    for (k, v, visibility) in kvs.iter() {
+        entry.set_kvs(&[(k, v)], *visibility, false);
+    }
+
+    let mut written = vec![];
+    let saved = attachments.iter()
+        .try_for_each(|(attachment, data)| {
+            let path = attachment_path(&key, name, attachment);
+            if path.exists() {
+                return Err(APError::Exists(attachment.to_owned()));
+            }
+            write_atomic(&path, |file| save_attachment(file, &key, name, attachment, &mut data.as_slice()))?;
+            written.push(path);
+            Ok(())
+        })
+        .and_then(|_| snapshot::take(&key, AuditOp::New, &[name]))
+        .and_then(|_| vault().save_service(&key, &entry));
+    if let Err(e) = saved {
+        for path in written {
+            let _ = remove_file(path);
+        }
+        return Err(e);
+    }
+    audit::record(&key, AuditOp::New, name);
     Ok(entry)
 }
 
 pub fn get(name: &str,
            pass: &str,
            clipboard: bool) -> Result<Option<String>, APError> {
-    let (entry, _key) = load_entry(&name, &pass)?;
+    let _lock = lock::shared()?;
+    let (entry, key) = load_entry(&name, &pass)?;
+    audit::record(&key, AuditOp::Get, name);
     Ok(match entry.get_pass(clipboard) {
         Some(pass) => Some(pass.to_string()),
         None => None
@@ -176,31 +253,97 @@ pub fn get(name: &str,
 
 pub fn get_all(name: &str,
                pass: &str) -> Result<ServiceType, APError> {
-    load_entry(name, pass).map(|(entry, _k)| entry)
+    let _lock = lock::shared()?;
+    let (entry, key) = load_entry(name, pass)?;
+    audit::record(&key, AuditOp::GetAll, name);
+    Ok(entry)
+}
+
+/// Callers that showed an entry before asking for a change pass the modify time they
+/// showed as seen. If it was changed since, by another window or process, the change
+/// fails rather than silently dropping that update. None when read and change are one call.
+fn check_seen(name: &str, modify_time: u64, seen: Option<u64>) -> Result<(), APError> {
+    match seen {
+        Some(seen) if seen != modify_time => Err(APError::Modified(name.to_owned())),
+        _ => Ok(())
+    }
+}
+
+/// Write back an entry, keeping what it was as a snapshot of op
+fn save_entry(key: &[u8], entry: &ServiceType, op: AuditOp) -> Result<(), APError> {
+    snapshot::take(key, op, &[entry.get_name()])?;
+    vault().save_service(key, entry)
 }
 
 pub fn set_kvs(name: &str,
                pass: &str,
                kvs: &[(&str, &str)],
-               reset: bool) -> Result<(), APError> {
+               visibility: Visibility,
+               reset: bool,
+               seen: Option<u64>) -> Result<(), APError> {
+    hooks::wrap(HookOp::SetKv, name, || set_kvs_int(name, pass, kvs, visibility, reset, seen))
+}
+
+fn set_kvs_int(name: &str,
+               pass: &str,
+               kvs: &[(&str, &str)],
+               visibility: Visibility,
+               reset: bool,
+               seen: Option<u64>) -> Result<(), APError> {
+    let _lock = lock::exclusive()?;
     let (mut entry, key) = load_entry(&name, &pass)?;
-    entry.set_kvs(kvs, reset);
-    let full_path = EncryptorType::full_path(&key, entry.get_name());
-    let mut file = File::create(full_path)?;
-    save(&mut file, &key, &entry)?;
+    check_seen(name, entry.modify_time(), seen)?;
+    entry.set_kvs(kvs, visibility, reset);
+    save_entry(&key, &entry, AuditOp::SetKvs)?;
+    audit::record(&key, AuditOp::SetKvs, name);
+    Ok(())
+}
+
+pub fn set_kv_visibility(name: &str,
+                         pass: &str,
+                         kvkey: &str,
+                         visibility: Visibility,
+                         seen: Option<u64>) -> Result<(), APError> {
+    let _lock = lock::exclusive()?;
+    let (mut entry, key) = load_entry(name, pass)?;
+    check_seen(name, entry.modify_time(), seen)?;
+    if !entry.set_kv_visibility(kvkey, visibility) {
+        return Err(APError::NotExist(kvkey.to_owned()));
+    }
+    save_entry(&key, &entry, AuditOp::SetKvVisibility)?;
+    audit::record(&key, AuditOp::SetKvVisibility, name);
+    Ok(())
+}
+
+pub fn remove_kvs(name: &str, pass: &str, keys: &[&str], seen: Option<u64>) -> Result<(), APError> {
+    let _lock = lock::exclusive()?;
+    let (mut entry, key) = load_entry(name, pass)?;
+    check_seen(name, entry.modify_time(), seen)?;
+    entry.remove_kvs(keys);
+    save_entry(&key, &entry, AuditOp::RemoveKvs)?;
+    audit::record(&key, AuditOp::RemoveKvs, name);
     Ok(())
 }
 
 pub fn set_tags<S: AsRef<str>>(name: &str,
                                pass: &str,
                                tags: &[S],
-                               reset: bool) -> Result<(), APError> {
+                               reset: bool,
+                               seen: Option<u64>) -> Result<(), APError> {
+    hooks::wrap(HookOp::SetTags, name, || set_tags_int(name, pass, tags, reset, seen))
+}
 
+fn set_tags_int<S: AsRef<str>>(name: &str,
+                               pass: &str,
+                               tags: &[S],
+                               reset: bool,
+                               seen: Option<u64>) -> Result<(), APError> {
+    let _lock = lock::exclusive()?;
     let (mut entry, key) = load_entry(&name, &pass)?;
+    check_seen(name, entry.modify_time(), seen)?;
     entry.set_tags(tags, reset);
-    let full_path = EncryptorType::full_path(&key, entry.get_name());
-    let mut file = File::create(full_path)?;
-    save(&mut file, &key, &entry)?;
+    save_entry(&key, &entry, AuditOp::SetTags)?;
+    audit::record(&key, AuditOp::SetTags, name);
     Ok(())
 }
 
@@ -209,47 +352,52 @@ pub fn empty() -> Result<bool, APError> {
     if !dir.exists() {
         return Ok(true);
     }
-    Ok(read_dir(dir)?
-        .nth(0)
-        .is_none())
-}
-
-fn has_tags(service_tags: &[String], tags: &[String]) -> bool {
-    tags.iter().fold(true, |valid, t| valid && service_tags.contains(t))
+    // Hooks can be set up before init
+    let lockfile = lock_path(&dir);
+    let hooksdir = hooks_path(&dir);
+    for entry in read_dir(&dir)? {
+        let path = entry?.path();
+        if path != lockfile && path != hooksdir {
+            return Ok(false);
+        }
+    }
+    Ok(true)
 }
 
 pub fn list(pass: &str, tags: &[&str]) -> Result<Vec<String>, APError> {
+    let _lock = lock::shared()?;
     Ok(list_all(pass, tags)?.iter().map(|s| s.name().to_owned()).collect())
 }
 
+/// Services with all of tags, skipping any that can't be read
 pub fn list_all(pass: &str, tags: &[&str]) -> Result<Vec<ServiceType>, APError> {
-    let dir = base_path();
-    let key = load_id(pass)?.key();
-    let tags: Vec<String> = tags.iter().map(|t| (*t).to_owned()).collect();
-
-    let mut services: Vec<ServiceType> = vec![];
-    for filename in &crate::spec::list(&dir, Some(SpecType::Service), None)? {
-        check_upgrade::<EncryptorType>(filename, &key)?;
-        let mut file = File::open(filename)?;
-        let entry = load::<ServiceType, EncryptorType>(&mut file, &key)?;
-        if has_tags(&entry.get_tags(), &tags) {
-            services.push(entry);
-        }
-    }
-    services.sort_by(|s1, s2| s1.name().cmp(s2.name()));
-    Ok(services)
+    list_checked(pass, tags).map(|(services, _)| services)
+}
 
+/// Same as `list_all` but also returns the files it had to skip because they
+/// couldn't be read. `fsck` tells what's wrong with them.
+pub fn list_checked(pass: &str, tags: &[&str]) -> Result<(Vec<ServiceType>, Vec<PathBuf>), APError> {
+    list_query(pass, &TagQuery::all_of(tags))
 }
 
-pub fn list_tags(pass: &str) -> Result<Vec<String>, APError> {
-    let dir = base_path();
+/// Services whose tags match query, along with the files that couldn't be read
+pub fn list_query(pass: &str, query: &TagQuery) -> Result<(Vec<ServiceType>, Vec<PathBuf>), APError> {
+    let _lock = lock::shared()?;
     let key = load_id(pass)?.key();
 
+    let (services, corrupt) = vault().list_services(&key)?;
+    let index = TagIndex::new(services.iter().flat_map(|entry| entry.get_tags()));
+    let query = index.compile(query);
+    let services = services.into_iter()
+        .filter(|entry| query.matches_bitmap(&index.bitmap(entry.get_tags())))
+        .collect();
+    let dir = base_path();
+    Ok((services, corrupt.iter().map(|name| dir.join(name)).collect()))
+}
+
+pub fn list_tags(pass: &str) -> Result<Vec<String>, APError> {
     let mut tags = HashSet::new();
-    for filename in &crate::spec::list(&dir, Some(SpecType::Service), None)? {
-        check_upgrade::<EncryptorType>(filename, &key)?;
-        let mut file = File::open(filename)?;
-        let entry = load::<ServiceType, EncryptorType>(&mut file, &key)?;
+    for entry in list_all(pass, &[])? {
         for tag in entry.get_tags() {
             tags.insert(tag.to_owned());
         }
@@ -259,11 +407,83 @@ pub fn list_tags(pass: &str) -> Result<Vec<String>, APError> {
     Ok(tagvec)
 }
 
+/// Tags as a hierarchy of folders, with how many services are under each
+pub fn list_tag_tree(pass: &str) -> Result<Vec<TagNode>, APError> {
+    let services = list_all(pass, &[])?;
+    Ok(tagtree::build(services.iter().map(|entry| entry.get_tags())))
+}
+
+/// Replace from with to on every service tagged with any of them, or remove them
+/// if to is None, as one batch along with any max age policies on them. Services
+/// that can't be read are left alone. Returns how many services changed.
+fn retag<S: AsRef<str>>(pass: &str, from: &[S], to: Option<&str>, merge: bool) -> Result<usize, APError> {
+    let _lock = lock::exclusive()?;
+    let mut id = load_id(pass)?;
+    let key = id.key();
+    let mut vault = vault();
+
+    let (services, _) = vault.list_services(&key)?;
+    if let Some(to) = to {
+        if !merge && services.iter().any(|entry| entry.get_tags().iter().any(|t| t == to)) {
+            return Err(APError::Exists(to.to_owned()));
+        }
+    }
+    let changed: Vec<ServiceType> = services.into_iter()
+        .filter_map(|mut entry| entry.replace_tags(from, to).then_some(entry))
+        .collect();
+    let names: Vec<&str> = changed.iter().map(|entry| entry.name()).collect();
+    snapshot::take(&key, AuditOp::SetTags, &names)?;
+    let id_changed = id.replace_tag_max_ages(from, to);
+    if id_changed {
+        vault.save_id_and_services(pass, &id, &changed)?;
+    } else if changed.is_empty() {
+        let names: Vec<&str> = from.iter().map(|t| t.as_ref()).collect();
+        return Err(APError::NotExist(names.join(", ")));
+    } else {
+        vault.save_services(&key, &changed)?;
+    }
+    for name in names.iter() {
+        audit::record(&key, AuditOp::SetTags, name);
+    }
+    // The max age policies are the vault's own rather than any service's
+    if id_changed {
+        audit::record(&key, AuditOp::SetMaxAge, "");
+    }
+    Ok(changed.len())
+}
+
+/// Rename a tag on every service. Renaming onto a tag that's already in use is
+/// a merge and has to be asked for with `merge_tags`.
+pub fn rename_tag(pass: &str, from: &str, to: &str) -> Result<usize, APError> {
+    retag(pass, &[from], Some(to), false)
+}
+
+/// Replace each of tags with into, which may already be in use
+pub fn merge_tags<S: AsRef<str>>(pass: &str, tags: &[S], into: &str) -> Result<usize, APError> {
+    let tags: Vec<&str> = tags.iter().map(|t| t.as_ref()).filter(|t| *t != into).collect();
+    retag(pass, &tags, Some(into), true)
+}
+
+/// Remove a tag from every service
+pub fn delete_tag(pass: &str, tag: &str) -> Result<usize, APError> {
+    retag(pass, &[tag], None, false)
+}
+
 pub fn upgrade(name: &str,
                pass: &str,
-               service_pass: Option<&str>) -> Result<(String, String), APError> {
+               service_pass: Option<&str>,
+               seen: Option<u64>) -> Result<(String, String), APError> {
+    hooks::wrap(HookOp::Upgrade, name, || upgrade_int(name, pass, service_pass, seen))
+}
+
+fn upgrade_int(name: &str,
+               pass: &str,
+               service_pass: Option<&str>,
+               seen: Option<u64>) -> Result<(String, String), APError> {
+    let _lock = lock::exclusive()?;
     match load_entry(&name, &pass) {
         Ok((mut entry, key)) => {
+            check_seen(name, entry.modify_time(), seen)?;
 
             let new_pass = match service_pass {
                 Some(s) => s.to_string(),
@@ -274,9 +494,8 @@ pub fn upgrade(name: &str,
             };
             let old_pass = entry.get_pass(false).unwrap().to_string();
             entry.set_pass(&new_pass);
-            let full_path = EncryptorType::full_path(&key, entry.get_name());
-            let mut file = File::create(full_path)?;
-            save(&mut file, &key, &entry)?;
+            save_entry(&key, &entry, AuditOp::Upgrade)?;
+            audit::record(&key, AuditOp::Upgrade, name);
             Ok((old_pass, new_pass))
         },
         Err(s) => {
@@ -285,54 +504,682 @@ pub fn upgrade(name: &str,
     }
 }
 
+/// What a bulk operation does to each service it's run on
+#[derive(Debug, Clone)]
+pub enum BulkAction {
+    /// Generate a new password
+    Upgrade,
+    SetKvs(Vec<(String, String)>, Visibility, bool),
+    SetTags(Vec<String>, bool),
+    /// Move to the trash
+    Delete
+}
+
+impl BulkAction {
+    fn run(&self, name: &str, pass: &str) -> Result<BulkOutcome, APError> {
+        match self {
+            BulkAction::Upgrade => upgrade(name, pass, None, None).map(Some),
+            BulkAction::SetKvs(kvs, visibility, reset) => {
+                let kvs: Vec<(&str, &str)> = kvs.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+                set_kvs(name, pass, &kvs, *visibility, *reset, None).map(|_| None)
+            }
+            BulkAction::SetTags(tags, reset) => set_tags(name, pass, tags, *reset, None).map(|_| None),
+            BulkAction::Delete => delete(name, pass).map(|_| None)
+        }
+    }
+}
+
+/// What a bulk action did to a service beyond succeeding, the old and new password
+/// for an upgrade
+pub type BulkOutcome = Option<(String, String)>;
+
+/// Each service a bulk action ran on and how it went
+pub type BulkResults = Vec<(String, Result<BulkOutcome, APError>)>;
+
+/// Run action on each of names, usually what `list_query` previewed, holding the
+/// lock throughout so nothing changes in between. A service failing doesn't stop
+/// the rest, each one gets its own result.
+pub fn bulk<S: AsRef<str>>(pass: &str,
+                           names: &[S],
+                           action: &BulkAction) -> Result<BulkResults, APError> {
+    let _lock = lock::exclusive()?;
+    load_id(pass)?;
+    Ok(names.iter()
+        .map(|name| (name.as_ref().to_owned(), action.run(name.as_ref(), pass)))
+        .collect())
+}
+
+/// A service whose password is older than the max age its policy allows
+pub struct StaleEntry {
+    pub service: ServiceType,
+    /// Password age in days
+    pub age: u64,
+    /// Max password age in days, from the service or its tags
+    pub max_age: u32
+}
+
+/// A max age set on the service wins, otherwise the strictest of its tags applies.
+fn max_age(entry: &ServiceType, tag_max_ages: &[(String, u32)]) -> Option<u32> {
+    entry.get_max_age().or_else(|| {
+        tag_max_ages.iter()
+            .filter(|(tag, _)| entry.get_tags().contains(tag))
+            .map(|(_, days)| *days)
+            .min()
+    })
+}
+
+fn stale_entry(entry: ServiceType, tag_max_ages: &[(String, u32)]) -> Option<StaleEntry> {
+    let max_age = max_age(&entry, tag_max_ages)?;
+    let age = entry.password_age();
+    if age <= max_age as u64 * SECONDS_PER_DAY {
+        return None;
+    }
+    Some(StaleEntry { age: age / SECONDS_PER_DAY, max_age, service: entry })
+}
+
+pub fn set_max_age(name: &str, pass: &str, days: Option<u32>) -> Result<(), APError> {
+    let _lock = lock::exclusive()?;
+    let (mut entry, key) = load_entry(name, pass)?;
+    entry.set_max_age(days);
+    save_entry(&key, &entry, AuditOp::SetMaxAge)?;
+    audit::record(&key, AuditOp::SetMaxAge, name);
+    Ok(())
+}
+
+pub fn set_tag_max_age(pass: &str, tag: &str, days: Option<u32>) -> Result<(), APError> {
+    let _lock = lock::exclusive()?;
+    let mut id = load_id(pass)?;
+    id.set_tag_max_age(tag, days);
+    vault().save_id(pass, &id)
+}
+
+/// Services overdue for a new password, oldest password first
+pub fn stale(pass: &str) -> Result<Vec<StaleEntry>, APError> {
+    let _lock = lock::shared()?;
+    let id = load_id(pass)?;
+    let mut stale: Vec<StaleEntry> = list_all(pass, &[])?
+        .into_iter()
+        .filter_map(|entry| stale_entry(entry, id.get_tag_max_ages()))
+        .collect();
+    stale.sort_by_key(|s| std::cmp::Reverse(s.age));
+    Ok(stale)
+}
+
+/// A service matching a search, higher scores are better matches
+pub struct SearchHit {
+    pub service: ServiceType,
+    pub score: u32
+}
+
+/// Services matching every word of query, best match first. Names, tags, keys
+/// and plain values are searched, never passwords or concealed values.
+pub fn search(pass: &str, query: &str) -> Result<Vec<SearchHit>, APError> {
+    let _lock = lock::shared()?;
+    let mut hits: Vec<SearchHit> = list_all(pass, &[])?
+        .into_iter()
+        .filter_map(|service| {
+            SearchDoc::new(&service).score(query).map(|score| SearchHit { service, score })
+        })
+        .collect();
+    hits.sort_by(|h1, h2| h2.score.cmp(&h1.score).then_with(|| h1.service.name().cmp(h2.service.name())));
+    Ok(hits)
+}
+
+/// A file in the vault and what `fsck` made of it
+pub struct FsckEntry {
+    pub path: PathBuf,
+    pub status: FileStatus,
+    /// Where the file went if it was quarantined
+    pub quarantined: Option<PathBuf>
+}
+
+fn check_path(path: PathBuf, key: &[u8]) -> Result<(PathBuf, FileStatus), APError> {
+    let mut file = File::open(&path)?;
+    let status = check_file(&mut file, key)?;
+    Ok((path, status))
+}
+
+/// Check every file in the vault without changing any of them. With quarantine
+/// set, anything unreadable is moved under the quarantine directory so the rest
+/// of the vault keeps working. A leftover legacy directory is only reported since
+/// the encryptor upgrade needs it to carry on.
+pub fn fsck(pass: &str, quarantine: bool) -> Result<Vec<FsckEntry>, APError> {
+    let _lock = match quarantine {
+        true => lock::exclusive()?,
+        false => lock::shared()?
+    };
+    // Nothing gets quarantined with the wrong password
+    let key = load_id(pass)?.key();
+    let dir = base_path();
+
+    let mut files = vec![];
+    for entry in read_dir(&dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            if path == trash_path(&dir) {
+                for trashdir in read_dir(&path)? {
+                    let trashdir = trashdir?.path();
+                    if !trashdir.is_dir() {
+                        files.push((trashdir, FileStatus::Unexpected));
+                        continue;
+                    }
+                    for trashfile in read_dir(&trashdir)? {
+                        files.push(check_path(trashfile?.path(), &key)?);
+                    }
+                }
+            } else if path == legacy_path(&dir) {
+                files.push((path, FileStatus::Legacy));
+            } else if path != quarantine_path(&dir) && path != backup_path(&dir) && path != hooks_path(&dir)
+                && path != audit_path(&dir) && path != snapshots_path(&dir) && path != git_path(&dir) {
+                files.push((path, FileStatus::Unexpected));
+            }
+        } else if path == lock_path(&dir) || path == config_path(&dir) {
+            continue;
+        } else if path == identity_path(&dir) {
+            // The identity is the one file encrypted with the password itself
+            files.push(check_path(path, &EncryptorType::genkey(pass))?);
+        } else if entry.file_name().to_string_lossy().starts_with('.') {
+            // Temp files from an interrupted write
+            files.push((path, FileStatus::Unexpected));
+        } else {
+            files.push(check_path(path, &key)?);
+        }
+    }
+    files.sort_by(|f1, f2| f1.0.cmp(&f2.0));
+
+    let quarantine_dir = quarantine_path(&dir).join(crate::spec::now().to_string());
+    let mut report = vec![];
+    for (path, status) in files {
+        let quarantined = match status {
+            FileStatus::Valid(_, _) | FileStatus::Legacy => None,
+            _ if !quarantine => None,
+            _ => {
+                let dest = quarantine_dir.join(path.strip_prefix(&dir).unwrap_or(&path));
+                create_dir_all(dest.parent().unwrap_or(&quarantine_dir))?;
+                rename(&path, &dest)?;
+                Some(dest)
+            }
+        };
+        report.push(FsckEntry { path, status, quarantined });
+    }
+    Ok(report)
+}
+
+/// Move a service and its attachments to the trash. They can be brought back
+/// with `restore` until the trash is emptied or they pass the retention.
 pub fn delete(name: &str, pass: &str) -> Result<(), APError> {
+    hooks::wrap(HookOp::Delete, name, || delete_int(name, pass))
+}
+
+fn delete_int(name: &str, pass: &str) -> Result<(), APError> {
+    let _lock = lock::exclusive()?;
     let key = load_id(pass)?.key();
     if !exists_int(&key, name) {
         return Err(APError::NotExist(name.to_owned()));
     }
-    remove_file(EncryptorType::full_path(&key, name))?;
+    let attachments = load_attachments(&key, name)?;
+    let names: Vec<&str> = attachments.iter().map(|(a, _)| a.name()).collect();
+    let trash = TrashType::new(name, &names);
+    let dir = trash_dir(&key, &trash);
+    create_dir_all(&dir)?;
+
+    snapshot::take(&key, AuditOp::Delete, &[name])?;
+    // The record goes first so a partially moved service can still be restored
+    save_file(trash_info_path(&dir), &key, &trash)?;
+    rename(EncryptorType::full_path(&key, name), dir.join(EncryptorType::filename(&key, name)))?;
+    for (_attachment, path) in attachments {
+        let filename = path.file_name().unwrap().to_owned();
+        rename(&path, dir.join(filename))?;
+    }
+    audit::record(&key, AuditOp::Delete, name);
+    // The service is in the trash either way, older ones can go next time
+    if let Err(e) = purge_trash(&key) {
+        warnings::push(format!("Unable to purge the trash: {}", e));
+    }
+    Ok(())
+}
+
+/// A directory in the trash for a deletion. Deleting the same name twice in a second
+/// gives the same storage name, so the second gets a count after it.
+fn trash_dir(key: &[u8], trash: &TrashType) -> PathBuf {
+    let trashdir = trash_path(base_path());
+    let name = trash.storage_name();
+    let mut dir = trashdir.join(EncryptorType::filename(key, &name));
+    let mut count = 1;
+    while dir.exists() {
+        dir = trashdir.join(EncryptorType::filename(key, &format!("{}\0{}", name, count)));
+        count += 1;
+    }
+    dir
+}
+
+fn load_trash(key: &[u8]) -> Result<Vec<(TrashType, PathBuf)>, APError> {
+    let dir = trash_path(base_path());
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+    let mut trash = vec![];
+    for entry in read_dir(dir)? {
+        let path = entry?.path();
+        let info = trash_info_path(&path);
+        if !info.exists() {
+            continue;
+        }
+        let mut file = File::open(&info)?;
+        // Deletions in the same second are told apart by when their record was written
+        let written = file.metadata()?.modified()?;
+        trash.push((load::<TrashType, EncryptorType>(&mut file, key)?, path, written));
+    }
+    trash.sort_by_key(|(t, _, written)| std::cmp::Reverse((t.delete_time(), *written)));
+    let trash = trash.into_iter().map(|(t, path, _)| (t, path)).collect();
+    Ok(trash)
+}
+
+/// Permanently remove trashed services older than the retention
+fn purge_trash(key: &[u8]) -> Result<(), APError> {
+    let retention = trash_retention();
+    if retention == 0 {
+        return Ok(());
+    }
+    for (trash, path) in load_trash(key)? {
+        if trash.age() / SECONDS_PER_DAY >= retention {
+            remove_dir_all(path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Deleted services, most recently deleted first
+pub fn list_trash(pass: &str) -> Result<Vec<TrashType>, APError> {
+    // Exclusive since listing purges expired entries
+    let _lock = lock::exclusive()?;
+    let key = load_id(pass)?.key();
+    purge_trash(&key)?;
+    Ok(load_trash(&key)?.into_iter().map(|(t, _)| t).collect())
+}
+
+/// Bring back the most recent deletion of name with its attachments
+pub fn restore(name: &str, pass: &str) -> Result<(), APError> {
+    let _lock = lock::exclusive()?;
+    let key = load_id(pass)?.key();
+    if exists_int(&key, name) {
+        return Err(APError::Exists(name.to_owned()));
+    }
+    let (_trash, dir) = load_trash(&key)?
+        .into_iter()
+        .find(|(t, _)| t.name() == name)
+        .ok_or(APError::NotExist(name.to_owned()))?;
+    let info = trash_info_path(&dir);
+    for entry in read_dir(&dir)? {
+        let path = entry?.path();
+        if path != info {
+            rename(&path, base_path().join(path.file_name().unwrap()))?;
+        }
+    }
+    remove_dir_all(dir)?;
+    audit::record(&key, AuditOp::Restore, name);
+    Ok(())
+}
+
+/// Permanently remove everything in the trash, returning how many services were removed
+pub fn empty_trash(pass: &str) -> Result<usize, APError> {
+    let _lock = lock::exclusive()?;
+    let key = load_id(pass)?.key();
+    let trash = load_trash(&key)?;
+    for (_trash, path) in trash.iter() {
+        remove_dir_all(path)?;
+    }
+    Ok(trash.len())
+}
+
+/// Where the attachment is, or would go if there isn't one
+fn attachment_path(key: &[u8], name: &str, attachment: &str) -> PathBuf {
+    base_path().join(AttachmentType::filename(key, name, attachment))
+}
+
+/// Attachments of name. Only its own are decrypted.
+fn load_attachments(key: &[u8], name: &str) -> Result<Vec<(AttachmentType, PathBuf)>, APError> {
+    let prefix = format!("{}.", EncryptorType::filename(key, name));
+    let mut attachments = vec![];
+    for (filename, _) in crate::spec::scan(base_path())? {
+        if !filename.file_name().unwrap_or_default().to_string_lossy().starts_with(&prefix) {
+            continue;
+        }
+        let mut file = File::open(&filename)?;
+        let attachment = load_attachment(&mut file, key)?;
+        if attachment.get_service() == name {
+            attachments.push((attachment, filename));
+        }
+    }
+    attachments.sort_by(|a1, a2| a1.0.name().cmp(a2.0.name()));
+    Ok(attachments)
+}
+
+pub fn attach<R: Read>(name: &str,
+                       pass: &str,
+                       attachment: &str,
+                       reader: &mut R) -> Result<AttachmentType, APError> {
+    let _lock = lock::exclusive()?;
+    let (_entry, key) = load_entry(name, pass)?;
+    let path = attachment_path(&key, name, attachment);
+    if path.exists() {
+        return Err(APError::Exists(attachment.to_owned()));
+    }
+
+    write_atomic(&path, |file| save_attachment(file, &key, name, attachment, reader))
+}
+
+pub fn list_attachments(name: &str, pass: &str) -> Result<Vec<AttachmentType>, APError> {
+    let _lock = lock::shared()?;
+    let (_entry, key) = load_entry(name, pass)?;
+    Ok(load_attachments(&key, name)?.into_iter().map(|(attachment, _)| attachment).collect())
+}
+
+pub fn get_attachment<W: Write>(name: &str,
+                                pass: &str,
+                                attachment: &str,
+                                writer: &mut W) -> Result<u64, APError> {
+    let _lock = lock::shared()?;
+    let (_entry, key) = load_entry(name, pass)?;
+    let path = attachment_path(&key, name, attachment);
+    if !path.exists() {
+        return Err(APError::NotExist(attachment.to_owned()));
+    }
+    let mut file = File::open(&path)?;
+    load_attachment_data(&mut file, &key, writer)
+}
+
+pub fn remove_attachment(name: &str, pass: &str, attachment: &str) -> Result<(), APError> {
+    let _lock = lock::exclusive()?;
+    let (_entry, key) = load_entry(name, pass)?;
+    let path = attachment_path(&key, name, attachment);
+    if !path.exists() {
+        return Err(APError::NotExist(attachment.to_owned()));
+    }
+    remove_file(path)?;
     Ok(())
 }
 
 pub fn get_id(pass: &str) -> Result<IdentityType, APError> {
+    let _lock = lock::shared()?;
     load_id(pass)
 }
 
 pub fn set_kvs_id(
     pass: &str,
     kvs: &[(&str, &str)],
-    reset: bool) -> Result<(), APError>
+    visibility: Visibility,
+    reset: bool,
+    seen: Option<u64>) -> Result<(), APError>
 {
+    let _lock = lock::exclusive()?;
     let mut id = load_id(&pass)?;
-    id.set_kvs(kvs, reset);
-    let idpath = identity_path(base_path());
-    let key = EncryptorType::genkey(pass);
-    let mut file = File::create(idpath)?;
-    save(&mut file, &key, &id)?;
-    Ok(())
+    check_seen(id.name(), id.modify_time(), seen)?;
+    id.set_kvs(kvs, visibility, reset);
+    vault().save_id(pass, &id)
+}
+
+pub fn set_kv_visibility_id(pass: &str, kvkey: &str, visibility: Visibility, seen: Option<u64>) -> Result<(), APError> {
+    let _lock = lock::exclusive()?;
+    let mut id = load_id(pass)?;
+    check_seen(id.name(), id.modify_time(), seen)?;
+    if !id.set_kv_visibility(kvkey, visibility) {
+        return Err(APError::NotExist(kvkey.to_owned()));
+    }
+    vault().save_id(pass, &id)
+}
+
+pub fn remove_kvs_id(pass: &str, keys: &[&str], seen: Option<u64>) -> Result<(), APError> {
+    let _lock = lock::exclusive()?;
+    let mut id = load_id(pass)?;
+    check_seen(id.name(), id.modify_time(), seen)?;
+    id.remove_kvs(keys);
+    vault().save_id(pass, &id)
 }
 
 #[cfg(test)]
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
+    use crate::testvault::{TestVault, PASS};
 
     #[test]
     fn test_tag_filter() {
-        assert!(has_tags(&[], &[]));
+        let none: &[String] = &[];
+        assert!(TagQuery::all_of(none).matches(none));
 
         let service_tags = vec!["TAG1".to_owned(), "TAG2".to_owned()];
-        assert!(has_tags(&service_tags, &[]));
+        assert!(TagQuery::all_of(none).matches(&service_tags));
 
         let service_tags = vec!["TAG1".to_owned(), "TAG2".to_owned()];
-        assert!(has_tags(&service_tags, &["TAG1".to_owned()]));
+        assert!(TagQuery::all_of(&["TAG1".to_owned()]).matches(&service_tags));
 
         let service_tags = vec!["TAG1".to_owned(), "TAG2".to_owned()];
-        assert!(has_tags(&service_tags, &service_tags));
+        assert!(TagQuery::all_of(&service_tags).matches(&service_tags));
 
         let service_tags = vec!["TAG1".to_owned(), "TAG2".to_owned()];
-        assert!(!has_tags(&service_tags, &["TAG3".to_owned()]));
+        assert!(!TagQuery::all_of(&["TAG3".to_owned()]).matches(&service_tags));
+
+        assert!(!TagQuery::all_of(&["TAG3".to_owned()]).matches(none));
+    }
+
+    #[test]
+    fn test_max_age_policy() {
+        let mut entry = ServiceType::new("svc", "pass", 0, &[("k", "v")], &["work", "cloud"], 16, &TextMode::NoWhiteSpace);
+        let policies = vec![("cloud".to_owned(), 30), ("home".to_owned(), 5), ("work".to_owned(), 90)];
+
+        assert_eq!(max_age(&entry, &[]), None);
+        assert_eq!(max_age(&entry, &policies), Some(30));
+
+        entry.set_max_age(Some(365));
+        assert_eq!(max_age(&entry, &policies), Some(365));
+
+        // A brand new password is never stale
+        assert!(stale_entry(entry, &policies).is_none());
+    }
+
+    #[test]
+    fn test_retag() {
+        let mut entry = ServiceType::new::<&str>("svc", "pass", 0, &[], &["aws", "work"], 16, &TextMode::NoWhiteSpace);
+        assert!(!entry.replace_tags(&["gcp"], Some("cloud")));
+        assert!(entry.replace_tags(&["aws", "gcp"], Some("work")));
+        assert_eq!(entry.get_tags(), ["work"]);
+        assert!(entry.replace_tags(&["work"], None));
+        assert!(entry.get_tags().is_empty());
+
+        let mut id = IdentityType::new::<&str>("me", &[0; 32], &[]);
+        id.set_tag_max_age("aws", Some(90));
+        id.set_tag_max_age("gcp", Some(30));
+        id.set_tag_max_age("cloud", Some(60));
+        assert!(id.replace_tag_max_ages(&["aws", "gcp"], Some("cloud")));
+        assert_eq!(id.get_tag_max_ages(), [("cloud".to_owned(), 30)]);
+        assert!(!id.replace_tag_max_ages(&["aws"], None));
+        assert!(id.replace_tag_max_ages(&["cloud"], None));
+        assert!(id.get_tag_max_ages().is_empty());
+    }
+
+    fn new_service(name: &str, kvs: &[(&str, &str)]) {
+        new(name, PASS, &TextMode::NoWhiteSpace, 16, kvs, &[], None).unwrap();
+    }
+
+    #[test]
+    fn test_retag_audit() {
+        let _vault = TestVault::new("retag-audit");
+        for name in ["mail", "shop", "bank"] {
+            new_service(name, &[]);
+        }
+        set_tags("mail", PASS, &["web"], false, None).unwrap();
+        set_tags("shop", PASS, &["web"], false, None).unwrap();
+        set_tag_max_age(PASS, "web", Some(90)).unwrap();
+        set_tag_max_age(PASS, "old", Some(30)).unwrap();
+        let since = audit::load(PASS, false).unwrap().records.len();
+        let recorded = || -> Vec<(String, AuditOp)> {
+            audit::load(PASS, false).unwrap().records.iter()
+                .skip(since)
+                .map(|r| (r.name().to_owned(), r.get_op()))
+                .collect()
+        };
+
+        rename_tag(PASS, "web", "online").unwrap();
+        let mut records = recorded();
+        records[..2].sort_by(|r1, r2| r1.0.cmp(&r2.0));
+        assert_eq!(records, [("mail".to_owned(), AuditOp::SetTags), ("shop".to_owned(), AuditOp::SetTags),
+                             ("".to_owned(), AuditOp::SetMaxAge)]);
+        // Only the policy had the tag, the vault's record is the only one
+        delete_tag(PASS, "old").unwrap();
+        assert_eq!(recorded()[3..], [("".to_owned(), AuditOp::SetMaxAge)]);
+    }
+
+    #[test]
+    fn test_trash() {
+        let _vault = TestVault::new("trash");
+        new_service("mail", &[("user", "first")]);
+        attach("mail", PASS, "note.txt", &mut "attached".as_bytes()).unwrap();
+        delete("mail", PASS).unwrap();
+        assert!(!exists(PASS, "mail").unwrap());
+        assert!(matches!(delete("mail", PASS), Err(APError::NotExist(_))));
+        // Deleted again within the same second, both deletions are kept
+        new_service("mail", &[("user", "second")]);
+        delete("mail", PASS).unwrap();
+        let trash = list_trash(PASS).unwrap();
+        assert_eq!(trash.len(), 2);
+        assert!(trash[0].get_attachments().is_empty());
+        assert_eq!(trash[1].get_attachments(), ["note.txt"]);
+
+        // The most recent deletion comes back first
+        restore("mail", PASS).unwrap();
+        assert_eq!(get_all("mail", PASS).unwrap().get_kv("user"), Some(("second", Visibility::Plain)));
+        assert!(matches!(restore("mail", PASS), Err(APError::Exists(_))));
+        delete("mail", PASS).unwrap();
+        assert_eq!(list_trash(PASS).unwrap().len(), 2);
+        assert!(matches!(restore("news", PASS), Err(APError::NotExist(_))));
+
+        assert_eq!(empty_trash(PASS).unwrap(), 2);
+        assert!(list_trash(PASS).unwrap().is_empty());
+        assert!(matches!(restore("mail", PASS), Err(APError::NotExist(_))));
+    }
+
+    #[test]
+    fn test_trash_attachments() {
+        let _vault = TestVault::new("trash-attachments");
+        new_service("mail", &[]);
+        attach("mail", PASS, "note.txt", &mut "attached".as_bytes()).unwrap();
+        delete("mail", PASS).unwrap();
+        restore("mail", PASS).unwrap();
+        let mut contents = vec![];
+        get_attachment("mail", PASS, "note.txt", &mut contents).unwrap();
+        assert_eq!(contents, b"attached");
+        assert!(list_trash(PASS).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_delete_purge_fails() {
+        let vault = TestVault::new("purge-fails");
+        // A deletion whose record won't open stops the trash from being purged
+        let bad = trash_path(&vault.dir).join("bad");
+        create_dir_all(&bad).unwrap();
+        std::fs::write(trash_info_path(&bad), b"not a record").unwrap();
+        warnings::take();
+
+        new_service("mail", &[]);
+        delete("mail", PASS).unwrap();
+        assert!(!exists(PASS, "mail").unwrap());
+        let warnings = warnings::take();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("Unable to purge the trash"));
+    }
 
-        assert!(!has_tags(&[], &["TAG3".to_owned()]));
+    #[test]
+    fn test_seen() {
+        let _vault = TestVault::new("seen");
+        new_service("mail", &[]);
+        let shown = get_all("mail", PASS).unwrap().modify_time();
+        let shown_id = get_id(PASS).unwrap().modify_time();
+        std::thread::sleep(std::time::Duration::from_millis(1100));
+        // Changed elsewhere after they were shown
+        set_kvs("mail", PASS, &[("user", "other")], Visibility::Plain, false, None).unwrap();
+        set_kvs_id(PASS, &[("email", "me@example.com")], Visibility::Plain, false, None).unwrap();
+
+        assert!(matches!(set_kvs("mail", PASS, &[("user", "me")], Visibility::Plain, false, Some(shown)),
+                         Err(APError::Modified(_))));
+        assert!(matches!(upgrade("mail", PASS, None, Some(shown)), Err(APError::Modified(_))));
+        assert!(matches!(remove_kvs_id(PASS, &["email"], Some(shown_id)), Err(APError::Modified(_))));
+        let entry = get_all("mail", PASS).unwrap();
+        assert_eq!(entry.get_kv("user"), Some(("other", Visibility::Plain)));
+        assert_eq!(entry.get_nonce(), 0);
+        assert!(get_id(PASS).unwrap().get_kvs().iter().any(|(k, _, _)| k == "email"));
+
+        set_tags("mail", PASS, &["web"], false, Some(entry.modify_time())).unwrap();
+        assert_eq!(get_all("mail", PASS).unwrap().get_tags(), ["web"]);
+    }
+
+    #[test]
+    fn test_attachment_files() {
+        let vault = TestVault::new("attachment-files");
+        new_service("mail", &[]);
+        new_service("bank", &[]);
+        let key = get_id(PASS).unwrap().key();
+        attach("mail", PASS, "new.txt", &mut "new".as_bytes()).unwrap();
+        attach("mail", PASS, "old.txt", &mut "old".as_bytes()).unwrap();
+        attach("bank", PASS, "statement.pdf", &mut "bank".as_bytes()).unwrap();
+        assert!(vault.dir.join(AttachmentType::filename(&key, "mail", "new.txt")).exists());
+        // Another service's attachments aren't even opened
+        std::fs::write(vault.dir.join(AttachmentType::filename(&key, "bank", "statement.pdf")), b"damaged").unwrap();
+
+        let names: Vec<String> = list_attachments("mail", PASS).unwrap().iter().map(|a| a.name().to_owned()).collect();
+        assert_eq!(names, vec!["new.txt", "old.txt"]);
+        let mut contents = vec![];
+        get_attachment("mail", PASS, "old.txt", &mut contents).unwrap();
+        assert_eq!(contents, b"old");
+        assert!(matches!(attach("mail", PASS, "old.txt", &mut "again".as_bytes()), Err(APError::Exists(_))));
+        remove_attachment("mail", PASS, "old.txt").unwrap();
+        assert_eq!(list_attachments("mail", PASS).unwrap().len(), 1);
+        assert!(list_attachments("bank", PASS).is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_bulk() {
+        let _vault = TestVault::new("bulk");
+        for (name, tag) in [("mail", "web"), ("shop", "web"), ("bank", "money")] {
+            new_service(name, &[]);
+            set_tags(name, PASS, &[tag], false, None).unwrap();
+        }
+        let query = |query: &str| {
+            let mut names: Vec<String> = list_query(PASS, &TagQuery::parse(query).unwrap()).unwrap().0.iter()
+                .map(|entry| entry.name().to_owned())
+                .collect();
+            names.sort();
+            names
+        };
+        let mut names = query("web");
+        assert_eq!(names, ["mail", "shop"]);
+        // Gone since the query was previewed, the ones after it still run
+        names.insert(1, "gone".to_owned());
+
+        let results = bulk(PASS, &names, &BulkAction::SetTags(vec!["old".to_owned()], false)).unwrap();
+        let names: Vec<&str> = results.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["mail", "gone", "shop"]);
+        assert!(results[0].1.is_ok() && results[2].1.is_ok());
+        assert!(matches!(results[1].1, Err(APError::NotExist(_))));
+        assert_eq!(get_all("shop", PASS).unwrap().get_tags(), ["old", "web"]);
+        assert_eq!(get_all("bank", PASS).unwrap().get_tags(), ["money"]);
+        assert!(results[0].1.as_ref().unwrap().is_none());
+
+        // An upgrade hands back each service's old and new password
+        let old = get("bank", PASS, false).unwrap().unwrap();
+        let results = bulk(PASS, &["bank"], &BulkAction::Upgrade).unwrap();
+        let new = get("bank", PASS, false).unwrap().unwrap();
+        assert_ne!(old, new);
+        assert_eq!(results[0].1.as_ref().unwrap(), &Some((old, new)));
+
+        let results = bulk(PASS, &query("old"), &BulkAction::Delete).unwrap();
+        assert_eq!(results.len(), 2);
+        assert!(results.iter().all(|(_, res)| res.is_ok()));
+        assert_eq!(list(PASS, &[]).unwrap(), ["bank"]);
+        assert_eq!(list_trash(PASS).unwrap().len(), 2);
+        // A bad password fails the whole call rather than each service
+        assert!(bulk("wrong", &["bank"], &BulkAction::Delete).is_err());
+        assert!(exists(PASS, "bank").unwrap());
+    }
+}
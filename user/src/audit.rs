@@ -0,0 +1,364 @@
+use std::{fs::{create_dir_all, read_dir, remove_file, rename, File}, io::{Read, Seek, SeekFrom, Write}, path::PathBuf, sync::OnceLock};
+
+use fs2::FileExt;
+use sha2::{Digest, Sha256};
+
+use crate::api::{self, APError};
+use crate::lock;
+use crate::warnings;
+use crate::spec::{audit_path, base_path, now, AuditOp, AuditType, Client, Encryptor, EncryptorType, Header, Serializable, HEADER_SIZE};
+
+/*
+ * Every read of a password and every change to a service is recorded in an encrypted
+ * log in the vault's audit directory, along with what made the call. The log is only
+ * ever appended to. Each record holds the hash of the one before it as written, so
+ * editing, removing or reordering records breaks the chain where it was done. Records
+ * chopped off the end leave nothing to check against, rotated logs carry the chain on.
+ *
+ * Records are written after the call is done, so a record that can't be written, in a
+ * vault on a read-only disk say, doesn't turn a change that was saved into a failure.
+ * Reads go ahead too: whoever can stop the log being written has the files anyway, and
+ * the log is there to show what happened, not to guard the passwords. Either way the
+ * call warns that it went unrecorded.
+ *
+ * On disk the log is a header and then each encrypted record with its length before
+ * and after it. The length after lets the next record find the one it chains from
+ * without reading the whole log.
+ */
+
+/// Rotated logs kept unless told otherwise
+pub const DEFAULT_KEEP: usize = 5;
+const LOG_FNAME: &str = "log";
+const ROTATED_PREFIX: &str = "log-";
+const LEN_SIZE: usize = 8;
+
+static CLIENT: OnceLock<Client> = OnceLock::new();
+
+/// Say what's making calls, once at startup. Anything that doesn't is a library caller.
+pub fn set_client(client: Client) {
+    let _ = CLIENT.set(client);
+}
+
+pub fn client() -> Client {
+    CLIENT.get().copied().unwrap_or(Client::Library)
+}
+
+fn log_path() -> PathBuf {
+    audit_path(base_path()).join(LOG_FNAME)
+}
+
+/// Rotated logs, oldest first
+fn rotated_paths() -> Result<Vec<PathBuf>, APError> {
+    let dir = audit_path(base_path());
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+    let mut paths = vec![];
+    for entry in read_dir(&dir)? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with(ROTATED_PREFIX) {
+            paths.push(entry.path());
+        }
+    }
+    // Names carry a zero padded timestamp so they sort by age
+    paths.sort();
+    Ok(paths)
+}
+
+fn hash(record: &[u8]) -> [u8; 32] {
+    Sha256::digest(record).into()
+}
+
+fn read_len(data: &[u8]) -> u64 {
+    let mut len = [0u8; LEN_SIZE];
+    len.copy_from_slice(&data[..LEN_SIZE]);
+    u64::from_le_bytes(len)
+}
+
+/// The whole records after the header and where the last one ends
+fn frames(data: &[u8]) -> (Vec<&[u8]>, usize) {
+    let mut records = vec![];
+    let mut pos = HEADER_SIZE.min(data.len());
+    while data.len() - pos >= 2 * LEN_SIZE {
+        let len = read_len(&data[pos..]) as usize;
+        let end = match pos.checked_add(LEN_SIZE + len + LEN_SIZE) {
+            Some(end) if end <= data.len() => end,
+            _ => break
+        };
+        if read_len(&data[end - LEN_SIZE..]) as usize != len {
+            break;
+        }
+        records.push(&data[pos + LEN_SIZE..end - LEN_SIZE]);
+        pos = end;
+    }
+    (records, pos)
+}
+
+fn check_header(data: &[u8]) -> Result<(), APError> {
+    let header = crate::spec::decode_header(data)?;
+    if header.spec_type != AuditType::spec_type() {
+        return Err(APError::WrongSpecType(AuditType::spec_type(), header.spec_type));
+    }
+    if header.encrypt_version != EncryptorType::encrypt_version() {
+        return Err(APError::WrongEncryptVersion(EncryptorType::encrypt_version(), header.encrypt_version));
+    }
+    Ok(())
+}
+
+/// Where the last whole record ends and what the next record chains from. Anything
+/// past the end was torn off by a crash part way through writing a record.
+fn tail<R: Read + Seek>(file: &mut R) -> Result<(u64, [u8; 32]), APError> {
+    let filelen = file.seek(SeekFrom::End(0))?;
+    let min = (HEADER_SIZE + 2 * LEN_SIZE) as u64;
+    if filelen >= min {
+        let mut lenbuf = [0u8; LEN_SIZE];
+        file.seek(SeekFrom::End(-(LEN_SIZE as i64)))?;
+        file.read_exact(&mut lenbuf)?;
+        let len = u64::from_le_bytes(lenbuf);
+        let start = len.checked_add(2 * LEN_SIZE as u64).and_then(|n| filelen.checked_sub(n));
+        if let Some(start) = start.filter(|s| *s >= HEADER_SIZE as u64) {
+            let mut data = vec![0u8; len as usize + LEN_SIZE];
+            file.seek(SeekFrom::Start(start))?;
+            file.read_exact(&mut data)?;
+            if read_len(&data) == len {
+                return Ok((filelen, hash(&data[LEN_SIZE..])));
+            }
+        }
+    }
+    // The end isn't a whole record, find the last one from the start
+    let mut data = vec![];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_to_end(&mut data)?;
+    if data.len() < HEADER_SIZE {
+        return Ok((0, [0u8; 32]));
+    }
+    check_header(&data)?;
+    let (records, end) = frames(&data);
+    Ok((end as u64, records.last().map(|r| hash(r)).unwrap_or([0u8; 32])))
+}
+
+fn append<W: Write + Seek>(file: &mut W, key: &[u8], record: &AuditType) -> Result<(), APError> {
+    let mut data = vec![];
+    if file.seek(SeekFrom::End(0))? == 0 {
+        let header = Header {
+            spec_type: AuditType::spec_type(),
+            spec_version: AuditType::version(),
+            encrypt_version: EncryptorType::encrypt_version()
+        };
+        data.extend(bincode::serialize(&header)?);
+    }
+    let encrypted = bincode::serialize(&EncryptorType::encrypt(key, record))?;
+    let len = (encrypted.len() as u64).to_le_bytes();
+    data.extend(len);
+    data.extend(encrypted);
+    data.extend(len);
+    // One write so a reader never sees half a record unless the process died
+    file.write_all(&data)?;
+    Ok(())
+}
+
+/// Add to the end of the log at path, chaining from prev or else the last record in it
+fn append_file(path: &PathBuf, key: &[u8], name: &str, op: AuditOp, prev: Option<[u8; 32]>) -> Result<(), APError> {
+    create_dir_all(audit_path(base_path()))?;
+    let mut file = File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    // Readers share the vault lock, this keeps their records from interleaving
+    file.lock_exclusive()?;
+    let res = tail(&mut file).and_then(|(end, last)| {
+        if end < file.metadata()?.len() {
+            file.set_len(end)?;
+        }
+        append(&mut file, key, &AuditType::new(name, op, client(), prev.unwrap_or(last)))?;
+        file.sync_data()?;
+        Ok(())
+    });
+    let _ = FileExt::unlock(&file);
+    res
+}
+
+/// Record a call on the named service, once it's done. The caller holds the vault lock.
+/// A record that can't be written is a warning, the call still succeeds.
+pub(crate) fn record(key: &[u8], op: AuditOp, name: &str) {
+    if let Err(e) = append_file(&log_path(), key, name, op, None) {
+        let name = if name.is_empty() { "the vault" } else { name };
+        warnings::push(format!("Unable to record {} of {} in the audit log: {}", op, name, e));
+    }
+}
+
+pub struct AuditLog {
+    /// Oldest first
+    pub records: Vec<AuditType>,
+    /// Places in records where the chain is broken. Something just before the record
+    /// at that index was changed, removed or can't be read, records.len() if it was
+    /// at the end.
+    pub breaks: Vec<usize>
+}
+
+impl AuditLog {
+    fn broken(&mut self) {
+        if self.breaks.last() != Some(&self.records.len()) {
+            self.breaks.push(self.records.len());
+        }
+    }
+
+    /// Add the records in data, prev being the hash of the last record before them if known
+    fn read(&mut self, data: &[u8], key: &[u8], prev: &mut Option<[u8; 32]>) -> Result<(), APError> {
+        check_header(data)?;
+        let (frames, end) = frames(data);
+        for frame in frames {
+            let record = bincode::deserialize::<EncryptorType>(frame).ok()
+                .and_then(|encrypted| encrypted.decrypt::<AuditType>(key))
+                .filter(|record| record.sanity_check());
+            match record {
+                Some(record) => {
+                    if prev.map(|p| &p != record.get_prev()).unwrap_or(false) {
+                        self.broken();
+                    }
+                    self.records.push(record);
+                }
+                None => self.broken()
+            }
+            *prev = Some(hash(frame));
+        }
+        if end < data.len() {
+            self.broken();
+        }
+        Ok(())
+    }
+}
+
+/// The current log, or with all every rotated one before it too
+pub fn load(pass: &str, all: bool) -> Result<AuditLog, APError> {
+    let _lock = lock::shared()?;
+    let key = api::load_id(pass)?.key();
+    let mut paths = match all {
+        true => rotated_paths()?,
+        false => vec![]
+    };
+    paths.push(log_path());
+
+    let mut log = AuditLog { records: vec![], breaks: vec![] };
+    let mut prev = None;
+    for path in paths.iter().filter(|p| p.exists()) {
+        let mut data = vec![];
+        File::open(path)?.read_to_end(&mut data)?;
+        log.read(&data, &key, &mut prev)?;
+    }
+    Ok(log)
+}
+
+/// Start a new log, keeping the newest keep rotated ones. The new log opens with a
+/// record chained to the end of the old one. Returns how many rotated logs were removed.
+pub fn rotate(pass: &str, keep: usize) -> Result<usize, APError> {
+    let _lock = lock::exclusive()?;
+    let key = api::load_id(pass)?.key();
+    let current = log_path();
+    let mut prev = None;
+    if current.exists() {
+        prev = Some(tail(&mut File::open(&current)?)?.1);
+        let mut time = now();
+        let rotated = |time| audit_path(base_path()).join(format!("{}{:020}", ROTATED_PREFIX, time));
+        while rotated(time).exists() {
+            time += 1;
+        }
+        rename(&current, rotated(time))?;
+    }
+    append_file(&current, &key, "", AuditOp::Rotate, prev)?;
+
+    let rotated = rotated_paths()?;
+    let remove = rotated.len().saturating_sub(keep);
+    for path in &rotated[..remove] {
+        remove_file(path)?;
+    }
+    Ok(remove)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::hash::TextMode;
+    use crate::spec::Visibility;
+    use crate::testvault::{TestVault, PASS};
+
+    fn write(data: &mut Vec<u8>, key: &[u8], name: &str) {
+        let mut cursor = Cursor::new(std::mem::take(data));
+        let (end, prev) = tail(&mut cursor).unwrap();
+        let mut kept = cursor.into_inner();
+        kept.truncate(end as usize);
+        let mut cursor = Cursor::new(kept);
+        append(&mut cursor, key, &AuditType::new(name, AuditOp::Get, Client::Library, prev)).unwrap();
+        *data = cursor.into_inner();
+    }
+
+    fn read(data: &[u8], key: &[u8]) -> AuditLog {
+        let mut log = AuditLog { records: vec![], breaks: vec![] };
+        log.read(data, key, &mut None).unwrap();
+        log
+    }
+
+    #[test]
+    fn test_audit_chain() {
+        let key = [3u8; 32];
+        let mut data = vec![];
+        for name in ["a", "b", "c"] {
+            write(&mut data, &key, name);
+        }
+        let log = read(&data, &key);
+        let names: Vec<&str> = log.records.iter().map(|r| r.name()).collect();
+        assert_eq!(names, vec!["a", "b", "c"]);
+        assert!(log.breaks.is_empty());
+
+        // Dropping the middle record breaks the chain at the one after it
+        let (frames, _) = frames(&data);
+        let start = frames[1].as_ptr() as usize - data.as_ptr() as usize - LEN_SIZE;
+        let end = frames[2].as_ptr() as usize - data.as_ptr() as usize - LEN_SIZE;
+        let mut removed = data.clone();
+        removed.drain(start..end);
+        let log = read(&removed, &key);
+        assert_eq!(log.records.len(), 2);
+        assert_eq!(log.breaks, vec![1]);
+
+        // So does changing a byte in it
+        let mut changed = data.clone();
+        changed[start + LEN_SIZE + 4] ^= 1;
+        let log = read(&changed, &key);
+        assert_eq!(log.records.len(), 2);
+        assert_eq!(log.breaks, vec![1]);
+
+        // A torn record at the end shows, and the next write drops it
+        let mut torn = data.clone();
+        torn.extend([9u8; 20]);
+        assert_eq!(read(&torn, &key).breaks, vec![3]);
+        write(&mut torn, &key, "d");
+        let log = read(&torn, &key);
+        assert_eq!(log.records.len(), 4);
+        assert!(log.breaks.is_empty());
+    }
+
+    #[test]
+    fn test_unwritable() {
+        let vault = TestVault::new("audit-unwritable");
+        api::new::<&str>("mail", PASS, &TextMode::NoWhiteSpace, 16, &[], &[], None).unwrap();
+        assert_eq!(load(PASS, false).unwrap().records.len(), 1);
+        // Where the log goes can't be made a directory
+        let dir = audit_path(&vault.dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::write(&dir, b"").unwrap();
+        warnings::take();
+
+        // Saved and handed back all the same, with a warning for each
+        let (old, new) = api::upgrade("mail", PASS, None, None).unwrap();
+        assert_ne!(old, new);
+        assert_eq!(api::get("mail", PASS, false).unwrap(), Some(new));
+        api::set_kvs("mail", PASS, &[("user", "me")], Visibility::Plain, false, None).unwrap();
+        assert!(api::get_all("mail", PASS).unwrap().get_kv("user").is_some());
+        let warnings = warnings::take();
+        assert_eq!(warnings.len(), 4);
+        assert!(warnings[0].starts_with("Unable to record upgrade of mail in the audit log"));
+    }
+}
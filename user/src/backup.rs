@@ -0,0 +1,461 @@
+use std::{collections::BTreeMap, fs::{create_dir_all, read_dir, File}, io::{Cursor, Read, Write}, path::{Component, Path, PathBuf}};
+
+use serde::{Deserialize, Serialize};
+
+use crate::api::{self, APError};
+use crate::audit;
+use crate::git::git_path;
+use crate::lock;
+use crate::spec::{audit_path, backup_path, base_path, check_file, config_path, decode, decode_header, hooks_path, legacy_path, load_attachment, load_attachment_data, lock_path, now, quarantine_path, save_attachment, save_file, snapshots_path, stream, timestamp_as_string, trash_info_path, trash_path, write_atomic, AttachmentType, AuditOp, Encryptor, EncryptorType, FileStatus, IdentityType, Serializable, ServiceType, SpecType, TrashType, IDENTITY_FNAME, TMP_PREFIX};
+use crate::storage::DirStorage;
+use crate::vault::Vault;
+
+/*
+ * A backup is every file in the vault directory packed into one archive, which is
+ * encrypted as a whole with a key from the master password or a passphrase of its own.
+ * The files inside are the vault's files as they were, so restoring into an empty vault
+ * puts back exactly what was there. Restoring into a vault that already has entries
+ * merges: services, attachments and trash are decrypted with the backup's identity and
+ * encrypted again with the vault's, so the backup can be of another vault as long as
+ * the same password opens both. The vault keeps its own identity, settings and audit log.
+ *
+ * Layout, version 1:
+ *   magic (u32 LE) | version (u16 LE) | the bincode Archive sealed with `stream`
+ *
+ * The stream authenticates every chunk and where the archive ends, so a changed or
+ * truncated backup fails to open rather than restoring part of a vault. Left out are
+ * the lock, temp files, hooks, git, snapshots and the legacy, quarantine and backup
+ * directories.
+ */
+
+const BACKUP_MAGIC: u32 = 0x6b2f0a8d;
+pub const BACKUP_VERSION: u16 = 1;
+
+#[derive(Deserialize, Serialize)]
+struct BackupHeader {
+    magic: u32,
+    version: u16
+}
+
+#[derive(Deserialize, Serialize)]
+struct Archive {
+    created: u64,
+    /// Paths relative to the vault directory with / between components, sorted
+    files: Vec<(String, Vec<u8>)>
+}
+
+fn seal<W: Write>(writer: &mut W, key: &[u8], archive: &Archive) -> Result<(), APError> {
+    bincode::serialize_into(&mut *writer, &BackupHeader { magic: BACKUP_MAGIC, version: BACKUP_VERSION })?;
+    stream::encrypt_stream(key, &mut bincode::serialize(archive)?.as_slice(), writer)?;
+    Ok(())
+}
+
+fn unseal<R: Read>(reader: &mut R, key: &[u8]) -> Result<Archive, APError> {
+    let header: BackupHeader = bincode::deserialize_from(&mut *reader).map_err(|_| APError::CorruptHeader)?;
+    if header.magic != BACKUP_MAGIC {
+        return Err(APError::CorruptHeader);
+    }
+    if header.version != BACKUP_VERSION {
+        return Err(APError::Backup(format!("Backup version {} isn't supported, expected {}", header.version, BACKUP_VERSION)));
+    }
+    let mut data = vec![];
+    stream::decrypt_stream(key, reader, &mut data).map_err(|e| match e {
+        APError::Decryption => APError::Backup("Backup doesn't open with this password, or has been changed".to_owned()),
+        e => e
+    })?;
+    bincode::deserialize(&data).map_err(|_| APError::CorruptCiphertext)
+}
+
+fn open(path: &Path, pass: &str, passphrase: Option<&str>) -> Result<Archive, APError> {
+    unseal(&mut File::open(path)?, &EncryptorType::genkey(passphrase.unwrap_or(pass)))
+}
+
+/// Local to this copy of the vault, or only there while something is running
+fn left_out(basedir: &Path, path: &Path) -> bool {
+    let tmp = path.file_name()
+        .map(|name| name.to_string_lossy().starts_with(TMP_PREFIX))
+        .unwrap_or(false);
+    tmp || path == lock_path(basedir) || path == git_path(basedir) || path == hooks_path(basedir)
+        || path == legacy_path(basedir) || path == quarantine_path(basedir) || path == backup_path(basedir)
+        || path == snapshots_path(basedir)
+}
+
+/// Where a path from an archive goes in the vault at basedir. None if it would land
+/// outside the vault, or somewhere a backup never has anything of.
+fn dest_path(basedir: &Path, path: &str) -> Option<PathBuf> {
+    let rel = Path::new(path);
+    if path.is_empty() || !rel.components().all(|c| matches!(c, Component::Normal(_))) {
+        return None;
+    }
+    let dest = basedir.join(rel);
+    match dest.ancestors().take_while(|a| *a != basedir).any(|a| left_out(basedir, a)) {
+        true => None,
+        false => Some(dest)
+    }
+}
+
+fn collect(basedir: &Path, dir: &Path, files: &mut Vec<(String, Vec<u8>)>) -> Result<(), APError> {
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if left_out(basedir, &path) {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            collect(basedir, &path, files)?;
+            continue;
+        }
+        let rel: Vec<String> = path.strip_prefix(basedir).unwrap_or(&path)
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy().into_owned())
+            .collect();
+        let mut data = vec![];
+        File::open(&path)?.read_to_end(&mut data)?;
+        files.push((rel.join("/"), data));
+    }
+    Ok(())
+}
+
+/// Write the whole vault to path, encrypted with passphrase or else the master password.
+/// Returns how many files went in.
+pub fn create<P: AsRef<Path>>(path: P, pass: &str, passphrase: Option<&str>) -> Result<usize, APError> {
+    let _lock = lock::shared()?;
+    api::load_id(pass)?;
+    let basedir = base_path();
+    let mut files = vec![];
+    collect(&basedir, &basedir, &mut files)?;
+    files.sort();
+    let archive = Archive { created: now(), files };
+    let key = EncryptorType::genkey(passphrase.unwrap_or(pass));
+    write_atomic(path, |file| seal(file, &key, &archive))?;
+    Ok(archive.files.len())
+}
+
+pub struct BackupEntry {
+    /// Relative to the vault directory
+    pub path: String,
+    pub size: u64,
+    /// None for files that aren't entries, like the settings and audit log
+    pub status: Option<FileStatus>
+}
+
+impl BackupEntry {
+    pub fn is_valid(&self) -> bool {
+        self.status.as_ref().map(|s| s.is_valid()).unwrap_or(true)
+    }
+}
+
+/// What a backup holds, from `verify`
+pub struct BackupInfo {
+    pub version: u16,
+    pub created: u64,
+    /// Name of the backed up vault's identity
+    pub identity: String,
+    /// Services outside the trash, sorted
+    pub services: Vec<String>,
+    pub files: Vec<BackupEntry>
+}
+
+impl BackupInfo {
+    pub fn created(&self) -> String {
+        timestamp_as_string(self.created)
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.files.iter().all(|f| f.is_valid())
+    }
+}
+
+/// Check every file in the archive against the identity it holds
+fn check(archive: &Archive, pass: &str) -> Result<(IdentityType, BackupInfo), APError> {
+    let iddata = archive.files.iter()
+        .find(|(path, _)| path == IDENTITY_FNAME)
+        .map(|(_, data)| data)
+        .ok_or_else(|| APError::Backup("Backup has no identity".to_owned()))?;
+    let id = decode::<IdentityType, EncryptorType>(iddata, &EncryptorType::genkey(pass))?;
+    let key = id.key();
+
+    let mut services = vec![];
+    let mut files = vec![];
+    for (path, data) in archive.files.iter() {
+        // Nothing is written anywhere before every path has been checked
+        if dest_path(Path::new(""), path).is_none() {
+            return Err(APError::Backup(format!("Backup has {}, which is outside what a backup restores", path)));
+        }
+        let status = if path == IDENTITY_FNAME {
+            Some(check_file(&mut Cursor::new(data), &EncryptorType::genkey(pass))?)
+        } else if Path::new(path) == config_path("") || Path::new(path).starts_with(audit_path("")) {
+            None
+        } else {
+            Some(check_file(&mut Cursor::new(data), &key)?)
+        };
+        if matches!(status, Some(FileStatus::Valid(SpecType::Service, _))) && !path.contains('/') {
+            services.push(decode::<ServiceType, EncryptorType>(data, &key)?.name().to_owned());
+        }
+        files.push(BackupEntry { path: path.clone(), size: data.len() as u64, status });
+    }
+    services.sort();
+    let info = BackupInfo {
+        version: BACKUP_VERSION,
+        created: archive.created,
+        identity: id.name().to_owned(),
+        services,
+        files
+    };
+    Ok((id, info))
+}
+
+/// Open a backup and check everything in it without restoring anything
+pub fn verify<P: AsRef<Path>>(path: P, pass: &str, passphrase: Option<&str>) -> Result<BackupInfo, APError> {
+    let archive = open(path.as_ref(), pass, passphrase)?;
+    Ok(check(&archive, pass)?.1)
+}
+
+/// What `restore` did
+#[derive(Debug, Default)]
+pub struct RestoreReport {
+    /// The vault was empty and is now exactly what was backed up, nothing else is set
+    pub full: bool,
+    /// Services that weren't in the vault
+    pub added: Vec<String>,
+    /// Services that were newer in the backup and replaced the vault's
+    pub updated: Vec<String>,
+    /// Services that were newer in the vault and were left alone
+    pub kept: Vec<String>,
+    /// Attachments the vault didn't have
+    pub attachments: usize,
+    /// Deletions the vault's trash didn't have
+    pub trash: usize
+}
+
+/// Put a backup into the vault. An empty vault gets everything back as it was, otherwise
+/// the backup is merged in, keeping the newer of any service both have. Fails without
+/// changing anything if any entry in the backup is damaged.
+pub fn restore<P: AsRef<Path>>(path: P, pass: &str, passphrase: Option<&str>) -> Result<RestoreReport, APError> {
+    let archive = open(path.as_ref(), pass, passphrase)?;
+    create_dir_all(base_path())?;
+    let _lock = lock::exclusive()?;
+    let (id, info) = check(&archive, pass)?;
+    if let Some(bad) = info.files.iter().find(|f| !f.is_valid()) {
+        return Err(APError::Backup(format!("{} in the backup is damaged: {}", bad.path, bad.status.as_ref().unwrap())));
+    }
+
+    if api::empty()? {
+        let basedir = base_path();
+        for (path, data) in archive.files.iter() {
+            let dest = dest_path(&basedir, path)
+                .ok_or_else(|| APError::Backup(format!("Backup has {}, which is outside what a backup restores", path)))?;
+            create_dir_all(dest.parent().unwrap_or(&basedir))?;
+            write_atomic(&dest, |file| Ok(file.write_all(data)?))?;
+        }
+        audit::record(&id.key(), AuditOp::Import, "");
+        return Ok(RestoreReport { full: true, ..Default::default() });
+    }
+
+    let key = api::load_id(pass)?.key();
+    merge(&archive, &id.key(), &key)
+}
+
+/// Decrypt an attachment with from and write it into dir encrypted with to, unless
+/// there's one there already. True if it was written.
+fn copy_attachment(data: &[u8], from: &[u8], to: &[u8], dir: &Path) -> Result<bool, APError> {
+    let mut file = Cursor::new(data);
+    let attachment = load_attachment(&mut file, from)?;
+    let (service, name) = (attachment.get_service(), attachment.name());
+    let dest = dir.join(AttachmentType::filename(to, service, name));
+    if dest.exists() {
+        return Ok(false);
+    }
+    let mut contents = vec![];
+    load_attachment_data(&mut file, from, &mut contents)?;
+    write_atomic(&dest, |file| save_attachment(file, to, service, name, &mut contents.as_slice()))?;
+    Ok(true)
+}
+
+/// Move one trashed deletion over, unless the vault's trash has it already
+fn merge_trash(files: &[&(String, Vec<u8>)], from: &[u8], to: &[u8]) -> Result<bool, APError> {
+    let info = files.iter()
+        .find(|(path, _)| Path::new(path).file_name() == trash_info_path("").file_name())
+        .ok_or_else(|| APError::Backup("Backup has a deletion without its record".to_owned()))?;
+    let trash = decode::<TrashType, EncryptorType>(&info.1, from)?;
+    let dir = trash_path(base_path()).join(EncryptorType::filename(to, &trash.storage_name()));
+    if dir.exists() {
+        return Ok(false);
+    }
+    create_dir_all(&dir)?;
+    // The record goes first, same as `delete`
+    save_file(trash_info_path(&dir), to, &trash)?;
+    for (_, data) in files.iter().filter(|(path, _)| *path != info.0) {
+        match decode_header(data)?.spec_type {
+            SpecType::Service => {
+                let entry = decode::<ServiceType, EncryptorType>(data, from)?;
+                save_file(dir.join(EncryptorType::filename(to, entry.name())), to, &entry)?;
+            }
+            SpecType::Attachment => {
+                copy_attachment(data, from, to, &dir)?;
+            }
+            _ => {}
+        }
+    }
+    Ok(true)
+}
+
+fn merge(archive: &Archive, from: &[u8], to: &[u8]) -> Result<RestoreReport, APError> {
+    let mut report = RestoreReport::default();
+    let mut vault = Vault::new(DirStorage::new(base_path()));
+    let mut attachments = vec![];
+    let mut trash: BTreeMap<&str, Vec<&(String, Vec<u8>)>> = BTreeMap::new();
+    let trashdir = trash_path("");
+    for file in archive.files.iter() {
+        let (path, data) = file;
+        if Path::new(path).starts_with(&trashdir) {
+            let deletion = path.split('/').nth(1).unwrap_or_default();
+            trash.entry(deletion).or_default().push(file);
+            continue;
+        }
+        if path.contains('/') || path == IDENTITY_FNAME || Path::new(path) == config_path("") {
+            continue;
+        }
+        match decode_header(data)?.spec_type {
+            SpecType::Service => {}
+            SpecType::Attachment => {
+                // After the services, so they have somewhere to go
+                attachments.push(data);
+                continue;
+            }
+            _ => continue
+        }
+        let entry = decode::<ServiceType, EncryptorType>(data, from)?;
+        let name = entry.name().to_owned();
+        match vault.load_service(to, &name) {
+            Ok(current) if current.modify_time() >= entry.modify_time() => {
+                report.kept.push(name);
+                continue;
+            }
+            Ok(_) => report.updated.push(name.clone()),
+            Err(APError::NotExist(_)) => report.added.push(name.clone()),
+            Err(e) => return Err(e)
+        }
+        vault.save_service(to, &entry)?;
+        audit::record(to, AuditOp::Import, &name);
+    }
+
+    for data in attachments {
+        let service = load_attachment(&mut Cursor::new(data), from)?.get_service().to_owned();
+        if vault.exists(to, &service)? && copy_attachment(data, from, to, &base_path())? {
+            report.attachments += 1;
+        }
+    }
+    for files in trash.values() {
+        if merge_trash(files, from, to)? {
+            report.trash += 1;
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::TextMode;
+    use crate::spec::Visibility;
+    use crate::testvault::{self, TestVault, PASS};
+
+    #[test]
+    fn test_backup_seal() {
+        let key = EncryptorType::genkey("backup");
+        let archive = Archive {
+            created: 7,
+            files: vec![(IDENTITY_FNAME.to_owned(), vec![1, 2, 3]), ("trash/a/b".to_owned(), vec![4; 100])]
+        };
+        let mut sealed = vec![];
+        seal(&mut sealed, &key, &archive).unwrap();
+        let opened = unseal(&mut sealed.as_slice(), &key).unwrap();
+        assert_eq!(opened.created, 7);
+        assert_eq!(opened.files, archive.files);
+
+        assert!(matches!(unseal(&mut sealed.as_slice(), &EncryptorType::genkey("other")), Err(APError::Backup(_))));
+        // Changed or cut short, it doesn't open at all
+        let mut changed = sealed.clone();
+        let last = changed.len() - 1;
+        changed[last] ^= 1;
+        assert!(unseal(&mut changed.as_slice(), &key).is_err());
+        assert!(unseal(&mut &sealed[..sealed.len() - 20], &key).is_err());
+        assert!(matches!(unseal(&mut &[0u8; 6][..], &key), Err(APError::CorruptHeader)));
+    }
+
+    fn new_service(name: &str) {
+        api::new::<&str>(name, PASS, &TextMode::NoWhiteSpace, 16, &[], &[], None).unwrap();
+    }
+
+    #[test]
+    fn test_restore_full() {
+        let vault = TestVault::new("backup-full");
+        new_service("mail");
+        api::attach("mail", PASS, "note.txt", &mut "attached".as_bytes()).unwrap();
+        new_service("old");
+        api::delete("old", PASS).unwrap();
+        let file = std::env::temp_dir().join(format!("ap-backup-full-{}", std::process::id()));
+        let _ = std::fs::remove_file(&file);
+        create(&file, PASS, None).unwrap();
+
+        std::fs::remove_dir_all(&vault.dir).unwrap();
+        let report = restore(&file, PASS, None).unwrap();
+        std::fs::remove_file(&file).unwrap();
+        assert!(report.full);
+        assert_eq!(api::list(PASS, &[]).unwrap(), vec!["mail"]);
+        assert_eq!(api::list_attachments("mail", PASS).unwrap().len(), 1);
+        assert_eq!(api::list_trash(PASS).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_restore_merge() {
+        let vault = TestVault::new("backup-merge");
+        new_service("news");
+        std::thread::sleep(std::time::Duration::from_millis(1100));
+
+        // The backup is of another vault, opened by the same password
+        let other = testvault::dir("backup-merge-other");
+        testvault::use_dir(&other);
+        api::init::<&str>("me", PASS, &[]).unwrap();
+        for name in ["news", "bank", "mail", "old"].iter() {
+            new_service(name);
+        }
+        api::set_kvs("news", PASS, &[("from", "backup")], Visibility::Plain, false, None).unwrap();
+        api::attach("mail", PASS, "note.txt", &mut "attached".as_bytes()).unwrap();
+        api::delete("old", PASS).unwrap();
+        let file = other.join("backup.ap");
+        create(&file, PASS, None).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(1100));
+
+        testvault::use_dir(&vault.dir);
+        new_service("bank");
+        let report = restore(&file, PASS, None).unwrap();
+        std::fs::remove_dir_all(&other).unwrap();
+        assert!(!report.full);
+        assert_eq!(report.added, vec!["mail"]);
+        assert_eq!(report.updated, vec!["news"]);
+        assert_eq!(report.kept, vec!["bank"]);
+        assert_eq!((report.attachments, report.trash), (1, 1));
+        assert_eq!(api::list(PASS, &[]).unwrap(), vec!["bank", "mail", "news"]);
+        assert_eq!(api::get_all("news", PASS).unwrap().get_kv("from").map(|(v, _)| v), Some("backup"));
+        assert_eq!(api::list_trash(PASS).unwrap()[0].name(), "old");
+    }
+
+    #[test]
+    fn test_restore_outside() {
+        let vault = TestVault::empty("backup-outside");
+        let outside = vault.dir.with_file_name(format!("ap-backup-escaped-{}", std::process::id()));
+        let name = outside.file_name().unwrap().to_string_lossy().into_owned();
+        let file = std::env::temp_dir().join(format!("ap-backup-outside-{}", std::process::id()));
+        for path in [format!("audit/../../{}", name), format!("../{}", name), "/etc/passwd".to_owned(),
+                     "hooks/post-new".to_owned(), "snapshots/x".to_owned(), "trash/../hooks/pre-get".to_owned()].iter() {
+            let archive = Archive { created: now(), files: vec![(path.clone(), b"#!/bin/sh".to_vec())] };
+            seal(&mut File::create(&file).unwrap(), &EncryptorType::genkey(PASS), &archive).unwrap();
+            assert!(matches!(restore(&file, PASS, None), Err(APError::Backup(_))), "{}", path);
+            assert!(!outside.exists());
+            assert!(!hooks_path(&vault.dir).exists() && !snapshots_path(&vault.dir).exists());
+        }
+        std::fs::remove_file(&file).unwrap();
+    }
+}
@@ -1,13 +1,19 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 
-use egui::{Button, Color32, Label, Layout, RichText, SelectableLabel, Separator, Ui, ViewportBuilder};
+use egui::collapsing_header::CollapsingState;
+use egui::{Button, Color32, ComboBox, Label, Layout, RichText, SelectableLabel, Separator, Ui, ViewportBuilder};
 
 use pass::{api::APError, gui::{
-    confirmbox::{Action, ConfirmBox}, inputprompt::prompt_input, msgbox::launch_msgbox, servicelist::ServiceList, validator::{textedit2, LengthBounds, NotEmpty, NotInList, Validator}, Display, Windowed
-}, spec::{IdentityType, ServiceType}};
-use pass::{api, spec::Serializable};
+    confirmbox::{Action, ConfirmBox}, inputprompt::prompt_input, msgbox::launch_msgbox, servicelist::{ServiceList, TagMode}, validator::{textedit2, LengthBounds, NotEmpty, NotInList, Validator}, Display, Windowed
+}, spec::{copy_to_clipboard, AttachmentType, IdentityType, ServiceType, TrashType, Visibility, MASK}};
+use pass::{api, audit::{self, AuditLog}, config, snapshot, spec::{Client, Serializable}, tagtree::TagNode, warnings};
 
 
 fn main() -> Result<(), APError> {
+    audit::set_client(Client::Ap);
     let empty = api::empty()?;
     let pwd = if empty {
         let pwd1 = prompt_input(
@@ -66,6 +72,9 @@ struct ApCtx {
     refresh_service: bool,
     refresh_service_list: bool,
     set_service: Option<Option<Current>>, // First optional: are we setting anything, second optional: what we're setting to
+    bulk_results: Option<Result<Vec<(String, Result<api::BulkOutcome, String>)>, String>>,
+    undo: Option<String>, // What the Undo button would take back, shown after destructive actions
+    problems: Vec<String> // Errors and warnings, shown until dismissed
 }
 
 impl ApCtx {
@@ -77,6 +86,9 @@ impl ApCtx {
             refresh_service: false,
             refresh_service_list: false,
             set_service: None,
+            bulk_results: None,
+            undo: None,
+            problems: vec![]
         }
     }
 }
@@ -92,50 +104,59 @@ impl Action<ApCtx> for Box<DeleteService> {
         } else {
             apctx.refresh_service_list = true;
             apctx.set_service = Some(None);
+            apctx.undo = Some(format!("Deleted {}", self.service));
         }
     }
 }
 
-struct KvDelete {
-    service: Option<String>, // None for id kv delete
-    key: String
-}
+struct EmptyTrash;
 
-impl KvDelete {
-    fn save(&self, existing: &[(String, String)], apctx: &mut ApCtx) {
-        let mut kvs = vec![];
-        for (key, value) in existing {
-            if key != &self.key {
-                kvs.push((key.as_str(), value.as_str()));
-            }
+impl Action<ApCtx> for EmptyTrash {
+    fn doit(&mut self, apctx: &mut ApCtx) {
+        if let Err(e) = api::empty_trash(&apctx.masterpwd) {
+            eprintln!("Error emptying trash: {}", e);
         }
+        apctx.refresh_service = true;
+    }
+}
+
+struct RotateAudit;
 
-        match &self.service {
-            Some(s) => api::set_kvs(s, &apctx.masterpwd, &kvs, true),
-            None => api::set_kvs_id(&apctx.masterpwd, &kvs, true)
-        }.unwrap_or_else(|e| {
-            panic!("Failed to save kvs: {}", e);
-        })
+impl Action<ApCtx> for RotateAudit {
+    fn doit(&mut self, apctx: &mut ApCtx) {
+        if let Err(e) = audit::rotate(&apctx.masterpwd, audit::DEFAULT_KEEP) {
+            eprintln!("Error rotating audit log: {}", e);
+        }
+        apctx.refresh_service = true;
     }
 }
 
+struct KvDelete {
+    service: Option<String>, // None for id kv delete
+    key: String,
+    seen: u64 // Modify time of what was shown
+}
+
 impl Action<ApCtx> for Box<KvDelete> {
     fn doit(&mut self, apctx: &mut ApCtx) {
-        match &self.service {
-            Some(s) => api::get_all(s, &apctx.masterpwd)
-                .map(|s| self.save(s.get_kvs(), apctx)),
-            None => api::get_id(&apctx.masterpwd)
-                .map(|id| self.save(id.get_kvs(), apctx))
-        }.unwrap_or_else(|e| {
-            panic!("Unable to retrieve kvs: {}", e);
-        });
+        let res = match &self.service {
+            Some(s) => api::remove_kvs(s, &apctx.masterpwd, &[&self.key], Some(self.seen)),
+            None => api::remove_kvs_id(&apctx.masterpwd, &[&self.key], Some(self.seen))
+        };
+        match (res, &self.service) {
+            (Err(e), _) => apctx.problems.push(format!("Error removing {}: {}", self.key, e)),
+            // Only services keep snapshots
+            (Ok(()), Some(s)) => apctx.undo = Some(format!("Removed {} from {}", self.key, s)),
+            (Ok(()), None) => {}
+        }
         apctx.refresh_service = true;
     }
 }
 
 struct TagDelete {
     service: String,
-    tag_to_remove: String
+    tag_to_remove: String,
+    seen: u64 // Modify time of what was shown
 }
 
 impl TagDelete {
@@ -147,20 +168,366 @@ impl TagDelete {
                 tags.push(t);
             }
         }
-        api::set_tags(&self.service, &apctx.masterpwd, &tags, true)
+        api::set_tags(&self.service, &apctx.masterpwd, &tags, true, Some(self.seen))
     }
 }
 
 impl Action<ApCtx> for Box<TagDelete> {
     fn doit(&mut self, apctx: &mut ApCtx) {
-        if let Err(e) = self.save(apctx) {
-            panic!("Unable to remove tag from service {}: {}", self.service, e);
+        match self.save(apctx) {
+            Ok(()) => apctx.undo = Some(format!("Removed tag {} from {}", self.tag_to_remove, self.service)),
+            Err(e) => apctx.problems.push(format!("Unable to remove tag from service {}: {}", self.service, e))
         }
         apctx.refresh_service = true;
         apctx.refresh_service_list = true;
     }
 }
 
+struct AttachmentDelete {
+    service: String,
+    attachment: String
+}
+
+impl Action<ApCtx> for Box<AttachmentDelete> {
+    fn doit(&mut self, apctx: &mut ApCtx) {
+        if let Err(e) = api::remove_attachment(&self.service, &apctx.masterpwd, &self.attachment) {
+            eprintln!("Error removing attachment {} from service {}: {}", self.attachment, self.service, e);
+        }
+        apctx.refresh_service = true;
+    }
+}
+
+fn human_size(size: u64) -> String {
+    let units = ["B", "KB", "MB", "GB"];
+    let mut size = size as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", size, units[unit])
+    } else {
+        format!("{:.1} {}", size, units[unit])
+    }
+}
+
+fn default_save_path(attachment: &str) -> String {
+    dirs::home_dir()
+        .map(|home| home.join(attachment))
+        .unwrap_or_else(|| PathBuf::from(attachment))
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn path_prompt(ui: &mut Ui, path: &mut String, hint: &str) -> bool {
+    let (_, valid) = textedit2(ui, path, NotEmpty{}, |te, _valid| {
+        te
+            .desired_width(300.0)
+            .hint_text(hint)
+    });
+    valid
+}
+
+fn save_cancel(ui: &mut Ui, enabled: bool) -> (bool, bool) {
+    let mut save = false;
+    let mut cancel = false;
+    ui.horizontal(|ui| {
+        ui.with_layout(Layout::left_to_right(egui::Align::Max), |ui| {
+            if ui.add_enabled(enabled, Button::new("Save")).clicked() {
+                save = true;
+            }
+        });
+        ui.with_layout(Layout::right_to_left(egui::Align::Max), |ui| {
+            if ui.button("Cancel").clicked() {
+                cancel = true;
+            }
+        });
+    });
+    (save, cancel)
+}
+
+/// A bulk action on the services picked in the sidebar, the results are shown once it's run
+struct BulkRun {
+    names: Vec<String>,
+    action: api::BulkAction
+}
+
+impl BulkRun {
+    fn run(&self, apctx: &mut ApCtx) {
+        let results = api::bulk(&apctx.masterpwd, &self.names, &self.action)
+            .map(|results| results.into_iter()
+                 .map(|(name, res)| (name, res.map_err(|e| e.to_string())))
+                 .collect())
+            .map_err(|e| e.to_string());
+        if results.is_ok() {
+            apctx.undo = Some(format!("Changed {} services", self.names.len()));
+        }
+        apctx.bulk_results = Some(results);
+        apctx.refresh_service = true;
+        apctx.refresh_service_list = true;
+        if let api::BulkAction::Delete = self.action {
+            apctx.set_service = Some(None);
+        }
+    }
+}
+
+impl Action<ApCtx> for Box<BulkRun> {
+    fn doit(&mut self, apctx: &mut ApCtx) {
+        self.run(apctx);
+    }
+}
+
+fn bulk_preview(ui: &mut Ui, names: &[String]) {
+    ui.label(format!("{} services:", names.len()));
+    egui::ScrollArea::vertical().max_height(100.0).show(ui, |ui| {
+        for name in names {
+            ui.label(name);
+        }
+    });
+}
+
+enum BulkEdit {
+    Tags { tags: String, reset: bool },
+    Kv { key: String, value: String, visibility: Visibility }
+}
+
+/// Tags or a key value pair to set on every picked service
+struct BulkEditor {
+    names: Vec<String>,
+    edit: BulkEdit
+}
+
+impl Display<ApCtx, bool> for BulkEditor {
+    fn display(&mut self, _ctx: &egui::Context, ui: &mut Ui, apctx: &mut ApCtx) -> bool {
+        bulk_preview(ui, &self.names);
+        ui.add(Separator::default());
+        let valid = match &mut self.edit {
+            BulkEdit::Tags { tags, reset } => {
+                let (_, valid) = textedit2(ui, tags, NotEmpty{}, |te, _valid| {
+                    te.hint_text("Tags, separated by commas")
+                });
+                ui.checkbox(reset, "Replace existing tags");
+                valid
+            }
+            BulkEdit::Kv { key, value, visibility } => {
+                let (_, key_valid) = textedit2(ui, key, NotEmpty{}, |te, _valid| te.hint_text("Key"));
+                ui.add(egui::TextEdit::singleline(value).hint_text("Value"));
+                ComboBox::from_id_salt("bulk_visibility")
+                    .selected_text(visibility.to_string())
+                    .show_ui(ui, |ui| {
+                        for v in [Visibility::Plain, Visibility::Concealed, Visibility::CopyOnly] {
+                            ui.selectable_value(visibility, v, v.to_string());
+                        }
+                    });
+                key_valid
+            }
+        };
+        let (save, cancel) = save_cancel(ui, valid);
+        if save {
+            let action = match &self.edit {
+                BulkEdit::Tags { tags, reset } => {
+                    let tags = tags.split(',')
+                        .map(|t| t.trim().to_owned())
+                        .filter(|t| !t.is_empty())
+                        .collect();
+                    api::BulkAction::SetTags(tags, *reset)
+                }
+                BulkEdit::Kv { key, value, visibility } => {
+                    api::BulkAction::SetKvs(vec![(key.clone(), value.clone())], *visibility, false)
+                }
+            };
+            BulkRun { names: self.names.clone(), action }.run(apctx);
+            return false;
+        }
+        !cancel
+    }
+}
+
+struct BulkReport {
+    results: Result<Vec<(String, Result<api::BulkOutcome, String>)>, String>
+}
+
+impl Display<ApCtx, bool> for BulkReport {
+    fn display(&mut self, _ctx: &egui::Context, ui: &mut Ui, _apctx: &mut ApCtx) -> bool {
+        match &self.results {
+            Ok(results) => {
+                egui::ScrollArea::vertical().max_height(200.0).show(ui, |ui| {
+                    for (name, result) in results {
+                        match result {
+                            Ok(Some((old_pass, new_pass))) => ui.label(format!("✔ {}\n  Old pass: {}\n  New pass: {}", name, old_pass, new_pass)),
+                            Ok(None) => ui.label(format!("✔ {}", name)),
+                            Err(e) => ui.colored_label(Color32::DARK_RED, format!("✖ {}: {}", name, e))
+                        };
+                    }
+                });
+            }
+            Err(e) => {
+                ui.colored_label(Color32::DARK_RED, e);
+            }
+        }
+        !ui.button("Close").clicked()
+    }
+}
+
+enum BulkMenu {
+    Upgrade,
+    Tags,
+    Kv,
+    Delete
+}
+
+struct RenameTag {
+    tag: String,
+    tags: Vec<String>,
+    newname: String,
+    error: Option<String>
+}
+
+impl RenameTag {
+    fn new(tag: String, tags: Vec<String>) -> Self {
+        Self { newname: tag.clone(), tag, tags, error: None }
+    }
+
+    fn save(&self, apctx: &mut ApCtx) -> Result<(), String> {
+        let res = if self.tags.contains(&self.newname) {
+            api::merge_tags(&apctx.masterpwd, &[&self.tag], &self.newname)
+        } else {
+            api::rename_tag(&apctx.masterpwd, &self.tag, &self.newname)
+        };
+        res.map_err(|e| format!("Unable to rename tag {}: {}", self.tag, e))?;
+        apctx.undo = Some(format!("Renamed tag {} to {}", self.tag, self.newname));
+        apctx.refresh_service = true;
+        apctx.refresh_service_list = true;
+        Ok(())
+    }
+}
+
+impl Display<ApCtx, bool> for RenameTag {
+    fn display(&mut self, _ctx: &egui::Context, ui: &mut Ui, apctx: &mut ApCtx) -> bool {
+        let (_, valid) = textedit2(ui, &mut self.newname, NotEmpty{}, |te, _valid| {
+            te.hint_text("New tag name")
+        });
+        if self.newname != self.tag && self.tags.contains(&self.newname) {
+            ui.label(format!("Services tagged {} will be merged into {}", self.tag, self.newname));
+        }
+        if let Some(e) = &self.error {
+            ui.colored_label(Color32::DARK_RED, e);
+        }
+        let (save, cancel) = save_cancel(ui, valid && self.newname != self.tag);
+        if save {
+            match self.save(apctx) {
+                Ok(()) => return false,
+                Err(e) => self.error = Some(e)
+            }
+        }
+        !cancel
+    }
+}
+
+struct DeleteTag {
+    tag: String
+}
+
+impl Action<ApCtx> for Box<DeleteTag> {
+    fn doit(&mut self, apctx: &mut ApCtx) {
+        match api::delete_tag(&apctx.masterpwd, &self.tag) {
+            Ok(_) => apctx.undo = Some(format!("Removed tag {}", self.tag)),
+            Err(e) => eprintln!("Error removing tag {}: {}", self.tag, e)
+        }
+        apctx.refresh_service = true;
+        apctx.refresh_service_list = true;
+    }
+}
+
+struct AddAttachment {
+    service: String,
+    path: String,
+    error: Option<String>
+}
+
+impl AddAttachment {
+    fn new(service: String) -> Self {
+        Self { service, path: String::new(), error: None }
+    }
+
+    fn save(&self, apctx: &mut ApCtx) -> Result<(), String> {
+        let path = PathBuf::from(&self.path);
+        let attachment = path.file_name()
+            .map(|f| f.to_string_lossy().into_owned())
+            .ok_or_else(|| format!("{} is not a file", self.path))?;
+        let mut file = File::open(&path)
+            .map_err(|e| format!("Unable to open {}: {}", self.path, e))?;
+        api::attach(&self.service, &apctx.masterpwd, &attachment, &mut file)
+            .map_err(|e| format!("Unable to attach {}: {}", attachment, e))?;
+        apctx.refresh_service = true;
+        Ok(())
+    }
+}
+
+impl Display<ApCtx, bool> for AddAttachment {
+    fn display(&mut self, _ctx: &egui::Context, ui: &mut Ui, apctx: &mut ApCtx) -> bool {
+        let valid = path_prompt(ui, &mut self.path, "File to attach");
+        if let Some(e) = &self.error {
+            ui.colored_label(Color32::DARK_RED, e);
+        }
+        let (save, cancel) = save_cancel(ui, valid);
+        if save {
+            match self.save(apctx) {
+                Ok(()) => return false,
+                Err(e) => self.error = Some(e)
+            }
+        }
+        !cancel
+    }
+}
+
+struct SaveAttachment {
+    service: String,
+    attachment: String,
+    path: String,
+    error: Option<String>
+}
+
+impl SaveAttachment {
+    fn new(service: String, attachment: String) -> Self {
+        let path = default_save_path(&attachment);
+        Self { service, attachment, path, error: None }
+    }
+
+    fn save(&self, apctx: &mut ApCtx) -> Result<(), String> {
+        let path = PathBuf::from(&self.path);
+        if path.exists() {
+            return Err(format!("{} already exists", self.path));
+        }
+        let mut file = File::create(&path)
+            .map_err(|e| format!("Unable to create {}: {}", self.path, e))?;
+        api::get_attachment(&self.service, &apctx.masterpwd, &self.attachment, &mut file)
+            .map_err(|e| {
+                let _ = std::fs::remove_file(&path);
+                format!("Unable to save {}: {}", self.attachment, e)
+            })?;
+        Ok(())
+    }
+}
+
+impl Display<ApCtx, bool> for SaveAttachment {
+    fn display(&mut self, _ctx: &egui::Context, ui: &mut Ui, apctx: &mut ApCtx) -> bool {
+        let valid = path_prompt(ui, &mut self.path, "Save as");
+        if let Some(e) = &self.error {
+            ui.colored_label(Color32::DARK_RED, e);
+        }
+        let (save, cancel) = save_cancel(ui, valid);
+        if save {
+            match self.save(apctx) {
+                Ok(()) => return false,
+                Err(e) => self.error = Some(e)
+            }
+        }
+        !cancel
+    }
+}
+
 fn newpwdprompt(ui: &mut Ui, password: &mut Option<String>) -> bool {
     ui.horizontal(|ui| {
         match password {
@@ -196,19 +563,21 @@ fn newpwdprompt(ui: &mut Ui, password: &mut Option<String>) -> bool {
 struct PasswordRefresh {
     service: String,
     password: Option<String>,
+    seen: u64 // Modify time of what was shown
 }
 
 impl PasswordRefresh {
-    fn new(service: String) -> Self {
+    fn new(service: String, seen: u64) -> Self {
         Self {
             service,
-            password: None
+            password: None,
+            seen
         }
     }
 
     fn refresh_password(&self, apctx: &mut ApCtx) {
-        if let Err(e) = api::upgrade(&self.service, &apctx.masterpwd, self.password.as_ref().map(|s| s.as_str())) {
-            eprintln!("Error updating password for service {}: {}", self.service, e);
+        if let Err(e) = api::upgrade(&self.service, &apctx.masterpwd, self.password.as_deref(), Some(self.seen)) {
+            apctx.problems.push(format!("Error updating password for service {}: {}", self.service, e));
         }
         apctx.refresh_service = true;
     }
@@ -237,15 +606,26 @@ impl Display<ApCtx, bool> for PasswordRefresh {
     }
 }
 
-fn display_new_kvs(ui: &mut Ui, newkvp: &mut Option<(String, String)>, is_save: bool) -> bool {
+fn display_new_kvs(ui: &mut Ui, newkvp: &mut Option<(String, String, Visibility)>, is_save: bool) -> bool {
     let mut rmnew = false;
     let mut savekv = false;
     match newkvp {
-        Some((key, val)) => {
+        Some((key, val, visibility)) => {
             ui.horizontal(|ui| {
                 let (_, key_valid) = textedit2(ui, key, NotEmpty{}, |te, _valid| te.desired_width(50.0));
                 ui.add(Label::new("="));
-                let (_, val_valid) = textedit2(ui, val, NotEmpty{}, |te, _valid| te.desired_width(50.0));
+                let (_, val_valid) = textedit2(ui, val, NotEmpty{}, |te, _valid| {
+                    te.desired_width(50.0)
+                        .password(*visibility != Visibility::Plain)
+                });
+                ComboBox::from_id_salt("newkv_visibility")
+                    .selected_text(visibility.to_string())
+                    .width(70.0)
+                    .show_ui(ui, |ui| {
+                        for v in [Visibility::Plain, Visibility::Concealed, Visibility::CopyOnly] {
+                            ui.selectable_value(visibility, v, v.to_string());
+                        }
+                    });
 
                 let msg = if is_save { "Save" } else { "Commit" };
                 let commit = Button::new(msg);
@@ -262,7 +642,7 @@ fn display_new_kvs(ui: &mut Ui, newkvp: &mut Option<(String, String)>, is_save:
         }
         None => {
             if ui.add(Button::new("Add key/value")).clicked() {
-                *newkvp = Some((String::new(), String::new()));
+                *newkvp = Some((String::new(), String::new(), Visibility::Plain));
             }
         }
     }
@@ -273,15 +653,43 @@ fn display_new_kvs(ui: &mut Ui, newkvp: &mut Option<(String, String)>, is_save:
     savekv
 }
 
-fn display_kvs(ui: &mut Ui, service: Option<&str>, kvs: &[(String, String)], confirm: &mut Windowed<Box<dyn Display<ApCtx, bool>>>) {
+/// Shows the kvs with concealed values masked unless their key is in revealed.
+/// Returns a key whose visibility the user asked to change along with the new visibility.
+fn display_kvs(ui: &mut Ui,
+               service: Option<&str>,
+               seen: u64,
+               kvs: &[(String, String, Visibility)],
+               revealed: &mut HashSet<String>,
+               confirm: &mut Windowed<Box<dyn Display<ApCtx, bool>>>) -> Option<(String, Visibility)> {
     let mut delkey = None;
+    let mut change = None;
 
-    for (key, value) in kvs {
+    for (key, value, visibility) in kvs {
         ui.horizontal(|ui| {
             ui.add(Label::new(key));
             ui.add(Label::new("="));
-            ui.add(Label::new(value)
+            let shown = match visibility {
+                Visibility::Plain => value.as_str(),
+                Visibility::Concealed if revealed.contains(key) => value.as_str(),
+                Visibility::Concealed => MASK,
+                Visibility::CopyOnly => "<copy only>"
+            };
+            ui.add(Label::new(shown)
                 .truncate());
+            if *visibility == Visibility::Concealed {
+                let showtxt = if revealed.contains(key) { "Hide" } else { "Show" };
+                if ui.add(Button::new(showtxt)).clicked() && !revealed.remove(key) {
+                    revealed.insert(key.to_owned());
+                }
+            }
+            if *visibility != Visibility::Plain && ui.add(Button::new("Copy")).clicked() {
+                copy_to_clipboard(value);
+            }
+            let resp = ui.add(Button::new(visibility.to_string()).small())
+                .on_hover_text("Change visibility");
+            if resp.clicked() {
+                change = Some((key.to_owned(), visibility.next()));
+            }
             ui.scope(|ui| {
                 ui.visuals_mut().override_text_color = Some(Color32::DARK_RED);
                 if ui.add(Button::new("X")).clicked() {
@@ -295,15 +703,17 @@ fn display_kvs(ui: &mut Ui, service: Option<&str>, kvs: &[(String, String)], con
             "Delete key/value pair".to_owned(),
             Box::new(ConfirmBox::new(
                 format!("Are you sure you want to delete {}?", key),
-                Box::new(KvDelete { service: service.map(|s| s.to_owned()), key: key.to_owned() })
+                Box::new(KvDelete { service: service.map(|s| s.to_owned()), key: key.to_owned(), seen })
             )
         ));
     }
+    change
 }
 
 struct CurrentId {
     entry: IdentityType,
-    newkvp: Option<(String, String)>,
+    newkvp: Option<(String, String, Visibility)>,
+    revealed: HashSet<String>,
     confirm: Windowed<Box<dyn Display<ApCtx, bool>>>,
 }
 
@@ -314,6 +724,7 @@ impl CurrentId {
         Self {
             entry,
             newkvp: None,
+            revealed: HashSet::new(),
             confirm: Windowed::new()
         }
     }
@@ -322,12 +733,14 @@ impl CurrentId {
         let entry = api::get_id(&apctx.masterpwd)
             .expect("Unable to parse id entry");
         self.entry = entry;
+        self.revealed.clear();
     }
 
     fn savekvs(&mut self, apctx: &mut ApCtx) {
-        if let Some((k, v)) = &self.newkvp {
-            api::set_kvs_id(&apctx.masterpwd, &[(k, v)], false)
-                .expect("Error saving key value");
+        if let Some((k, v, visibility)) = &self.newkvp {
+            if let Err(e) = api::set_kvs_id(&apctx.masterpwd, &[(k, v)], *visibility, false, Some(self.entry.modify_time())) {
+                apctx.problems.push(format!("Error saving {}: {}", k, e));
+            }
             self.newkvp = None;
 
             self.refresh(apctx);
@@ -336,7 +749,7 @@ impl CurrentId {
 
     fn dirty_msg(&self) -> Option<String> {
         match &self.newkvp {
-            Some((nk, nv)) => {
+            Some((nk, nv, _)) => {
                 if nk.len() == 0 && nv.len() == 0 {
                     None
                 } else {
@@ -358,7 +771,12 @@ impl Display<ApCtx, bool> for CurrentId {
         let kvs = self.entry.get_kvs();
 
         ui.add(Separator::default());
-        display_kvs(ui, None, &kvs, &mut self.confirm);
+        if let Some((key, visibility)) = display_kvs(ui, None, self.entry.modify_time(), kvs, &mut self.revealed, &mut self.confirm) {
+            if let Err(e) = api::set_kv_visibility_id(&apctx.masterpwd, &key, visibility, Some(self.entry.modify_time())) {
+                apctx.problems.push(format!("Error saving visibility of {}: {}", key, e));
+            }
+            self.refresh(apctx);
+        }
 
         if display_new_kvs(ui, &mut self.newkvp, true) {
             self.savekvs(apctx);
@@ -371,9 +789,11 @@ impl Display<ApCtx, bool> for CurrentId {
 
 struct CurrentService {
     entry: ServiceType,
+    attachments: Result<Vec<AttachmentType>, String>, // Why they couldn't be listed, shown in their place
     show_pass: bool,
     copied: bool,
-    newkvp: Option<(String, String)>,
+    newkvp: Option<(String, String, Visibility)>,
+    revealed: HashSet<String>,
     newtag: String,
     confirm: Windowed<Box<dyn Display<ApCtx, bool>>>,
 }
@@ -382,11 +802,15 @@ impl CurrentService {
     fn new(service: &str, apctx: &ApCtx) -> Self {
         let entry = api::get_all(service, &apctx.masterpwd)
             .expect("Unable to parse service entry");
+        let attachments = api::list_attachments(service, &apctx.masterpwd)
+            .map_err(|e| e.to_string());
         Self {
             entry,
+            attachments,
             show_pass: false,
             copied: false,
             newkvp: None,
+            revealed: HashSet::new(),
             newtag: String::new(),
             confirm: Windowed::new()
         }
@@ -395,15 +819,19 @@ impl CurrentService {
     fn refresh(&mut self, apctx: &ApCtx) {
         let entry = api::get_all(self.entry.name(), &apctx.masterpwd)
             .expect("Unable to parse service entry");
+        self.attachments = api::list_attachments(self.entry.name(), &apctx.masterpwd)
+            .map_err(|e| e.to_string());
         self.entry = entry;
         self.show_pass = false;
         self.copied = false;
+        self.revealed.clear();
     }
 
     fn savekvs(&mut self, apctx: &mut ApCtx) {
-        if let Some((k, v)) = &self.newkvp {
-            api::set_kvs(self.entry.name(), &apctx.masterpwd, &[(k, v)], false)
-                .expect("Error saving key value");
+        if let Some((k, v, visibility)) = &self.newkvp {
+            if let Err(e) = api::set_kvs(self.entry.name(), &apctx.masterpwd, &[(k, v)], *visibility, false, Some(self.entry.modify_time())) {
+                apctx.problems.push(format!("Error saving {}: {}", k, e));
+            }
             self.newkvp = None;
 
             self.refresh(apctx);
@@ -411,8 +839,9 @@ impl CurrentService {
     }
 
     fn savetag(&mut self, apctx: &mut ApCtx) {
-        api::set_tags(self.entry.name(), &apctx.masterpwd, &[&self.newtag], false)
-            .expect("Error saving tag");
+        if let Err(e) = api::set_tags(self.entry.name(), &apctx.masterpwd, &[&self.newtag], false, Some(self.entry.modify_time())) {
+            apctx.problems.push(format!("Error saving tag {}: {}", self.newtag, e));
+        }
         self.newtag = String::new();
 
         self.refresh(apctx);
@@ -421,7 +850,7 @@ impl CurrentService {
 
     fn dirty_msg(&self) -> Option<String> {
         match &self.newkvp {
-            Some((nk, nv)) => {
+            Some((nk, nv, _)) => {
                 if nk.len() == 0 && nv.len() == 0 {
                     None
                 } else {
@@ -463,19 +892,28 @@ impl Display<ApCtx, bool> for CurrentService {
                 if ui.add(incrpwd).clicked() {
                     self.confirm.set(
                         format!("New password for {}", self.entry.name()),
-                        Box::new(PasswordRefresh::new(self.entry.name().to_owned()))
+                        Box::new(PasswordRefresh::new(self.entry.name().to_owned(), self.entry.modify_time()))
                     );
                 }
             })
         });
         ui.add(Label::new(format!("Created: {}", self.entry.created())));
         ui.add(Label::new(format!("Last Modified: {}", self.entry.modified())));
+        ui.add(Label::new(format!("Password Changed: {}", self.entry.password_changed())));
+        if let Some(age) = apctx.services.stale_age(self.entry.name()) {
+            ui.colored_label(Color32::DARK_RED, format!("Password is {} days old, past its max age", age));
+        }
 
         /* Kvs section */
         let kvs = self.entry.get_kvs();
 
         ui.add(Separator::default());
-        display_kvs(ui, Some(self.entry.name()), &kvs, &mut self.confirm);
+        if let Some((key, visibility)) = display_kvs(ui, Some(self.entry.name()), self.entry.modify_time(), kvs, &mut self.revealed, &mut self.confirm) {
+            if let Err(e) = api::set_kv_visibility(self.entry.name(), &apctx.masterpwd, &key, visibility, Some(self.entry.modify_time())) {
+                apctx.problems.push(format!("Error saving visibility of {}: {}", key, e));
+            }
+            self.refresh(apctx);
+        }
 
         if display_new_kvs(ui, &mut self.newkvp, true) {
             self.savekvs(apctx);
@@ -505,13 +943,63 @@ impl Display<ApCtx, bool> for CurrentService {
                         "Delete Tag".to_owned(), 
                         Box::new(ConfirmBox::new(
                             format!("Are you sure you want to delete tag {} from {}?", tag, self.entry.name()),
-                            Box::new(TagDelete { service: self.entry.name().to_owned(), tag_to_remove: tag.to_owned() })
+                            Box::new(TagDelete {
+                                service: self.entry.name().to_owned(),
+                                tag_to_remove: tag.to_owned(),
+                                seen: self.entry.modify_time()
+                            })
                         ))
                     );
                 }
             }
         });
 
+        ui.add(Separator::default());
+
+        /* Attachments section */
+        let mut saveas = None;
+        let mut delattachment = None;
+        if let Err(e) = &self.attachments {
+            ui.colored_label(Color32::DARK_RED, format!("Unable to list attachments: {}", e));
+        }
+        for attachment in self.attachments.iter().flatten() {
+            ui.horizontal(|ui| {
+                ui.add(Label::new(attachment.name())
+                    .truncate());
+                ui.add(Label::new(human_size(attachment.get_size())));
+                if ui.add(Button::new("Save As")).clicked() {
+                    saveas = Some(attachment.name().to_owned());
+                }
+                ui.scope(|ui| {
+                    ui.visuals_mut().override_text_color = Some(Color32::DARK_RED);
+                    if ui.add(Button::new("X")).clicked() {
+                        delattachment = Some(attachment.name().to_owned());
+                    }
+                });
+            });
+        }
+        if ui.add(Button::new("Add attachment")).clicked() {
+            self.confirm.set(
+                "Add Attachment".to_owned(),
+                Box::new(AddAttachment::new(self.entry.name().to_owned()))
+            );
+        }
+        if let Some(attachment) = saveas {
+            self.confirm.set(
+                format!("Save {}", attachment),
+                Box::new(SaveAttachment::new(self.entry.name().to_owned(), attachment))
+            );
+        }
+        if let Some(attachment) = delattachment {
+            self.confirm.set(
+                "Delete Attachment".to_owned(),
+                Box::new(ConfirmBox::new(
+                    format!("Are you sure you want to delete attachment {} from {}?", attachment, self.entry.name()),
+                    Box::new(AttachmentDelete { service: self.entry.name().to_owned(), attachment })
+                ))
+            );
+        }
+
         ui.add(Separator::default());
         
         /* Service level buttons */
@@ -523,7 +1011,7 @@ impl Display<ApCtx, bool> for CurrentService {
                 self.confirm.set(
                     "Delete Service".to_owned(), 
                     Box::new(ConfirmBox::new(
-                        format!("Are you sure you want to delete service {}", self.entry.name()),
+                        format!("Are you sure you want to move service {} to the trash?", self.entry.name()),
                         Box::new(DeleteService { service: self.entry.name().to_owned() })
                     ))
                 );
@@ -537,8 +1025,8 @@ impl Display<ApCtx, bool> for CurrentService {
 struct NewService {
     name: String,
     password: Option<String>,
-    kvs: Vec<(String, String)>,
-    newkvp: Option<(String, String)>,
+    kvs: Vec<(String, String, Visibility)>,
+    newkvp: Option<(String, String, Visibility)>,
     tags: Vec<String>,
     newtag: Option<String>
 }
@@ -549,16 +1037,26 @@ impl NewService {
     }
 
     fn save(&self, apctx: &mut ApCtx) {
+        let kvs: Vec<(String, String)> = self.kvs.iter()
+            .map(|(k, v, _)| (k.clone(), v.clone()))
+            .collect();
+        let defaults = config::current();
         if let Err(e) = api::new(
             &self.name,
             &apctx.masterpwd,
-            &pass::hash::TextMode::NoWhiteSpace,
-            16,
-            &self.kvs,
+            &defaults.text_mode,
+            defaults.length,
+            &kvs,
             &self.tags,
             self.password.as_ref().map(|s| s.as_str())
         ) {
             eprintln!("Error saving new service {}: {}", self.name, e);
+        } else {
+            for (key, _, visibility) in self.kvs.iter().filter(|(_, _, v)| *v != Visibility::Plain) {
+                if let Err(e) = api::set_kv_visibility(&self.name, &apctx.masterpwd, key, *visibility, None) {
+                    eprintln!("Error setting visibility of {} for {}: {}", key, self.name, e);
+                }
+            }
         }
         apctx.refresh_service_list = true;
     }
@@ -580,12 +1078,17 @@ impl Display<ApCtx, bool> for NewService {
         ui.add(Separator::default());
 
         let mut delidx = None;
-        for (idx, (key, value)) in self.kvs.iter().enumerate() {
+        for (idx, (key, value, visibility)) in self.kvs.iter().enumerate() {
             ui.horizontal(|ui| {
                 ui.add(Label::new(key));
                 ui.add(Label::new("="));
-                ui.add(Label::new(value)
+                let shown = match visibility {
+                    Visibility::Plain => value.as_str(),
+                    _ => MASK
+                };
+                ui.add(Label::new(shown)
                     .truncate());
+                ui.add(Label::new(RichText::new(visibility.to_string()).small()));
                 ui.scope(|ui| {
                     ui.visuals_mut().override_text_color = Some(Color32::DARK_RED);
                     if ui.add(Button::new("X")).clicked() {
@@ -600,8 +1103,8 @@ impl Display<ApCtx, bool> for NewService {
         }
 
         if display_new_kvs(ui, &mut self.newkvp, false) {
-            if let Some((k, v)) = self.newkvp.take() {
-                self.kvs.push((k, v));
+            if let Some(kvp) = self.newkvp.take() {
+                self.kvs.push(kvp);
             }
         }
 
@@ -681,16 +1184,197 @@ impl Display<ApCtx, bool> for NewService {
     }
 }
 
+struct CurrentTrash {
+    trash: Vec<TrashType>,
+    confirm: Windowed<Box<dyn Display<ApCtx, bool>>>,
+}
+
+impl CurrentTrash {
+    fn new(apctx: &ApCtx) -> Self {
+        let trash = api::list_trash(&apctx.masterpwd)
+            .expect("Unable to list trash");
+        Self {
+            trash,
+            confirm: Windowed::new()
+        }
+    }
+
+    fn refresh(&mut self, apctx: &ApCtx) {
+        self.trash = api::list_trash(&apctx.masterpwd)
+            .expect("Unable to list trash");
+    }
+}
+
+impl Display<ApCtx, bool> for CurrentTrash {
+    fn display(&mut self, ctx: &egui::Context, ui: &mut Ui, apctx: &mut ApCtx) -> bool {
+        self.confirm.display(ctx, apctx);
+
+        let mut keep = true;
+        ui.add(Label::new(RichText::new("Trash").strong()));
+        let retention = match pass::spec::trash_retention() {
+            0 => "Deleted services are kept until the trash is emptied".to_owned(),
+            days => format!("Deleted services are permanently removed after {} days", days)
+        };
+        ui.add(Label::new(retention));
+        ui.add(Separator::default());
+
+        if self.trash.is_empty() {
+            ui.add(Label::new("Trash is empty"));
+        }
+
+        let mut restore = None;
+        egui::ScrollArea::vertical().max_height(300.0).show(ui, |ui| {
+            for trash in &self.trash {
+                ui.horizontal(|ui| {
+                    ui.add(Label::new(trash.name())
+                        .truncate());
+                    ui.add(Label::new(trash.deleted()));
+                    if !trash.get_attachments().is_empty() {
+                        ui.add(Label::new(format!("📎{}", trash.get_attachments().len())))
+                            .on_hover_text(trash.get_attachments().join("\n"));
+                    }
+                    let exists = apctx.services.not_in_services().valid(&trash.name().to_owned()).is_err();
+                    let resp = ui.add_enabled(!exists, Button::new("Restore"))
+                        .on_disabled_hover_text("A service with this name already exists");
+                    if resp.clicked() {
+                        restore = Some(trash.name().to_owned());
+                    }
+                });
+            }
+        });
+        if let Some(name) = restore {
+            if let Err(e) = api::restore(&name, &apctx.masterpwd) {
+                eprintln!("Error restoring service {}: {}", name, e);
+            }
+            self.refresh(apctx);
+            apctx.refresh_service_list = true;
+        }
+
+        ui.add(Separator::default());
+
+        ui.horizontal(|ui| {
+            if ui.add(Button::new("Hide Trash")).clicked() {
+                keep = false;
+            }
+            if ui.add_enabled(!self.trash.is_empty(), Button::new("Empty Trash")).clicked() {
+                self.confirm.set(
+                    "Empty Trash".to_owned(),
+                    Box::new(ConfirmBox::new(
+                        format!("Are you sure you want to permanently delete {} services?", self.trash.len()),
+                        EmptyTrash
+                    ))
+                );
+            }
+        });
+
+        keep
+    }
+}
+
+struct CurrentAudit {
+    log: Result<AuditLog, String>,
+    all: bool,
+    service: String,
+    confirm: Windowed<Box<dyn Display<ApCtx, bool>>>,
+}
+
+impl CurrentAudit {
+    fn new(apctx: &ApCtx) -> Self {
+        let mut audit = Self {
+            log: Err(String::new()),
+            all: false,
+            service: String::new(),
+            confirm: Windowed::new()
+        };
+        audit.refresh(apctx);
+        audit
+    }
+
+    fn refresh(&mut self, apctx: &ApCtx) {
+        self.log = audit::load(&apctx.masterpwd, self.all).map_err(|e| e.to_string());
+    }
+}
+
+impl Display<ApCtx, bool> for CurrentAudit {
+    fn display(&mut self, ctx: &egui::Context, ui: &mut Ui, apctx: &mut ApCtx) -> bool {
+        self.confirm.display(ctx, apctx);
+
+        let mut keep = true;
+        ui.add(Label::new(RichText::new("Audit Log").strong()));
+        ui.horizontal(|ui| {
+            if ui.checkbox(&mut self.all, "Include rotated logs").changed() {
+                self.refresh(apctx);
+            }
+            ui.add(Label::new("Service:"));
+            ui.add(egui::TextEdit::singleline(&mut self.service).hint_text("all").desired_width(120.0));
+        });
+        ui.add(Separator::default());
+
+        match &self.log {
+            Ok(log) => {
+                for idx in log.breaks.iter() {
+                    let msg = match log.records.get(*idx) {
+                        Some(record) => format!("⚠ Log was tampered with before: {}", record),
+                        None => "⚠ Log was tampered with at the end".to_owned()
+                    };
+                    ui.colored_label(Color32::DARK_RED, msg);
+                }
+                egui::ScrollArea::vertical().max_height(300.0).show(ui, |ui| {
+                    egui::Grid::new("audit").striped(true).show(ui, |ui| {
+                        // Most recent first
+                        let records = log.records.iter()
+                            .rev()
+                            .filter(|r| self.service.is_empty() || r.name() == self.service);
+                        for record in records {
+                            ui.add(Label::new(record.when()));
+                            ui.add(Label::new(record.get_client().to_string()));
+                            ui.add(Label::new(record.get_op().to_string()));
+                            ui.add(Label::new(record.name()).truncate());
+                            ui.end_row();
+                        }
+                    });
+                });
+            }
+            Err(e) => {
+                ui.colored_label(Color32::DARK_RED, e);
+            }
+        }
+
+        ui.add(Separator::default());
+
+        ui.horizontal(|ui| {
+            if ui.add(Button::new("Hide Audit Log")).clicked() {
+                keep = false;
+            }
+            if ui.add(Button::new("Rotate")).clicked() {
+                self.confirm.set(
+                    "Rotate Audit Log".to_owned(),
+                    Box::new(ConfirmBox::new(
+                        format!("Start a new audit log? Only the newest {} old logs are kept.", audit::DEFAULT_KEEP),
+                        RotateAudit
+                    ))
+                );
+            }
+        });
+
+        keep
+    }
+}
+
 enum Current {
     Id(CurrentId),
-    Service(CurrentService)
+    Service(CurrentService),
+    Trash(CurrentTrash),
+    Audit(CurrentAudit)
 }
 
 impl Current {
     fn refresh(&mut self, apctx: &ApCtx) {
         match self {
             Self::Id(i) => i.refresh(apctx),
-            Self::Service(s) => s.refresh(apctx)
+            Self::Service(s) => s.refresh(apctx),
+            Self::Trash(t) => t.refresh(apctx),
+            Self::Audit(a) => a.refresh(apctx)
         }
     }
 
@@ -709,10 +1393,25 @@ impl Current {
         }
     }
 
+    fn is_trash(&self) -> bool {
+        match self {
+            Self::Trash(_) => true,
+            _ => false
+        }
+    }
+
+    fn is_audit(&self) -> bool {
+        match self {
+            Self::Audit(_) => true,
+            _ => false
+        }
+    }
+
     fn dirty_msg(&self) -> Option<String> {
         match self {
             Self::Service(s) => s.dirty_msg(),
-            Self::Id(id) => id.dirty_msg()
+            Self::Id(id) => id.dirty_msg(),
+            Self::Trash(_) | Self::Audit(_) => None
         }
     }
 }
@@ -721,7 +1420,9 @@ impl Display<ApCtx, bool> for Current {
     fn display(&mut self, ctx: &egui::Context, ui: &mut Ui, apctx: &mut ApCtx) -> bool {
         match self {
             Current::Id(c) => c.display(ctx, ui, apctx),
-            Current::Service(s) => s.display(ctx, ui, apctx)
+            Current::Service(s) => s.display(ctx, ui, apctx),
+            Current::Trash(t) => t.display(ctx, ui, apctx),
+            Current::Audit(a) => a.display(ctx, ui, apctx)
         }
     }
 }
@@ -736,10 +1437,65 @@ impl Action<ApCtx> for MoveTo {
     }
 }
 
+fn tag_button(ui: &mut Ui,
+              node: &TagNode,
+              services: &ServiceList,
+              toggled: &mut Option<String>,
+              managetag: &mut Option<(String, bool)>) {
+    let color = if services.tag_toggled(&node.path) { Color32::GRAY } else { Color32::LIGHT_GRAY };
+    let tagfilter = Button::new(format!("{} ({})", node.name, node.count)).fill(color).corner_radius(5);
+    let resp = ui.add(tagfilter)
+        .on_hover_text(format!("{}, right click to rename or delete", node.path));
+    if resp.clicked() {
+        *toggled = Some(node.path.clone());
+    }
+    resp.context_menu(|ui| {
+        if ui.button("Rename or merge").clicked() {
+            *managetag = Some((node.path.clone(), false));
+            ui.close_menu();
+        }
+        if ui.button("Delete from all services").clicked() {
+            *managetag = Some((node.path.clone(), true));
+            ui.close_menu();
+        }
+    });
+}
+
+/// Tags for filtering results, folders take in everything under them
+fn tag_tree(ui: &mut Ui,
+            nodes: &[TagNode],
+            services: &ServiceList,
+            toggled: &mut Option<String>,
+            managetag: &mut Option<(String, bool)>) {
+    for node in nodes {
+        if node.children.is_empty() {
+            ui.horizontal(|ui| {
+                ui.add_space(ui.spacing().indent);
+                tag_button(ui, node, services, toggled, managetag);
+            });
+        } else {
+            let id = ui.make_persistent_id(("tagfolder", &node.path));
+            CollapsingState::load_with_default_open(ui.ctx(), id, false)
+                .show_header(ui, |ui| tag_button(ui, node, services, toggled, managetag))
+                .body(|ui| tag_tree(ui, &node.children, services, toggled, managetag));
+        }
+    }
+}
+
+/// Unlock prompt shown once the app has been idle for the configured time
+struct Locked {
+    pwd: String,
+    error: Option<String>
+}
+
 struct ApApp {
     current: Option<Current>,
+    /// Services picked for a bulk action, None unless picking
+    picked: Option<HashSet<String>>,
     newservice: Windowed<NewService>,
     confirm: Windowed<Box<dyn Display<ApCtx, bool>>>,
+    last_input: Instant,
+    locked: Option<Locked>,
     ctx: ApCtx
 }
 
@@ -754,12 +1510,75 @@ impl ApApp {
 
         Self {
             current: None,
+            picked: None,
             newservice: Windowed::new(),
             confirm: Windowed::new(),
+            last_input: Instant::now(),
+            locked: None,
             ctx: ApCtx::new(username, pwd, services)
         }
     }
 
+    /// Forget the master password and anything being edited until it's entered again
+    fn lock(&mut self) {
+        self.current = None;
+        self.picked = None;
+        self.newservice = Windowed::new();
+        self.confirm = Windowed::new();
+        self.ctx.masterpwd.clear();
+        self.locked = Some(Locked { pwd: String::new(), error: None });
+    }
+
+    /// Lock once there's been no input for the configured time. While locked only the
+    /// unlock prompt is shown and this returns true.
+    fn check_lock(&mut self, ctx: &egui::Context) -> bool {
+        let auto_lock = config::current().auto_lock;
+        if auto_lock == 0 {
+            return false;
+        }
+        if ctx.input(|i| !i.events.is_empty() || i.pointer.is_moving()) {
+            self.last_input = Instant::now();
+        }
+        if self.locked.is_none() && self.last_input.elapsed() >= Duration::from_secs(auto_lock) {
+            self.lock();
+        }
+        // Idle means no input to trigger a repaint, so keep checking
+        ctx.request_repaint_after(Duration::from_secs(1));
+
+        let locked = match self.locked.as_mut() {
+            Some(locked) => locked,
+            None => return false
+        };
+        let mut unlock = false;
+        egui::CentralPanel::default().show(ctx, |ui| {
+            ui.add(Label::new(RichText::new("Locked").strong()));
+            ui.add(Label::new("Enter the master password to unlock"));
+            let resp = ui.add(egui::TextEdit::singleline(&mut locked.pwd).password(true));
+            resp.request_focus();
+            if let Some(e) = &locked.error {
+                ui.colored_label(Color32::DARK_RED, e);
+            }
+            let enter = resp.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+            unlock = ui.add(Button::new("Unlock")).clicked() || enter;
+        });
+        if !unlock {
+            return true;
+        }
+        match api::get_id(&locked.pwd) {
+            Ok(_) => {
+                self.ctx.masterpwd = std::mem::take(&mut locked.pwd);
+                self.locked = None;
+                self.ctx.refresh_service_list = true;
+                false
+            }
+            Err(e) => {
+                locked.error = Some(e.to_string());
+                locked.pwd.clear();
+                true
+            }
+        }
+    }
+
     fn set_current(&mut self, target: Option<Current>) {
         /* Reset the service to none if you reclick on the same service */
         if let Some(msg) = self.current
@@ -779,8 +1598,18 @@ impl ApApp {
 
 impl eframe::App for ApApp {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        if self.check_lock(ctx) {
+            return;
+        }
         self.confirm.display(ctx, &mut self.ctx);
 
+        if let Some(results) = self.ctx.bulk_results.take() {
+            self.confirm.set("Bulk Results".to_owned(), Box::new(BulkReport { results }));
+            if let Some(picked) = self.picked.as_mut() {
+                picked.clear();
+            }
+        }
+
         if self.ctx.refresh_service_list {
             self.ctx.services.refresh(&self.ctx.masterpwd).unwrap_or_else(|e| {
                 panic!("Unable to list services: {}", e);
@@ -801,6 +1630,41 @@ impl eframe::App for ApApp {
 
         self.newservice.display(ctx, &mut self.ctx);
 
+        self.ctx.problems.extend(warnings::take());
+        if !self.ctx.problems.is_empty() {
+            egui::TopBottomPanel::bottom("problems").show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    ui.vertical(|ui| {
+                        for problem in self.ctx.problems.iter() {
+                            ui.colored_label(Color32::DARK_RED, problem);
+                        }
+                    });
+                    if ui.add(Button::new("✖")).clicked() {
+                        self.ctx.problems.clear();
+                    }
+                });
+            });
+        }
+
+        if let Some(what) = self.ctx.undo.clone() {
+            egui::TopBottomPanel::bottom("undo").show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    ui.label(what);
+                    if ui.add(Button::new("↶ Undo")).on_hover_text("Put back what this changed").clicked() {
+                        if let Err(e) = snapshot::undo(&self.ctx.masterpwd) {
+                            eprintln!("Error undoing: {}", e);
+                        }
+                        self.ctx.undo = None;
+                        self.ctx.refresh_service = true;
+                        self.ctx.refresh_service_list = true;
+                    }
+                    if ui.add(Button::new("✖")).clicked() {
+                        self.ctx.undo = None;
+                    }
+                });
+            });
+        }
+
         egui::SidePanel::left("services")
             .resizable(false)
             .max_width(120.0)
@@ -811,11 +1675,32 @@ impl eframe::App for ApApp {
                         if ui.add(addservice).clicked() {
                             self.newservice.set("New Service".to_owned(), NewService::new());
                         }
+                        let is_trash = self.current.as_ref().map(|c| c.is_trash()).unwrap_or(false);
+                        let trash = SelectableLabel::new(is_trash, "🗑 Trash");
+                        if ui.add(trash).clicked() {
+                            let target = if is_trash {
+                                None
+                            } else {
+                                Some(Current::Trash(CurrentTrash::new(&self.ctx)))
+                            };
+                            self.set_current(target);
+                        }
+                        let is_audit = self.current.as_ref().map(|c| c.is_audit()).unwrap_or(false);
+                        let auditlog = SelectableLabel::new(is_audit, "📜 Audit");
+                        if ui.add(auditlog).clicked() {
+                            let target = if is_audit {
+                                None
+                            } else {
+                                Some(Current::Audit(CurrentAudit::new(&self.ctx)))
+                            };
+                            self.set_current(target);
+                        }
                     });
                 });
 
                 egui::ScrollArea::vertical().show(ui, |ui| {
                     let mut selected = None;
+                    let mut managetag = None;
 
                     let is_selected = self.current.as_ref().map(|c| c.is_id()).unwrap_or(false);
                     if ui.add(SelectableLabel::new(is_selected, RichText::new(&self.ctx.username).strong())).clicked() {
@@ -827,46 +1712,100 @@ impl eframe::App for ApApp {
                         selected = Some(target);
                     }
 
+                    ui.add(Separator::default());
+                    let search = egui::TextEdit::singleline(self.ctx.services.query_mut())
+                        .hint_text("🔍 Search");
+                    ui.add(search)
+                        .on_hover_text("Matches names, tags and key value pairs, close misspellings too");
+
                     // Tags for filtering results
-                    let tags = self.ctx.services.tags_mut();
-                    if tags.len() > 0 {
+                    if !self.ctx.services.tag_tree().is_empty() {
                         ui.add(Separator::default());
 
-                        let mut selected = false;
-                        ui.horizontal_wrapped(|ui| {
-                            for (tag, enabled) in &mut *tags {
-                                let color = if *enabled {
-                                    selected = true;
-                                    Color32::GRAY
-                                } else {
-                                    Color32::LIGHT_GRAY
-                                };
-
-                                let tagfilter = Button::new(tag.clone()).fill(color).corner_radius(5);
-
-                                if ui.add(tagfilter).clicked() {
-                                    *enabled = !*enabled;
+                        ui.horizontal(|ui| {
+                            let current = self.ctx.services.tag_mode();
+                            for (mode, label, hover) in [
+                                (TagMode::Any, "Any", "Show services with any of the selected tags"),
+                                (TagMode::All, "All", "Show services with all of the selected tags"),
+                                (TagMode::Query, "Query", "Filter with a query like (aws or gcp) and not legacy")
+                            ] {
+                                if ui.add(SelectableLabel::new(current == mode, label)).on_hover_text(hover).clicked() {
+                                    self.ctx.services.set_tag_mode(mode);
                                 }
                             }
                         });
+                    }
 
-                        /*
-                        ui.vertical_centered(|ui| {
-                            let toggle_text = if selected { "Unselect Tags" } else { "List All" };
-                            if ui.add(Button::new(toggle_text)).clicked() {
-                                for (_, enabled) in tags {
-                                    *enabled = !selected;
-                                }
-                            }
-                        });
-                        */
+                    if self.ctx.services.tag_mode() == TagMode::Query {
+                        let query = egui::TextEdit::singleline(self.ctx.services.tag_query_mut())
+                            .hint_text("work and not legacy");
+                        ui.add(query);
+                        if let Err(e) = self.ctx.services.tag_filter() {
+                            ui.colored_label(Color32::DARK_RED, e.to_string());
+                        }
+                    }
+
+                    if self.ctx.services.tag_mode() != TagMode::Query {
+                        let mut toggled = None;
+                        tag_tree(ui, self.ctx.services.tag_tree(), &self.ctx.services, &mut toggled, &mut managetag);
+                        if let Some(tag) = toggled {
+                            self.ctx.services.toggle_tag(&tag);
+                        }
                     }
 
                     ui.add(Separator::default());
 
+                    if self.ctx.services.corrupt() > 0 {
+                        ui.colored_label(Color32::DARK_RED, format!("⚠ {} unreadable", self.ctx.services.corrupt()))
+                            .on_hover_text("Some files in the vault couldn't be read, run apcli fsck for details");
+                    }
+
+                    let mut bulkmenu = None;
+                    ui.horizontal(|ui| {
+                        let picking = self.picked.is_some();
+                        let pick = SelectableLabel::new(picking, "☑ Select");
+                        if ui.add(pick).on_hover_text("Pick services to change all at once").clicked() {
+                            self.picked = if picking { None } else { Some(HashSet::new()) };
+                        }
+                        if let Some(picked) = self.picked.as_ref().filter(|p| !p.is_empty()) {
+                            ui.menu_button(format!("{} ⏷", picked.len()), |ui| {
+                                for (menu, label) in [
+                                    (BulkMenu::Upgrade, "New passwords"),
+                                    (BulkMenu::Tags, "Set tags"),
+                                    (BulkMenu::Kv, "Set key value"),
+                                    (BulkMenu::Delete, "Move to trash")
+                                ] {
+                                    if ui.button(label).clicked() {
+                                        bulkmenu = Some(menu);
+                                        ui.close_menu();
+                                    }
+                                }
+                            });
+                        }
+                    });
+
                     for service in self.ctx.services.iter_visible_services() {
+                        if let Some(picked) = self.picked.as_mut() {
+                            let mut checked = picked.contains(service);
+                            if ui.checkbox(&mut checked, service).changed() {
+                                if checked {
+                                    picked.insert(service.to_owned());
+                                } else {
+                                    picked.remove(service);
+                                }
+                            }
+                            continue;
+                        }
                         let is_selected = self.current.as_ref().map(|c| c.is_service(&service)).unwrap_or(false);
-                        if ui.add(SelectableLabel::new(is_selected, service)).clicked() {
+                        let resp = match self.ctx.services.stale_age(service) {
+                            Some(age) => {
+                                let text = RichText::new(format!("⚠ {}", service)).color(Color32::DARK_RED);
+                                ui.add(SelectableLabel::new(is_selected, text))
+                                    .on_hover_text(format!("Password is {} days old", age))
+                            }
+                            None => ui.add(SelectableLabel::new(is_selected, service))
+                        };
+                        if resp.clicked() {
                             let target = if self.current.is_none() || !self.current.as_ref().unwrap().is_service(service) {
                                 Some(Current::Service(CurrentService::new(service, &self.ctx)))
                             } else {
@@ -878,6 +1817,43 @@ impl eframe::App for ApApp {
                     if let Some(target) = selected {
                         self.set_current(target);
                     }
+                    if let (Some(menu), Some(picked)) = (bulkmenu, self.picked.as_ref()) {
+                        let mut names: Vec<String> = picked.iter().cloned().collect();
+                        names.sort();
+                        let confirm = |verb: &str, names: Vec<String>, action| -> Box<dyn Display<ApCtx, bool>> {
+                            let msg = format!("Are you sure you want to {} {} services?\n{}", verb, names.len(), names.join("\n"));
+                            Box::new(ConfirmBox::new(msg, Box::new(BulkRun { names, action })))
+                        };
+                        let (title, window) = match menu {
+                            BulkMenu::Upgrade => ("New Passwords", confirm("generate new passwords for", names, api::BulkAction::Upgrade)),
+                            BulkMenu::Delete => ("Delete Services", confirm("move to the trash", names, api::BulkAction::Delete)),
+                            BulkMenu::Tags => {
+                                let edit = BulkEdit::Tags { tags: String::new(), reset: false };
+                                ("Set Tags", Box::new(BulkEditor { names, edit }) as Box<dyn Display<ApCtx, bool>>)
+                            }
+                            BulkMenu::Kv => {
+                                let edit = BulkEdit::Kv { key: String::new(), value: String::new(), visibility: Visibility::Plain };
+                                ("Set Key Value", Box::new(BulkEditor { names, edit }) as Box<dyn Display<ApCtx, bool>>)
+                            }
+                        };
+                        self.confirm.set(title.to_owned(), window);
+                    }
+                    match managetag {
+                        Some((tag, false)) => {
+                            let tags = self.ctx.services.tags().to_vec();
+                            self.confirm.set("Rename Tag".to_owned(), Box::new(RenameTag::new(tag, tags)));
+                        }
+                        Some((tag, true)) => {
+                            self.confirm.set(
+                                "Delete Tag".to_owned(),
+                                Box::new(ConfirmBox::new(
+                                    format!("Are you sure you want to remove tag {} from every service?", tag),
+                                    Box::new(DeleteTag { tag })
+                                ))
+                            );
+                        }
+                        None => {}
+                    }
                 });
         });
 
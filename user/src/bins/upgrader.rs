@@ -1,14 +1,47 @@
-use pass::spec::{encryptor::Encrypt, service_v1::ServiceEntryV1};
+use clap::{App, SubCommand};
+use pass::upgrade::{self, NewEncryptor, OldEncryptor};
 
 
-type Current = ServiceEntryV1;
-type OldEncryptor = Encrypt;
-type NewEncryptor = Encrypt;
-
+fn status() {
+    match upgrade::upgrade_status() {
+        Ok(Some(status)) => {
+            println!("Upgrading from encryptor v{} to v{}", status.from, status.to);
+            println!("{} of {} files re-encrypted, {} in progress", status.written, status.total, status.moved);
+            if status.committed {
+                println!("Cleaning up, resume to finish");
+            }
+        }
+        Ok(None) => println!("No upgrade in progress"),
+        Err(e) => eprintln!("Error reading upgrade journal: {}", e)
+    }
+}
 
 fn main() {
-    let pwd = pass::cli::read_pass();
-    if let Err(e) = pass::upgrade::upgrade_encryptor::<OldEncryptor, NewEncryptor, Current>(&pwd) {
+    let matches = App::new("AP Upgrader")
+        .version("1.0")
+        .about(concat!("Re-encrypts the vault with the current encryptor. Progress is journaled ",
+                       "so an interrupted upgrade can be resumed or rolled back"))
+        .subcommand(SubCommand::with_name("status")
+                    .about("Show how far an interrupted upgrade got"))
+        .subcommand(SubCommand::with_name("resume")
+                    .about("Carry on an interrupted upgrade"))
+        .subcommand(SubCommand::with_name("rollback")
+                    .about("Undo an interrupted upgrade"))
+        .get_matches();
+
+    let res = match matches.subcommand_name() {
+        Some("status") => return status(),
+        Some("rollback") => upgrade::rollback_encryptor(),
+        Some("resume") => {
+            let pwd = pass::cli::read_pass();
+            upgrade::resume_encryptor::<OldEncryptor, NewEncryptor>(&pwd)
+        }
+        _ => {
+            let pwd = pass::cli::read_pass();
+            upgrade::upgrade_encryptor::<OldEncryptor, NewEncryptor>(&pwd)
+        }
+    };
+    if let Err(e) = res {
         eprintln!("Error upgrading: {}", e);
     }
-}
\ No newline at end of file
+}
@@ -1,12 +1,26 @@
+use std::fs::File;
 use std::io::{stdin, stdout, Write};
+use std::path::Path;
 use std::str::FromStr;
 
-use clap::{Arg, App, SubCommand, ArgMatches};
+use clap::{Arg, App, AppSettings, ArgGroup, SubCommand, ArgMatches};
 use termion::input::TermRead;
 
 use crate::api;
+use crate::audit;
+use crate::backup;
+use crate::config::{self, Scope};
+use crate::export;
+use crate::git;
+use crate::import::{self, csv::Format, OnDuplicate, Outcome, Parsed};
+use crate::snapshot;
+use crate::upgrade;
+use crate::warnings;
 use crate::hash::TextMode;
-use crate::spec::{Serializable, VERSION};
+use crate::kdbx::Kdf;
+use crate::spec::{clear_clipboard_if, copy_to_clipboard, pending_clipboard_clear, Client, Serializable, Visibility, VERSION};
+use crate::tagquery::TagQuery;
+use crate::tagtree::TagNode;
 
 
 pub fn read_pass_raw(prompt: &str) -> String {
@@ -57,6 +71,23 @@ fn arg_kvs() -> Arg<'static, 'static> {
          .number_of_values(1)
 }
 
+fn arg_visibility() -> Arg<'static, 'static> {
+    Arg::with_name("visibility")
+        .long("visibility")
+        .short("v")
+        .value_name("VISIBILITY")
+        .help("How the values are shown: plain, concealed (masked until revealed) or copy-only (never printed)")
+        .takes_value(true)
+        .default_value("plain")
+        .possible_values(&["plain", "concealed", "copy-only"])
+}
+
+fn arg_reveal() -> Arg<'static, 'static> {
+    Arg::with_name("reveal")
+        .long("reveal")
+        .help("Show concealed key value pairs")
+}
+
 fn arg_tags() -> Arg<'static, 'static> {
     Arg::with_name("tags")
         .help("Tag the service with keywords for grouping")
@@ -65,6 +96,19 @@ fn arg_tags() -> Arg<'static, 'static> {
 }
 
 
+fn arg_backup_file() -> Arg<'static, 'static> {
+    Arg::with_name("file")
+        .value_name("FILE")
+        .help("Backup file")
+        .required(true)
+}
+
+fn arg_passphrase() -> Arg<'static, 'static> {
+    Arg::with_name("passphrase")
+        .long("passphrase")
+        .help("Ask for the backup's own passphrase instead of using the master password")
+}
+
 fn arg_set_pass() -> Arg<'static, 'static> {
     Arg::with_name("set-password")
                    .long("set-password")
@@ -73,6 +117,117 @@ fn arg_set_pass() -> Arg<'static, 'static> {
                    .takes_value(true)
 }
 
+fn args_bulk() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("tag")
+            .long("tag")
+            .value_name("TAG")
+            .help("Run on every service with all of these tags instead of one by name")
+            .multiple(true)
+            .number_of_values(1)
+            .conflicts_with("name"),
+        Arg::with_name("query")
+            .long("query")
+            .value_name("QUERY")
+            .help("Run on every service matching a tag query instead of one by name")
+            .takes_value(true)
+            .conflicts_with_all(&["name", "tag"]),
+        Arg::with_name("yes")
+            .short("y")
+            .long("yes")
+            .help("Don't ask before running on more than one service")
+    ]
+}
+
+fn args_import() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("file")
+            .value_name("FILE")
+            .help("Exported file to import")
+            .required(true),
+        Arg::with_name("duplicates")
+            .long("duplicates")
+            .value_name("ACTION")
+            .help("What to do with a service whose name is taken: skip it, overwrite the one there or rename it")
+            .takes_value(true)
+            .default_value("skip")
+            .possible_values(&["skip", "overwrite", "rename"]),
+        Arg::with_name("preview")
+            .long("preview")
+            .help("Only show what would be imported"),
+        Arg::with_name("yes")
+            .short("y")
+            .long("yes")
+            .help("Don't ask before importing")
+    ]
+}
+
+fn is_bulk(matches: &ArgMatches) -> bool {
+    matches.is_present("tag") || matches.is_present("query")
+}
+
+fn confirm(prompt: &str) -> bool {
+    print!("{} [y/N] ", prompt);
+    stdout().flush().unwrap();
+    let mut answer = String::new();
+    if stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+/// Show which services --tag or --query picks, then run action on each of them
+/// once confirmed and report how each went
+fn bulk_cmd(matches: &ArgMatches, pass: &str, action: api::BulkAction, verb: &str) {
+    let query = match matches.value_of("query") {
+        Some(query) => match TagQuery::parse(query) {
+            Ok(query) => query,
+            Err(e) => {
+                eprintln!("Error selecting services: {}", e);
+                return;
+            }
+        },
+        None => TagQuery::all_of(&matches.values_of("tag").map(|v| v.collect()).unwrap_or(vec![]))
+    };
+    let names: Vec<String> = match api::list_query(pass, &query) {
+        Ok((services, _)) => services.iter().map(|s| s.name().to_owned()).collect(),
+        Err(e) => {
+            eprintln!("Error selecting services: {}", e);
+            return;
+        }
+    };
+    if names.is_empty() {
+        println!("No services match");
+        return;
+    }
+    println!("This will {} {} services:", verb, names.len());
+    for name in names.iter() {
+        println!("  {}", name);
+    }
+    if !matches.is_present("yes") && !confirm("Continue?") {
+        return;
+    }
+    match api::bulk(pass, &names, &action) {
+        Ok(results) => {
+            let mut failed = 0;
+            for (name, result) in results {
+                match result {
+                    Ok(Some((old_pass, new_pass))) => println!("{}:\n  Old pass: {}\n  New pass: {}", name, old_pass, new_pass),
+                    Ok(None) => println!("{}: done", name),
+                    Err(e) => {
+                        failed += 1;
+                        eprintln!("{}: {}", name, e);
+                    }
+                }
+            }
+            if failed > 0 {
+                eprintln!("{} of {} services failed", failed, names.len());
+            }
+        }
+        Err(e) => eprintln!("Error running on services: {}", e)
+    }
+}
+
 fn fetch_kvs<'a>(matches: &'a ArgMatches) -> Result<Vec<(&'a str, &'a str)>, &'static str> {
     let mut valid = true;
     let kvs: Vec<(&str, &str)> = match matches.values_of("kvs") {
@@ -94,6 +249,14 @@ fn fetch_kvs<'a>(matches: &'a ArgMatches) -> Result<Vec<(&'a str, &'a str)>, &'s
     }
 }
 
+fn fetch_visibility(matches: &ArgMatches) -> Visibility {
+    match matches.value_of("visibility").unwrap() {
+        "concealed" => Visibility::Concealed,
+        "copy-only" => Visibility::CopyOnly,
+        _ => Visibility::Plain
+    }
+}
+
 fn init_cmd(matches: &ArgMatches) {
     let pwd = read_pass_raw("password: ");
     let pwdconfirm = read_pass_raw("re-enter password: ");
@@ -115,24 +278,46 @@ fn init_cmd(matches: &ArgMatches) {
     }
 }
 
+/// True if name is in the vault, otherwise says why not
+fn service_exists(pass: &str, name: &str) -> bool {
+    match api::exists(pass, name) {
+        Ok(true) => true,
+        Ok(false) => {
+            eprintln!("{} does not exist", name);
+            false
+        }
+        Err(e) => {
+            eprintln!("Error looking up {}: {}", name, e);
+            false
+        }
+    }
+}
+
 fn new_cmd(matches: &ArgMatches) {
     let pass = read_pass();
 
     let name = matches.value_of("name").unwrap();
     println!("Adding '{}' as new service", name);
-    if api::exists(&pass, name) {
-        println!("{} already exists", name);
-        return;
+    match api::exists(&pass, name) {
+        Ok(false) => {}
+        Ok(true) => {
+            println!("{} already exists", name);
+            return;
+        }
+        Err(e) => {
+            eprintln!("Error creating service: {}", e);
+            return;
+        }
     }
 
-    let text_mode = match matches.value_of("text_mode").unwrap() {
-        "alphanumeric" => TextMode::AlphaNumeric,
-        "alphanumericunderscore" => TextMode::AlphaNumericUnderscore,
-        "nowhitespace" => TextMode::NoWhiteSpace,
-        _ => TextMode::NoWhiteSpace
+    let defaults = config::current();
+    let text_mode = match matches.value_of("text_mode").and_then(TextMode::from_name) {
+        Some(text_mode) => text_mode,
+        None => defaults.text_mode
     };
 
-    let len: u8 = match usize::from_str(matches.value_of("length").unwrap()) {
+    let len = defaults.length.to_string();
+    let len: u8 = match usize::from_str(matches.value_of("length").unwrap_or(&len)) {
         Err(_) => {
             eprintln!("Length provided not an integer");
             return;
@@ -168,11 +353,11 @@ fn new_cmd(matches: &ArgMatches) {
     };
 }
 
-fn get_id_cmd(_matches: &ArgMatches) {
+fn get_id_cmd(matches: &ArgMatches) {
     let pass = read_pass();
 
     match api::get_id(&pass) {
-        Ok(id) => println!("{}", id),
+        Ok(id) => println!("{}", id.format(matches.is_present("reveal"))),
         Err(s) => eprintln!("Error getting id info: {}", s)
     }
 }
@@ -180,13 +365,17 @@ fn get_id_cmd(_matches: &ArgMatches) {
 fn get_cmd(matches: &ArgMatches) {
     let pass = read_pass();
     let name = matches.value_of("name").unwrap();
-    if !api::exists(&pass, name) {
-        println!("{} does not exist", name);
+    if !service_exists(&pass, name) {
         return;
     }
 
     let clipboard = matches.is_present("clipboard");
 
+    if let Some(kvkey) = matches.value_of("key") {
+        get_kv(name, &pass, kvkey, clipboard);
+        return;
+    }
+
     let all = matches.is_present("all");
     
     match all {
@@ -201,23 +390,64 @@ fn get_cmd(matches: &ArgMatches) {
         },
         true => {
             match api::get_all(name, &pass) {
-                Ok(entry) => println!("{}", entry),
+                Ok(entry) => println!("{}", entry.format(matches.is_present("reveal"))),
                 Err(s) => eprintln!("Error getting service: {}", s)
             }
         }
     }
 }
 
+/// What `get -k` prints, None when the value goes to the clipboard instead.
+/// Copy only values never go to the terminal.
+fn kv_output(value: &str, visibility: Visibility, clipboard: bool) -> Option<&str> {
+    match clipboard || visibility == Visibility::CopyOnly {
+        true => None,
+        false => Some(value)
+    }
+}
+
+fn get_kv(name: &str, pass: &str, kvkey: &str, clipboard: bool) {
+    let entry = match api::get_all(name, pass) {
+        Ok(entry) => entry,
+        Err(s) => {
+            eprintln!("Error getting service: {}", s);
+            return;
+        }
+    };
+    match entry.get_kv(kvkey) {
+        None => eprintln!("{} has no key {}", name, kvkey),
+        Some((value, visibility)) => match kv_output(value, visibility, clipboard) {
+            Some(value) => println!("{}", value),
+            None => {
+                copy_to_clipboard(value);
+                println!("Copied to clipboard");
+            }
+        }
+    }
+}
+
 fn list_cmd(matches: &ArgMatches) {
     let pass = read_pass();
     if !matches.is_present("simple") {
         println!("\nServices\n--------");
     }
-    let tags = matches.values_of("tags").map(|v| v.collect()).unwrap_or(vec![]);
-    match api::list(&pass, &tags) {
-        Ok(items) => {
+    let query = match matches.value_of("query") {
+        Some(query) => match TagQuery::parse(query) {
+            Ok(query) => query,
+            Err(e) => {
+                eprintln!("Error listing services: {}", e);
+                return;
+            }
+        },
+        None => TagQuery::all_of(&matches.values_of("tags").map(|v| v.collect()).unwrap_or(vec![]))
+    };
+    match api::list_query(&pass, &query) {
+        Ok((items, corrupt)) => {
             for n in items {
-                println!("{}", n);
+                println!("{}", n.name());
+            }
+            if !corrupt.is_empty() {
+                eprintln!("Skipped {} unreadable files, run fsck for details", corrupt.len());
             }
         }
         Err(e) => {
@@ -226,11 +456,25 @@ fn list_cmd(matches: &ArgMatches) {
     }
 }
 
+fn print_tag_tree(nodes: &[TagNode], depth: usize) {
+    for node in nodes {
+        println!("{}{} ({})", "  ".repeat(depth), node.name, node.count);
+        print_tag_tree(&node.children, depth + 1);
+    }
+}
+
 fn list_tags(matches: &ArgMatches) {
     let pass = read_pass();
     if !matches.is_present("simple") {
         println!("\nTags\n--------");
     }
+    if matches.is_present("tree") {
+        match api::list_tag_tree(&pass) {
+            Ok(tree) => print_tag_tree(&tree, 0),
+            Err(e) => eprintln!("Error listing tags: {}", e)
+        }
+        return;
+    }
     match api::list_tags(&pass) {
         Ok(items) => {
             for n in items {
@@ -245,16 +489,26 @@ fn list_tags(matches: &ArgMatches) {
 
 fn setkv_cmd(matches: &ArgMatches) {
     let pass = read_pass();
+    if is_bulk(matches) {
+        match fetch_kvs(matches) {
+            Err(s) => println!("{}", s),
+            Ok(kvs) => {
+                let kvs = kvs.iter().map(|(k, v)| ((*k).to_owned(), (*v).to_owned())).collect();
+                let action = api::BulkAction::SetKvs(kvs, fetch_visibility(matches), matches.is_present("reset"));
+                bulk_cmd(matches, &pass, action, "set key value pairs on");
+            }
+        }
+        return;
+    }
     let name = matches.value_of("name").unwrap();
-    if !api::exists(&pass, name) {
-        eprintln!("{} does not exist", name);
+    if !service_exists(&pass, name) {
         return;
     }
     let reset = matches.is_present("reset");
     match fetch_kvs(matches) {
         Err(s) => println!("{}", s),
         Ok(kvs) => {
-            match api::set_kvs(name, &pass, &kvs, reset) {
+            match api::set_kvs(name, &pass, &kvs, fetch_visibility(matches), reset, None) {
                 Err(s) => eprintln!("Error saving kvs for service {}: {}", name, s),
                 _ => {}
             }
@@ -268,7 +522,7 @@ fn setkv_id_cmd(matches: &ArgMatches) {
     match fetch_kvs(matches) {
         Err(s) => println!("{}", s),
         Ok(kvs) => {
-            match api::set_kvs_id(&pass, &kvs, reset) {
+            match api::set_kvs_id(&pass, &kvs, fetch_visibility(matches), reset, None) {
                 Err(s) => eprintln!("Error saving kvs to id: {}", s),
                 _ => {}
             }
@@ -276,29 +530,114 @@ fn setkv_id_cmd(matches: &ArgMatches) {
     };
 }
 
+fn set_kv_visibility_cmd(matches: &ArgMatches) {
+    let pass = read_pass();
+    let visibility = fetch_visibility(matches);
+    let kvkey = matches.value_of("key").unwrap();
+    let res = match matches.value_of("name") {
+        Some(name) => api::set_kv_visibility(name, &pass, kvkey, visibility, None),
+        None => api::set_kv_visibility_id(&pass, kvkey, visibility, None)
+    };
+    if let Err(s) = res {
+        eprintln!("Error setting visibility of {}: {}", kvkey, s);
+    }
+}
+
 fn set_tags(matches: &ArgMatches) {
-    let name = matches.value_of("name").unwrap();
     let pass = read_pass();
     let reset = matches.is_present("reset");
+    if is_bulk(matches) {
+        let tags = matches.values_of("tags").map(|v| v.map(|t| t.to_owned()).collect()).unwrap_or(vec![]);
+        bulk_cmd(matches, &pass, api::BulkAction::SetTags(tags, reset), "set tags on");
+        return;
+    }
+    let name = matches.value_of("name").unwrap();
     if let Some(tags) = matches.values_of("tags") {
         let tags = tags.collect::<Vec<&str>>();
-        match api::set_tags(name, &pass, &tags, reset) {
+        match api::set_tags(name, &pass, &tags, reset, None) {
             Err(s) => eprintln!("Error saving tags for service {}: {}", name, s),
             _ => {}
         }
     }
 }
 
+fn tag_rename_cmd(matches: &ArgMatches) {
+    let pass = read_pass();
+    let from = matches.value_of("from").unwrap();
+    let to = matches.value_of("to").unwrap();
+    match api::rename_tag(&pass, from, to) {
+        Ok(count) => println!("Renamed tag {} to {} on {} services.", from, to, count),
+        Err(e) => eprintln!("Error renaming tag {}: {}", from, e)
+    }
+}
+
+fn tag_merge_cmd(matches: &ArgMatches) {
+    let pass = read_pass();
+    let tags: Vec<&str> = matches.values_of("tags").unwrap().collect();
+    let into = matches.value_of("into").unwrap();
+    match api::merge_tags(&pass, &tags, into) {
+        Ok(count) => println!("Merged tags into {} on {} services.", into, count),
+        Err(e) => eprintln!("Error merging tags into {}: {}", into, e)
+    }
+}
+
+fn tag_rm_cmd(matches: &ArgMatches) {
+    let pass = read_pass();
+    let tag = matches.value_of("tag").unwrap();
+    match api::delete_tag(&pass, tag) {
+        Ok(count) => println!("Removed tag {} from {} services.", tag, count),
+        Err(e) => eprintln!("Error removing tag {}: {}", tag, e)
+    }
+}
+
+fn tag_cmd(matches: &ArgMatches) {
+    match matches.subcommand() {
+        ("rename", Some(matches)) => tag_rename_cmd(matches),
+        ("merge", Some(matches)) => tag_merge_cmd(matches),
+        ("rm", Some(matches)) => tag_rm_cmd(matches),
+        _ => println!("{}", matches.usage())
+    }
+}
+
+fn upgrade_stale(pass: &str) {
+    let stale = match api::stale(pass) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Error finding stale services: {}", e);
+            return;
+        }
+    };
+    if stale.is_empty() {
+        println!("No stale passwords");
+        return;
+    }
+    for entry in stale {
+        let name = entry.service.name();
+        match api::upgrade(name, pass, None, None) {
+            Err(s) => eprintln!("Error upgrading {}: {}", name, s),
+            Ok((old_pass, new_pass)) => {
+                println!("{}:\n  Old pass: {}\n  New pass: {}", name, old_pass, new_pass);
+            }
+        }
+    }
+}
+
 fn upgrade_cmd(matches: &ArgMatches) {
     let pass = read_pass();
+    if matches.is_present("stale") {
+        upgrade_stale(&pass);
+        return;
+    }
+    if is_bulk(matches) {
+        bulk_cmd(matches, &pass, api::BulkAction::Upgrade, "generate new passwords for");
+        return;
+    }
     let name = matches.value_of("name").unwrap();
-    if !api::exists(&pass, name) {
-        eprintln!("{} does not exist", name);
+    if !service_exists(&pass, name) {
         return;
     }
     let set_password = matches.value_of("set-password");
-    let pass = read_pass();
-    match api::upgrade(name, &pass, set_password) {
+    match api::upgrade(name, &pass, set_password, None) {
         Err(s) => println!("{}", s),
         Ok((old_pass, new_pass)) => {
             println!("Old pass: {}\nNew pass: {}", old_pass, new_pass);
@@ -306,18 +645,800 @@ fn upgrade_cmd(matches: &ArgMatches) {
     };
 }
 
+fn stale_cmd(matches: &ArgMatches) {
+    let pass = read_pass();
+    let mut stale = match api::stale(&pass) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Error finding stale services: {}", e);
+            return;
+        }
+    };
+    match matches.value_of("sort").unwrap() {
+        "name" => stale.sort_by(|s1, s2| s1.service.name().cmp(s2.service.name())),
+        "overdue" => stale.sort_by_key(|s| std::cmp::Reverse(s.age - s.max_age as u64)),
+        _ => {}
+    }
+    if !matches.is_present("simple") {
+        println!("\nStale Services\n--------");
+    }
+    for entry in stale {
+        println!("{}: {} days old (max {} days)", entry.service.name(), entry.age, entry.max_age);
+    }
+}
+
+fn search_cmd(matches: &ArgMatches) {
+    let pass = read_pass();
+    let query = matches.values_of("query").unwrap().collect::<Vec<&str>>().join(" ");
+    let hits = match api::search(&pass, &query) {
+        Ok(hits) => hits,
+        Err(e) => {
+            eprintln!("Error searching services: {}", e);
+            return;
+        }
+    };
+    if !matches.is_present("simple") {
+        println!("\nMatching Services\n--------");
+    }
+    for hit in hits {
+        println!("{}", hit.service.name());
+    }
+}
+
+fn set_max_age_cmd(matches: &ArgMatches) {
+    let days = match matches.value_of("days") {
+        None => None,
+        Some(d) => match u32::from_str(d) {
+            Ok(d) => Some(d),
+            Err(_) => {
+                eprintln!("Days provided not an integer");
+                return;
+            }
+        }
+    };
+    let pass = read_pass();
+    let res = match (matches.value_of("name"), matches.value_of("tag")) {
+        (Some(name), _) => api::set_max_age(name, &pass, days),
+        (_, Some(tag)) => api::set_tag_max_age(&pass, tag, days),
+        _ => unreachable!()
+    };
+    if let Err(e) = res {
+        eprintln!("Error setting max password age: {}", e);
+    }
+}
+
+fn fsck_cmd(matches: &ArgMatches) {
+    let pass = read_pass();
+    let verbose = matches.is_present("verbose");
+    let report = match api::fsck(&pass, matches.is_present("quarantine")) {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("Error checking vault: {}", e);
+            return;
+        }
+    };
+    let mut problems = 0;
+    for entry in report.iter() {
+        if !entry.status.is_valid() {
+            problems += 1;
+        } else if !verbose {
+            continue;
+        }
+        match &entry.quarantined {
+            Some(dest) => println!("{}: {}, moved to {}", entry.path.display(), entry.status, dest.display()),
+            None => println!("{}: {}", entry.path.display(), entry.status)
+        }
+    }
+    println!("Checked {} files, {} problems found", report.len(), problems);
+}
+
+fn migrate_cmd(matches: &ArgMatches) {
+    let pass = read_pass();
+    let dry_run = matches.is_present("dry-run");
+    let report = match upgrade::migrate(&pass, dry_run) {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("Error migrating vault: {}", e);
+            return;
+        }
+    };
+    for m in report.files.iter() {
+        println!("{}: {:?} v{} -> v{}", m.path.display(), m.spec_type, m.from, m.to);
+    }
+    if let Some(backup) = &report.backup {
+        println!("Backed up to {}", backup.display());
+    }
+    match dry_run {
+        true => println!("{} files would be migrated", report.files.len()),
+        false => println!("Migrated {} files", report.files.len())
+    }
+}
+
 fn delete_cmd(matches: &ArgMatches) {
+    let pass = read_pass();
+    if is_bulk(matches) {
+        bulk_cmd(matches, &pass, api::BulkAction::Delete, "move to the trash");
+        return;
+    }
+    let name = matches.value_of("name").unwrap();
+    if !service_exists(&pass, name) {
+        return;
+    }
+    match api::delete(name, &pass) {
+        Ok(()) => println!("Service {} moved to trash.", name),
+        Err(e) => eprintln!("Error deleting service {}: {}", name, e)
+    }
+}
+
+fn attach_add_cmd(matches: &ArgMatches) {
+    let pass = read_pass();
+    let name = matches.value_of("name").unwrap();
+    let path = Path::new(matches.value_of("file").unwrap());
+    let attachment = match matches.value_of("as") {
+        Some(a) => a.to_owned(),
+        None => match path.file_name() {
+            Some(f) => f.to_string_lossy().into_owned(),
+            None => {
+                eprintln!("Can't derive an attachment name from {}, use --as", path.display());
+                return;
+            }
+        }
+    };
+    let mut file = match File::open(path) {
+        Ok(f) => f,
+        Err(e) => {
+            eprintln!("Error opening {}: {}", path.display(), e);
+            return;
+        }
+    };
+    match api::attach(name, &pass, &attachment, &mut file) {
+        Ok(a) => println!("Attached {} to service {}", a, name),
+        Err(e) => eprintln!("Error attaching {} to service {}: {}", attachment, name, e)
+    }
+}
+
+fn attach_get_cmd(matches: &ArgMatches) {
     let pass = read_pass();
     let name = matches.value_of("name").unwrap();
-    if !api::exists(&pass, name) {
-        eprintln!("{} does not exist", name);
+    let attachment = matches.value_of("attachment").unwrap();
+    let output = Path::new(matches.value_of("output").unwrap_or(attachment));
+    if output.exists() {
+        eprintln!("{} already exists", output.display());
         return;
     }
-    api::delete(&pass, name).unwrap();
-    println!("Service {} deleted.", name);
+    let mut file = match File::create(output) {
+        Ok(f) => f,
+        Err(e) => {
+            eprintln!("Error creating {}: {}", output.display(), e);
+            return;
+        }
+    };
+    match api::get_attachment(name, &pass, attachment, &mut file) {
+        Ok(size) => println!("Saved {} ({} bytes) to {}", attachment, size, output.display()),
+        Err(e) => {
+            let _ = std::fs::remove_file(output);
+            eprintln!("Error getting attachment {} for service {}: {}", attachment, name, e);
+        }
+    }
+}
+
+fn attach_rm_cmd(matches: &ArgMatches) {
+    let pass = read_pass();
+    let name = matches.value_of("name").unwrap();
+    let attachment = matches.value_of("attachment").unwrap();
+    match api::remove_attachment(name, &pass, attachment) {
+        Ok(()) => println!("Attachment {} removed from service {}", attachment, name),
+        Err(e) => eprintln!("Error removing attachment {} from service {}: {}", attachment, name, e)
+    }
+}
+
+fn attach_list_cmd(matches: &ArgMatches) {
+    let pass = read_pass();
+    let name = matches.value_of("name").unwrap();
+    match api::list_attachments(name, &pass) {
+        Ok(attachments) => {
+            for a in attachments {
+                println!("{}", a);
+            }
+        }
+        Err(e) => eprintln!("Error listing attachments for service {}: {}", name, e)
+    }
+}
+
+fn attach_cmd(matches: &ArgMatches) {
+    match matches.subcommand() {
+        ("add", Some(matches)) => attach_add_cmd(matches),
+        ("get", Some(matches)) => attach_get_cmd(matches),
+        ("rm", Some(matches)) => attach_rm_cmd(matches),
+        ("list", Some(matches)) => attach_list_cmd(matches),
+        _ => println!("{}", matches.usage())
+    }
+}
+
+fn trash_list_cmd(_matches: &ArgMatches) {
+    let pass = read_pass();
+    match api::list_trash(&pass) {
+        Ok(trash) => {
+            for t in trash {
+                println!("{}", t);
+            }
+        }
+        Err(e) => eprintln!("Error listing trash: {}", e)
+    }
+}
+
+fn trash_restore_cmd(matches: &ArgMatches) {
+    let pass = read_pass();
+    let name = matches.value_of("name").unwrap();
+    match api::restore(name, &pass) {
+        Ok(()) => println!("Service {} restored.", name),
+        Err(e) => eprintln!("Error restoring service {}: {}", name, e)
+    }
+}
+
+fn trash_empty_cmd(_matches: &ArgMatches) {
+    let pass = read_pass();
+    match api::empty_trash(&pass) {
+        Ok(count) => println!("Permanently deleted {} services.", count),
+        Err(e) => eprintln!("Error emptying trash: {}", e)
+    }
+}
+
+fn trash_cmd(matches: &ArgMatches) {
+    match matches.subcommand() {
+        ("list", Some(matches)) => trash_list_cmd(matches),
+        ("restore", Some(matches)) => trash_restore_cmd(matches),
+        ("empty", Some(matches)) => trash_empty_cmd(matches),
+        _ => println!("{}", matches.usage())
+    }
+}
+
+fn git_init_cmd(matches: &ArgMatches) {
+    let pass = read_pass();
+    match git::init(&pass, matches.value_of("remote")) {
+        Ok(()) => println!("Vault history is kept in git."),
+        Err(e) => eprintln!("Error setting up git: {}", e)
+    }
+}
+
+fn git_sync_cmd(_matches: &ArgMatches) {
+    let pass = read_pass();
+    match git::sync(&pass) {
+        Ok(report) => {
+            for path in report.resolved.iter() {
+                println!("Changed on both sides, kept the newer: {}", path);
+            }
+            match report.pulled {
+                true => println!("Synced with {}.", git::REMOTE),
+                false => println!("Pushed to {}.", git::REMOTE)
+            }
+        }
+        Err(e) => eprintln!("Error syncing: {}", e)
+    }
+}
+
+fn git_log_cmd(matches: &ArgMatches) {
+    let limit = match usize::from_str(matches.value_of("count").unwrap()) {
+        Ok(limit) => limit,
+        Err(_) => {
+            eprintln!("Count must be a number");
+            return;
+        }
+    };
+    match git::log(limit) {
+        Ok(entries) => {
+            for entry in entries {
+                println!("{} {} {}", entry.commit, entry.date(), entry.message);
+            }
+        }
+        Err(e) => eprintln!("Error reading history: {}", e)
+    }
+}
+
+fn git_cmd(matches: &ArgMatches) {
+    match matches.subcommand() {
+        ("init", Some(matches)) => git_init_cmd(matches),
+        ("sync", Some(matches)) => git_sync_cmd(matches),
+        ("log", Some(matches)) => git_log_cmd(matches),
+        _ => println!("{}", matches.usage())
+    }
+}
+
+fn audit_rotate_cmd(matches: &ArgMatches) {
+    let keep = match matches.value_of("keep").map(usize::from_str).unwrap_or(Ok(audit::DEFAULT_KEEP)) {
+        Ok(keep) => keep,
+        Err(_) => {
+            eprintln!("Keep must be a number");
+            return;
+        }
+    };
+    let pass = read_pass();
+    match audit::rotate(&pass, keep) {
+        Ok(removed) => println!("Started a new audit log, removed {} old ones.", removed),
+        Err(e) => eprintln!("Error rotating audit log: {}", e)
+    }
+}
+
+fn audit_cmd(matches: &ArgMatches) {
+    if let ("rotate", Some(matches)) = matches.subcommand() {
+        return audit_rotate_cmd(matches);
+    }
+    let count = match matches.value_of("count").map(usize::from_str) {
+        Some(Ok(count)) => Some(count),
+        Some(Err(_)) => {
+            eprintln!("Count must be a number");
+            return;
+        }
+        None => None
+    };
+    let pass = read_pass();
+    let log = match audit::load(&pass, matches.is_present("all")) {
+        Ok(log) => log,
+        Err(e) => {
+            eprintln!("Error reading audit log: {}", e);
+            return;
+        }
+    };
+    let service = matches.value_of("name");
+    let shown: Vec<usize> = (0..log.records.len())
+        .filter(|&i| service.map(|s| log.records[i].name() == s).unwrap_or(true))
+        .collect();
+    let skip = count.map(|c| shown.len().saturating_sub(c)).unwrap_or(0);
+    for &i in &shown[skip..] {
+        println!("{}", log.records[i]);
+    }
+    for idx in log.breaks.iter() {
+        match log.records.get(*idx) {
+            Some(record) => eprintln!("Audit log was tampered with before: {}", record),
+            None => eprintln!("Audit log was tampered with at the end")
+        }
+    }
+}
+
+/// The separate passphrase a backup is encrypted with, if --passphrase was given.
+/// None if it was given but mistyped the second time.
+fn read_passphrase(matches: &ArgMatches, confirm: bool) -> Option<Option<String>> {
+    if !matches.is_present("passphrase") {
+        return Some(None);
+    }
+    let passphrase = read_pass_raw("backup passphrase: ");
+    if confirm && passphrase != read_pass_raw("re-enter backup passphrase: ") {
+        eprintln!("Passphrases don't match");
+        return None;
+    }
+    Some(Some(passphrase))
+}
+
+fn backup_create_cmd(matches: &ArgMatches) {
+    let file = matches.value_of("file").unwrap();
+    let pass = read_pass();
+    let passphrase = match read_passphrase(matches, true) {
+        Some(passphrase) => passphrase,
+        None => return
+    };
+    match backup::create(file, &pass, passphrase.as_deref()) {
+        Ok(count) => println!("Backed up {} files to {}", count, file),
+        Err(e) => eprintln!("Error backing up to {}: {}", file, e)
+    }
+}
+
+fn backup_verify_cmd(matches: &ArgMatches) {
+    let file = matches.value_of("file").unwrap();
+    let pass = read_pass();
+    let passphrase = match read_passphrase(matches, false) {
+        Some(passphrase) => passphrase,
+        None => return
+    };
+    let info = match backup::verify(file, &pass, passphrase.as_deref()) {
+        Ok(info) => info,
+        Err(e) => {
+            eprintln!("Error reading backup {}: {}", file, e);
+            return;
+        }
+    };
+    println!("Backup version {} of {} taken {}", info.version, info.identity, info.created());
+    println!("Services");
+    println!("--------");
+    for service in info.services.iter() {
+        println!("{}", service);
+    }
+    let mut problems = 0;
+    for entry in info.files.iter() {
+        if !entry.is_valid() {
+            problems += 1;
+        } else if !matches.is_present("verbose") {
+            continue;
+        }
+        match &entry.status {
+            Some(status) => println!("{} ({} bytes): {}", entry.path, entry.size, status),
+            None => println!("{} ({} bytes)", entry.path, entry.size)
+        }
+    }
+    println!("Checked {} files, {} problems found", info.files.len(), problems);
+}
+
+fn backup_restore_cmd(matches: &ArgMatches) {
+    let file = matches.value_of("file").unwrap();
+    let pass = read_pass();
+    let passphrase = match read_passphrase(matches, false) {
+        Some(passphrase) => passphrase,
+        None => return
+    };
+    let report = match backup::restore(file, &pass, passphrase.as_deref()) {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("Error restoring {}: {}", file, e);
+            return;
+        }
+    };
+    if report.full {
+        println!("Restored the vault from {}", file);
+        return;
+    }
+    for name in report.added.iter() {
+        println!("Added {}", name);
+    }
+    for name in report.updated.iter() {
+        println!("Updated {}, the backup's was newer", name);
+    }
+    for name in report.kept.iter() {
+        println!("Kept {}, the vault's was as new or newer", name);
+    }
+    println!("Merged {} services, {} attachments and {} deletions from {}",
+             report.added.len() + report.updated.len(), report.attachments, report.trash, file);
+}
+
+fn backup_cmd(matches: &ArgMatches) {
+    match matches.subcommand() {
+        ("create", Some(matches)) => backup_create_cmd(matches),
+        ("verify", Some(matches)) => backup_verify_cmd(matches),
+        ("restore", Some(matches)) => backup_restore_cmd(matches),
+        _ => println!("{}", matches.usage())
+    }
+}
+
+/// Show what parsed holds and what will happen to each entry, then import them once confirmed
+fn import_run(matches: &ArgMatches, parsed: Parsed) {
+    for problem in parsed.unsupported.iter() {
+        eprintln!("Not imported: {}", problem);
+    }
+    if parsed.entries.is_empty() {
+        println!("Nothing to import");
+        return;
+    }
+    let on_duplicate = OnDuplicate::from_str(matches.value_of("duplicates").unwrap()).unwrap();
+    let pass = read_pass();
+    let outcomes = match import::preview(&pass, &parsed.entries, on_duplicate) {
+        Ok(outcomes) => outcomes,
+        Err(e) => {
+            eprintln!("Error checking for duplicates: {}", e);
+            return;
+        }
+    };
+    println!("{} services to import:", parsed.entries.len());
+    for (entry, outcome) in parsed.entries.iter().zip(outcomes.iter()) {
+        let keys: Vec<&str> = entry.kvs.iter().map(|(k, _, _)| k.as_str()).collect();
+        let attachments = match entry.attachments.len() {
+            0 => String::new(),
+            n => format!(", {} attachments", n)
+        };
+        println!("  {}: {}, password {}, kvs [{}], tags [{}]{}",
+                 entry.name,
+                 outcome,
+                 if entry.password.is_some() { "kept" } else { "generated" },
+                 keys.join(", "),
+                 entry.tags.join(", "),
+                 attachments);
+    }
+    if matches.is_present("preview") || (!matches.is_present("yes") && !confirm("Import?")) {
+        return;
+    }
+    let results = match import::import(&pass, &parsed.entries, on_duplicate) {
+        Ok(results) => results,
+        Err(e) => {
+            eprintln!("Error importing: {}", e);
+            return;
+        }
+    };
+    let (mut imported, mut skipped, mut failed) = (0, 0, 0);
+    for (name, result) in results {
+        match result {
+            Ok(Outcome::Skipped) => {
+                skipped += 1;
+                println!("{}: skipped, already exists", name);
+            }
+            Ok(Outcome::Renamed(to)) => {
+                imported += 1;
+                println!("{}: added as {}", name, to);
+            }
+            Ok(Outcome::Overwritten) => {
+                imported += 1;
+                println!("{}: overwritten, the old one is in the trash", name);
+            }
+            Ok(Outcome::Added) => {
+                imported += 1;
+                println!("{}: added", name);
+            }
+            Err(e) => {
+                failed += 1;
+                eprintln!("{}: {}", name, e);
+            }
+        }
+    }
+    println!("Imported {} services, skipped {}, {} failed", imported, skipped, failed);
+}
+
+fn import_csv_cmd(matches: &ArgMatches) {
+    let file = matches.value_of("file").unwrap();
+    let format = Format::from_str(matches.value_of("format").unwrap()).unwrap();
+    let mut mapping = vec![];
+    for map in matches.values_of("map").map(|v| v.collect()).unwrap_or(vec![]) {
+        match import::csv::parse_mapping(map) {
+            Ok(map) => mapping.push(map),
+            Err(e) => {
+                eprintln!("{}", e);
+                return;
+            }
+        }
+    }
+    let parsed = match File::open(file).map_err(api::APError::from).and_then(|f| import::csv::read(f, format, &mapping)) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("Error reading {}: {}", file, e);
+            return;
+        }
+    };
+    import_run(matches, parsed);
+}
+
+fn import_kdbx_cmd(matches: &ArgMatches) {
+    let file = matches.value_of("file").unwrap();
+    let kdbx_pass = read_pass_raw("KeePass password: ");
+    let parsed = match File::open(file).map_err(api::APError::from).and_then(|mut f| import::kdbx::read(&mut f, &kdbx_pass)) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("Error reading {}: {}", file, e);
+            return;
+        }
+    };
+    import_run(matches, parsed);
+}
+
+fn import_bitwarden_cmd(matches: &ArgMatches) {
+    let file = matches.value_of("file").unwrap();
+    let parsed = match File::open(file).map_err(api::APError::from).and_then(import::bitwarden::read) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("Error reading {}: {}", file, e);
+            return;
+        }
+    };
+    import_run(matches, parsed);
+}
+
+fn import_1pux_cmd(matches: &ArgMatches) {
+    let file = matches.value_of("file").unwrap();
+    let parsed = match File::open(file).map_err(api::APError::from).and_then(import::onepux::read) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("Error reading {}: {}", file, e);
+            return;
+        }
+    };
+    import_run(matches, parsed);
+}
+
+fn import_cmd(matches: &ArgMatches) {
+    match matches.subcommand() {
+        ("csv", Some(matches)) => import_csv_cmd(matches),
+        ("kdbx", Some(matches)) => import_kdbx_cmd(matches),
+        ("bitwarden", Some(matches)) => import_bitwarden_cmd(matches),
+        ("1pux", Some(matches)) => import_1pux_cmd(matches),
+        _ => println!("{}", matches.usage())
+    }
+}
+
+fn export_cmd(matches: &ArgMatches) {
+    let file = matches.value_of("file").unwrap();
+    let format = export::Format::from_str(matches.value_of("format").unwrap()).unwrap();
+    if Path::new(file).exists() {
+        eprintln!("{} already exists", file);
+        return;
+    }
+    let query = match matches.value_of("query") {
+        Some(query) => match TagQuery::parse(query) {
+            Ok(query) => query,
+            Err(e) => {
+                eprintln!("Error selecting services: {}", e);
+                return;
+            }
+        },
+        None => TagQuery::all_of(&matches.values_of("tag").map(|v| v.collect()).unwrap_or(vec![]))
+    };
+    let pass = read_pass();
+    let count = match api::list_query(&pass, &query) {
+        Ok((services, _)) => services.len(),
+        Err(e) => {
+            eprintln!("Error selecting services: {}", e);
+            return;
+        }
+    };
+    if count == 0 {
+        println!("No services match");
+        return;
+    }
+    let kdbx_pass = match format {
+        export::Format::Kdbx => {
+            let kdbx_pass = read_pass_raw("KeePass password: ");
+            if kdbx_pass != read_pass_raw("re-enter KeePass password: ") {
+                eprintln!("Passwords don't match");
+                return;
+            }
+            kdbx_pass
+        }
+        _ => String::new()
+    };
+    if format.is_plain_text() {
+        println!(concat!("Warning: {} will hold the passwords of {} services in plain text, and all it takes ",
+                         "with the master password to generate them again. Anyone who can read it can read ",
+                         "them, keep it somewhere safe and delete it once it's done with."), file, count);
+        if !matches.is_present("yes") && !confirm("Export?") {
+            return;
+        }
+    }
+
+    let mut out = vec![];
+    let res = match format {
+        export::Format::Kdbx => export::kdbx(&mut out, &pass, &kdbx_pass, &Kdf::default(), &query),
+        export::Format::Json => export::json(&mut out, &pass, &query),
+        export::Format::Csv => export::csv(&mut out, &pass, &query),
+        export::Format::PassTree => export::pass_tree(Path::new(file), &pass, &query)
+    };
+    let res = match format {
+        export::Format::PassTree => res,
+        _ => res.and_then(|count| Ok(export::create_file(Path::new(file))?.write_all(&out).map(|_| count)?))
+    };
+    match res {
+        Ok(count) => println!("Exported {} services to {}", count, file),
+        Err(e) => eprintln!("Error exporting to {}: {}", file, e)
+    }
+}
+
+fn undo_cmd(_matches: &ArgMatches) {
+    let pass = read_pass();
+    match snapshot::undo(&pass) {
+        Ok(undone) if undone.is_empty() => println!("Nothing to undo"),
+        Ok(undone) => {
+            for snapshot in undone {
+                println!("Undid {} on {}", snapshot.get_op(), snapshot.name());
+            }
+        }
+        Err(e) => eprintln!("Error undoing: {}", e)
+    }
+}
+
+fn snapshots_list_cmd(matches: &ArgMatches) {
+    let pass = read_pass();
+    match snapshot::list(&pass, matches.value_of("name")) {
+        Ok(snapshots) => {
+            for snapshot in snapshots {
+                println!("{}", snapshot);
+            }
+        }
+        Err(e) => eprintln!("Error listing snapshots: {}", e)
+    }
+}
+
+fn snapshots_restore_cmd(matches: &ArgMatches) {
+    let id = matches.value_of("snapshot").unwrap();
+    let (name, time) = match snapshot::parse_id(id) {
+        Some(parsed) => parsed,
+        None => {
+            eprintln!("Snapshot must be given as NAME@TIME, as snapshots list shows it");
+            return;
+        }
+    };
+    let pass = read_pass();
+    match snapshot::restore(&pass, name, time) {
+        Ok(()) => println!("Service {} put back as it was at {}", name, id),
+        Err(e) => eprintln!("Error restoring {}: {}", id, e)
+    }
+}
+
+fn snapshots_cmd(matches: &ArgMatches) {
+    match matches.subcommand() {
+        ("list", Some(matches)) => snapshots_list_cmd(matches),
+        ("restore", Some(matches)) => snapshots_restore_cmd(matches),
+        _ => println!("{}", matches.usage())
+    }
+}
+
+fn config_get_cmd(matches: &ArgMatches) {
+    let config = config::current();
+    match matches.value_of("key") {
+        Some(key) => match config.get(key) {
+            Ok(value) => println!("{}", value),
+            Err(e) => eprintln!("{}", e)
+        },
+        None => print!("{}", config)
+    }
+}
+
+fn config_set_cmd(matches: &ArgMatches) {
+    let key = matches.value_of("key").unwrap();
+    let value = matches.value_of("value").unwrap();
+    let scope = match matches.is_present("user") {
+        true => Scope::User,
+        false => Scope::Vault
+    };
+    match config::set(scope, key, value) {
+        Ok(()) => println!("Set {} to {}", key, value),
+        Err(e) => eprintln!("Error setting {}: {}", key, e)
+    }
+}
+
+fn config_cmd(matches: &ArgMatches) {
+    match matches.subcommand() {
+        ("get", Some(matches)) => config_get_cmd(matches),
+        ("set", Some(matches)) => config_set_cmd(matches),
+        _ => println!("{}", matches.usage())
+    }
+}
+
+/// Run by `hand_off_clipboard_clear` in a process of its own
+fn clear_clipboard_cmd(matches: &ArgMatches) {
+    let secs = u64::from_str(matches.value_of("secs").unwrap()).unwrap_or(0);
+    let mut digest = String::new();
+    if stdin().read_line(&mut digest).is_err() {
+        return;
+    }
+    std::thread::sleep(std::time::Duration::from_secs(secs));
+    clear_clipboard_if(digest.trim());
+}
+
+/// apcli exits long before the clipboard is due to be cleared, so leave it to a copy
+/// of itself running in the background
+fn hand_off_clipboard_clear() {
+    let (digest, secs) = match pending_clipboard_clear() {
+        Some(pending) => pending,
+        None => return
+    };
+    let mut command = std::process::Command::new(match std::env::current_exe() {
+        Ok(exe) => exe,
+        Err(e) => {
+            eprintln!("Unable to clear the clipboard later: {}", e);
+            return;
+        }
+    });
+    command.arg("clear-clipboard")
+        .arg(secs.to_string())
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null());
+    // Out of the terminal's process group so closing it doesn't take this along
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut command, 0);
+    match command.spawn() {
+        Ok(mut child) => {
+            // The digest goes over stdin rather than where ps would show it
+            if let Some(mut input) = child.stdin.take() {
+                let _ = writeln!(input, "{}", digest);
+            }
+            println!("Clipboard will be cleared in {} seconds", secs);
+        }
+        Err(e) => eprintln!("Unable to clear the clipboard later: {}", e)
+    }
+}
+
+fn arg_attachment() -> Arg<'static, 'static> {
+    Arg::with_name("attachment")
+        .value_name("ATTACHMENT")
+        .help("Attachment name")
+        .required(true)
 }
 
 pub fn cli() {
+    audit::set_client(Client::Apcli);
     let app = App::new("Auto-pass")
         .about("Auto-generate and encrypt passwords")
         .version(&*crate::spec::VERSION.to_string())
@@ -330,15 +1451,15 @@ pub fn cli() {
                     .arg(arg_name())
                     .arg(Arg::with_name("text_mode")
                          .long("text_mode")
-                         .help("New password text mode")
-                         .default_value("default")
+                         .help("New password text mode, defaults to the configured text-mode")
+                         .takes_value(true)
                          .possible_values(&["default", "alphanumeric", "alphanumericunderscore", "nowhitespace"]))
                     .arg(Arg::with_name("length")
                          .long("length")
                          .short("l")
                          .value_name("LEN")
-                         .help("New password's length")
-                         .default_value("16"))
+                         .help("New password's length, defaults to the configured length")
+                         .takes_value(true))
                     .arg(arg_kvs())
                     .arg(Arg::with_name("tags")
                          .short("t")
@@ -357,9 +1478,18 @@ pub fn cli() {
                          .short("a")
                          .long("all")
                          .help("Print everything about the service"))
+                    .arg(Arg::with_name("key")
+                         .short("k")
+                         .long("key")
+                         .value_name("KEY")
+                         .help("Get the value of a key value pair instead of the password")
+                         .takes_value(true)
+                         .conflicts_with("all"))
+                    .arg(arg_reveal())
                     .display_order(20))
         .subcommand(SubCommand::with_name("get-id")
-                    .about("Get generic information not associated with a particular service"))
+                    .about("Get generic information not associated with a particular service")
+                    .arg(arg_reveal()))
         .subcommand(SubCommand::with_name("list")
                     .about("List services unlocked by password")
                     .display_order(0)
@@ -368,19 +1498,43 @@ pub fn cli() {
                         .help("Simple output"))
                     .arg(Arg::with_name("tags")
                         .short("t")
-                        .help("Filter services by these tags")
+                        .help("Filter services by these tags, a service needs all of them")
                         .multiple(true)
-                        .number_of_values(1)))
+                        .number_of_values(1))
+                    .arg(Arg::with_name("query")
+                        .short("q")
+                        .long("query")
+                        .value_name("QUERY")
+                        .help("Filter services by a tag query like '(aws or gcp) and not legacy'")
+                        .takes_value(true)
+                        .conflicts_with("tags")))
+        .subcommand(SubCommand::with_name("search")
+                    .about("Search services by name, tags and key value pairs, best match first")
+                    .display_order(0)
+                    .arg(Arg::with_name("simple")
+                        .short("s")
+                        .help("Simple output"))
+                    .arg(Arg::with_name("query")
+                        .value_name("QUERY")
+                        .help("Words to look for, close misspellings match too")
+                        .required(true)
+                        .multiple(true)))
         .subcommand(SubCommand::with_name("list-tags")
                     .about("List all tags in the list of services")
                     .display_order(0)
                     .arg(Arg::with_name("simple")
                         .short("s")
-                        .help("Simple output")))
+                        .help("Simple output"))
+                    .arg(Arg::with_name("tree")
+                        .long("tree")
+                        .help("Show tags with / in them as folders, with how many services each has")))
         .subcommand(SubCommand::with_name("set-kv")
                     .about("Set key value pairs for a service")
-                    .arg(arg_name())
+                    .arg(arg_name()
+                         .required_unless_one(&["tag", "query"]))
+                    .args(&args_bulk())
                     .arg(arg_kvs())
+                    .arg(arg_visibility())
                     .arg(Arg::with_name("reset")
                          .short("r")
                          .long("reset")
@@ -390,15 +1544,37 @@ pub fn cli() {
         .subcommand(SubCommand::with_name("set-kv-id")
                     .about("Set generic key value pairs not associated with a particular service")
                     .arg(arg_kvs())
+                    .arg(arg_visibility())
                     .arg(Arg::with_name("reset")
                          .short("r")
                          .long("reset")
                          .takes_value(false)
                          .help("Clear all existing values"))
                     .display_order(50))
+        .subcommand(SubCommand::with_name("set-kv-visibility")
+                    .about("Change how a key value pair is shown, for a service or the identity if no name is given")
+                    .arg(arg_name().required(false))
+                    .arg(Arg::with_name("key")
+                         .short("k")
+                         .long("key")
+                         .value_name("KEY")
+                         .help("Key of the pair to change")
+                         .takes_value(true)
+                         .required(true))
+                    .arg(Arg::with_name("visibility")
+                         .long("visibility")
+                         .short("v")
+                         .value_name("VISIBILITY")
+                         .help("New visibility for the pair")
+                         .takes_value(true)
+                         .required(true)
+                         .possible_values(&["plain", "concealed", "copy-only"]))
+                    .display_order(50))
         .subcommand(SubCommand::with_name("set-tags")
                     .about("Set tags for the service")
-                    .arg(arg_name())
+                    .arg(arg_name()
+                         .required_unless_one(&["tag", "query"]))
+                    .args(&args_bulk())
                     .arg(arg_tags())
                     .arg(Arg::with_name("reset")
                         .short("r")
@@ -406,15 +1582,338 @@ pub fn cli() {
                         .takes_value(false)
                         .help("Clear all existing values"))
                     .display_order(50))
+        .subcommand(SubCommand::with_name("tag")
+                    .about("Rename, merge or remove a tag on every service at once")
+                    .subcommand(SubCommand::with_name("rename")
+                                .about("Rename a tag that no service has yet")
+                                .arg(Arg::with_name("from")
+                                    .value_name("FROM")
+                                    .required(true))
+                                .arg(Arg::with_name("to")
+                                    .value_name("TO")
+                                    .required(true)))
+                    .subcommand(SubCommand::with_name("merge")
+                                .about("Replace tags with another, which services may already have")
+                                .arg(Arg::with_name("tags")
+                                    .value_name("TAGS")
+                                    .help("Tags to replace")
+                                    .required(true)
+                                    .multiple(true))
+                                .arg(Arg::with_name("into")
+                                    .short("i")
+                                    .long("into")
+                                    .value_name("TAG")
+                                    .help("Tag to replace them with")
+                                    .required(true)
+                                    .takes_value(true)))
+                    .subcommand(SubCommand::with_name("rm")
+                                .about("Remove a tag from every service")
+                                .arg(Arg::with_name("tag")
+                                    .value_name("TAG")
+                                    .required(true)))
+                    .display_order(50))
         .subcommand(SubCommand::with_name("upgrade")
                     .about("Upgrade password")
-                    .arg(arg_name())
-                    .arg(arg_set_pass())
+                    .arg(arg_name()
+                         .required_unless_one(&["stale", "tag", "query"]))
+                    .arg(arg_set_pass()
+                         .conflicts_with_all(&["stale", "tag", "query"]))
+                    .arg(Arg::with_name("stale")
+                         .long("stale")
+                         .conflicts_with_all(&["name", "tag", "query"])
+                         .help("Upgrade every service whose password is past its max age"))
+                    .args(&args_bulk())
+                    .display_order(50))
+        .subcommand(SubCommand::with_name("stale")
+                    .about("List services whose password is past its max age")
+                    .arg(Arg::with_name("simple")
+                        .short("s")
+                        .help("Simple output"))
+                    .arg(Arg::with_name("sort")
+                        .long("sort")
+                        .help("Sort by password age, service name or days overdue")
+                        .default_value("age")
+                        .possible_values(&["age", "name", "overdue"]))
+                    .display_order(50))
+        .subcommand(SubCommand::with_name("set-max-age")
+                    .about("Set the max password age for a service or for every service with a tag")
+                    .arg(Arg::with_name("name")
+                         .short("n")
+                         .long("name")
+                         .value_name("NAME")
+                         .help("Service name")
+                         .takes_value(true))
+                    .arg(Arg::with_name("tag")
+                         .short("t")
+                         .long("tag")
+                         .value_name("TAG")
+                         .help("Tag name")
+                         .takes_value(true))
+                    .group(ArgGroup::with_name("target")
+                           .args(&["name", "tag"])
+                           .required(true))
+                    .arg(Arg::with_name("days")
+                         .value_name("DAYS")
+                         .help("Max password age in days")
+                         .required_unless("clear"))
+                    .arg(Arg::with_name("clear")
+                         .long("clear")
+                         .conflicts_with("days")
+                         .help("Remove the max password age"))
                     .display_order(50))
         .subcommand(SubCommand::with_name("delete")
-                    .about("Delete an existing service")
-                    .arg(arg_name())
+                    .about("Move an existing service to the trash")
+                    .arg(arg_name()
+                         .required_unless_one(&["tag", "query"]))
+                    .args(&args_bulk())
                     .display_order(50))
+        .subcommand(SubCommand::with_name("fsck")
+                    .about("Check every file in the vault can be read")
+                    .arg(Arg::with_name("quarantine")
+                         .short("q")
+                         .long("quarantine")
+                         .help("Move unreadable files into the quarantine directory"))
+                    .arg(Arg::with_name("verbose")
+                         .short("v")
+                         .long("verbose")
+                         .help("List valid files too"))
+                    .display_order(70))
+        .subcommand(SubCommand::with_name("migrate")
+                    .about("Rewrite files saved by older versions of ap in the current format")
+                    .arg(Arg::with_name("dry-run")
+                         .short("n")
+                         .long("dry-run")
+                         .help("Only show which files would change"))
+                    .display_order(75))
+        .subcommand(SubCommand::with_name("trash")
+                    .about(concat!("Manage deleted services. They are permanently removed after ",
+                                   "trash-retention-days, set with config (30 by default, 0 keeps them forever)"))
+                    .subcommand(SubCommand::with_name("list")
+                                .about("List deleted services, most recent first"))
+                    .subcommand(SubCommand::with_name("restore")
+                                .about("Restore the most recent deletion of a service")
+                                .arg(arg_name()))
+                    .subcommand(SubCommand::with_name("empty")
+                                .about("Permanently remove everything in the trash"))
+                    .display_order(55))
+        .subcommand(SubCommand::with_name("attach")
+                    .about("Manage encrypted files attached to a service")
+                    .subcommand(SubCommand::with_name("add")
+                                .about("Attach a file to a service")
+                                .arg(arg_name())
+                                .arg(Arg::with_name("file")
+                                     .value_name("FILE")
+                                     .help("File to attach")
+                                     .required(true))
+                                .arg(Arg::with_name("as")
+                                     .long("as")
+                                     .value_name("ATTACHMENT")
+                                     .help("Name to store the attachment under, defaults to the file name")
+                                     .takes_value(true)))
+                    .subcommand(SubCommand::with_name("get")
+                                .about("Decrypt an attachment to a file")
+                                .arg(arg_name())
+                                .arg(arg_attachment())
+                                .arg(Arg::with_name("output")
+                                     .short("o")
+                                     .long("output")
+                                     .value_name("FILE")
+                                     .help("Where to save the attachment, defaults to the attachment name")
+                                     .takes_value(true)))
+                    .subcommand(SubCommand::with_name("rm")
+                                .about("Remove an attachment")
+                                .arg(arg_name())
+                                .arg(arg_attachment()))
+                    .subcommand(SubCommand::with_name("list")
+                                .about("List attachments for a service")
+                                .arg(arg_name()))
+                    .display_order(60))
+        .subcommand(SubCommand::with_name("audit")
+                    .about("Show when passwords were read or changed and from where, oldest first")
+                    .arg(Arg::with_name("name")
+                         .short("n")
+                         .long("name")
+                         .value_name("NAME")
+                         .help("Only show this service")
+                         .takes_value(true))
+                    .arg(Arg::with_name("count")
+                         .short("c")
+                         .long("count")
+                         .value_name("COUNT")
+                         .help("Only show the most recent COUNT records")
+                         .takes_value(true))
+                    .arg(Arg::with_name("all")
+                         .long("all")
+                         .help("Include rotated logs"))
+                    .subcommand(SubCommand::with_name("rotate")
+                                .about("Start a new audit log, the current one is kept as a rotated log")
+                                .arg(Arg::with_name("keep")
+                                     .long("keep")
+                                     .value_name("COUNT")
+                                     .help("How many rotated logs to keep, 5 by default")
+                                     .takes_value(true)))
+                    .display_order(62))
+        .subcommand(SubCommand::with_name("config")
+                    .about(concat!("Manage defaults, kept in the vault or in the user's config directory. Keys are ",
+                                   "length, text-mode, clipboard-clear (seconds, 0 never clears), ",
+                                   "auto-lock (seconds, 0 never locks), date-format, ",
+                                   "snapshot-keep (earlier versions kept of each service, 0 keeps none) and ",
+                                   "trash-retention-days (0 keeps deleted services forever)"))
+                    .subcommand(SubCommand::with_name("get")
+                                .about("Show a setting, or all of them")
+                                .arg(Arg::with_name("key")
+                                     .value_name("KEY")
+                                     .help("Setting to show")))
+                    .subcommand(SubCommand::with_name("set")
+                                .about("Change a setting")
+                                .arg(Arg::with_name("key")
+                                     .value_name("KEY")
+                                     .help("Setting to change")
+                                     .required(true))
+                                .arg(Arg::with_name("value")
+                                     .value_name("VALUE")
+                                     .help("New value")
+                                     .required(true))
+                                .arg(Arg::with_name("user")
+                                     .long("user")
+                                     .help("Set it for every vault rather than just this one")))
+                    .display_order(80))
+        .subcommand(SubCommand::with_name("clear-clipboard")
+                    .setting(AppSettings::Hidden)
+                    .arg(Arg::with_name("secs")
+                         .required(true)))
+        .subcommand(SubCommand::with_name("git")
+                    .about("Keep the vault's history in git and sync it with a remote")
+                    .subcommand(SubCommand::with_name("init")
+                                .about(concat!("Put the vault under git, every change is committed from then on. ",
+                                               "With no vault yet it is fetched from the remote"))
+                                .arg(Arg::with_name("remote")
+                                     .long("remote")
+                                     .value_name("URL")
+                                     .help("Repository to sync with")
+                                     .takes_value(true)))
+                    .subcommand(SubCommand::with_name("sync")
+                                .about(concat!("Merge in changes from the remote and push. An entry changed ",
+                                               "on both sides keeps whichever was modified last")))
+                    .subcommand(SubCommand::with_name("log")
+                                .about("Show the vault's history, most recent first")
+                                .arg(Arg::with_name("count")
+                                     .short("n")
+                                     .value_name("COUNT")
+                                     .help("How many commits to show")
+                                     .default_value("20")))
+                    .display_order(65))
+        .subcommand(SubCommand::with_name("backup")
+                    .about(concat!("Back up the whole vault to one encrypted file, or restore from one. ",
+                                   "The backup is encrypted with the master password unless --passphrase is given"))
+                    .subcommand(SubCommand::with_name("create")
+                                .about("Write the vault to FILE")
+                                .arg(arg_backup_file())
+                                .arg(arg_passphrase()))
+                    .subcommand(SubCommand::with_name("verify")
+                                .about("List the services in a backup and check every file in it, restoring nothing")
+                                .arg(arg_backup_file())
+                                .arg(arg_passphrase())
+                                .arg(Arg::with_name("verbose")
+                                     .short("v")
+                                     .long("verbose")
+                                     .help("Show every file, not just damaged ones")))
+                    .subcommand(SubCommand::with_name("restore")
+                                .about(concat!("Restore a backup. An empty vault gets back exactly what was backed up, ",
+                                               "otherwise it's merged in keeping the newer of any service in both"))
+                                .arg(arg_backup_file())
+                                .arg(arg_passphrase()))
+                    .display_order(67))
+        .subcommand(SubCommand::with_name("import")
+                    .about(concat!("Bring in services from another password manager's export, keeping their ",
+                                   "passwords and tagging them imported"))
+                    .subcommand(SubCommand::with_name("csv")
+                                .about(concat!("Import a CSV export. Fields are name, password, username, url, notes, ",
+                                               "totp, tags (comma separated), folder and fields (Bitwarden's custom ",
+                                               "fields), any other field is kept as a kv of that name"))
+                                .args(&args_import())
+                                .arg(Arg::with_name("format")
+                                     .long("format")
+                                     .value_name("FORMAT")
+                                     .help("What exported the file, generic expects columns named like the fields")
+                                     .takes_value(true)
+                                     .default_value("generic")
+                                     .possible_values(&["chrome", "firefox", "bitwarden", "generic"]))
+                                .arg(Arg::with_name("map")
+                                     .long("map")
+                                     .value_name("FIELD=COLUMN")
+                                     .help("Take a field from another column, or from none if COLUMN is empty")
+                                     .multiple(true)
+                                     .number_of_values(1)))
+                    .subcommand(SubCommand::with_name("kdbx")
+                                .about(concat!("Import a KeePass KDBX 4 database. Groups become tags, fields other ",
+                                               "than title, username, password, url and notes are kept as kvs"))
+                                .args(&args_import()))
+                    .subcommand(SubCommand::with_name("bitwarden")
+                                .about(concat!("Import Bitwarden's unencrypted JSON export. Logins, notes, cards, ",
+                                               "identities and SSH keys are all kept, their fields as kvs, and ",
+                                               "folders and collections become tags"))
+                                .args(&args_import()))
+                    .subcommand(SubCommand::with_name("1pux")
+                                .about(concat!("Import 1Password's 1PUX export. Section fields are kept as kvs named by ",
+                                               "their label and files as attachments, vaults become tags when ",
+                                               "there's more than one"))
+                                .args(&args_import()))
+                    .display_order(68))
+        .subcommand(SubCommand::with_name("export")
+                    .about(concat!("Write services to a file another password manager can open, with all it ",
+                                   "takes to generate their passwords again"))
+                    .arg(Arg::with_name("file")
+                         .value_name("FILE")
+                         .help("File to write, or directory for pass-tree, which mustn't exist yet")
+                         .required(true))
+                    .arg(Arg::with_name("format")
+                         .long("format")
+                         .value_name("FORMAT")
+                         .help(concat!("kdbx is a KeePass database with a password of its own. json, csv and ",
+                                       "pass-tree are plain text, pass-tree a directory with a file for each ",
+                                       "service as pass keeps them"))
+                         .takes_value(true)
+                         .required(true)
+                         .possible_values(&["kdbx", "json", "csv", "pass-tree"]))
+                    .arg(Arg::with_name("tag")
+                         .long("tag")
+                         .value_name("TAG")
+                         .help("Only export services with all of these tags")
+                         .multiple(true)
+                         .number_of_values(1))
+                    .arg(Arg::with_name("query")
+                         .long("query")
+                         .value_name("QUERY")
+                         .help("Only export services matching a tag query")
+                         .takes_value(true)
+                         .conflicts_with("tag"))
+                    .arg(Arg::with_name("yes")
+                         .short("y")
+                         .long("yes")
+                         .help("Don't ask before writing passwords in plain text"))
+                    .display_order(69))
+        .subcommand(SubCommand::with_name("undo")
+                    .about(concat!("Put back every service the last change touched as it was before. ",
+                                   "Running it again goes further back"))
+                    .display_order(56))
+        .subcommand(SubCommand::with_name("snapshots")
+                    .about("Earlier versions of services, kept each time one is changed")
+                    .subcommand(SubCommand::with_name("list")
+                                .about("Show snapshots, newest first")
+                                .arg(Arg::with_name("name")
+                                     .short("n")
+                                     .long("name")
+                                     .value_name("NAME")
+                                     .help("Only show this service")
+                                     .takes_value(true)))
+                    .subcommand(SubCommand::with_name("restore")
+                                .about("Put a service back as a snapshot has it, what it is now is kept as a snapshot")
+                                .arg(Arg::with_name("snapshot")
+                                     .value_name("NAME@TIME")
+                                     .help("Snapshot as snapshots list shows it")
+                                     .required(true)))
+                    .display_order(57))
         .get_matches();
 
     match app.subcommand() {
@@ -424,14 +1923,52 @@ pub fn cli() {
         ("get-id", Some(matches)) => get_id_cmd(matches),
         ("list", Some(matches)) => list_cmd(matches),
         ("list-tags", Some(matches)) => list_tags(matches),
+        ("search", Some(matches)) => search_cmd(matches),
         ("set-kv", Some(matches)) => setkv_cmd(matches),
         ("set-kv-id", Some(matches)) => setkv_id_cmd(matches),
+        ("set-kv-visibility", Some(matches)) => set_kv_visibility_cmd(matches),
         ("set-tags", Some(matches)) => set_tags(matches),
+        ("tag", Some(matches)) => tag_cmd(matches),
         ("upgrade", Some(matches)) => upgrade_cmd(matches),
+        ("stale", Some(matches)) => stale_cmd(matches),
+        ("set-max-age", Some(matches)) => set_max_age_cmd(matches),
         ("delete", Some(matches)) => delete_cmd(matches),
+        ("attach", Some(matches)) => attach_cmd(matches),
+        ("trash", Some(matches)) => trash_cmd(matches),
+        ("audit", Some(matches)) => audit_cmd(matches),
+        ("git", Some(matches)) => git_cmd(matches),
+        ("config", Some(matches)) => config_cmd(matches),
+        ("backup", Some(matches)) => backup_cmd(matches),
+        ("import", Some(matches)) => import_cmd(matches),
+        ("export", Some(matches)) => export_cmd(matches),
+        ("undo", Some(matches)) => undo_cmd(matches),
+        ("snapshots", Some(matches)) => snapshots_cmd(matches),
+        ("clear-clipboard", Some(matches)) => clear_clipboard_cmd(matches),
+        ("fsck", Some(matches)) => fsck_cmd(matches),
+        ("migrate", Some(matches)) => migrate_cmd(matches),
         
         _ => {
             println!("{}", app.usage());
         }
     };
+    for warning in warnings::take() {
+        eprintln!("Warning: {}", warning);
+    }
+    hand_off_clipboard_clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kv_output() {
+        assert_eq!(kv_output("me", Visibility::Plain, false), Some("me"));
+        // Concealed is only masked when a whole service is shown, asking for it by key shows it
+        assert_eq!(kv_output("1234", Visibility::Concealed, false), Some("1234"));
+        assert_eq!(kv_output("secret", Visibility::CopyOnly, false), None);
+        for visibility in [Visibility::Plain, Visibility::Concealed, Visibility::CopyOnly] {
+            assert_eq!(kv_output("value", visibility, true), None);
+        }
+    }
 }
@@ -0,0 +1,255 @@
+use std::{fmt, path::PathBuf, sync::RwLock};
+
+use time::format_description;
+
+use crate::api::APError;
+use crate::hash::TextMode;
+use crate::lock;
+use crate::spec::{base_path, config_path, identity_path, write_atomic};
+use crate::warnings;
+
+/*
+ * Defaults for new passwords and how the front ends behave, kept as plain `key = value`
+ * lines since none of it is secret. The user's file in their config directory applies
+ * to every vault and the vault's own file overrides it key by key, so settings in the
+ * vault follow it through git sync. Lines with a # at the start are comments.
+ */
+
+const USER_DIR: &str = "autopass";
+const USER_FNAME: &str = "config";
+pub const DEFAULT_DATE_FORMAT: &str = "[year]-[month]-[day] [hour]:[minute]:[second]";
+
+pub const LENGTH: &str = "length";
+pub const TEXT_MODE: &str = "text-mode";
+pub const CLIPBOARD_CLEAR: &str = "clipboard-clear";
+pub const AUTO_LOCK: &str = "auto-lock";
+pub const DATE_FORMAT: &str = "date-format";
+pub const SNAPSHOT_KEEP: &str = "snapshot-keep";
+pub const TRASH_RETENTION: &str = "trash-retention-days";
+pub const KEYS: &[&str] = &[LENGTH, TEXT_MODE, CLIPBOARD_CLEAR, AUTO_LOCK, DATE_FORMAT, SNAPSHOT_KEEP, TRASH_RETENTION];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    /// Length of generated passwords
+    pub length: u8,
+    /// Characters generated passwords are made of
+    pub text_mode: TextMode,
+    /// Seconds before a copied value is cleared from the clipboard, 0 leaves it there
+    pub clipboard_clear: u64,
+    /// Seconds without input before the GUI locks, 0 never locks
+    pub auto_lock: u64,
+    /// How dates are shown, in the `time` crate's format description syntax
+    pub date_format: String,
+    /// Earlier versions kept of each service, 0 keeps none
+    pub snapshot_keep: usize,
+    /// Days deleted services are kept in the trash, 0 keeps them forever
+    pub trash_retention_days: u64
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            length: 16,
+            text_mode: TextMode::NoWhiteSpace,
+            clipboard_clear: 0,
+            auto_lock: 0,
+            date_format: DEFAULT_DATE_FORMAT.to_owned(),
+            snapshot_keep: 10,
+            trash_retention_days: 30
+        }
+    }
+}
+
+impl Config {
+    pub fn get(&self, key: &str) -> Result<String, APError> {
+        Ok(match key {
+            LENGTH => self.length.to_string(),
+            TEXT_MODE => self.text_mode.name().to_owned(),
+            CLIPBOARD_CLEAR => self.clipboard_clear.to_string(),
+            AUTO_LOCK => self.auto_lock.to_string(),
+            DATE_FORMAT => self.date_format.clone(),
+            SNAPSHOT_KEEP => self.snapshot_keep.to_string(),
+            TRASH_RETENTION => self.trash_retention_days.to_string(),
+            _ => return Err(APError::Config(format!("Unknown key {}, expected one of {}", key, KEYS.join(", "))))
+        })
+    }
+
+    /// Checks value makes sense for key before taking it
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), APError> {
+        let invalid = |expected: &str| APError::Config(format!("Invalid {} {}, expected {}", key, value, expected));
+        match key {
+            LENGTH => {
+                self.length = value.parse().ok()
+                    .filter(|len| *len > 0)
+                    .ok_or_else(|| invalid("a number from 1 to 255"))?;
+            }
+            TEXT_MODE => {
+                self.text_mode = TextMode::from_name(value)
+                    .ok_or_else(|| invalid("alphanumeric, alphanumericunderscore or nowhitespace"))?;
+            }
+            CLIPBOARD_CLEAR => self.clipboard_clear = value.parse().map_err(|_| invalid("seconds"))?,
+            AUTO_LOCK => self.auto_lock = value.parse().map_err(|_| invalid("seconds"))?,
+            DATE_FORMAT => {
+                format_description::parse_borrowed::<2>(value).map_err(|e| invalid(&e.to_string()))?;
+                self.date_format = value.to_owned();
+            }
+            SNAPSHOT_KEEP => self.snapshot_keep = value.parse().map_err(|_| invalid("a count"))?,
+            TRASH_RETENTION => self.trash_retention_days = value.parse().map_err(|_| invalid("days"))?,
+            _ => return Err(APError::Config(format!("Unknown key {}, expected one of {}", key, KEYS.join(", "))))
+        }
+        Ok(())
+    }
+
+    /// Apply the settings in a file's text. A bad line is reported and skipped rather
+    /// than keeping the front ends from starting.
+    fn apply(&mut self, text: &str) {
+        for (key, value) in lines(text) {
+            if let Err(e) = self.set(key, value) {
+                warnings::push(format!("Ignoring config: {}", e));
+            }
+        }
+    }
+}
+
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for key in KEYS {
+            writeln!(f, "{} = {}", key, self.get(key).map_err(|_| fmt::Error)?)?;
+        }
+        Ok(())
+    }
+}
+
+/// The key value pairs in a file, skipping comments and blank lines
+fn lines(text: &str) -> impl Iterator<Item=(&str, &str)> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.trim(), value.trim()))
+}
+
+/// Which file a setting is written to
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Scope {
+    /// The vault's own file, overrides the user's
+    Vault,
+    /// The user's file, applies to every vault
+    User
+}
+
+pub fn path(scope: Scope) -> Option<PathBuf> {
+    match scope {
+        Scope::Vault => Some(config_path(base_path())),
+        Scope::User => dirs::config_dir().map(|dir| dir.join(USER_DIR).join(USER_FNAME))
+    }
+}
+
+fn read(scope: Scope) -> Result<String, APError> {
+    match path(scope).filter(|path| path.exists()) {
+        Some(path) => Ok(std::fs::read_to_string(path)?),
+        None => Ok(String::new())
+    }
+}
+
+/// Read the config files, the user's and then the vault's
+pub fn load() -> Config {
+    let mut config = Config::default();
+    for scope in [Scope::User, Scope::Vault] {
+        match read(scope) {
+            Ok(text) => config.apply(&text),
+            Err(e) => warnings::push(format!("Unable to read config: {}", e))
+        }
+    }
+    config
+}
+
+static CURRENT: RwLock<Option<Config>> = RwLock::new(None);
+
+/// The config as it was first read by this process, or as it was last set
+pub fn current() -> Config {
+    if let Some(config) = CURRENT.read().unwrap().as_ref() {
+        return config.clone();
+    }
+    let config = load();
+    *CURRENT.write().unwrap() = Some(config.clone());
+    config
+}
+
+/// Change a setting in scope's file. Other lines in the file are left as they are.
+pub fn set(scope: Scope, key: &str, value: &str) -> Result<(), APError> {
+    // Fails here, before the file is touched, if it's a bad setting
+    Config::default().set(key, value)?;
+
+    let _lock = match scope {
+        Scope::Vault => {
+            if !identity_path(base_path()).exists() {
+                return Err(APError::NotInited);
+            }
+            Some(lock::exclusive()?)
+        }
+        Scope::User => None
+    };
+    let path = path(scope).ok_or_else(|| APError::Config("No config directory for this user".to_owned()))?;
+    let mut found = false;
+    let mut text = String::new();
+    for line in read(scope)?.lines() {
+        match line.split_once('=') {
+            Some((k, _)) if k.trim() == key && !line.trim().starts_with('#') => {
+                if !found {
+                    text += &format!("{} = {}\n", key, value);
+                }
+                found = true;
+            }
+            _ => text += &format!("{}\n", line)
+        }
+    }
+    if !found {
+        text += &format!("{} = {}\n", key, value);
+    }
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    write_atomic(&path, |file| {
+        use std::io::Write;
+        Ok(file.write_all(text.as_bytes())?)
+    })?;
+    *CURRENT.write().unwrap() = Some(load());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config() {
+        let mut config = Config::default();
+        config.apply("# comment\nlength = 24\n\ntext-mode=alphanumeric\nbogus = 1\nauto-lock = soon\n");
+        assert_eq!(config.length, 24);
+        assert_eq!(config.text_mode, TextMode::AlphaNumeric);
+        // Bad lines keep the default
+        assert_eq!(config.auto_lock, 0);
+
+        assert!(config.set(LENGTH, "0").is_err());
+        assert!(config.set(DATE_FORMAT, "[year").is_err());
+        config.set(DATE_FORMAT, "[day]/[month]/[year]").unwrap();
+        assert_eq!(config.get(DATE_FORMAT).unwrap(), "[day]/[month]/[year]");
+        assert_eq!(config.trash_retention_days, 30);
+        assert!(config.set(TRASH_RETENTION, "-1").is_err());
+        config.set(TRASH_RETENTION, "0").unwrap();
+        assert_eq!(config.get(TRASH_RETENTION).unwrap(), "0");
+        assert!(config.to_string().contains("text-mode = alphanumeric\n"));
+    }
+
+    #[test]
+    fn test_bad_lines_warn() {
+        let vault = crate::testvault::TestVault::new("config");
+        std::fs::write(config_path(&vault.dir), "length = 0\nauto-lock = 5\n").unwrap();
+        warnings::take();
+        let config = load();
+        assert_eq!(config.length, Config::default().length);
+        assert_eq!(config.auto_lock, 5);
+        assert!(warnings::take().iter().any(|w| w.starts_with("Ignoring config: Invalid length 0")));
+    }
+}
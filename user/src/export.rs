@@ -0,0 +1,483 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::Serialize;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::api::{self, APError};
+use crate::audit;
+use crate::kdbx::{self, Database, Entry, Group, Kdf, NOTES, PASSWORD, TITLE, URL, USERNAME};
+use crate::lock;
+use crate::spec::{AuditOp, IdentityType, Serializable, ServiceType, Visibility};
+use crate::tagquery::TagQuery;
+use crate::tagtree::SEPARATOR;
+
+/*
+ * Services written out of the vault for other password managers, passwords and all.
+ * Each service exported is recorded in the audit log as reading its password is.
+ *
+ * KDBX is encrypted with a password of its own. JSON, CSV and the pass-tree, a
+ * directory with a file per service laid out as pass(1) keeps them, are plain text.
+ * Those carry the identity and its kvs, and each service's nonce, length and text mode
+ * besides its password, which with the master password is all it takes to generate
+ * the password again: the first length characters, in the text mode's alphabet, of
+ * sha256(nonce, sha256(name), sha256(master password)). `generated` says whether the
+ * password is still the one generated, rather than one set by hand.
+ */
+
+/// Where a format is written to
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Format {
+    Kdbx,
+    Json,
+    Csv,
+    /// A directory rather than a file
+    PassTree
+}
+
+impl FromStr for Format {
+    type Err = APError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "kdbx" => Ok(Self::Kdbx),
+            "json" => Ok(Self::Json),
+            "csv" => Ok(Self::Csv),
+            "pass-tree" => Ok(Self::PassTree),
+            _ => Err(APError::Export(format!("Unknown export format {}", s)))
+        }
+    }
+}
+
+impl Format {
+    /// Whether anyone who can read the export can read the passwords
+    pub fn is_plain_text(self) -> bool {
+        self != Self::Kdbx
+    }
+}
+
+/// Create a file to export to, only readable by its owner. It mustn't exist already.
+pub fn create_file(path: &Path) -> Result<File, APError> {
+    let mut options = File::options();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    Ok(options.open(path)?)
+}
+
+fn create_dir(path: &Path) -> Result<(), APError> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    Ok(builder.create(path)?)
+}
+
+/// The identity and the services query picks, each recorded as exported. The caller
+/// holds the lock.
+fn selected(pass: &str, query: &TagQuery) -> Result<(IdentityType, Vec<ServiceType>), APError> {
+    let id = api::load_id(pass)?;
+    let key = id.key();
+    let (services, _) = api::list_query(pass, query)?;
+    for service in services.iter() {
+        audit::record(&key, AuditOp::Export, service.name());
+    }
+    Ok((id, services))
+}
+
+fn time(ts: u64) -> String {
+    OffsetDateTime::from_unix_timestamp(ts as i64)
+        .ok()
+        .and_then(|t| t.format(&Rfc3339).ok())
+        .unwrap_or_default()
+}
+
+/// Whether service's password is the one generated for it
+fn is_generated(id: &IdentityType, service: &ServiceType) -> bool {
+    let generated = api::generate_pass(service.name(), &id.key(), service.get_nonce(), service.get_len(),
+                                       service.get_text_mode());
+    service.get_pass(false) == Some(generated.as_str())
+}
+
+/// The tag a service is filed under in formats with folders. Hierarchical tags are
+/// the likeliest to have been folders, so the first of those or else the first tag.
+fn folder(service: &ServiceType) -> Option<&str> {
+    let tags = service.get_tags();
+    tags.iter().find(|t| t.contains(SEPARATOR)).or(tags.first()).map(|t| t.as_str())
+}
+
+fn kdbx_entry(pass: &str, service: &ServiceType, folder: Option<&str>) -> Result<Entry, APError> {
+    let mut entry = Entry {
+        tags: service.get_tags().iter().filter(|t| Some(t.as_str()) != folder).cloned().collect(),
+        created: Some(service.create_time()),
+        modified: Some(service.modify_time()),
+        ..Default::default()
+    };
+    entry.set(TITLE, service.name(), false);
+    entry.set(PASSWORD, service.get_pass(false).unwrap_or(""), true);
+    for (key, value, visibility) in service.get_kvs() {
+        let key = match key.as_str() {
+            "username" => USERNAME,
+            "url" => URL,
+            "notes" => NOTES,
+            key => key
+        };
+        entry.set(key, value, *visibility != Visibility::Plain);
+    }
+    for attachment in api::list_attachments(service.name(), pass)? {
+        let mut data = vec![];
+        api::get_attachment(service.name(), pass, attachment.name(), &mut data)?;
+        entry.attachments.push((attachment.name().to_owned(), data));
+    }
+    Ok(entry)
+}
+
+/// Write the services query picks to a KDBX 4 database opened with kdbx_pass, each in
+/// the group of its folder tag. Returns how many were written.
+pub fn kdbx<W: Write>(writer: &mut W, pass: &str, kdbx_pass: &str, kdf: &Kdf, query: &TagQuery) -> Result<usize, APError> {
+    let _lock = lock::shared()?;
+    let (id, services) = selected(pass, query)?;
+    let mut db = Database {
+        name: id.name().to_owned(),
+        root: Group { name: id.name().to_owned(), ..Default::default() }
+    };
+    for service in services.iter() {
+        let folder = folder(service);
+        let path: Vec<&str> = folder.map(|f| f.split(SEPARATOR).collect()).unwrap_or_default();
+        let entry = kdbx_entry(pass, service, folder)?;
+        db.root.group_mut(&path).entries.push(entry);
+    }
+    kdbx::write(writer, kdbx_pass, &db, kdf)?;
+    Ok(services.len())
+}
+
+#[derive(Serialize)]
+struct JsonKv<'a> {
+    key: &'a str,
+    value: &'a str,
+    visibility: String
+}
+
+fn json_kvs(kvs: &[(String, String, Visibility)]) -> Vec<JsonKv<'_>> {
+    kvs.iter().map(|(key, value, visibility)| JsonKv { key, value, visibility: visibility.to_string() }).collect()
+}
+
+#[derive(Serialize)]
+struct JsonIdentity<'a> {
+    name: &'a str,
+    created: String,
+    modified: String,
+    kvs: Vec<JsonKv<'a>>
+}
+
+#[derive(Serialize)]
+struct JsonService<'a> {
+    name: &'a str,
+    password: &'a str,
+    generated: bool,
+    nonce: u8,
+    length: u8,
+    text_mode: &'static str,
+    kvs: Vec<JsonKv<'a>>,
+    tags: &'a [String],
+    created: String,
+    modified: String,
+    password_changed: String,
+    max_age: Option<u32>
+}
+
+#[derive(Serialize)]
+struct JsonExport<'a> {
+    identity: JsonIdentity<'a>,
+    services: Vec<JsonService<'a>>
+}
+
+fn json_export<'a>(id: &'a IdentityType, services: &'a [ServiceType]) -> JsonExport<'a> {
+    JsonExport {
+        identity: JsonIdentity {
+            name: id.name(),
+            created: time(id.create_time()),
+            modified: time(id.modify_time()),
+            kvs: json_kvs(id.get_kvs())
+        },
+        services: services.iter().map(|service| JsonService {
+            name: service.name(),
+            password: service.get_pass(false).unwrap_or(""),
+            generated: is_generated(id, service),
+            nonce: service.get_nonce(),
+            length: service.get_len(),
+            text_mode: service.get_text_mode().name(),
+            kvs: json_kvs(service.get_kvs()),
+            tags: service.get_tags(),
+            created: time(service.create_time()),
+            modified: time(service.modify_time()),
+            password_changed: time(service.pass_time()),
+            max_age: service.get_max_age()
+        }).collect()
+    }
+}
+
+/// Write the identity and the services query picks as JSON. Returns how many were written.
+pub fn json<W: Write>(writer: &mut W, pass: &str, query: &TagQuery) -> Result<usize, APError> {
+    let _lock = lock::shared()?;
+    let (id, services) = selected(pass, query)?;
+    serde_json::to_writer_pretty(&mut *writer, &json_export(&id, &services))
+        .map_err(|e| APError::Export(format!("Error writing JSON: {}", e)))?;
+    writer.write_all(b"\n")?;
+    Ok(services.len())
+}
+
+const CSV_COLUMNS: &[&str] = &["type", "name", "password", "username", "url", "notes", "tags", "fields", "created",
+                               "modified", "password_changed", "nonce", "length", "text_mode", "generated"];
+
+/// Kvs other than those with columns of their own, one per line as KEY: VALUE
+fn csv_fields(kvs: &[(String, String, Visibility)]) -> String {
+    kvs.iter()
+        .filter(|(key, _, _)| !["username", "url", "notes"].contains(&key.as_str()))
+        .map(|(key, value, _)| format!("{}: {}", key, value))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+fn csv_records(id: &IdentityType, services: &[ServiceType]) -> Vec<Vec<String>> {
+    let mut records = vec![];
+    let mut identity = vec![String::new(); CSV_COLUMNS.len()];
+    identity[0] = "identity".to_owned();
+    identity[1] = id.name().to_owned();
+    identity[7] = csv_fields(id.get_kvs());
+    identity[8] = time(id.create_time());
+    identity[9] = time(id.modify_time());
+    records.push(identity);
+    for service in services {
+        let kv = |key: &str| service.get_kv(key).map(|(value, _)| value.to_owned()).unwrap_or_default();
+        records.push(vec![
+            "service".to_owned(),
+            service.name().to_owned(),
+            service.get_pass(false).unwrap_or("").to_owned(),
+            kv("username"),
+            kv("url"),
+            kv("notes"),
+            service.get_tags().join(","),
+            csv_fields(service.get_kvs()),
+            time(service.create_time()),
+            time(service.modify_time()),
+            time(service.pass_time()),
+            service.get_nonce().to_string(),
+            service.get_len().to_string(),
+            service.get_text_mode().name().to_owned(),
+            is_generated(id, service).to_string()
+        ]);
+    }
+    records
+}
+
+/// Write the identity and the services query picks as CSV, the identity on the first
+/// row. Returns how many services were written.
+pub fn csv<W: Write>(writer: &mut W, pass: &str, query: &TagQuery) -> Result<usize, APError> {
+    let _lock = lock::shared()?;
+    let (id, services) = selected(pass, query)?;
+    let csv_error = |e: ::csv::Error| APError::Export(format!("Error writing CSV: {}", e));
+    let mut writer = ::csv::Writer::from_writer(writer);
+    writer.write_record(CSV_COLUMNS).map_err(csv_error)?;
+    for record in csv_records(&id, &services) {
+        writer.write_record(&record).map_err(csv_error)?;
+    }
+    writer.flush()?;
+    Ok(services.len())
+}
+
+/// Name of the identity's file in a pass-tree, hidden so pass doesn't list it
+const IDENTITY_FILE: &str = ".identity";
+
+/// A name or tag part as a file name that stays inside the tree
+fn path_part(name: &str) -> String {
+    let part = name.replace(['/', '\\', '\0'], "-");
+    match part.trim_start_matches('.').is_empty() {
+        true => part.replace('.', "_") + "_",
+        false => part
+    }
+}
+
+/// Lines of KEY: VALUE, lines after the first of a value indented so they're told apart
+fn pass_lines(lines: &[(&str, String)]) -> String {
+    let mut out = String::new();
+    for (key, value) in lines.iter().filter(|(_, value)| !value.is_empty()) {
+        out.push_str(&format!("{}: {}\n", key, value.replace('\n', "\n  ")));
+    }
+    out
+}
+
+/// Each file of a pass-tree and its contents. A service's file has its password on the
+/// first line as pass expects, then its kvs and everything else as KEY: VALUE lines.
+fn pass_tree_files(id: &IdentityType, services: &[ServiceType]) -> Vec<(PathBuf, String)> {
+    let mut identity = vec![
+        ("name", id.name().to_owned()),
+        ("created", time(id.create_time())),
+        ("modified", time(id.modify_time()))
+    ];
+    identity.extend(id.get_kvs().iter().map(|(key, value, _)| (key.as_str(), value.clone())));
+    let mut files = vec![(PathBuf::from(IDENTITY_FILE), pass_lines(&identity))];
+
+    for service in services {
+        let mut path: PathBuf = folder(service).map(|f| f.split(SEPARATOR).map(path_part).collect()).unwrap_or_default();
+        path.push(path_part(service.name()));
+        let mut lines: Vec<(&str, String)> = service.get_kvs().iter()
+            .map(|(key, value, _)| (key.as_str(), value.clone()))
+            .collect();
+        lines.extend(vec![
+            ("tags", service.get_tags().join(", ")),
+            ("created", time(service.create_time())),
+            ("modified", time(service.modify_time())),
+            ("password changed", time(service.pass_time())),
+            ("nonce", service.get_nonce().to_string()),
+            ("length", service.get_len().to_string()),
+            ("text mode", service.get_text_mode().name().to_owned()),
+            ("generated", is_generated(id, service).to_string())
+        ]);
+        let contents = format!("{}\n{}", service.get_pass(false).unwrap_or(""), pass_lines(&lines));
+        files.push((path, contents));
+    }
+    let paths = unique_paths(files.iter().map(|(path, _)| path.clone()).collect());
+    files.into_iter().zip(paths).map(|((_, contents), path)| (path, contents)).collect()
+}
+
+/// How a path is compared for collisions, case folded for file systems that ignore case
+fn path_key(path: &Path) -> String {
+    path.to_string_lossy().to_lowercase()
+}
+
+/// Paths that don't land on each other. Names that map to the same file, like `a/b`
+/// and `a-b`, or a file that's also a folder's directory get -2, -3.. added in order.
+/// Directories are never renamed so folders keep their layout.
+fn unique_paths(paths: Vec<PathBuf>) -> Vec<PathBuf> {
+    let dirs: HashSet<String> = paths.iter()
+        .flat_map(|path| path.ancestors().skip(1))
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .map(path_key)
+        .collect();
+    let mut taken = HashSet::new();
+    paths.into_iter().map(|path| {
+        let name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        let mut unique = path.clone();
+        let mut n = 1;
+        while dirs.contains(&path_key(&unique)) || taken.contains(&path_key(&unique)) {
+            n += 1;
+            unique.set_file_name(format!("{}-{}", name, n));
+        }
+        taken.insert(path_key(&unique));
+        unique
+    }).collect()
+}
+
+/// Write the identity and the services query picks to a new directory dir, a file
+/// for each service in the directory of its folder tag. Returns how many were written.
+pub fn pass_tree(dir: &Path, pass: &str, query: &TagQuery) -> Result<usize, APError> {
+    let files = {
+        let _lock = lock::shared()?;
+        let (id, services) = selected(pass, query)?;
+        pass_tree_files(&id, &services)
+    };
+    if dir.exists() {
+        return Err(APError::Export(format!("{} already exists", dir.display())));
+    }
+    let write = || -> Result<(), APError> {
+        for (path, contents) in files.iter() {
+            let path = dir.join(path);
+            if let Some(parent) = path.parent() {
+                create_dir(parent)?;
+            }
+            create_file(&path)?.write_all(contents.as_bytes())?;
+        }
+        Ok(())
+    };
+    // Half a tree of plain text passwords is no use to anyone
+    if let Err(e) = write() {
+        let _ = fs::remove_dir_all(dir);
+        return Err(e);
+    }
+    Ok(files.len() - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::TextMode;
+    use crate::testvault::{self, TestVault, PASS};
+
+    #[test]
+    fn test_path_part() {
+        assert_eq!(path_part("mail"), "mail");
+        assert_eq!(path_part("a/b\\c"), "a-b-c");
+        assert_eq!(path_part(".."), "___");
+        assert_eq!(path_part(".hidden"), ".hidden");
+    }
+
+    #[test]
+    fn test_pass_tree_files() {
+        let id = IdentityType::new("me", &[7; 32], &[("email", "me@example.com")]);
+        let generated = api::generate_pass("mail", &id.key(), 0, 16, &TextMode::AlphaNumeric);
+        let mail = ServiceType::new("mail", &generated, 0, &[("notes", "two\nlines"), ("username", "me")],
+                                    &["web/mail", "personal"], 16, &TextMode::AlphaNumeric);
+        let bank = ServiceType::new::<&str>("bank/main", "set by hand", 2, &[], &[], 32, &TextMode::NoWhiteSpace);
+        let files = pass_tree_files(&id, &[mail, bank]);
+
+        let paths: Vec<&Path> = files.iter().map(|(path, _)| path.as_path()).collect();
+        assert_eq!(paths, vec![Path::new(".identity"), Path::new("web/mail/mail"), Path::new("bank-main")]);
+        assert!(files[0].1.starts_with("name: me\n"));
+        assert!(files[0].1.ends_with("email: me@example.com\n"));
+
+        let lines: Vec<&str> = files[1].1.lines().collect();
+        assert_eq!(lines[0], generated);
+        assert_eq!(&lines[1..4], &["notes: two", "  lines", "username: me"]);
+        assert!(lines.contains(&"tags: personal, web/mail"));
+        assert!(lines.contains(&"text mode: alphanumeric"));
+        assert!(lines.contains(&"generated: true"));
+        assert!(files[2].1.starts_with("set by hand\n"));
+        assert!(files[2].1.contains("nonce: 2\n"));
+        assert!(files[2].1.contains("generated: false\n"));
+    }
+
+    #[test]
+    fn test_unique_paths() {
+        let paths = |paths: &[&str]| unique_paths(paths.iter().map(PathBuf::from).collect());
+        assert_eq!(paths(&[".identity", "mail", "web/mail"]), vec![PathBuf::from(".identity"), PathBuf::from("mail"), PathBuf::from("web/mail")]);
+        // A service named .identity, a/b and a-b, and a file where a folder's directory goes
+        assert_eq!(paths(&[".identity", ".identity", "a-b", "a-b", "a-b-2", "web", "web/mail", "Mail", "web/mail/x"]),
+                   vec![PathBuf::from(".identity"), PathBuf::from(".identity-2"), PathBuf::from("a-b"),
+                        PathBuf::from("a-b-2"), PathBuf::from("a-b-2-2"), PathBuf::from("web-2"),
+                        PathBuf::from("web/mail-2"), PathBuf::from("Mail"), PathBuf::from("web/mail/x")]);
+    }
+
+    #[test]
+    fn test_pass_tree() {
+        let _vault = TestVault::new("pass-tree");
+        for (name, tags) in [(".identity", vec![]), ("a/b", vec![]), ("a-b", vec![]), ("web", vec![]), ("mail", vec!["web"])].iter() {
+            api::new(name, PASS, &TextMode::NoWhiteSpace, 16, &[], tags, None).unwrap();
+        }
+        let dir = testvault::dir("pass-tree-out");
+        assert_eq!(pass_tree(&dir, PASS, &TagQuery::all_of::<&str>(&[])).unwrap(), 5);
+        let mut written = vec![];
+        for entry in fs::read_dir(&dir).unwrap() {
+            written.push(entry.unwrap().file_name().to_string_lossy().into_owned());
+        }
+        written.sort();
+        assert_eq!(written, [".identity", ".identity-2", "a-b", "a-b-2", "web", "web-2"]);
+        assert!(dir.join("web/mail").is_file());
+        assert!(fs::read_to_string(dir.join(".identity")).unwrap().starts_with("name: me\n"));
+        fs::remove_dir_all(&dir).unwrap();
+
+        // Too long a name for a file fails part way through, nothing is left behind
+        api::new::<&str>(&"x".repeat(300), PASS, &TextMode::NoWhiteSpace, 16, &[], &[], None).unwrap();
+        assert!(pass_tree(&dir, PASS, &TagQuery::all_of::<&str>(&[])).is_err());
+        assert!(!dir.exists());
+    }
+}
@@ -0,0 +1,373 @@
+use std::{path::{Path, PathBuf}, process::{Command, Output, Stdio}};
+
+use crate::api::{self, APError};
+use crate::lock;
+use crate::warnings;
+use crate::spec::{base_path, config_path, decode, decode_header, load_attachment, timestamp_as_string, write_atomic, Encryptor, EncryptorType, IdentityType, ServiceType, SpecType, TrashType, IDENTITY_FNAME};
+
+/*
+ * Keeps the vault directory in a git repository. Once it's set up every change made
+ * through `api` is committed when the vault lock is let go, with a generic message so
+ * the history never says more than the hashed filenames already do. `sync` pulls from
+ * and pushes to the remote, and settles conflicting entries by keeping whichever side
+ * was modified last. The other side stays in history as the merge's other parent.
+ */
+
+pub const REMOTE: &str = "origin";
+const COMMIT_MESSAGE: &str = "Update vault";
+/// Never committed, they're local to this copy of the vault
+const EXCLUDE: &[&str] = &["/.lock", "/.tmp-*", "/legacy/", "/quarantine/", "/backup/", "/hooks/", "/audit/", "/snapshots/"];
+
+pub fn git_path<P: AsRef<Path>>(basedir: P) -> PathBuf {
+    basedir.as_ref().join(".git")
+}
+
+pub fn is_repo() -> bool {
+    git_path(base_path()).is_dir()
+}
+
+fn git(args: &[&str]) -> Result<Output, APError> {
+    Ok(Command::new("git")
+        .arg("-C")
+        .arg(base_path())
+        .args(args)
+        .stdin(Stdio::null())
+        .output()?)
+}
+
+/// Run git, failing with whatever it printed if it exits non-zero
+fn git_ok(args: &[&str]) -> Result<String, APError> {
+    let output = git(args)?;
+    if !output.status.success() {
+        let err = String::from_utf8_lossy(&output.stderr).trim().to_owned();
+        return Err(APError::Git(format!("git {}: {}", args[0], err)));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// The branch HEAD points at, even before its first commit
+fn branch() -> Result<String, APError> {
+    Ok(git_ok(&["symbolic-ref", "--short", "HEAD"])?.trim().to_owned())
+}
+
+/// Commit whatever changed, true if there was anything
+fn commit(message: &str) -> Result<bool, APError> {
+    git_ok(&["add", "-A"])?;
+    if git_ok(&["status", "--porcelain"])?.trim().is_empty() {
+        return Ok(false);
+    }
+    git_ok(&["commit", "-q", "-m", message])?;
+    Ok(true)
+}
+
+/// Called as the outermost exclusive vault lock is let go, so the commit has
+/// everything the operation changed and nothing else is writing. The change is made
+/// either way, a failed commit is a warning and the next one picks it up.
+pub(crate) fn autocommit() {
+    if !is_repo() {
+        return;
+    }
+    if let Err(e) = commit(COMMIT_MESSAGE) {
+        warnings::push(format!("Unable to commit vault changes: {}", e));
+    }
+}
+
+/// Put the vault under git and commit what's there. With nothing here yet the vault is
+/// fetched from remote instead, which is how a second machine joins. Running it again
+/// on a repository just changes the remote.
+pub fn init(pass: &str, remote: Option<&str>) -> Result<(), APError> {
+    if remote.is_some() {
+        std::fs::create_dir_all(base_path())?;
+    }
+    let _lock = lock::exclusive()?;
+    let fetch = api::empty()?;
+    if !fetch {
+        // Only an inited vault with the right password
+        api::load_id(pass)?;
+    } else if remote.is_none() {
+        return Err(APError::Git("Nothing to put under git, init the vault or give a remote to fetch it from".to_owned()));
+    }
+    if !is_repo() {
+        git_ok(&["init", "-q"])?;
+        let exclude = git_path(base_path()).join("info").join("exclude");
+        std::fs::create_dir_all(exclude.parent().unwrap())?;
+        write_atomic(&exclude, |file| {
+            use std::io::Write;
+            Ok(file.write_all((EXCLUDE.join("\n") + "\n").as_bytes())?)
+        })?;
+        // Commits need an author, don't make the user set one up first
+        if git(&["config", "user.email"])?.stdout.is_empty() {
+            git_ok(&["config", "user.name", "ap"])?;
+            git_ok(&["config", "user.email", "ap@localhost"])?;
+        }
+    }
+    if let Some(remote) = remote {
+        let remotes = git_ok(&["remote"])?;
+        if remotes.lines().any(|r| r == REMOTE) {
+            git_ok(&["remote", "set-url", REMOTE, remote])?;
+        } else {
+            git_ok(&["remote", "add", REMOTE, remote])?;
+        }
+    }
+    if fetch {
+        if let Err(e) = fetch_vault(pass) {
+            // Leave nothing behind, the next try starts from scratch
+            for path in git_ok(&["ls-files"]).unwrap_or_default().lines() {
+                let top = path.split('/').next().unwrap_or(path);
+                let _ = std::fs::remove_file(base_path().join(top))
+                    .or_else(|_| std::fs::remove_dir_all(base_path().join(top)));
+            }
+            let _ = std::fs::remove_dir_all(git_path(base_path()));
+            return Err(e);
+        }
+    } else {
+        commit("Start vault history")?;
+    }
+    Ok(())
+}
+
+/// Check out the vault from the remote into an empty vault directory
+fn fetch_vault(pass: &str) -> Result<(), APError> {
+    git_ok(&["fetch", "-q", REMOTE])?;
+    // Follow the remote if it uses a different branch name
+    let mut branch = branch()?;
+    let prefix = format!("{}/", REMOTE);
+    let branches = git_ok(&["branch", "-r", "--format=%(refname:short)"])?;
+    let remote_branches: Vec<&str> = branches.lines()
+        .filter_map(|b| b.strip_prefix(&prefix))
+        .filter(|b| *b != "HEAD")
+        .collect();
+    if !remote_branches.contains(&branch.as_str()) {
+        match remote_branches.first() {
+            Some(b) => branch = b.to_string(),
+            None => return Err(APError::Git(format!("Nothing to fetch from {}", REMOTE)))
+        }
+    }
+    git_ok(&["checkout", "-q", "-B", &branch, &format!("{}{}", prefix, branch)])?;
+    // Only keep it if it's a vault this password opens
+    api::load_id(pass)?;
+    Ok(())
+}
+
+/// A version of a conflicted file, None if that side deleted it
+fn conflict_side(path: &str, stage: u8) -> Result<Option<Vec<u8>>, APError> {
+    let output = git(&["show", &format!(":{}:{}", stage, path)])?;
+    Ok(output.status.success().then_some(output.stdout))
+}
+
+/// When an entry was last changed, going by what it says inside
+fn entry_time(path: &str, data: &[u8], pass: &str, key: &[u8]) -> Result<u64, APError> {
+    let header = decode_header(data)?;
+    Ok(match header.spec_type {
+        SpecType::Identity if path == IDENTITY_FNAME => {
+            decode::<IdentityType, EncryptorType>(data, &EncryptorType::genkey(pass))?.modify_time()
+        }
+        SpecType::Service => decode::<ServiceType, EncryptorType>(data, key)?.modify_time(),
+        SpecType::Trash => decode::<TrashType, EncryptorType>(data, key)?.delete_time(),
+        SpecType::Attachment => load_attachment(&mut std::io::Cursor::new(data), key)?.create_time(),
+        spec_type => return Err(APError::WrongSpecType(SpecType::Service, spec_type))
+    })
+}
+
+/// Keep the newer side of every conflicted entry. If one side deleted it the other
+/// side is kept, losing nothing. Returns the entries whose other side lost out.
+fn resolve(pass: &str, key: &[u8]) -> Result<Vec<String>, APError> {
+    let conflicted = git_ok(&["diff", "--name-only", "--diff-filter=U"])?;
+    let mut resolved = vec![];
+    for path in conflicted.lines() {
+        let ours = conflict_side(path, 2)?;
+        let theirs = conflict_side(path, 3)?;
+        let keep = match (ours, theirs) {
+            // Settings aren't an entry with a time in them, this copy's win
+            (Some(ours), Some(_)) if base_path().join(path) == config_path(base_path()) => ours,
+            (Some(ours), Some(theirs)) => {
+                match entry_time(path, &theirs, pass, key)? > entry_time(path, &ours, pass, key)? {
+                    true => theirs,
+                    false => ours
+                }
+            }
+            (Some(data), None) | (None, Some(data)) => data,
+            (None, None) => continue
+        };
+        write_atomic(base_path().join(path), |file| {
+            use std::io::Write;
+            Ok(file.write_all(&keep)?)
+        })?;
+        git_ok(&["add", "--", path])?;
+        resolved.push(path.to_owned());
+    }
+    Ok(resolved)
+}
+
+/// What `sync` did
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    /// Whether there was anything on the remote to merge
+    pub pulled: bool,
+    /// Entries changed on both sides, the newer one was kept
+    pub resolved: Vec<String>
+}
+
+/// Commit anything outstanding, merge in the remote's changes and push the result
+pub fn sync(pass: &str) -> Result<SyncReport, APError> {
+    let _lock = lock::exclusive()?;
+    let key = api::load_id(pass)?.key();
+    if !is_repo() {
+        return Err(APError::Git("Vault isn't under git, run git init first".to_owned()));
+    }
+    commit(COMMIT_MESSAGE)?;
+    let branch = branch()?;
+    let mut report = SyncReport::default();
+
+    git_ok(&["fetch", "-q", REMOTE])?;
+    let upstream = format!("{}/{}", REMOTE, branch);
+    if git(&["rev-parse", "--verify", "-q", &upstream])?.status.success() {
+        report.pulled = true;
+        let merge = git(&["merge", "-q", "--no-edit", "--allow-unrelated-histories", &upstream])?;
+        if !merge.status.success() {
+            match resolve(pass, &key) {
+                Ok(resolved) if !resolved.is_empty() => {
+                    let message = format!("Merge {}, keeping the newer side of {} entries", upstream, resolved.len());
+                    git_ok(&["commit", "-q", "-m", &message])?;
+                    report.resolved = resolved;
+                }
+                res => {
+                    // Leave the vault as it was rather than half merged
+                    git(&["merge", "--abort"])?;
+                    res?;
+                    let err = String::from_utf8_lossy(&merge.stderr).trim().to_owned();
+                    return Err(APError::Git(format!("git merge: {}", err)));
+                }
+            }
+        }
+    }
+    git_ok(&["push", "-q", REMOTE, &format!("HEAD:{}", branch)])?;
+    Ok(report)
+}
+
+pub struct LogEntry {
+    pub commit: String,
+    pub time: u64,
+    pub message: String
+}
+
+impl LogEntry {
+    pub fn date(&self) -> String {
+        timestamp_as_string(self.time)
+    }
+}
+
+/// Most recent commits first
+pub fn log(limit: usize) -> Result<Vec<LogEntry>, APError> {
+    if !is_repo() {
+        return Err(APError::Git("Vault isn't under git, run git init first".to_owned()));
+    }
+    let out = git_ok(&["log", &format!("-{}", limit), "--format=%h%x09%ct%x09%s"])?;
+    Ok(out.lines()
+        .filter_map(|line| {
+            let mut parts = line.splitn(3, '\t');
+            Some(LogEntry {
+                commit: parts.next()?.to_owned(),
+                time: parts.next()?.parse().ok()?,
+                message: parts.next()?.to_owned()
+            })
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::TextMode;
+    use crate::spec::Visibility;
+    use crate::testvault::{self, TestVault, PASS};
+
+    fn new_service(name: &str) {
+        api::new::<&str>(name, PASS, &TextMode::NoWhiteSpace, 16, &[], &[], None).unwrap();
+    }
+
+    fn set_from(name: &str, from: &str) {
+        api::set_kvs(name, PASS, &[("from", from)], Visibility::Plain, false, None).unwrap();
+    }
+
+    fn from(name: &str) -> String {
+        api::get_all(name, PASS).unwrap().get_kv("from").map(|(v, _)| v.to_owned()).unwrap_or_default()
+    }
+
+    fn commits() -> Vec<String> {
+        log(10).unwrap().into_iter().map(|e| e.message).collect()
+    }
+
+    #[test]
+    fn test_autocommit() {
+        let vault = TestVault::new("git-autocommit");
+        init(PASS, None).unwrap();
+        assert_eq!(commits(), vec!["Start vault history"]);
+        new_service("mail");
+        assert_eq!(commits(), vec![COMMIT_MESSAGE, "Start vault history"]);
+        // Reads and changes to local only files don't make commits
+        api::get_all("mail", PASS).unwrap();
+        assert_eq!(commits().len(), 2);
+
+        // A commit that fails doesn't fail the change, it's a warning
+        let indexlock = git_path(&vault.dir).join("index.lock");
+        std::fs::write(&indexlock, b"").unwrap();
+        warnings::take();
+        new_service("bank");
+        assert!(api::exists(PASS, "bank").unwrap());
+        let warnings = warnings::take();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("Unable to commit vault changes"));
+        std::fs::remove_file(&indexlock).unwrap();
+        set_from("mail", "here");
+        assert_eq!(commits().len(), 3);
+        assert!(git_ok(&["status", "--porcelain"]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_sync() {
+        let vault = TestVault::new("git-sync");
+        let remote = testvault::dir("git-sync-remote");
+        let other = testvault::dir("git-sync-other");
+        let status = Command::new("git").args(["init", "-q", "--bare"]).arg(&remote).status().unwrap();
+        assert!(status.success());
+        let remote_url = remote.to_string_lossy().into_owned();
+
+        new_service("mail");
+        init(PASS, Some(&remote_url)).unwrap();
+        let report = sync(PASS).unwrap();
+        assert!(!report.pulled);
+
+        // A second machine joins from the remote
+        testvault::use_dir(&other);
+        init(PASS, Some(&remote_url)).unwrap();
+        assert_eq!(api::list(PASS, &[]).unwrap(), vec!["mail"]);
+        new_service("bank");
+        set_from("mail", "other");
+        assert!(sync(PASS).unwrap().resolved.is_empty());
+
+        // Both change mail, the one changed last wins whichever side it's on
+        for (first, last) in [(&vault.dir, &other), (&other, &vault.dir)].iter() {
+            testvault::use_dir(first);
+            set_from("mail", "first");
+            std::thread::sleep(std::time::Duration::from_millis(1100));
+            testvault::use_dir(last);
+            set_from("mail", "last");
+            // Whoever syncs second has to settle it
+            testvault::use_dir(&other);
+            sync(PASS).unwrap();
+            testvault::use_dir(&vault.dir);
+            let report = sync(PASS).unwrap();
+            assert!(report.pulled);
+            assert_eq!(report.resolved.len(), 1);
+            assert_eq!(from("mail"), "last");
+            assert_eq!(api::list(PASS, &[]).unwrap(), vec!["bank", "mail"]);
+            testvault::use_dir(&other);
+            assert!(sync(PASS).unwrap().resolved.is_empty());
+            assert_eq!(from("mail"), "last");
+        }
+
+        std::fs::remove_dir_all(&remote).unwrap();
+        std::fs::remove_dir_all(&other).unwrap();
+    }
+}
@@ -1,77 +1,170 @@
 use std::collections::{HashMap, HashSet};
 
-use crate::{api::{self, APError}, bitmap::Bitmap, spec::Serializable};
+use crate::{api::{self, APError}, bitmap::Bitmap, search::SearchDoc, spec::Serializable};
+use crate::tagquery::{TagIndex, TagQuery, TagQueryError};
+use crate::tagtree::{self, TagNode};
 
 use super::validator::Validator;
 
 
+/// How toggled tags filter services, or a typed in tag query instead
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TagMode {
+    Any,
+    All,
+    Query
+}
+
 pub struct ServiceList {
+    /// Every tag and folder in the tree, and whether it's toggled on
     tags: Vec<(String, bool)>,
-    services: Vec<(String, Bitmap)>
+    tree: Vec<TagNode>,
+    index: TagIndex,
+    tag_mode: TagMode,
+    tag_query: String,
+    services: Vec<(String, Bitmap)>,
+    docs: HashMap<String, SearchDoc>,
+    query: String,
+    stale: HashMap<String, u64>,
+    corrupt: usize
 }
 
 impl ServiceList {
     pub fn refresh(&mut self, pass: &str) -> Result<(), APError> {
-        let rawservices = api::list_all(pass, &[])?;
-        let mut tagset = HashSet::new();
-
-        for service in &rawservices {
-            for tag in service.get_tags() {
-                tagset.insert(tag);
-            }
-        }
-        self.tags = tagset.drain().map(|t| (t.to_owned(), false)).collect();
+        let (rawservices, corrupt) = api::list_checked(pass, &[])?;
+        self.corrupt = corrupt.len();
+        self.index = TagIndex::new(rawservices.iter().flat_map(|service| service.get_tags()));
+        self.tree = tagtree::build(rawservices.iter().map(|service| service.get_tags()));
+        // Toggles survive a refresh as long as the tag or folder is still around
+        let toggled: HashSet<String> = self.tags.drain(..)
+            .filter_map(|(tag, set)| set.then_some(tag))
+            .collect();
+        self.tags = self.tree.iter()
+            .flat_map(|node| node.iter())
+            .map(|node| (node.path.clone(), toggled.contains(&node.path)))
+            .collect();
         self.tags.sort();
-        let mut taglookup = HashMap::new();
-        for tag in self.tags.iter().enumerate() {
-            taglookup.insert(&tag.1.0, tag.0);
-        }
 
         self.services.clear();
         for service in &rawservices {
-            let mut bmp = Bitmap::new(self.tags.len());
-            for tag in service.get_tags() {
-                let idx = taglookup.get(tag).unwrap();
-                bmp.set(*idx);
-            }
-            self.services.push((service.name().to_owned(), bmp));
+            self.services.push((service.name().to_owned(), self.index.bitmap(service.get_tags())));
         }
         self.services.sort_by(|a, b| a.0.cmp(&b.0));
+        self.docs = rawservices.iter()
+            .map(|service| (service.name().to_owned(), SearchDoc::new(service)))
+            .collect();
+
+        self.stale = api::stale(pass)?
+            .into_iter()
+            .map(|s| (s.service.name().to_owned(), s.age))
+            .collect();
         Ok(())
     }
 
     pub fn new(pass: &str) -> Result<Self, APError> {
         let mut inst = Self {
             tags: vec![],
-            services: vec![]
+            tree: vec![],
+            index: TagIndex::default(),
+            tag_mode: TagMode::Any,
+            tag_query: String::new(),
+            services: vec![],
+            docs: HashMap::new(),
+            query: String::new(),
+            stale: HashMap::new(),
+            corrupt: 0
         };
         inst.refresh(pass)?;
         Ok(inst)
     }
 
-    pub fn tags_mut(&mut self) -> &mut [(String, bool)] {
-        &mut self.tags
+    /// Tags services actually have, without the folders above them
+    pub fn tags(&self) -> &[String] {
+        self.index.tags()
     }
 
-    fn service_visible(&self, bmp: &Bitmap) -> bool {
-        self.tags.iter().enumerate().fold(false, |show, (idx, (_, tagset))| {
-            show || (*tagset && bmp.check_set(idx))
-        })
+    /// Tags as folders, with how many services are in each
+    pub fn tag_tree(&self) -> &[TagNode] {
+        &self.tree
+    }
+
+    pub fn tag_toggled(&self, tag: &str) -> bool {
+        self.tags.iter().any(|(t, set)| t == tag && *set)
+    }
+
+    pub fn toggle_tag(&mut self, tag: &str) {
+        if let Some((_, set)) = self.tags.iter_mut().find(|(t, _)| t == tag) {
+            *set = !*set;
+        }
+    }
+
+    pub fn tag_mode(&self) -> TagMode {
+        self.tag_mode
+    }
+
+    /// Switching to a query starts it off with whatever the toggled tags were selecting
+    pub fn set_tag_mode(&mut self, mode: TagMode) {
+        if mode == TagMode::Query && self.tag_mode != TagMode::Query {
+            if let Ok(query) = self.tag_filter() {
+                self.tag_query = query.to_string();
+            }
+        }
+        self.tag_mode = mode;
+    }
+
+    pub fn tag_query_mut(&mut self) -> &mut String {
+        &mut self.tag_query
+    }
+
+    /// What tags visible services need, going by the mode
+    pub fn tag_filter(&self) -> Result<TagQuery, TagQueryError> {
+        let toggled: Vec<&String> = self.tags.iter()
+            .filter(|(_, set)| *set)
+            .map(|(tag, _)| tag)
+            .collect();
+        match self.tag_mode {
+            TagMode::Any => Ok(TagQuery::any_of(&toggled)),
+            TagMode::All => Ok(TagQuery::all_of(&toggled)),
+            TagMode::Query => TagQuery::parse(&self.tag_query)
+        }
+    }
+
+    /// Search box contents, visible services are narrowed down and ranked by it
+    pub fn query_mut(&mut self) -> &mut String {
+        &mut self.query
+    }
+
+    /// Password age in days if the service is past its max age
+    pub fn stale_age(&self, service: &str) -> Option<u64> {
+        self.stale.get(service).copied()
+    }
+
+    /// Number of files skipped because they couldn't be read
+    pub fn corrupt(&self) -> usize {
+        self.corrupt
     }
 
     fn iter_tags<'a, 'b>(&'a self, bmp: &'b Bitmap) -> impl Iterator<Item=&'a String> + use<'a, 'b> {
-        self.tags.iter()
+        self.index.tags().iter()
             .enumerate()
-            .filter_map(move |(idx, (tag, _))| bmp.check_set(idx).then(|| tag) )
+            .filter(move |(idx, _)| bmp.check_set(*idx))
+            .map(|(_, tag)| tag)
     }
 
+    /// Services passing the tag filter and search, best match first. A tag query
+    /// that doesn't parse doesn't filter anything.
     pub fn iter_visible_services(&self) -> impl Iterator<Item=&String> {
-        let none_set = !self.tags.iter()
-            .fold(false, |none_set, (_, set)| none_set || *set);
-
-        self.services.iter()
-            .filter(move |(_, bmp)| none_set || self.service_visible(bmp))
-            .map(|(s, _)| s)
+        let filter = self.index.compile(&self.tag_filter().unwrap_or(TagQuery::All));
+
+        let mut visible: Vec<(&String, u32)> = self.services.iter()
+            .filter(|(_, bmp)| filter.matches_bitmap(bmp))
+            .filter_map(|(s, _)| match self.docs.get(s) {
+                Some(doc) => doc.score(&self.query).map(|score| (s, score)),
+                None => Some((s, 0))
+            })
+            .collect();
+        visible.sort_by(|(s1, score1), (s2, score2)| score2.cmp(score1).then_with(|| s1.cmp(s2)));
+        visible.into_iter().map(|(s, _)| s)
     }
 
     pub fn not_in_services<'a>(&'a self) -> NotAService<'a> {
@@ -1,12 +1,32 @@
 use serde::{Serialize, Deserialize};
 
-#[derive(Deserialize, Serialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
 pub enum TextMode {
     AlphaNumeric,
     AlphaNumericUnderscore,
     NoWhiteSpace
 }
 
+impl TextMode {
+    /// What the CLI and config file call it
+    pub fn name(&self) -> &'static str {
+        match self {
+            TextMode::AlphaNumeric => "alphanumeric",
+            TextMode::AlphaNumericUnderscore => "alphanumericunderscore",
+            TextMode::NoWhiteSpace => "nowhitespace"
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "alphanumeric" => Some(TextMode::AlphaNumeric),
+            "alphanumericunderscore" => Some(TextMode::AlphaNumericUnderscore),
+            "nowhitespace" => Some(TextMode::NoWhiteSpace),
+            _ => None
+        }
+    }
+}
+
 fn set_alphanumeric(map: &mut Vec<u8>) {
     for i in 48..(48 + 10) {
         map.push(i);
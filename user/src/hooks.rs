@@ -0,0 +1,156 @@
+use std::{path::{Path, PathBuf}, process::{Command, Stdio}};
+
+use crate::api::APError;
+use crate::spec::{base_path, hooks_path, PASS_BASE_ENVVAR};
+use crate::warnings;
+
+/*
+ * Scripts from the vault's hooks directory run around changes to services, to commit
+ * to git, send a notification or take a backup. `pre-<op>` runs before the change and
+ * stops it by exiting non-zero, `post-<op>` runs once it's made, and `pre` and `post`
+ * run for every operation. Only executable files are run, like git hooks.
+ *
+ * Hooks get the operation, phase and service name in the environment, never a password.
+ * They run outside the vault lock except inside bulk operations, so a hook that
+ * changes the vault itself will wait on the lock there.
+ */
+
+pub const OP_ENVVAR: &str = "AP_HOOK_OP";
+pub const PHASE_ENVVAR: &str = "AP_HOOK_PHASE";
+pub const SERVICE_ENVVAR: &str = "AP_HOOK_SERVICE";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HookOp {
+    New,
+    SetKv,
+    SetTags,
+    Upgrade,
+    Delete
+}
+
+impl HookOp {
+    pub fn name(&self) -> &'static str {
+        match self {
+            HookOp::New => "new",
+            HookOp::SetKv => "set-kv",
+            HookOp::SetTags => "set-tags",
+            HookOp::Upgrade => "upgrade",
+            HookOp::Delete => "delete"
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    Pre,
+    Post
+}
+
+impl Phase {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Phase::Pre => "pre",
+            Phase::Post => "post"
+        }
+    }
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata().map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0).unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+/// Hooks for phase of op, the catch all one first
+pub fn hooks(phase: Phase, op: HookOp) -> Vec<PathBuf> {
+    let dir = hooks_path(base_path());
+    [phase.name().to_owned(), format!("{}-{}", phase.name(), op.name())].iter()
+        .map(|name| dir.join(name))
+        .filter(|path| is_executable(path))
+        .collect()
+}
+
+/// Run the hooks for phase of op on service, stopping at the first that fails
+pub fn run(phase: Phase, op: HookOp, service: &str) -> Result<(), APError> {
+    for hook in hooks(phase, op) {
+        let status = Command::new(&hook)
+            .current_dir(base_path())
+            .env(PASS_BASE_ENVVAR, base_path())
+            .env(OP_ENVVAR, op.name())
+            .env(PHASE_ENVVAR, phase.name())
+            .env(SERVICE_ENVVAR, service)
+            .stdin(Stdio::null())
+            .status()?;
+        if !status.success() {
+            let name = hook.file_name().unwrap().to_string_lossy().into_owned();
+            return Err(APError::Hook(name, status.to_string()));
+        }
+    }
+    Ok(())
+}
+
+/// Make the change in f between op's hooks. A failing post hook can't take the
+/// change back, so it's a warning.
+pub fn wrap<T, F: FnOnce() -> Result<T, APError>>(op: HookOp, service: &str, f: F) -> Result<T, APError> {
+    run(Phase::Pre, op, service)?;
+    let res = f()?;
+    if let Err(e) = run(Phase::Post, op, service) {
+        warnings::push(e.to_string());
+    }
+    Ok(res)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use crate::api;
+    use crate::hash::TextMode;
+    use crate::spec::Visibility;
+    use crate::testvault::{TestVault, PASS};
+
+    fn add_hook(name: &str, script: &str) {
+        let dir = hooks_path(base_path());
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        std::fs::write(&path, format!("#!/bin/sh\n{}\n", script)).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[test]
+    fn test_hooks() {
+        let vault = TestVault::new("hooks");
+        let env = vault.dir.join("hook-env");
+        add_hook("post", &format!("env > '{}'", env.display()));
+        let mail = api::new::<&str>("mail", PASS, &TextMode::NoWhiteSpace, 16, &[], &[], None).unwrap();
+
+        let env = std::fs::read_to_string(&env).unwrap();
+        assert!(env.lines().any(|l| l == "AP_HOOK_OP=new"));
+        assert!(env.lines().any(|l| l == "AP_HOOK_PHASE=post"));
+        assert!(env.lines().any(|l| l == "AP_HOOK_SERVICE=mail"));
+        assert!(!env.contains(PASS));
+        assert!(!env.contains(mail.get_pass(false).unwrap()));
+
+        // A failing pre hook stops the change before anything is touched
+        add_hook("pre-set-kv", "exit 3");
+        let res = api::set_kvs("mail", PASS, &[("user", "me")], Visibility::Plain, false, None);
+        assert!(matches!(res, Err(APError::Hook(ref hook, _)) if hook == "pre-set-kv"));
+        let entry = api::get_all("mail", PASS).unwrap();
+        assert!(entry.get_kvs().is_empty());
+        assert_eq!(entry.modify_time(), mail.modify_time());
+
+        // A failing post hook can't, the change is made and the failure is a warning
+        add_hook("post-set-tags", "exit 4");
+        warnings::take();
+        api::set_tags("mail", PASS, &["web"], false, None).unwrap();
+        assert_eq!(api::get_all("mail", PASS).unwrap().get_tags(), ["web"]);
+        let warnings = warnings::take();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("Hook post-set-tags failed"));
+    }
+}
@@ -0,0 +1,233 @@
+use std::{collections::HashMap, io::Read};
+
+use serde_json::Value;
+
+use super::{csv::host_of, ImportEntry, Parsed};
+use crate::api::APError;
+use crate::spec::Visibility;
+
+/*
+ * Bitwarden's JSON export, unencrypted. Every kind of item becomes a service: logins
+ * keep their password, username, urls and TOTP seed, cards, identities and SSH keys
+ * keep their fields as kvs with numbers, codes and private keys concealed. Notes and
+ * custom fields come along, hidden fields concealed. Folders and collections become
+ * tags. Passkeys, linked fields, password history and attachments, which the export
+ * doesn't hold, have nowhere to go and are reported, as is anything in the trash.
+ */
+
+const LOGIN: u64 = 1;
+const CARD: u64 = 3;
+const IDENTITY: u64 = 4;
+const SSH_KEY: u64 = 5;
+
+/// Custom field types
+const HIDDEN: u64 = 1;
+const LINKED: u64 = 3;
+
+/// A string member of value, trimmed, empty if it's missing or not a string
+fn text<'a>(value: &'a Value, key: &str) -> &'a str {
+    value.get(key).and_then(Value::as_str).map(|s| s.trim()).unwrap_or("")
+}
+
+fn items<'a>(value: &'a Value, key: &str) -> &'a [Value] {
+    value.get(key).and_then(Value::as_array).map(|a| a.as_slice()).unwrap_or(&[])
+}
+
+/// Names of the folders or collections in an export, by id
+fn names(export: &Value, key: &str) -> HashMap<String, String> {
+    items(export, key).iter().map(|f| (text(f, "id").to_owned(), text(f, "name").to_owned())).collect()
+}
+
+fn add_login(entry: &mut ImportEntry, login: &Value, unsupported: &mut Vec<String>) {
+    // Not trimmed, spaces can be part of a password
+    entry.password = login.get("password").and_then(Value::as_str).filter(|p| !p.is_empty()).map(|p| p.to_owned());
+    entry.add_kv("username", text(login, "username"), Visibility::Plain);
+    for uri in items(login, "uris") {
+        entry.add_kv("url", text(uri, "uri"), Visibility::Plain);
+    }
+    entry.add_kv("totp", text(login, "totp"), Visibility::Concealed);
+    for passkey in items(login, "fido2Credentials") {
+        unsupported.push(format!("{}: passkey for {}", entry.name, text(passkey, "rpId")));
+    }
+}
+
+fn add_card(entry: &mut ImportEntry, card: &Value) {
+    entry.add_kv("cardholder", text(card, "cardholderName"), Visibility::Plain);
+    entry.add_kv("brand", text(card, "brand"), Visibility::Plain);
+    entry.add_kv("number", text(card, "number"), Visibility::Concealed);
+    let expiry = match (text(card, "expMonth"), text(card, "expYear")) {
+        ("", year) => year.to_owned(),
+        (month, "") => month.to_owned(),
+        (month, year) => format!("{}/{}", month, year)
+    };
+    entry.add_kv("expiry", &expiry, Visibility::Plain);
+    entry.add_kv("code", text(card, "code"), Visibility::Concealed);
+}
+
+fn add_identity(entry: &mut ImportEntry, identity: &Value) {
+    const FIELDS: &[(&str, &str, Visibility)] = &[
+        ("title", "title", Visibility::Plain),
+        ("firstName", "first name", Visibility::Plain),
+        ("middleName", "middle name", Visibility::Plain),
+        ("lastName", "last name", Visibility::Plain),
+        ("username", "username", Visibility::Plain),
+        ("company", "company", Visibility::Plain),
+        ("email", "email", Visibility::Plain),
+        ("phone", "phone", Visibility::Plain),
+        ("address1", "address", Visibility::Plain),
+        ("address2", "address", Visibility::Plain),
+        ("address3", "address", Visibility::Plain),
+        ("city", "city", Visibility::Plain),
+        ("state", "state", Visibility::Plain),
+        ("postalCode", "postal code", Visibility::Plain),
+        ("country", "country", Visibility::Plain),
+        ("ssn", "ssn", Visibility::Concealed),
+        ("passportNumber", "passport number", Visibility::Concealed),
+        ("licenseNumber", "license number", Visibility::Concealed)
+    ];
+    for (field, key, visibility) in FIELDS {
+        entry.add_kv(key, text(identity, field), *visibility);
+    }
+}
+
+fn add_ssh_key(entry: &mut ImportEntry, key: &Value) {
+    entry.add_kv("private key", text(key, "privateKey"), Visibility::Concealed);
+    entry.add_kv("public key", text(key, "publicKey"), Visibility::Plain);
+    entry.add_kv("fingerprint", text(key, "keyFingerprint"), Visibility::Plain);
+}
+
+/// The item as a service, None if there's nothing to name it by
+fn entry(item: &Value, folders: &HashMap<String, String>, collections: &HashMap<String, String>,
+         unsupported: &mut Vec<String>) -> Option<ImportEntry> {
+    let login = item.get("login").unwrap_or(&Value::Null);
+    let name = Some(text(item, "name")).filter(|n| !n.is_empty()).map(|n| n.to_owned())
+        .or_else(|| items(login, "uris").first().and_then(|u| host_of(text(u, "uri"))))
+        .or_else(|| Some(text(login, "username")).filter(|u| !u.is_empty()).map(|u| u.to_owned()))?;
+
+    let mut entry = ImportEntry::new(&name);
+    match item.get("type").and_then(Value::as_u64) {
+        Some(LOGIN) => add_login(&mut entry, login, unsupported),
+        Some(CARD) => add_card(&mut entry, item.get("card").unwrap_or(&Value::Null)),
+        Some(IDENTITY) => add_identity(&mut entry, item.get("identity").unwrap_or(&Value::Null)),
+        Some(SSH_KEY) => add_ssh_key(&mut entry, item.get("sshKey").unwrap_or(&Value::Null)),
+        // Secure notes have nothing but their notes
+        _ => {}
+    }
+    entry.add_kv("notes", text(item, "notes"), Visibility::Plain);
+    for field in items(item, "fields") {
+        let key = Some(text(field, "name")).filter(|k| !k.is_empty()).unwrap_or("field");
+        // Booleans are kept as the true or false they're exported as
+        let value = match field.get("value") {
+            Some(Value::String(value)) => value.trim().to_owned(),
+            Some(Value::Bool(value)) => value.to_string(),
+            _ => String::new()
+        };
+        match field.get("type").and_then(Value::as_u64) {
+            Some(LINKED) => unsupported.push(format!("{}: linked field {}", name, key)),
+            Some(HIDDEN) => entry.add_kv(key, &value, Visibility::Concealed),
+            _ => entry.add_kv(key, &value, Visibility::Plain)
+        }
+    }
+    if let Some(folder) = item.get("folderId").and_then(Value::as_str).and_then(|id| folders.get(id)) {
+        entry.add_tag(folder);
+    }
+    for collection in items(item, "collectionIds").iter().filter_map(|id| id.as_str().and_then(|id| collections.get(id))) {
+        entry.add_tag(collection);
+    }
+
+    let history = items(item, "passwordHistory").len();
+    if history > 0 {
+        unsupported.push(format!("{}: password history of {}", name, history));
+    }
+    for attachment in items(item, "attachments") {
+        unsupported.push(format!("{}: attachment {}, which isn't in the export", name, text(attachment, "fileName")));
+    }
+    Some(entry)
+}
+
+/// Read an unencrypted JSON export
+pub fn read<R: Read>(reader: R) -> Result<Parsed, APError> {
+    let export: Value = serde_json::from_reader(reader).map_err(|e| APError::Import(format!("Bad JSON: {}", e)))?;
+    if export.get("encrypted").and_then(Value::as_bool) == Some(true) {
+        return Err(APError::Import("The export is encrypted, export again as unencrypted JSON".to_owned()));
+    }
+    let folders = names(&export, "folders");
+    let collections = names(&export, "collections");
+
+    let mut parsed = Parsed::default();
+    for (idx, item) in items(&export, "items").iter().enumerate() {
+        let mut unsupported = vec![];
+        match entry(item, &folders, &collections, &mut unsupported) {
+            Some(entry) if item.get("deletedDate").is_some_and(|d| !d.is_null()) => {
+                parsed.unsupported.push(format!("{}: in the trash", entry.name));
+                continue;
+            }
+            Some(entry) => parsed.entries.push(entry),
+            None => parsed.unsupported.push(format!("Item {}: no name, url or username to name it by", idx + 1))
+        }
+        parsed.unsupported.extend(unsupported);
+    }
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXPORT: &str = r#"{
+      "encrypted": false,
+      "folders": [{"id": "f1", "name": "Work/Cloud"}],
+      "items": [
+        {"id": "1", "folderId": "f1", "type": 1, "name": "AWS", "notes": "root account",
+         "fields": [{"name": "account", "value": "1234", "type": 0},
+                    {"name": "pin", "value": "0000", "type": 1},
+                    {"name": "sso", "value": "true", "type": 2},
+                    {"name": "user", "value": null, "type": 3, "linkedId": 100}],
+         "login": {"uris": [{"match": null, "uri": "https://aws.amazon.com"}, {"uri": "https://console.aws.amazon.com"}],
+                   "username": "root", "password": " aws-pass", "totp": "JBSWY3DPEHPK3PXP",
+                   "fido2Credentials": [{"rpId": "aws.amazon.com"}]},
+         "passwordHistory": [{"password": "old"}], "collectionIds": null, "deletedDate": null},
+        {"id": "2", "folderId": null, "type": 2, "name": "Wifi", "notes": "hunter2", "secureNote": {"type": 0}},
+        {"id": "3", "type": 3, "name": "Visa", "card": {"cardholderName": "Me", "brand": "Visa",
+         "number": "4111111111111111", "expMonth": "4", "expYear": "2030", "code": "123"}},
+        {"id": "4", "type": 1, "name": "", "login": {"uris": [], "username": "", "password": "x"}},
+        {"id": "5", "type": 1, "name": "Old", "login": {"password": "y"}, "deletedDate": "2024-01-01T00:00:00Z"},
+        {"id": "6", "type": 5, "name": "Server", "sshKey": {"privateKey": "-----BEGIN-----", "publicKey": "ssh-ed25519 AAAA",
+         "keyFingerprint": "SHA256:abc"}}
+      ]
+    }"#;
+
+    #[test]
+    fn test_read() {
+        let parsed = read(EXPORT.as_bytes()).unwrap();
+        let names: Vec<&str> = parsed.entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, vec!["AWS", "Wifi", "Visa", "Server"]);
+        assert_eq!(parsed.unsupported, vec!["AWS: passkey for aws.amazon.com", "AWS: linked field user",
+                                            "AWS: password history of 1",
+                                            "Item 4: no name, url or username to name it by", "Old: in the trash"]);
+
+        let aws = &parsed.entries[0];
+        assert_eq!(aws.password.as_deref(), Some(" aws-pass"));
+        assert_eq!(aws.tags, vec!["Work/Cloud"]);
+        assert_eq!(aws.get_kv("username"), Some("root"));
+        assert_eq!(aws.get_kv("url 2"), Some("https://console.aws.amazon.com"));
+        assert_eq!(aws.get_kv("notes"), Some("root account"));
+        assert_eq!(aws.get_kv("sso"), Some("true"));
+        assert!(aws.kvs.contains(&("totp".to_owned(), "JBSWY3DPEHPK3PXP".to_owned(), Visibility::Concealed)));
+        assert!(aws.kvs.contains(&("pin".to_owned(), "0000".to_owned(), Visibility::Concealed)));
+
+        let wifi = &parsed.entries[1];
+        assert_eq!(wifi.password, None);
+        assert_eq!(wifi.get_kv("notes"), Some("hunter2"));
+
+        let visa = &parsed.entries[2];
+        assert_eq!(visa.get_kv("expiry"), Some("4/2030"));
+        assert!(visa.kvs.contains(&("number".to_owned(), "4111111111111111".to_owned(), Visibility::Concealed)));
+        assert!(parsed.entries[3].kvs.contains(&("private key".to_owned(), "-----BEGIN-----".to_owned(), Visibility::Concealed)));
+    }
+
+    #[test]
+    fn test_encrypted() {
+        assert!(read(r#"{"encrypted": true, "encKeyValidation_DO_NOT_EDIT": "x", "items": []}"#.as_bytes()).is_err());
+    }
+}
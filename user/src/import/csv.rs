@@ -0,0 +1,239 @@
+use std::{collections::BTreeMap, io::Read, str::FromStr};
+
+use super::{ImportEntry, Parsed};
+use crate::api::APError;
+use crate::spec::Visibility;
+use crate::tagtree::SEPARATOR;
+
+/*
+ * Browsers and password managers export logins as CSV with a header row naming the
+ * columns. Each format says which column holds each field, and `--map FIELD=COLUMN`
+ * changes that or maps a column for a format with no fixed layout. A field that isn't
+ * one of `FIELDS` becomes a kv of that name. An entry is named by its name column,
+ * else the host of its url, else its username.
+ */
+
+/// Fields columns can be mapped to
+pub const FIELDS: &[&str] = &["name", "password", "username", "url", "notes", "totp", "tags", "folder", "fields"];
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Format {
+    Chrome,
+    Firefox,
+    Bitwarden,
+    /// Columns named like the fields, or mapped by hand
+    Generic
+}
+
+impl FromStr for Format {
+    type Err = APError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "chrome" => Ok(Self::Chrome),
+            "firefox" => Ok(Self::Firefox),
+            "bitwarden" => Ok(Self::Bitwarden),
+            "generic" => Ok(Self::Generic),
+            _ => Err(APError::Import(format!("Unknown CSV format {}", s)))
+        }
+    }
+}
+
+impl Format {
+    /// Each field and the column it's in
+    fn columns(self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            Self::Chrome => &[("name", "name"), ("url", "url"), ("username", "username"),
+                              ("password", "password"), ("notes", "note")],
+            Self::Firefox => &[("url", "url"), ("username", "username"), ("password", "password")],
+            Self::Bitwarden => &[("name", "name"), ("url", "login_uri"), ("username", "login_username"),
+                                 ("password", "login_password"), ("notes", "notes"), ("totp", "login_totp"),
+                                 ("folder", "folder"), ("fields", "fields")],
+            Self::Generic => &[("name", "name"), ("url", "url"), ("username", "username"),
+                               ("password", "password"), ("notes", "notes"), ("tags", "tags")]
+        }
+    }
+}
+
+/// Split a --map FIELD=COLUMN. An empty column unmaps the field.
+pub fn parse_mapping(mapping: &str) -> Result<(String, String), APError> {
+    match mapping.split_once('=') {
+        Some((field, column)) if !field.trim().is_empty() => Ok((field.trim().to_owned(), column.trim().to_lowercase())),
+        _ => Err(APError::Import(format!("Mapping {} must be of the form FIELD=COLUMN", mapping)))
+    }
+}
+
+/// The host of a url without www, `https://www.example.com:8080/login` gives `example.com`
+pub fn host_of(url: &str) -> Option<String> {
+    let rest = url.split_once("://").map(|(_, rest)| rest).unwrap_or(url);
+    let host = rest.split(['/', '?', '#']).next()?;
+    let host = host.rsplit('@').next()?.split(':').next()?;
+    let host = host.strip_prefix("www.").unwrap_or(host);
+    match host.is_empty() {
+        true => None,
+        false => Some(host.to_lowercase())
+    }
+}
+
+/// Which column index each field comes from
+fn map_columns(headers: &[String], format: Format, mapping: &[(String, String)]) -> Result<BTreeMap<String, usize>, APError> {
+    let index = |column: &str| headers.iter().position(|h| h == column);
+    let mut columns = BTreeMap::new();
+    // Formats change their columns between versions, so any missing are left out
+    for (field, column) in format.columns() {
+        if let Some(idx) = index(column) {
+            columns.insert(field.to_string(), idx);
+        }
+    }
+    for (field, column) in mapping {
+        if column.is_empty() {
+            columns.remove(field);
+            continue;
+        }
+        let idx = index(column).ok_or_else(|| APError::Import(format!("There's no column {}", column)))?;
+        columns.insert(field.clone(), idx);
+    }
+    if !["name", "url", "username"].iter().any(|field| columns.contains_key(*field)) {
+        return Err(APError::Import("Nothing to name services by, map a column to name with --map name=COLUMN".to_owned()));
+    }
+    Ok(columns)
+}
+
+fn entry(record: &::csv::StringRecord, columns: &BTreeMap<String, usize>) -> Option<ImportEntry> {
+    let get = |field: &str| columns.get(field)
+        .and_then(|idx| record.get(*idx))
+        .map(|value| value.trim())
+        .filter(|value| !value.is_empty());
+    let url = get("url");
+    let username = get("username");
+    let name = match get("name") {
+        Some(name) => name.to_owned(),
+        None => url.and_then(host_of).or_else(|| username.map(|u| u.to_owned()))?
+    };
+
+    let mut entry = ImportEntry::new(&name);
+    // Not trimmed, spaces can be part of a password
+    entry.password = columns.get("password")
+        .and_then(|idx| record.get(*idx))
+        .filter(|pass| !pass.is_empty())
+        .map(|pass| pass.to_owned());
+    entry.add_kv("username", username.unwrap_or(""), Visibility::Plain);
+    entry.add_kv("url", url.unwrap_or(""), Visibility::Plain);
+    entry.add_kv("notes", get("notes").unwrap_or(""), Visibility::Plain);
+    entry.add_kv("totp", get("totp").unwrap_or(""), Visibility::Concealed);
+    // Bitwarden puts custom fields one per line as NAME: VALUE
+    for line in get("fields").unwrap_or("").lines() {
+        match line.split_once(": ") {
+            Some((key, value)) => entry.add_kv(key.trim(), value.trim(), Visibility::Plain),
+            None => entry.add_kv("field", line.trim(), Visibility::Plain)
+        }
+    }
+    for (field, _) in columns.iter().filter(|(field, _)| !FIELDS.contains(&field.as_str())) {
+        entry.add_kv(field, get(field).unwrap_or(""), Visibility::Plain);
+    }
+
+    for tag in get("tags").unwrap_or("").split(',') {
+        entry.add_tag(tag.trim());
+    }
+    // A folder is one tag however it's named, its path kept as a hierarchy
+    if let Some(folder) = get("folder") {
+        let parts: Vec<&str> = folder.split(['/', '\\']).map(|p| p.trim()).filter(|p| !p.is_empty()).collect();
+        entry.add_tag(&parts.join(&SEPARATOR.to_string()));
+    }
+    Some(entry)
+}
+
+/// Read an export in format, with mapping changing which column each field comes from
+pub fn read<R: Read>(reader: R, format: Format, mapping: &[(String, String)]) -> Result<Parsed, APError> {
+    let mut reader = ::csv::ReaderBuilder::new().flexible(true).from_reader(reader);
+    let csv_error = |e: ::csv::Error| APError::Import(format!("Bad CSV: {}", e));
+    let headers: Vec<String> = reader.headers().map_err(csv_error)?
+        .iter()
+        .map(|h| h.trim_start_matches('\u{feff}').trim().to_lowercase())
+        .collect();
+    let columns = map_columns(&headers, format, mapping)?;
+
+    let mut parsed = Parsed::default();
+    for record in reader.records() {
+        let record = record.map_err(csv_error)?;
+        let line = record.position().map(|p| p.line()).unwrap_or(0);
+        if record.iter().all(|value| value.trim().is_empty()) {
+            continue;
+        }
+        match entry(&record, &columns) {
+            Some(entry) => parsed.entries.push(entry),
+            None => parsed.unsupported.push(format!("Line {}: no name, url or username to name it by", line))
+        }
+    }
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_str(csv: &str, format: Format, mapping: &[(&str, &str)]) -> Result<Parsed, APError> {
+        let mapping: Vec<(String, String)> = mapping.iter().map(|(f, c)| (f.to_string(), c.to_string())).collect();
+        read(csv.as_bytes(), format, &mapping)
+    }
+
+    #[test]
+    fn test_host_of() {
+        assert_eq!(host_of("https://www.Example.com:8080/login?next=/"), Some("example.com".to_owned()));
+        assert_eq!(host_of("android://hash@com.example.app/"), Some("com.example.app".to_owned()));
+        assert_eq!(host_of("mail.example.com"), Some("mail.example.com".to_owned()));
+        assert_eq!(host_of("https://"), None);
+    }
+
+    #[test]
+    fn test_chrome() {
+        let parsed = read_str("name,url,username,password,note\n\
+                               Mail,https://mail.example.com/,me@example.com,\" hunter2\",\"two\nlines\"\n",
+                               Format::Chrome, &[]).unwrap();
+        assert_eq!(parsed.entries.len(), 1);
+        let entry = &parsed.entries[0];
+        assert_eq!(entry.name, "Mail");
+        assert_eq!(entry.password.as_deref(), Some(" hunter2"));
+        assert_eq!(entry.get_kv("username"), Some("me@example.com"));
+        assert_eq!(entry.get_kv("url"), Some("https://mail.example.com/"));
+        assert_eq!(entry.get_kv("notes"), Some("two\nlines"));
+    }
+
+    #[test]
+    fn test_firefox() {
+        let parsed = read_str("\"url\",\"username\",\"password\",\"httpRealm\",\"formActionOrigin\",\"guid\"\n\
+                               \"https://www.example.com\",\"me\",\"pw\",,\"\",\"{1}\"\n\
+                               \"\",\"\",\"orphan\",,\"\",\"{2}\"\n",
+                               Format::Firefox, &[]).unwrap();
+        assert_eq!(parsed.entries.len(), 1);
+        assert_eq!(parsed.entries[0].name, "example.com");
+        assert_eq!(parsed.unsupported.len(), 1);
+    }
+
+    #[test]
+    fn test_bitwarden() {
+        let parsed = read_str("folder,favorite,type,name,notes,fields,reprompt,login_uri,login_username,login_password,login_totp\n\
+                               Work/Cloud,,login,AWS,,\"account: 1234\npin: 99\",0,https://aws.amazon.com,root,pw,otpauth://totp/x\n",
+                               Format::Bitwarden, &[]).unwrap();
+        let entry = &parsed.entries[0];
+        assert_eq!(entry.tags, vec!["Work/Cloud"]);
+        assert_eq!(entry.get_kv("account"), Some("1234"));
+        assert_eq!(entry.get_kv("pin"), Some("99"));
+        assert!(entry.kvs.contains(&("totp".to_owned(), "otpauth://totp/x".to_owned(), Visibility::Concealed)));
+    }
+
+    #[test]
+    fn test_generic_mapping() {
+        let csv = "Title,Login,Secret,Group,Account\nBank,me,pw,\"money, home\",42\n";
+        assert!(read_str(csv, Format::Generic, &[]).is_err());
+        assert!(read_str(csv, Format::Generic, &[("name", "missing")]).is_err());
+
+        let parsed = read_str(csv, Format::Generic, &[("name", "title"), ("username", "login"), ("password", "secret"),
+                                                      ("tags", "group"), ("account", "account")]).unwrap();
+        let entry = &parsed.entries[0];
+        assert_eq!(entry.name, "Bank");
+        assert_eq!(entry.password.as_deref(), Some("pw"));
+        assert_eq!(entry.tags, vec!["money", "home"]);
+        assert_eq!(entry.get_kv("account"), Some("42"));
+    }
+}
@@ -0,0 +1,96 @@
+use std::io::Read;
+
+use super::{csv::host_of, ImportEntry, Parsed};
+use crate::api::APError;
+use crate::kdbx::{self, Entry, Group, NOTES, PASSWORD, TITLE, URL, USERNAME};
+use crate::spec::Visibility;
+use crate::tagtree::SEPARATOR;
+
+/*
+ * Each KeePass entry becomes a service tagged with the path of groups it's in, below
+ * the root group which is the database itself. Username, url and notes become kvs as
+ * the CSV formats' do, other fields become kvs of their own name, concealed if KeePass
+ * protected them. Attachments come along, history and the recycle bin don't.
+ */
+
+/// A group's name as one part of a tag
+fn tag_part(name: &str) -> String {
+    name.replace(SEPARATOR, "-")
+}
+
+/// The entry as a service tagged path, None if there's nothing to name it by
+fn entry(kdbx_entry: &Entry, path: &str) -> Option<ImportEntry> {
+    let get = |key: &str| kdbx_entry.get(key).map(|v| v.trim()).filter(|v| !v.is_empty());
+    let name = get(TITLE).map(|t| t.to_owned())
+        .or_else(|| get(URL).and_then(host_of))
+        .or_else(|| get(USERNAME).map(|u| u.to_owned()))?;
+
+    let mut entry = ImportEntry::new(&name);
+    entry.password = kdbx_entry.get(PASSWORD).filter(|p| !p.is_empty()).map(|p| p.to_owned());
+    entry.add_kv("username", get(USERNAME).unwrap_or(""), Visibility::Plain);
+    entry.add_kv("url", get(URL).unwrap_or(""), Visibility::Plain);
+    entry.add_kv("notes", get(NOTES).unwrap_or(""), Visibility::Plain);
+    for field in kdbx_entry.fields.iter().filter(|f| ![TITLE, USERNAME, PASSWORD, URL, NOTES].contains(&f.key.as_str())) {
+        let visibility = match field.protected {
+            true => Visibility::Concealed,
+            false => Visibility::Plain
+        };
+        entry.add_kv(&field.key, &field.value, visibility);
+    }
+    entry.add_tag(path);
+    for tag in kdbx_entry.tags.iter() {
+        entry.add_tag(tag);
+    }
+    entry.attachments = kdbx_entry.attachments.clone();
+    Some(entry)
+}
+
+/// Add what's in group, which is tagged path and shown as location
+fn add_group(group: &Group, path: &str, location: &str, parsed: &mut Parsed) {
+    for kdbx_entry in group.entries.iter() {
+        match (group.recycle_bin, entry(kdbx_entry, path)) {
+            (true, Some(entry)) => parsed.unsupported.push(format!("{}/{}: in the recycle bin", location, entry.name)),
+            (false, Some(entry)) => parsed.entries.push(entry),
+            (_, None) => parsed.unsupported.push(format!("{}: an entry with no title, url or username to name it by", location))
+        }
+    }
+    for sub in group.groups.iter() {
+        let sub_path = match path.is_empty() {
+            true => tag_part(&sub.name),
+            false => format!("{}{}{}", path, SEPARATOR, tag_part(&sub.name))
+        };
+        add_group(sub, &sub_path, &format!("{}/{}", location, sub.name), parsed);
+    }
+}
+
+/// Read a KDBX 4 database opened with password
+pub fn read<R: Read>(reader: &mut R, password: &str) -> Result<Parsed, APError> {
+    let db = kdbx::read(reader, password)?;
+    let mut parsed = Parsed::default();
+    add_group(&db.root, "", &db.root.name, &mut parsed);
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_sample() {
+        let sample = include_bytes!("../../testdata/sample-aes-argon2id.kdbx");
+        let parsed = read(&mut &sample[..], "autopass").unwrap();
+        let names: Vec<&str> = parsed.entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, vec!["Bank", "AWS", "mail.example.com"]);
+        assert_eq!(parsed.unsupported, vec!["Sample: an entry with no title, url or username to name it by",
+                                            "Sample/Recycle Bin/Old: in the recycle bin"]);
+
+        let aws = &parsed.entries[1];
+        assert_eq!(aws.password.as_deref(), Some("aws-pass"));
+        assert_eq!(aws.tags, vec!["Work/Cloud", "cloud", "billing"]);
+        assert_eq!(aws.get_kv("username"), Some("root"));
+        assert_eq!(aws.get_kv("Account"), Some("1234"));
+        assert!(aws.kvs.contains(&("otp".to_owned(), "otpauth://totp/aws?secret=JBSWY3DPEHPK3PXP".to_owned(), Visibility::Concealed)));
+        assert_eq!(aws.attachments.len(), 1);
+        assert_eq!(parsed.entries[0].tags, Vec::<String>::new());
+    }
+}
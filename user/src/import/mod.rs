@@ -0,0 +1,266 @@
+use std::{collections::HashSet, fmt, str::FromStr};
+
+use crate::api::{self, APError};
+use crate::config;
+use crate::lock;
+use crate::spec::Visibility;
+
+/*
+ * Services brought in from other password managers. Each format has a reader turning
+ * its export into `ImportEntry`s, and `import` adds those to the vault keeping the
+ * passwords they had, the same as `new --set-password` would, tagged `imported`.
+ *
+ * Names already in the vault, or taken by an earlier entry of the same import, are
+ * skipped, overwritten or added under a new name. Overwriting moves the one there to
+ * the trash first, and back if the new one can't be added. An entry is added whole,
+ * kvs, visibilities and attachments, or not at all. The whole import runs under one
+ * lock, so `undo` takes it all back.
+ */
+
+pub mod bitwarden;
+pub mod csv;
+pub mod kdbx;
+pub mod onepux;
+
+/// Tag every imported service gets, so they can be found and checked over
+pub const IMPORTED_TAG: &str = "imported";
+
+/// A service read from another password manager's export
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ImportEntry {
+    pub name: String,
+    /// None has one generated as `new` would
+    pub password: Option<String>,
+    pub kvs: Vec<(String, String, Visibility)>,
+    pub tags: Vec<String>,
+    /// Name and contents
+    pub attachments: Vec<(String, Vec<u8>)>
+}
+
+impl ImportEntry {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    pub fn get_kv(&self, key: &str) -> Option<&str> {
+        self.kvs.iter().find(|(k, _, _)| k == key).map(|(_, v, _)| v.as_str())
+    }
+
+    /// Add a kv unless value is empty. A key already used gets a number after it.
+    pub fn add_kv(&mut self, key: &str, value: &str, visibility: Visibility) {
+        if value.is_empty() {
+            return;
+        }
+        let mut unique = key.to_owned();
+        let mut n = 2;
+        while self.get_kv(&unique).is_some() {
+            unique = format!("{} {}", key, n);
+            n += 1;
+        }
+        self.kvs.push((unique, value.to_owned(), visibility));
+    }
+
+    pub fn add_tag(&mut self, tag: &str) {
+        if !tag.is_empty() && !self.tags.iter().any(|t| t == tag) {
+            self.tags.push(tag.to_owned());
+        }
+    }
+}
+
+/// What an export held: the entries that can be imported, and a note on each item
+/// that couldn't be so the user knows what was left behind
+#[derive(Debug, Default)]
+pub struct Parsed {
+    pub entries: Vec<ImportEntry>,
+    pub unsupported: Vec<String>
+}
+
+/// What to do with an entry whose name is already taken
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum OnDuplicate {
+    Skip,
+    /// Move the one there to the trash and add this one
+    Overwrite,
+    /// Add this one as `NAME (2)`, `NAME (3)` and so on
+    Rename
+}
+
+impl FromStr for OnDuplicate {
+    type Err = APError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(Self::Skip),
+            "overwrite" => Ok(Self::Overwrite),
+            "rename" => Ok(Self::Rename),
+            _ => Err(APError::Import(format!("Unknown duplicate handling {}, expected skip, overwrite or rename", s)))
+        }
+    }
+}
+
+/// What happens, or happened, to an entry
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Added,
+    Overwritten,
+    /// Added under this name since its own was taken
+    Renamed(String),
+    Skipped
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Added => f.write_str("add"),
+            Self::Overwritten => f.write_str("overwrite"),
+            Self::Renamed(name) => write!(f, "add as {}", name),
+            Self::Skipped => f.write_str("skip")
+        }
+    }
+}
+
+/// Each entry's name and how importing it went
+pub type ImportResults = Vec<(String, Result<Outcome, APError>)>;
+
+fn free_name(name: &str, taken: &HashSet<String>) -> String {
+    (2..).map(|n| format!("{} ({})", name, n))
+        .find(|candidate| !taken.contains(candidate))
+        .unwrap()
+}
+
+/// Decide what happens to each entry given the names already taken
+fn plan(mut taken: HashSet<String>, entries: &[ImportEntry], on_duplicate: OnDuplicate) -> Vec<Outcome> {
+    let mut outcomes = vec![];
+    for entry in entries {
+        let outcome = match (taken.contains(&entry.name), on_duplicate) {
+            (false, _) => Outcome::Added,
+            (true, OnDuplicate::Skip) => Outcome::Skipped,
+            (true, OnDuplicate::Overwrite) => Outcome::Overwritten,
+            (true, OnDuplicate::Rename) => Outcome::Renamed(free_name(&entry.name, &taken))
+        };
+        match &outcome {
+            Outcome::Renamed(name) => taken.insert(name.clone()),
+            _ => taken.insert(entry.name.clone())
+        };
+        outcomes.push(outcome);
+    }
+    outcomes
+}
+
+fn taken_names(pass: &str) -> Result<HashSet<String>, APError> {
+    Ok(api::list(pass, &[])?.into_iter().collect())
+}
+
+/// What `import` would do with each entry, changing nothing
+pub fn preview(pass: &str, entries: &[ImportEntry], on_duplicate: OnDuplicate) -> Result<Vec<Outcome>, APError> {
+    let _lock = lock::shared()?;
+    api::load_id(pass)?;
+    Ok(plan(taken_names(pass)?, entries, on_duplicate))
+}
+
+fn add(pass: &str, name: &str, entry: &ImportEntry) -> Result<(), APError> {
+    let defaults = config::current();
+    let kvs: Vec<(&str, &str, Visibility)> = entry.kvs.iter().map(|(k, v, visibility)| (k.as_str(), v.as_str(), *visibility)).collect();
+    let mut tags: Vec<&str> = entry.tags.iter().map(|t| t.as_str()).collect();
+    if !tags.contains(&IMPORTED_TAG) {
+        tags.push(IMPORTED_TAG);
+    }
+    api::new_with_attachments(name, pass, &defaults.text_mode, defaults.length, &kvs, &tags,
+                              entry.password.as_deref(), &entry.attachments)?;
+    Ok(())
+}
+
+/// Add entries to the vault. An entry failing doesn't stop the rest, each gets its own result.
+pub fn import(pass: &str, entries: &[ImportEntry], on_duplicate: OnDuplicate) -> Result<ImportResults, APError> {
+    let _lock = lock::exclusive()?;
+    api::load_id(pass)?;
+    let outcomes = plan(taken_names(pass)?, entries, on_duplicate);
+    Ok(entries.iter().zip(outcomes).map(|(entry, outcome)| {
+        let result = match &outcome {
+            Outcome::Added => add(pass, &entry.name, entry),
+            Outcome::Overwritten => api::delete(&entry.name, pass).and_then(|_| {
+                add(pass, &entry.name, entry).inspect_err(|_| {
+                    let _ = api::restore(&entry.name, pass);
+                })
+            }),
+            Outcome::Renamed(name) => add(pass, name, entry),
+            Outcome::Skipped => Ok(())
+        };
+        (entry.name.clone(), result.map(|_| outcome))
+    }).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plan() {
+        let taken: HashSet<String> = ["mail", "mail (2)"].iter().map(|s| s.to_string()).collect();
+        let entries = vec![ImportEntry::new("mail"), ImportEntry::new("bank"), ImportEntry::new("bank")];
+
+        assert_eq!(plan(taken.clone(), &entries, OnDuplicate::Skip),
+                   vec![Outcome::Skipped, Outcome::Added, Outcome::Skipped]);
+        assert_eq!(plan(taken.clone(), &entries, OnDuplicate::Overwrite),
+                   vec![Outcome::Overwritten, Outcome::Added, Outcome::Overwritten]);
+        assert_eq!(plan(taken, &entries, OnDuplicate::Rename),
+                   vec![Outcome::Renamed("mail (3)".to_owned()), Outcome::Added, Outcome::Renamed("bank (2)".to_owned())]);
+    }
+
+    #[test]
+    fn test_add_kv() {
+        let mut entry = ImportEntry::new("mail");
+        entry.add_kv("url", "https://a", Visibility::Plain);
+        entry.add_kv("url", "https://b", Visibility::Plain);
+        entry.add_kv("notes", "", Visibility::Plain);
+        assert_eq!(entry.get_kv("url"), Some("https://a"));
+        assert_eq!(entry.get_kv("url 2"), Some("https://b"));
+        assert_eq!(entry.get_kv("notes"), None);
+    }
+
+    #[test]
+    fn test_import() {
+        use crate::snapshot;
+        use crate::testvault::{TestVault, PASS};
+
+        let vault = TestVault::new("import");
+        let files = || std::fs::read_dir(&vault.dir).unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().is_file())
+            .count();
+        let mut card = ImportEntry::new("card");
+        card.password = Some("pw".to_owned());
+        card.add_kv("number", "4111", Visibility::Concealed);
+        card.add_kv("user", "me", Visibility::Plain);
+        card.attachments.push(("scan.png".to_owned(), b"scan".to_vec()));
+        // The second attachment can't be written over the first
+        let mut broken = card.clone();
+        broken.name = "broken".to_owned();
+        broken.attachments.push(("scan.png".to_owned(), b"again".to_vec()));
+        let before = files();
+
+        let results = import(PASS, &[card.clone(), broken], OnDuplicate::Skip).unwrap();
+        assert_eq!(results[0].1.as_ref().unwrap(), &Outcome::Added);
+        assert!(matches!(results[1].1, Err(APError::Exists(_))));
+        // Nothing of the failed one is left, not even its first attachment
+        assert!(!api::exists(PASS, "broken").unwrap());
+        assert_eq!(files(), before + 2);
+
+        let service = api::get_all("card", PASS).unwrap();
+        assert_eq!(service.get_kv("number"), Some(("4111", Visibility::Concealed)));
+        assert_eq!(service.get_kv("user"), Some(("me", Visibility::Plain)));
+        assert_eq!(api::list_attachments("card", PASS).unwrap().len(), 1);
+        // Saved once, so there's the one snapshot of it
+        assert_eq!(snapshot::list(PASS, Some("card")).unwrap().len(), 1);
+
+        // Overwriting with an entry that can't be added leaves the one there
+        let mut twice = card;
+        twice.attachments.push(("scan.png".to_owned(), b"again".to_vec()));
+        let results = import(PASS, &[twice], OnDuplicate::Overwrite).unwrap();
+        assert!(results[0].1.is_err());
+        assert_eq!(api::get_all("card", PASS).unwrap().get_kv("user"), Some(("me", Visibility::Plain)));
+        assert_eq!(api::list_attachments("card", PASS).unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,293 @@
+use std::io::{Read, Seek};
+
+use serde_json::Value;
+use time::OffsetDateTime;
+use zip::ZipArchive;
+
+use super::{csv::host_of, ImportEntry, Parsed};
+use crate::api::APError;
+use crate::spec::Visibility;
+use crate::tagtree::SEPARATOR;
+
+/*
+ * 1Password's 1PUX export, a zip of `export.data` describing every account, vault and
+ * item as JSON, and `files/` holding documents and file fields. Each item becomes a
+ * service named by its title. A login's username and password come from its login
+ * fields, its urls become kvs, and every field of every section becomes a kv named by
+ * its label, concealed fields and TOTP seeds concealed. Files come along as
+ * attachments. Tags stay tags, and the vault an item's in is a tag too when there's
+ * more than one. References to other items, passkeys, form fields that aren't text
+ * and password history have nowhere to go and are reported.
+ */
+
+const DATA: &str = "export.data";
+const FILES: &str = "files/";
+
+fn error(msg: &str) -> APError {
+    APError::Import(msg.to_owned())
+}
+
+/// A string member of value, trimmed, empty if it's missing or not a string
+fn text<'a>(value: &'a Value, key: &str) -> &'a str {
+    value.get(key).and_then(Value::as_str).map(|s| s.trim()).unwrap_or("")
+}
+
+fn items<'a>(value: &'a Value, key: &str) -> &'a [Value] {
+    value.get(key).and_then(Value::as_array).map(|a| a.as_slice()).unwrap_or(&[])
+}
+
+struct Reader<R: Read + Seek> {
+    archive: ZipArchive<R>
+}
+
+impl<R: Read + Seek> Reader<R> {
+    /// Contents of the file with document id, saved as `files/ID__NAME`
+    fn file(&mut self, id: &str) -> Option<Vec<u8>> {
+        let prefix = format!("{}{}__", FILES, id);
+        let path = self.archive.file_names().find(|n| n.starts_with(&prefix))?.to_owned();
+        let mut data = vec![];
+        self.archive.by_name(&path).ok()?.read_to_end(&mut data).ok()?;
+        Some(data)
+    }
+
+    fn attach(&mut self, entry: &mut ImportEntry, file: &Value, unsupported: &mut Vec<String>) {
+        let name = text(file, "fileName");
+        let id = text(file, "documentId");
+        if id.is_empty() {
+            unsupported.push(format!("{}: file {}, which has no document id", entry.name, name));
+            return;
+        }
+        match self.file(id) {
+            Some(data) => entry.attachments.push((name.to_owned(), data)),
+            None => unsupported.push(format!("{}: file {}, which isn't in the export", entry.name, name))
+        }
+    }
+
+    /// Add a section field as a kv named label
+    fn add_field(&mut self, entry: &mut ImportEntry, label: &str, value: &Value, unsupported: &mut Vec<String>) {
+        // Each value is an object with one member, named for the kind of field
+        let (kind, value) = match value.as_object().and_then(|o| o.iter().next()) {
+            Some(kind) => kind,
+            None => return
+        };
+        match (kind.as_str(), value) {
+            ("concealed" | "creditCardNumber" | "totp", Value::String(value)) =>
+                entry.add_kv(label, value.trim(), Visibility::Concealed),
+            ("date", Value::Number(time)) => {
+                let date = time.as_i64().and_then(|t| OffsetDateTime::from_unix_timestamp(t).ok());
+                entry.add_kv(label, &date.map(|d| d.date().to_string()).unwrap_or_default(), Visibility::Plain);
+            }
+            // Given as YYYYMM
+            ("monthYear", Value::Number(month)) => {
+                let month = month.as_u64().unwrap_or(0);
+                entry.add_kv(label, &format!("{:02}/{}", month % 100, month / 100), Visibility::Plain);
+            }
+            ("email", Value::Object(_)) => entry.add_kv(label, text(value, "email_address"), Visibility::Plain),
+            ("address", Value::Object(_)) => {
+                let parts: Vec<&str> = ["street", "city", "state", "zip", "country"].iter()
+                    .map(|part| text(value, part))
+                    .filter(|part| !part.is_empty())
+                    .collect();
+                entry.add_kv(label, &parts.join(", "), Visibility::Plain);
+            }
+            ("sshKey", Value::Object(_)) => {
+                let metadata = value.get("metadata").unwrap_or(&Value::Null);
+                entry.add_kv(label, text(value, "privateKey"), Visibility::Concealed);
+                entry.add_kv(&format!("{} public key", label), text(metadata, "publicKey"), Visibility::Plain);
+                entry.add_kv(&format!("{} fingerprint", label), text(metadata, "fingerprint"), Visibility::Plain);
+            }
+            ("file", Value::Object(_)) => self.attach(entry, value, unsupported),
+            ("reference", _) => unsupported.push(format!("{}: {} refers to another item", entry.name, label)),
+            // Strings, urls, phone numbers, menus and the like
+            (_, Value::String(value)) => entry.add_kv(label, value.trim(), Visibility::Plain),
+            (kind, _) => unsupported.push(format!("{}: {} is a {} field", entry.name, label, kind))
+        }
+    }
+
+    /// The item as a service tagged vault, None if there's nothing to name it by
+    fn entry(&mut self, item: &Value, vault: Option<&str>, unsupported: &mut Vec<String>) -> Option<ImportEntry> {
+        let overview = item.get("overview").unwrap_or(&Value::Null);
+        let details = item.get("details").unwrap_or(&Value::Null);
+        let login_fields = items(details, "loginFields");
+        let designated = |designation: &str| login_fields.iter().find(|f| text(f, "designation") == designation);
+        let username = designated("username").map(|f| text(f, "value")).unwrap_or("");
+        let url = text(overview, "url");
+        let name = Some(text(overview, "title")).filter(|t| !t.is_empty()).map(|t| t.to_owned())
+            .or_else(|| host_of(url))
+            .or_else(|| Some(username).filter(|u| !u.is_empty()).map(|u| u.to_owned()))?;
+
+        let mut entry = ImportEntry::new(&name);
+        // Logins have it as a login field, password items as a detail. Not trimmed,
+        // spaces can be part of a password.
+        entry.password = designated("password").and_then(|f| f.get("value"))
+            .or_else(|| details.get("password"))
+            .and_then(Value::as_str)
+            .filter(|p| !p.is_empty())
+            .map(|p| p.to_owned());
+        entry.add_kv("username", username, Visibility::Plain);
+        match items(overview, "urls") {
+            [] => entry.add_kv("url", url, Visibility::Plain),
+            urls => {
+                for url in urls {
+                    entry.add_kv("url", text(url, "url"), Visibility::Plain);
+                }
+            }
+        }
+        entry.add_kv("notes", text(details, "notesPlain"), Visibility::Plain);
+        for field in login_fields.iter().filter(|f| !matches!(text(f, "designation"), "username" | "password")) {
+            let key = Some(text(field, "name")).filter(|k| !k.is_empty()).unwrap_or("field");
+            match text(field, "fieldType") {
+                "P" => entry.add_kv(key, text(field, "value"), Visibility::Concealed),
+                "T" | "E" | "U" | "N" | "A" | "I" => entry.add_kv(key, text(field, "value"), Visibility::Plain),
+                _ if text(field, "value").is_empty() => {}
+                _ => unsupported.push(format!("{}: form field {}", name, key))
+            }
+        }
+        for section in items(details, "sections") {
+            for field in items(section, "fields") {
+                let label = [text(field, "title"), text(field, "id")].iter()
+                    .find(|l| !l.is_empty())
+                    .copied()
+                    .unwrap_or("field");
+                self.add_field(&mut entry, label, field.get("value").unwrap_or(&Value::Null), unsupported);
+            }
+        }
+        if let Some(document) = details.get("documentAttributes") {
+            self.attach(&mut entry, document, unsupported);
+        }
+
+        for tag in items(overview, "tags").iter().filter_map(Value::as_str) {
+            entry.add_tag(tag.trim());
+        }
+        if let Some(vault) = vault {
+            entry.add_tag(&vault.replace(SEPARATOR, "-"));
+        }
+        if text(item, "state") == "archived" {
+            entry.add_tag("archived");
+        }
+
+        if details.get("passkey").is_some_and(|p| !p.is_null()) {
+            unsupported.push(format!("{}: passkey", name));
+        }
+        let history = items(details, "passwordHistory").len();
+        if history > 0 {
+            unsupported.push(format!("{}: password history of {}", name, history));
+        }
+        Some(entry)
+    }
+}
+
+/// Read a 1PUX export
+pub fn read<R: Read + Seek>(reader: R) -> Result<Parsed, APError> {
+    let mut reader = Reader {
+        archive: ZipArchive::new(reader).map_err(|e| error(&format!("Bad 1PUX file: {}", e)))?
+    };
+    let mut data = String::new();
+    reader.archive.by_name(DATA)
+        .map_err(|_| error("Bad 1PUX file: there's no export.data"))?
+        .read_to_string(&mut data)?;
+    let export: Value = serde_json::from_str(&data).map_err(|e| error(&format!("Bad export.data: {}", e)))?;
+
+    let vaults: Vec<&Value> = items(&export, "accounts").iter().flat_map(|a| items(a, "vaults")).collect();
+    let mut parsed = Parsed::default();
+    for vault in vaults.iter() {
+        let attrs = vault.get("attrs").unwrap_or(&Value::Null);
+        let vault_name = text(attrs, "name");
+        // Everything would have the same tag with just the one vault
+        let tag = match vaults.len() {
+            1 => None,
+            _ => Some(vault_name).filter(|v| !v.is_empty())
+        };
+        for (idx, item) in items(vault, "items").iter().enumerate() {
+            let mut unsupported = vec![];
+            match reader.entry(item, tag, &mut unsupported) {
+                Some(entry) if text(item, "state") == "trashed" => {
+                    parsed.unsupported.push(format!("{}: in the trash", entry.name));
+                    continue;
+                }
+                Some(entry) => parsed.entries.push(entry),
+                None => parsed.unsupported.push(format!("{} item {}: no title, url or username to name it by",
+                                                        vault_name, idx + 1))
+            }
+            parsed.unsupported.extend(unsupported);
+        }
+    }
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use zip::write::{FileOptions, ZipWriter};
+
+    use super::*;
+
+    const DATA: &str = r#"{"accounts": [{"attrs": {"accountName": "Me"}, "vaults": [
+      {"attrs": {"name": "Personal"}, "items": [
+        {"uuid": "a", "state": "active", "categoryUuid": "001",
+         "details": {
+           "loginFields": [{"value": "root", "name": "username", "fieldType": "T", "designation": "username"},
+                           {"value": "aws-pass", "name": "password", "fieldType": "P", "designation": "password"},
+                           {"value": "✓", "name": "remember", "fieldType": "C"}],
+           "notesPlain": "root account",
+           "sections": [{"title": "", "fields": [
+             {"title": "one-time password", "id": "TOTP_1", "value": {"totp": "otpauth://totp/aws?secret=JBSWY3DPEHPK3PXP"}},
+             {"title": "account", "id": "x", "value": {"string": "1234"}},
+             {"title": "pin", "id": "y", "value": {"concealed": "0000"}},
+             {"title": "expires", "id": "z", "value": {"monthYear": 203004}},
+             {"title": "key", "id": "k", "value": {"file": {"fileName": "key.pem", "documentId": "doc1"}}},
+             {"title": "cert", "id": "c", "value": {"file": {"fileName": "cert.pem", "documentId": "doc"}}},
+             {"title": "blank", "id": "n", "value": {"file": {"fileName": "blank.txt"}}},
+             {"title": "backup", "id": "b", "value": {"reference": "other"}}]}],
+           "passwordHistory": [{"value": "old", "time": 1}]},
+         "overview": {"title": "AWS", "url": "https://aws.amazon.com",
+                      "urls": [{"label": "", "url": "https://aws.amazon.com"}, {"label": "", "url": "https://console.aws.amazon.com"}],
+                      "tags": ["Work/Cloud"]}},
+        {"uuid": "b", "state": "archived", "categoryUuid": "003",
+         "details": {"notesPlain": "hunter2"}, "overview": {"title": "Wifi"}}]},
+      {"attrs": {"name": "Shared"}, "items": [
+        {"uuid": "c", "categoryUuid": "005", "details": {"password": "pw"}, "overview": {"title": ""}},
+        {"uuid": "d", "categoryUuid": "005", "details": {"password": "pw"}, "overview": {"title": "Router"}}]}
+    ]}]}"#;
+
+    fn sample() -> Vec<u8> {
+        let mut zip = ZipWriter::new(Cursor::new(vec![]));
+        zip.start_file("export.attributes", FileOptions::default()).unwrap();
+        zip.write_all(br#"{"version": 3}"#).unwrap();
+        zip.start_file("export.data", FileOptions::default()).unwrap();
+        zip.write_all(DATA.as_bytes()).unwrap();
+        zip.start_file("files/doc1__key.pem", FileOptions::default()).unwrap();
+        zip.write_all(b"-----BEGIN KEY-----").unwrap();
+        zip.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_read() {
+        let parsed = read(Cursor::new(sample())).unwrap();
+        let names: Vec<&str> = parsed.entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, vec!["AWS", "Wifi", "Router"]);
+        // doc isn't doc1 just because it starts the same
+        assert_eq!(parsed.unsupported, vec!["AWS: form field remember", "AWS: file cert.pem, which isn't in the export",
+                                            "AWS: file blank.txt, which has no document id",
+                                            "AWS: backup refers to another item",
+                                            "AWS: password history of 1",
+                                            "Shared item 1: no title, url or username to name it by"]);
+
+        let aws = &parsed.entries[0];
+        assert_eq!(aws.password.as_deref(), Some("aws-pass"));
+        assert_eq!(aws.tags, vec!["Work/Cloud", "Personal"]);
+        assert_eq!(aws.get_kv("username"), Some("root"));
+        assert_eq!(aws.get_kv("url 2"), Some("https://console.aws.amazon.com"));
+        assert_eq!(aws.get_kv("account"), Some("1234"));
+        assert_eq!(aws.get_kv("expires"), Some("04/2030"));
+        assert!(aws.kvs.contains(&("pin".to_owned(), "0000".to_owned(), Visibility::Concealed)));
+        assert!(aws.kvs.contains(&("one-time password".to_owned(),
+                                   "otpauth://totp/aws?secret=JBSWY3DPEHPK3PXP".to_owned(), Visibility::Concealed)));
+        assert_eq!(aws.attachments, vec![("key.pem".to_owned(), b"-----BEGIN KEY-----".to_vec())]);
+
+        assert_eq!(parsed.entries[1].tags, vec!["Personal", "archived"]);
+        assert_eq!(parsed.entries[1].get_kv("notes"), Some("hunter2"));
+        assert_eq!(parsed.entries[2].password.as_deref(), Some("pw"));
+    }
+}
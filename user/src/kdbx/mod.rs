@@ -0,0 +1,580 @@
+use std::convert::TryInto;
+use std::io::{Read, Write};
+
+use aes::cipher::{generic_array::GenericArray, BlockEncrypt, BlockDecryptMut, BlockEncryptMut, KeyInit, KeyIvInit, StreamCipher, block_padding::Pkcs7};
+use aes::Aes256;
+use chacha20::ChaCha20;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::{Digest, Sha256, Sha512};
+
+use crate::api::APError;
+
+mod xml;
+
+/*
+ * KeePass keeps its databases in KDBX files. Only version 4 is read and written, older
+ * files have to be saved again by KeePass first.
+ *
+ * Layout:
+ *   signatures and version | header fields | SHA-256 of the header | HMAC of the header
+ *   | payload split into blocks, each with its HMAC and length
+ *
+ * The password is hashed into a composite key and stretched by the header's KDF
+ * (Argon2 or AES-KDF). With the header's master seed that gives the key the payload is
+ * encrypted with (AES-256-CBC or ChaCha20) and the key each block's HMAC is keyed
+ * from. The payload is optionally gzipped, and starts with an inner header holding
+ * attachments and the key to the inner stream. Protected values in the XML after it
+ * are XORed with that stream in the order they appear.
+ */
+
+const SIGNATURE1: u32 = 0x9AA2D903;
+const SIGNATURE2: u32 = 0xB54BFB67;
+const VERSION_MAJOR: u16 = 4;
+
+const CIPHER_AES256: [u8; 16] = [0x31, 0xc1, 0xf2, 0xe6, 0xbf, 0x71, 0x43, 0x50, 0xbe, 0x58, 0x05, 0x21, 0x6a, 0xfc, 0x5a, 0xff];
+const CIPHER_CHACHA20: [u8; 16] = [0xd6, 0x03, 0x8a, 0x2b, 0x8b, 0x6f, 0x4c, 0xb5, 0xa5, 0x24, 0x33, 0x9a, 0x31, 0xdb, 0xb5, 0x9a];
+const KDF_AES: [u8; 16] = [0xc9, 0xd9, 0xf3, 0x9a, 0x62, 0x8a, 0x44, 0x60, 0xbf, 0x74, 0x0d, 0x08, 0xc1, 0x8a, 0x4f, 0xea];
+const KDF_ARGON2D: [u8; 16] = [0xef, 0x63, 0x6d, 0xdf, 0x8c, 0x29, 0x44, 0x4b, 0x91, 0xf7, 0xa9, 0xa4, 0x03, 0xe3, 0x0a, 0x0c];
+const KDF_ARGON2ID: [u8; 16] = [0x9e, 0x29, 0x8b, 0x19, 0x56, 0xdb, 0x47, 0x73, 0xb2, 0x3d, 0xfc, 0x3e, 0xc6, 0xf0, 0xa1, 0xe6];
+
+// Outer header fields
+const END_OF_HEADER: u8 = 0;
+const CIPHER_ID: u8 = 2;
+const COMPRESSION: u8 = 3;
+const MASTER_SEED: u8 = 4;
+const ENCRYPTION_IV: u8 = 7;
+const KDF_PARAMETERS: u8 = 11;
+
+// Inner header fields
+const INNER_STREAM_ID: u8 = 1;
+const INNER_STREAM_KEY: u8 = 2;
+const INNER_BINARY: u8 = 3;
+const INNER_STREAM_CHACHA20: u32 = 3;
+
+const BLOCK_SIZE: usize = 1024 * 1024;
+
+/// Standard fields every entry has
+pub const TITLE: &str = "Title";
+pub const USERNAME: &str = "UserName";
+pub const PASSWORD: &str = "Password";
+pub const URL: &str = "URL";
+pub const NOTES: &str = "Notes";
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Field {
+    pub key: String,
+    pub value: String,
+    /// Kept encrypted in the file, KeePass hides it
+    pub protected: bool
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Entry {
+    pub fields: Vec<Field>,
+    pub tags: Vec<String>,
+    pub attachments: Vec<(String, Vec<u8>)>,
+    /// Seconds since the unix epoch
+    pub created: Option<u64>,
+    pub modified: Option<u64>
+}
+
+impl Entry {
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.fields.iter().find(|f| f.key == key).map(|f| f.value.as_str())
+    }
+
+    pub fn set(&mut self, key: &str, value: &str, protected: bool) {
+        self.fields.retain(|f| f.key != key);
+        self.fields.push(Field { key: key.to_owned(), value: value.to_owned(), protected });
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Group {
+    pub name: String,
+    pub groups: Vec<Group>,
+    pub entries: Vec<Entry>,
+    /// Where KeePass moves deleted entries
+    pub recycle_bin: bool
+}
+
+impl Group {
+    /// The group at path under this one, made if it isn't there
+    pub fn group_mut<S: AsRef<str>>(&mut self, path: &[S]) -> &mut Group {
+        match path.split_first() {
+            None => self,
+            Some((name, rest)) => {
+                let idx = match self.groups.iter().position(|g| g.name == name.as_ref()) {
+                    Some(idx) => idx,
+                    None => {
+                        self.groups.push(Group { name: name.as_ref().to_owned(), ..Default::default() });
+                        self.groups.len() - 1
+                    }
+                };
+                self.groups[idx].group_mut(rest)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Database {
+    pub name: String,
+    /// Holds everything else, KeePass names it after the database
+    pub root: Group
+}
+
+/// How hard the password is stretched when writing, with Argon2d
+#[derive(Debug, Clone, Copy)]
+pub struct Kdf {
+    /// Bytes
+    pub memory: u64,
+    pub iterations: u64,
+    pub parallelism: u32
+}
+
+impl Default for Kdf {
+    /// What KeePass uses for new databases
+    fn default() -> Self {
+        Self {
+            memory: 64 * 1024 * 1024,
+            iterations: 2,
+            parallelism: 2
+        }
+    }
+}
+
+fn error(msg: &str) -> APError {
+    APError::Kdbx(msg.to_owned())
+}
+
+/// A value in the KDF parameters
+#[derive(Debug, Clone, PartialEq)]
+enum Variant {
+    U32(u32),
+    U64(u64),
+    Bool(bool),
+    I32(i32),
+    I64(i64),
+    Str(String),
+    Bytes(Vec<u8>)
+}
+
+struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], APError> {
+        if self.data.len() - self.pos < len {
+            return Err(error("File is truncated"));
+        }
+        self.pos += len;
+        Ok(&self.data[self.pos - len..self.pos])
+    }
+
+    fn u8(&mut self) -> Result<u8, APError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, APError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, APError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    /// A field of a header, its id and data
+    fn field(&mut self) -> Result<(u8, &'a [u8]), APError> {
+        let id = self.u8()?;
+        let len = self.u32()? as usize;
+        Ok((id, self.take(len)?))
+    }
+}
+
+fn fixed<const N: usize>(name: &str, value: &[u8]) -> Result<[u8; N], APError> {
+    value.try_into().map_err(|_| error(&format!("KDF parameter {} isn't {} bytes", name, N)))
+}
+
+fn parse_variants(data: &[u8]) -> Result<Vec<(String, Variant)>, APError> {
+    let mut cursor = Cursor { data, pos: 0 };
+    if cursor.u16()? >> 8 != 1 {
+        return Err(error("Unknown KDF parameters version"));
+    }
+    let mut variants = vec![];
+    loop {
+        let kind = cursor.u8()?;
+        if kind == 0 {
+            return Ok(variants);
+        }
+        let len = cursor.u32()? as usize;
+        let name = String::from_utf8_lossy(cursor.take(len)?).into_owned();
+        let len = cursor.u32()? as usize;
+        let value = cursor.take(len)?;
+        let variant = match kind {
+            0x04 => Variant::U32(u32::from_le_bytes(fixed(&name, value)?)),
+            0x05 => Variant::U64(u64::from_le_bytes(fixed(&name, value)?)),
+            0x08 => Variant::Bool(value.first() == Some(&1)),
+            0x0C => Variant::I32(i32::from_le_bytes(fixed(&name, value)?)),
+            0x0D => Variant::I64(i64::from_le_bytes(fixed(&name, value)?)),
+            0x18 => Variant::Str(String::from_utf8_lossy(value).into_owned()),
+            0x42 => Variant::Bytes(value.to_vec()),
+            _ => return Err(error(&format!("Unknown KDF parameter type {}", kind)))
+        };
+        variants.push((name, variant));
+    }
+}
+
+fn write_variants(variants: &[(&str, Variant)]) -> Vec<u8> {
+    let mut out = 0x0100u16.to_le_bytes().to_vec();
+    for (name, variant) in variants {
+        let (kind, value) = match variant {
+            Variant::U32(v) => (0x04, v.to_le_bytes().to_vec()),
+            Variant::U64(v) => (0x05, v.to_le_bytes().to_vec()),
+            Variant::Bool(v) => (0x08, vec![*v as u8]),
+            Variant::I32(v) => (0x0C, v.to_le_bytes().to_vec()),
+            Variant::I64(v) => (0x0D, v.to_le_bytes().to_vec()),
+            Variant::Str(v) => (0x18, v.as_bytes().to_vec()),
+            Variant::Bytes(v) => (0x42, v.clone())
+        };
+        out.push(kind);
+        out.extend((name.len() as u32).to_le_bytes());
+        out.extend(name.as_bytes());
+        out.extend((value.len() as u32).to_le_bytes());
+        out.extend(value);
+    }
+    out.push(0);
+    out
+}
+
+fn write_field(out: &mut Vec<u8>, id: u8, data: &[u8]) {
+    out.push(id);
+    out.extend((data.len() as u32).to_le_bytes());
+    out.extend(data);
+}
+
+/// Stretch the composite key as the KDF parameters say
+fn transform_key(composite: &[u8], params: &[(String, Variant)]) -> Result<[u8; 32], APError> {
+    let get = |name: &str| params.iter().find(|(n, _)| n == name).map(|(_, v)| v);
+    let bytes = |name: &str| match get(name) {
+        Some(Variant::Bytes(b)) => Ok(b.as_slice()),
+        _ => Err(error(&format!("KDF parameter {} is missing", name)))
+    };
+    let number = |name: &str| match get(name) {
+        Some(Variant::U32(n)) => Ok(*n as u64),
+        Some(Variant::U64(n)) => Ok(*n),
+        _ => Err(error(&format!("KDF parameter {} is missing", name)))
+    };
+
+    let uuid = bytes("$UUID")?;
+    let mut key = [0u8; 32];
+    if uuid == KDF_AES {
+        let cipher = Aes256::new_from_slice(bytes("S")?).map_err(|_| error("AES-KDF seed isn't 32 bytes"))?;
+        key.copy_from_slice(composite);
+        for half in key.chunks_mut(16) {
+            let block = GenericArray::from_mut_slice(half);
+            for _ in 0..number("R")? {
+                cipher.encrypt_block(block);
+            }
+        }
+        return Ok(Sha256::digest(key).into());
+    }
+
+    let algorithm = match uuid {
+        u if u == KDF_ARGON2D => argon2::Algorithm::Argon2d,
+        u if u == KDF_ARGON2ID => argon2::Algorithm::Argon2id,
+        _ => return Err(error("Unsupported KDF"))
+    };
+    let version = match number("V")? {
+        0x10 => argon2::Version::V0x10,
+        0x13 => argon2::Version::V0x13,
+        v => return Err(error(&format!("Unsupported Argon2 version {:#x}", v)))
+    };
+    let argon_params = argon2::Params::new((number("M")? / 1024) as u32, number("I")? as u32, number("P")? as u32, Some(32))
+        .map_err(|e| error(&format!("Bad Argon2 parameters: {}", e)))?;
+    argon2::Argon2::new(algorithm, version, argon_params)
+        .hash_password_into(composite, bytes("S")?, &mut key)
+        .map_err(|e| error(&format!("Argon2 failed: {}", e)))?;
+    Ok(key)
+}
+
+/// The key each HMAC block is checked with, the header's is block u64::MAX
+fn block_key(hmac_base: &[u8], index: u64) -> Vec<u8> {
+    let mut digest = Sha512::new();
+    digest.update(index.to_le_bytes());
+    digest.update(hmac_base);
+    digest.finalize().to_vec()
+}
+
+fn block_hmac(hmac_base: &[u8], index: u64, data: &[u8]) -> Vec<u8> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&block_key(hmac_base, index)).unwrap();
+    mac.update(&index.to_le_bytes());
+    mac.update(&(data.len() as u32).to_le_bytes());
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn header_hmac(hmac_base: &[u8], header: &[u8]) -> Hmac<Sha256> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&block_key(hmac_base, u64::MAX)).unwrap();
+    mac.update(header);
+    mac
+}
+
+/// The keys for the payload and the HMACs
+fn keys(password: &str, seed: &[u8], params: &[(String, Variant)]) -> Result<([u8; 32], Vec<u8>), APError> {
+    let composite = Sha256::digest(Sha256::digest(password.as_bytes()));
+    let transformed = transform_key(&composite, params)?;
+    let mut digest = Sha256::new();
+    digest.update(seed);
+    digest.update(transformed);
+    let master = digest.finalize().into();
+    let mut digest = Sha512::new();
+    digest.update(seed);
+    digest.update(transformed);
+    digest.update([1u8]);
+    Ok((master, digest.finalize().to_vec()))
+}
+
+/// The inner stream protected values are XORed with
+pub(crate) fn inner_stream(key: &[u8]) -> ChaCha20 {
+    let digest = Sha512::digest(key);
+    ChaCha20::new(GenericArray::from_slice(&digest[..32]), GenericArray::from_slice(&digest[32..44]))
+}
+
+/// Open a KDBX 4 database
+pub fn read<R: Read>(reader: &mut R, password: &str) -> Result<Database, APError> {
+    let mut data = vec![];
+    reader.read_to_end(&mut data)?;
+    let mut cursor = Cursor { data: &data, pos: 0 };
+    if cursor.u32()? != SIGNATURE1 || cursor.u32()? != SIGNATURE2 {
+        return Err(error("Not a KeePass database"));
+    }
+    let minor = cursor.u16()?;
+    let major = cursor.u16()?;
+    if major != VERSION_MAJOR {
+        return Err(error(&format!("Only KDBX 4 databases can be read, this one is {}.{}", major, minor)));
+    }
+
+    let (mut cipher, mut compressed, mut seed, mut iv, mut params) = (None, false, None, None, None);
+    loop {
+        match cursor.field()? {
+            (END_OF_HEADER, _) => break,
+            (CIPHER_ID, id) => cipher = Some(id),
+            (COMPRESSION, flag) => compressed = flag.first() == Some(&1),
+            (MASTER_SEED, s) => seed = Some(s),
+            (ENCRYPTION_IV, i) => iv = Some(i),
+            (KDF_PARAMETERS, p) => params = Some(parse_variants(p)?),
+            _ => {}
+        }
+    }
+    let header = &data[..cursor.pos];
+    let (cipher, seed, iv, params) = match (cipher, seed, iv, params) {
+        (Some(cipher), Some(seed), Some(iv), Some(params)) => (cipher, seed, iv, params),
+        _ => return Err(error("Header is missing fields"))
+    };
+    if cursor.take(32)? != Sha256::digest(header).as_slice() {
+        return Err(error("Header is corrupt"));
+    }
+    let (master, hmac_base) = keys(password, seed, &params)?;
+    header_hmac(&hmac_base, header).verify_slice(cursor.take(32)?).map_err(|_| APError::PasswordIncorrect)?;
+
+    let mut payload = vec![];
+    for index in 0.. {
+        let mac = cursor.take(32)?;
+        let len = cursor.u32()? as usize;
+        let block = cursor.take(len)?;
+        if block_hmac(&hmac_base, index, block) != mac {
+            return Err(error(&format!("Block {} is corrupt", index)));
+        }
+        if block.is_empty() {
+            break;
+        }
+        payload.extend_from_slice(block);
+    }
+
+    let payload = match cipher {
+        c if c == CIPHER_AES256 => cbc::Decryptor::<Aes256>::new_from_slices(&master, iv)
+            .map_err(|_| error("Bad encryption IV"))?
+            .decrypt_padded_vec_mut::<Pkcs7>(&payload)
+            .map_err(|_| APError::CorruptCiphertext)?,
+        c if c == CIPHER_CHACHA20 => {
+            let mut stream = ChaCha20::new_from_slices(&master, iv).map_err(|_| error("Bad encryption IV"))?;
+            stream.apply_keystream(&mut payload);
+            payload
+        }
+        _ => return Err(error("Unsupported cipher, only AES-256 and ChaCha20 can be read"))
+    };
+    let payload = match compressed {
+        true => {
+            let mut out = vec![];
+            GzDecoder::new(payload.as_slice()).read_to_end(&mut out)?;
+            out
+        }
+        false => payload
+    };
+
+    let mut cursor = Cursor { data: &payload, pos: 0 };
+    let (mut stream_key, mut binaries) = (None, vec![]);
+    loop {
+        match cursor.field()? {
+            (END_OF_HEADER, _) => break,
+            (INNER_STREAM_ID, id) if id != INNER_STREAM_CHACHA20.to_le_bytes() => {
+                return Err(error("Unsupported inner stream, only ChaCha20 can be read"));
+            }
+            (INNER_STREAM_KEY, key) => stream_key = Some(key),
+            // The first byte is flags
+            (INNER_BINARY, binary) if !binary.is_empty() => binaries.push(binary[1..].to_vec()),
+            _ => {}
+        }
+    }
+    let stream_key = stream_key.ok_or_else(|| error("Inner header is missing its stream key"))?;
+    let xml = std::str::from_utf8(&payload[cursor.pos..]).map_err(|_| error("Database XML isn't UTF-8"))?;
+    xml::parse(xml, &mut inner_stream(stream_key), &binaries)
+}
+
+fn random<const N: usize>() -> [u8; N] {
+    let mut bytes = [0u8; N];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes
+}
+
+/// Write db as a KDBX 4 database encrypted with AES-256, the password stretched with Argon2d
+pub fn write<W: Write>(writer: &mut W, password: &str, db: &Database, kdf: &Kdf) -> Result<(), APError> {
+    let (seed, iv, salt, stream_key) = (random::<32>(), random::<16>(), random::<32>(), random::<64>());
+    let params = [
+        ("$UUID", Variant::Bytes(KDF_ARGON2D.to_vec())),
+        ("S", Variant::Bytes(salt.to_vec())),
+        ("P", Variant::U32(kdf.parallelism)),
+        ("M", Variant::U64(kdf.memory)),
+        ("I", Variant::U64(kdf.iterations)),
+        ("V", Variant::U32(0x13))
+    ];
+    let mut header = vec![];
+    header.extend(SIGNATURE1.to_le_bytes());
+    header.extend(SIGNATURE2.to_le_bytes());
+    header.extend(0u16.to_le_bytes());
+    header.extend(VERSION_MAJOR.to_le_bytes());
+    write_field(&mut header, CIPHER_ID, &CIPHER_AES256);
+    write_field(&mut header, COMPRESSION, &1u32.to_le_bytes());
+    write_field(&mut header, MASTER_SEED, &seed);
+    write_field(&mut header, ENCRYPTION_IV, &iv);
+    write_field(&mut header, KDF_PARAMETERS, &write_variants(&params));
+    write_field(&mut header, END_OF_HEADER, b"\r\n\r\n");
+
+    let params: Vec<(String, Variant)> = params.iter().map(|(n, v)| (n.to_string(), v.clone())).collect();
+    let (master, hmac_base) = keys(password, &seed, &params)?;
+
+    let mut payload = vec![];
+    write_field(&mut payload, INNER_STREAM_ID, &INNER_STREAM_CHACHA20.to_le_bytes());
+    write_field(&mut payload, INNER_STREAM_KEY, &stream_key);
+    let (xml, binaries) = xml::write(db, &mut inner_stream(&stream_key));
+    for binary in binaries {
+        let mut field = vec![0u8];
+        field.extend(binary);
+        write_field(&mut payload, INNER_BINARY, &field);
+    }
+    write_field(&mut payload, END_OF_HEADER, &[]);
+    payload.extend(xml.as_bytes());
+
+    let mut gz = GzEncoder::new(vec![], Compression::default());
+    gz.write_all(&payload)?;
+    let payload = cbc::Encryptor::<Aes256>::new(GenericArray::from_slice(&master), GenericArray::from_slice(&iv))
+        .encrypt_padded_vec_mut::<Pkcs7>(&gz.finish()?);
+
+    writer.write_all(&header)?;
+    writer.write_all(&Sha256::digest(&header))?;
+    writer.write_all(&header_hmac(&hmac_base, &header).finalize().into_bytes())?;
+    for (index, block) in payload.chunks(BLOCK_SIZE).chain(std::iter::once(&[][..])).enumerate() {
+        writer.write_all(&block_hmac(&hmac_base, index as u64, block))?;
+        writer.write_all(&(block.len() as u32).to_le_bytes())?;
+        writer.write_all(block)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLES: &[&[u8]] = &[
+        include_bytes!("../../testdata/sample-aes-argon2id.kdbx"),
+        include_bytes!("../../testdata/sample-chacha-aeskdf.kdbx")
+    ];
+
+    fn group<'a>(group: &'a Group, name: &str) -> &'a Group {
+        group.groups.iter().find(|g| g.name == name).unwrap()
+    }
+
+    #[test]
+    fn test_read_samples() {
+        for sample in SAMPLES {
+            let db = read(&mut &sample[..], "autopass").unwrap();
+            assert_eq!(db.name, "Sample");
+            assert_eq!(db.root.name, "Sample");
+            assert_eq!(db.root.entries[0].get(TITLE), Some("Bank"));
+            assert_eq!(db.root.entries[0].get(PASSWORD), Some("bank-pass"));
+
+            let aws = &group(group(&db.root, "Work"), "Cloud").entries[0];
+            // Protected values in its history follow it in the inner stream
+            assert_eq!(aws.get(PASSWORD), Some("aws-pass"));
+            assert_eq!(aws.get(NOTES), Some("first line\nsecond line"));
+            assert_eq!(aws.get("otp"), Some("otpauth://totp/aws?secret=JBSWY3DPEHPK3PXP"));
+            assert!(aws.fields.iter().any(|f| f.key == "otp" && f.protected));
+            assert_eq!(aws.tags, vec!["cloud", "billing"]);
+            assert_eq!(aws.attachments, vec![("key.pem".to_owned(), b"-----BEGIN KEY-----\x00\x01binary\xff-----END KEY-----\n".to_vec())]);
+            assert_eq!(aws.created, Some(1700000000));
+            assert_eq!(aws.modified, Some(1700003600));
+
+            let mail = &group(&db.root, "Personal").entries[0];
+            assert_eq!(mail.get(PASSWORD), Some("mail & <pass>"));
+            assert!(group(&db.root, "Recycle Bin").recycle_bin);
+            assert!(!group(&db.root, "Work").recycle_bin);
+        }
+    }
+
+    #[test]
+    fn test_wrong_password() {
+        assert!(matches!(read(&mut &SAMPLES[1][..], "wrong"), Err(APError::PasswordIncorrect)));
+        let mut truncated = SAMPLES[1].to_vec();
+        truncated.truncate(truncated.len() - 40);
+        assert!(matches!(read(&mut truncated.as_slice(), "autopass"), Err(APError::Kdbx(_))));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut db = read(&mut &SAMPLES[1][..], "autopass").unwrap();
+        let entry = db.root.group_mut(&["New", "Nested"]);
+        let mut new = Entry::default();
+        new.set(TITLE, "<&\"quoted\">", false);
+        new.set(PASSWORD, "secret", true);
+        new.created = Some(1600000000);
+        new.modified = Some(1600000001);
+        entry.entries.push(new);
+
+        let mut out = vec![];
+        let kdf = Kdf { memory: 64 * 1024, iterations: 1, parallelism: 1 };
+        write(&mut out, "other", &db, &kdf).unwrap();
+        assert_eq!(read(&mut out.as_slice(), "other").unwrap(), db);
+    }
+
+    #[test]
+    fn test_argon2_version() {
+        let params = |version: u32| vec![
+            ("$UUID".to_owned(), Variant::Bytes(KDF_ARGON2ID.to_vec())),
+            ("S".to_owned(), Variant::Bytes(vec![7; 32])),
+            ("M".to_owned(), Variant::U64(64 * 1024)),
+            ("I".to_owned(), Variant::U64(1)),
+            ("P".to_owned(), Variant::U32(1)),
+            ("V".to_owned(), Variant::U32(version))
+        ];
+        let v10 = transform_key(&[1; 32], &params(0x10)).unwrap();
+        let v13 = transform_key(&[1; 32], &params(0x13)).unwrap();
+        assert_ne!(v10, v13);
+        assert!(matches!(transform_key(&[1; 32], &params(0x14)), Err(APError::Kdbx(_))));
+        assert!(matches!(transform_key(&[1; 32], &params(0)), Err(APError::Kdbx(_))));
+    }
+}
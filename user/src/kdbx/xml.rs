@@ -0,0 +1,252 @@
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chacha20::{cipher::StreamCipher, ChaCha20};
+use roxmltree::{Document, Node};
+
+use super::{error, random, Database, Entry, Field, Group};
+use crate::api::APError;
+
+/*
+ * The XML KeePass keeps inside the payload. Only what the vault has a use for is
+ * read, the rest of the document is ignored, and only that much is written back.
+ * Times are base64 of seconds since 0001-01-01 as a little endian i64.
+ */
+
+/// Seconds from 0001-01-01 to the unix epoch
+const EPOCH_OFFSET: i64 = 62135596800;
+
+fn child<'a, 'i>(node: Node<'a, 'i>, name: &str) -> Option<Node<'a, 'i>> {
+    node.children().find(|c| c.has_tag_name(name))
+}
+
+fn text<'a>(node: Node<'a, '_>, name: &str) -> &'a str {
+    child(node, name).and_then(|c| c.text()).unwrap_or("")
+}
+
+fn parse_time(text: &str) -> Option<u64> {
+    let bytes: [u8; 8] = BASE64.decode(text.trim()).ok()?.try_into().ok()?;
+    u64::try_from(i64::from_le_bytes(bytes) - EPOCH_OFFSET).ok()
+}
+
+fn format_time(time: u64) -> String {
+    BASE64.encode((time as i64 + EPOCH_OFFSET).to_le_bytes())
+}
+
+struct Parser<'a> {
+    /// Protected values already decrypted, by node
+    protected: HashMap<roxmltree::NodeId, String>,
+    binaries: &'a [Vec<u8>],
+    recycle_bin: Option<&'a str>
+}
+
+impl<'a> Parser<'a> {
+    fn entry(&self, node: Node) -> Entry {
+        let mut entry = Entry::default();
+        if let Some(times) = child(node, "Times") {
+            entry.created = parse_time(text(times, "CreationTime"));
+            entry.modified = parse_time(text(times, "LastModificationTime"));
+        }
+        entry.tags = text(node, "Tags")
+            .split([';', ','])
+            .map(|t| t.trim().to_owned())
+            .filter(|t| !t.is_empty())
+            .collect();
+        for string in node.children().filter(|c| c.has_tag_name("String")) {
+            let value = child(string, "Value");
+            let protected = value.and_then(|v| self.protected.get(&v.id()));
+            entry.fields.push(Field {
+                key: text(string, "Key").to_owned(),
+                value: protected.cloned().unwrap_or_else(|| value.and_then(|v| v.text()).unwrap_or("").to_owned()),
+                protected: protected.is_some()
+            });
+        }
+        for binary in node.children().filter(|c| c.has_tag_name("Binary")) {
+            let data = child(binary, "Value")
+                .and_then(|v| v.attribute("Ref"))
+                .and_then(|r| r.parse::<usize>().ok())
+                .and_then(|r| self.binaries.get(r));
+            if let Some(data) = data {
+                entry.attachments.push((text(binary, "Key").to_owned(), data.clone()));
+            }
+        }
+        entry
+    }
+
+    fn group(&self, node: Node) -> Group {
+        Group {
+            name: text(node, "Name").to_owned(),
+            groups: node.children().filter(|c| c.has_tag_name("Group")).map(|g| self.group(g)).collect(),
+            entries: node.children().filter(|c| c.has_tag_name("Entry")).map(|e| self.entry(e)).collect(),
+            recycle_bin: self.recycle_bin.is_some() && self.recycle_bin == Some(text(node, "UUID").trim())
+        }
+    }
+}
+
+pub(super) fn parse(xml: &str, stream: &mut ChaCha20, binaries: &[Vec<u8>]) -> Result<Database, APError> {
+    let doc = Document::parse(xml).map_err(|e| error(&format!("Bad database XML: {}", e)))?;
+    // Protected values share one stream in the order they appear, history included,
+    // so all of them are decrypted before anything is read
+    let mut protected = HashMap::new();
+    for node in doc.descendants().filter(|n| n.has_tag_name("Value") && n.attribute("Protected") == Some("True")) {
+        let mut value = BASE64.decode(node.text().unwrap_or("").trim())
+            .map_err(|_| error("Bad protected value"))?;
+        stream.apply_keystream(&mut value);
+        protected.insert(node.id(), String::from_utf8_lossy(&value).into_owned());
+    }
+
+    let file = doc.root_element();
+    let meta = child(file, "Meta").ok_or_else(|| error("Database has no Meta"))?;
+    let root = child(file, "Root").and_then(|r| child(r, "Group")).ok_or_else(|| error("Database has no root group"))?;
+    let parser = Parser {
+        protected,
+        binaries,
+        recycle_bin: match text(meta, "RecycleBinEnabled") {
+            "False" => None,
+            _ => Some(text(meta, "RecycleBinUUID").trim())
+        }
+    };
+    Ok(Database {
+        name: text(meta, "DatabaseName").to_owned(),
+        root: parser.group(root)
+    })
+}
+
+fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            // Not allowed in XML at all
+            c if c < ' ' && !matches!(c, '\t' | '\n' | '\r') => {}
+            c => out.push(c)
+        }
+    }
+    out
+}
+
+struct Writer<'a> {
+    out: String,
+    depth: usize,
+    stream: &'a mut ChaCha20,
+    binaries: Vec<Vec<u8>>,
+    recycle_bin: Option<String>
+}
+
+impl<'a> Writer<'a> {
+    fn indent(&mut self) {
+        self.out.push_str(&"\t".repeat(self.depth));
+    }
+
+    fn open(&mut self, tag: &str) {
+        self.indent();
+        self.out.push_str(&format!("<{}>\n", tag));
+        self.depth += 1;
+    }
+
+    fn close(&mut self, tag: &str) {
+        self.depth -= 1;
+        self.indent();
+        self.out.push_str(&format!("</{}>\n", tag));
+    }
+
+    fn leaf(&mut self, tag: &str, text: &str) {
+        self.indent();
+        self.out.push_str(&format!("<{}>{}</{}>\n", tag, escape(text), tag));
+    }
+
+    fn times(&mut self, created: Option<u64>, modified: Option<u64>) {
+        let now = crate::spec::now();
+        let created = format_time(created.unwrap_or(now));
+        let modified = format_time(modified.unwrap_or(now));
+        self.open("Times");
+        self.leaf("CreationTime", &created);
+        self.leaf("LastModificationTime", &modified);
+        self.leaf("LastAccessTime", &modified);
+        self.leaf("ExpiryTime", &modified);
+        self.leaf("Expires", "False");
+        self.leaf("UsageCount", "0");
+        self.leaf("LocationChanged", &modified);
+        self.close("Times");
+    }
+
+    fn entry(&mut self, entry: &Entry) {
+        self.open("Entry");
+        self.leaf("UUID", &BASE64.encode(random::<16>()));
+        self.leaf("Tags", &entry.tags.join(";"));
+        self.times(entry.created, entry.modified);
+        for field in entry.fields.iter() {
+            self.open("String");
+            self.leaf("Key", &field.key);
+            self.indent();
+            match field.protected {
+                true => {
+                    let mut value = field.value.as_bytes().to_vec();
+                    self.stream.apply_keystream(&mut value);
+                    self.out.push_str(&format!("<Value Protected=\"True\">{}</Value>\n", BASE64.encode(value)));
+                }
+                false => self.out.push_str(&format!("<Value>{}</Value>\n", escape(&field.value)))
+            }
+            self.close("String");
+        }
+        for (name, data) in entry.attachments.iter() {
+            self.open("Binary");
+            self.leaf("Key", name);
+            self.indent();
+            self.out.push_str(&format!("<Value Ref=\"{}\"/>\n", self.binaries.len()));
+            self.binaries.push(data.clone());
+            self.close("Binary");
+        }
+        self.close("Entry");
+    }
+
+    fn group(&mut self, group: &Group) {
+        let uuid = BASE64.encode(random::<16>());
+        if group.recycle_bin {
+            self.recycle_bin = Some(uuid.clone());
+        }
+        self.open("Group");
+        self.leaf("UUID", &uuid);
+        self.leaf("Name", &group.name);
+        self.times(None, None);
+        self.leaf("IsExpanded", "True");
+        for entry in group.entries.iter() {
+            self.entry(entry);
+        }
+        for group in group.groups.iter() {
+            self.group(group);
+        }
+        self.close("Group");
+    }
+}
+
+/// The XML for db and the attachments it refers to, in the order of their refs
+pub(super) fn write(db: &Database, stream: &mut ChaCha20) -> (String, Vec<Vec<u8>>) {
+    let mut writer = Writer { out: String::new(), depth: 2, stream, binaries: vec![], recycle_bin: None };
+    writer.group(&db.root);
+    let root = std::mem::take(&mut writer.out);
+
+    writer.depth = 0;
+    writer.out.push_str("<?xml version=\"1.0\" encoding=\"utf-8\" standalone=\"yes\"?>\n");
+    writer.open("KeePassFile");
+    writer.open("Meta");
+    writer.leaf("Generator", "autopass");
+    writer.leaf("DatabaseName", &db.name);
+    match writer.recycle_bin.clone() {
+        Some(uuid) => {
+            writer.leaf("RecycleBinEnabled", "True");
+            writer.leaf("RecycleBinUUID", &uuid);
+        }
+        None => writer.leaf("RecycleBinEnabled", "False")
+    }
+    writer.close("Meta");
+    writer.open("Root");
+    writer.out.push_str(&root);
+    writer.close("Root");
+    writer.close("KeePassFile");
+    (writer.out, writer.binaries)
+}
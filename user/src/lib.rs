@@ -3,6 +3,25 @@ pub mod spec;
 pub mod hash;
 pub mod upgrade;
 pub mod bitmap;
+pub mod search;
+pub mod tagquery;
+pub mod tagtree;
+pub mod lock;
+pub mod hooks;
+pub mod git;
+pub mod audit;
+pub mod backup;
+pub mod config;
+pub mod snapshot;
+pub mod import;
+pub mod export;
+pub mod kdbx;
+pub mod storage;
+pub mod vault;
+pub mod warnings;
+
+#[cfg(test)]
+mod testvault;
 
 #[cfg(feature = "gui")]
 pub mod gui;
@@ -0,0 +1,193 @@
+use std::{cell::RefCell, fs::File, time::{Duration, Instant}};
+
+use fs2::FileExt;
+
+use crate::api::APError;
+use crate::spec::{base_path, lock_path};
+
+pub const LOCK_TIMEOUT_ENVVAR: &str = "AP_LOCK_TIMEOUT_SECS";
+pub const DEFAULT_LOCK_TIMEOUT: u64 = 10;
+const LOCK_POLL: Duration = Duration::from_millis(50);
+
+/*
+ * Advisory lock over the whole vault, so the GUI, apcli and scripts don't interleave
+ * their read-modify-write cycles. Readers share the lock, writers hold it exclusively.
+ *
+ * The lock is reentrant within a thread: api functions calling each other only take
+ * the lock once, the outermost call decides the mode. An exclusive lock covers nested
+ * shared ones, asking for an exclusive lock inside a shared one is an error.
+ */
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum LockMode {
+    Shared,
+    Exclusive
+}
+
+struct Held {
+    file: File,
+    mode: LockMode,
+    depth: usize,
+    /// Tells this hold apart from others, everything done under it is one operation
+    operation: u64
+}
+
+thread_local! {
+    static HELD: RefCell<Option<Held>> = const { RefCell::new(None) };
+}
+
+/// Released when dropped
+pub struct VaultLock {
+    tracked: bool
+}
+
+impl Drop for VaultLock {
+    fn drop(&mut self) {
+        if !self.tracked {
+            return;
+        }
+        HELD.with(|held| {
+            let mut held = held.borrow_mut();
+            let done = match held.as_mut() {
+                Some(h) => {
+                    h.depth -= 1;
+                    h.depth == 0
+                }
+                None => false
+            };
+            if done {
+                if let Some(h) = held.take() {
+                    // Whatever the writer changed goes into history before anyone else gets in
+                    if h.mode == LockMode::Exclusive {
+                        crate::git::autocommit();
+                    }
+                    let _ = FileExt::unlock(&h.file);
+                }
+            }
+        });
+    }
+}
+
+fn timeout() -> Duration {
+    let secs = std::env::var(LOCK_TIMEOUT_ENVVAR).ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or(DEFAULT_LOCK_TIMEOUT);
+    Duration::from_secs(secs)
+}
+
+fn acquire(mode: LockMode) -> Result<VaultLock, APError> {
+    HELD.with(|held| {
+        let mut held = held.borrow_mut();
+        if let Some(h) = held.as_mut() {
+            if h.mode == LockMode::Shared && mode == LockMode::Exclusive {
+                return Err(APError::LockUpgrade);
+            }
+            h.depth += 1;
+            return Ok(VaultLock { tracked: true });
+        }
+
+        // Nothing to protect until the vault exists
+        let dir = base_path();
+        if !dir.exists() {
+            return Ok(VaultLock { tracked: false });
+        }
+
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(lock_path(&dir))?;
+        let deadline = Instant::now() + timeout();
+        loop {
+            let res = match mode {
+                LockMode::Shared => FileExt::try_lock_shared(&file),
+                LockMode::Exclusive => FileExt::try_lock_exclusive(&file)
+            };
+            match res {
+                Ok(()) => break,
+                Err(e) if e.kind() == fs2::lock_contended_error().kind() => {
+                    if Instant::now() >= deadline {
+                        return Err(APError::Locked);
+                    }
+                    std::thread::sleep(LOCK_POLL);
+                }
+                Err(e) => return Err(e.into())
+            }
+        }
+        *held = Some(Held { file, mode, depth: 1, operation: rand::random() });
+        Ok(VaultLock { tracked: true })
+    })
+}
+
+/// Identifies the outermost lock this thread holds, so changes made by nested api
+/// calls can be told apart from the next operation's
+pub(crate) fn operation() -> Option<u64> {
+    HELD.with(|held| held.borrow().as_ref().map(|h| h.operation))
+}
+
+/// Lock for reading, waits up to the lock timeout for writers to finish
+pub fn shared() -> Result<VaultLock, APError> {
+    acquire(LockMode::Shared)
+}
+
+/// Lock for writing, waits up to the lock timeout for everyone else to finish
+pub fn exclusive() -> Result<VaultLock, APError> {
+    acquire(LockMode::Exclusive)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testvault::TestVault;
+
+    #[test]
+    fn test_reentrant() {
+        let vault = TestVault::empty("lock-reentrant");
+        std::fs::create_dir_all(&vault.dir).unwrap();
+        assert_eq!(operation(), None);
+        {
+            let _outer = exclusive().unwrap();
+            let op = operation();
+            assert!(op.is_some());
+            {
+                let _inner = shared().unwrap();
+                let _again = exclusive().unwrap();
+                assert_eq!(operation(), op);
+            }
+            // Still held after the nested ones are let go
+            assert_eq!(operation(), op);
+        }
+        assert_eq!(operation(), None);
+
+        let _outer = shared().unwrap();
+        assert!(matches!(exclusive(), Err(APError::LockUpgrade)));
+        let _inner = shared().unwrap();
+    }
+
+    #[test]
+    fn test_contention() {
+        let vault = TestVault::empty("lock-contention");
+        std::fs::create_dir_all(&vault.dir).unwrap();
+        std::env::set_var(LOCK_TIMEOUT_ENVVAR, "1");
+
+        // Locks are per thread, another one is as good as another process
+        let (held_tx, held) = std::sync::mpsc::channel();
+        let (release, release_rx) = std::sync::mpsc::channel::<()>();
+        let writer = std::thread::spawn(move || {
+            let _lock = exclusive().unwrap();
+            held_tx.send(()).unwrap();
+            release_rx.recv().unwrap();
+        });
+        held.recv().unwrap();
+        let start = Instant::now();
+        assert!(matches!(shared(), Err(APError::Locked)));
+        assert!(matches!(exclusive(), Err(APError::Locked)));
+        assert!(start.elapsed() >= Duration::from_secs(2));
+
+        release.send(()).unwrap();
+        writer.join().unwrap();
+        std::env::remove_var(LOCK_TIMEOUT_ENVVAR);
+        let _lock = exclusive().unwrap();
+    }
+}
@@ -0,0 +1,208 @@
+use crate::spec::{ServiceType, Visibility};
+
+/*
+ * Fuzzy search over the parts of a service that aren't secret. Each word of a query
+ * has to match some field, and the best match per word counts towards the service's
+ * score. Closer matches score higher, and so do fields that say more about a service:
+ * a name match beats a tag match, which beats a key, which beats a value.
+ */
+
+const EXACT: u32 = 100;
+const PREFIX: u32 = 80;
+const WORD: u32 = 70;
+const SUBSTRING: u32 = 60;
+const TYPO: u32 = 40;
+const SUBSEQUENCE: u32 = 30;
+
+const NAME_WEIGHT: u32 = 4;
+const TAG_WEIGHT: u32 = 3;
+const KEY_WEIGHT: u32 = 2;
+const VALUE_WEIGHT: u32 = 1;
+
+/// Typos allowed for a word of this many characters
+fn typo_tolerance(len: usize) -> usize {
+    match len {
+        0..=3 => 0,
+        4..=7 => 1,
+        _ => 2
+    }
+}
+
+/// Edit distance counting a swap of neighbouring characters as one edit,
+/// None once it's over max
+fn edit_distance(a: &[char], b: &[char], max: usize) -> Option<usize> {
+    if a.len().abs_diff(b.len()) > max {
+        return None;
+    }
+    let mut rows = vec![(0..=b.len()).collect::<Vec<usize>>()];
+    for i in 1..=a.len() {
+        let mut row = vec![i; b.len() + 1];
+        for j in 1..=b.len() {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            row[j] = (rows[i - 1][j] + 1)
+                .min(row[j - 1] + 1)
+                .min(rows[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                row[j] = row[j].min(rows[i - 2][j - 2] + 1);
+            }
+        }
+        rows.push(row);
+    }
+    let dist = rows[a.len()][b.len()];
+    (dist <= max).then_some(dist)
+}
+
+/// Extra characters between the first and last matched one if pattern is a
+/// subsequence of text, going with the leftmost match
+fn subsequence_gaps(pattern: &[char], text: &[char]) -> Option<usize> {
+    let mut next = 0;
+    let mut first = None;
+    for (idx, c) in text.iter().enumerate() {
+        if next < pattern.len() && *c == pattern[next] {
+            first.get_or_insert(idx);
+            next += 1;
+            if next == pattern.len() {
+                return Some(idx + 1 - first.unwrap() - pattern.len());
+            }
+        }
+    }
+    None
+}
+
+/// How well pattern matches text, ignoring case. None if it doesn't match at all.
+pub fn fuzzy_score(pattern: &str, text: &str) -> Option<u32> {
+    let pattern = pattern.to_lowercase();
+    let text = text.to_lowercase();
+    if pattern.is_empty() {
+        return None;
+    }
+    if text == pattern {
+        return Some(EXACT);
+    }
+    if text.starts_with(&pattern) {
+        return Some(PREFIX);
+    }
+    if let Some(pos) = text.find(&pattern) {
+        let word_start = !text[..pos].chars().last().map(|c| c.is_alphanumeric()).unwrap_or(false);
+        return Some(if word_start { WORD } else { SUBSTRING });
+    }
+
+    let pchars: Vec<char> = pattern.chars().collect();
+    let tolerance = typo_tolerance(pchars.len());
+    if tolerance > 0 {
+        let typos = text.split(|c: char| !c.is_alphanumeric())
+            .filter_map(|word| edit_distance(&pchars, &word.chars().collect::<Vec<char>>(), tolerance))
+            .min();
+        if let Some(typos) = typos {
+            return Some(TYPO - 10 * typos as u32);
+        }
+    }
+
+    // Only for patterns long enough not to match everything, and not spread too thin
+    if pchars.len() < 3 {
+        return None;
+    }
+    let gaps = subsequence_gaps(&pchars, &text.chars().collect::<Vec<char>>())?;
+    if gaps > pchars.len() {
+        return None;
+    }
+    Some(SUBSEQUENCE.saturating_sub(gaps as u32).max(10))
+}
+
+/// What search looks at in a service. The password is never part of it, and
+/// neither are values that are concealed or copy only.
+#[derive(Debug, Clone)]
+pub struct SearchDoc {
+    name: String,
+    tags: Vec<String>,
+    keys: Vec<String>,
+    values: Vec<String>
+}
+
+impl SearchDoc {
+    pub fn new(entry: &ServiceType) -> Self {
+        Self {
+            name: entry.get_name().to_owned(),
+            tags: entry.get_tags().to_vec(),
+            keys: entry.get_kvs().iter().map(|(k, _, _)| k.clone()).collect(),
+            values: entry.get_kvs().iter()
+                .filter(|(_, _, visibility)| *visibility == Visibility::Plain)
+                .map(|(_, v, _)| v.clone())
+                .collect()
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn score_term(&self, term: &str) -> Option<u32> {
+        let best = |fields: &[String], weight: u32| {
+            fields.iter().filter_map(|f| fuzzy_score(term, f)).max().map(|s| s * weight)
+        };
+        [
+            fuzzy_score(term, &self.name).map(|s| s * NAME_WEIGHT),
+            best(&self.tags, TAG_WEIGHT),
+            best(&self.keys, KEY_WEIGHT),
+            best(&self.values, VALUE_WEIGHT)
+        ].iter().flatten().max().copied()
+    }
+
+    /// None unless every word of query matches something. An empty query matches
+    /// everything with a score of 0.
+    pub fn score(&self, query: &str) -> Option<u32> {
+        query.split_whitespace()
+            .map(|term| self.score_term(term))
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::TextMode;
+
+    #[test]
+    fn test_fuzzy_score() {
+        assert_eq!(fuzzy_score("GMail", "gmail"), Some(EXACT));
+        assert_eq!(fuzzy_score("gm", "gmail"), Some(PREFIX));
+        assert_eq!(fuzzy_score("bank", "my-bank"), Some(WORD));
+        assert_eq!(fuzzy_score("ank", "my-bank"), Some(SUBSTRING));
+        assert_eq!(fuzzy_score("gmial", "gmail"), Some(TYPO - 10));
+        assert_eq!(fuzzy_score("gml", "gmail"), Some(SUBSEQUENCE - 2));
+        assert_eq!(fuzzy_score("gm", "gxxm"), None);
+        assert_eq!(fuzzy_score("xyz", "gmail"), None);
+        assert_eq!(fuzzy_score("", "gmail"), None);
+
+        // As many gaps as the pattern is long, more than a subsequence scores
+        let pattern: String = ('a'..='z').chain('a'..='e').collect();
+        let spread = pattern.chars().map(|c| c.to_string()).collect::<Vec<String>>().join("-");
+        assert_eq!(fuzzy_score(&pattern, &spread), Some(10));
+        assert_eq!(fuzzy_score(&pattern, &spread.replacen('-', "--", 1)), Some(10));
+        assert_eq!(fuzzy_score(&pattern, &spread.replacen('-', "--", 2)), None);
+    }
+
+    #[test]
+    fn test_search_doc() {
+        let mut mail = ServiceType::new("mail", "hunter2", 0, &[("user", "bob")], &["work"], 16, &TextMode::AlphaNumeric);
+        mail.set_kvs(&[("pin", "9876")], crate::spec::Visibility::Concealed, false);
+        let bank = ServiceType::new("bank", "hunter2", 0, &[("mail", "bob@mail")], &["money"], 16, &TextMode::AlphaNumeric);
+        let docs = vec![SearchDoc::new(&bank), SearchDoc::new(&mail)];
+
+        let names = |query| {
+            let mut ranked: Vec<(&str, u32)> = docs.iter()
+                .filter_map(|doc| doc.score(query).map(|score| (doc.name(), score)))
+                .collect();
+            ranked.sort_by(|(n1, s1), (n2, s2)| s2.cmp(s1).then_with(|| n1.cmp(n2)));
+            ranked.into_iter().map(|(name, _)| name).collect::<Vec<&str>>()
+        };
+        // The name match beats the key and value matches
+        assert_eq!(names("mail"), vec!["mail", "bank"]);
+        assert_eq!(names("bob money"), vec!["bank"]);
+        assert_eq!(names("work"), vec!["mail"]);
+        assert!(names("hunter2").is_empty());
+        assert!(names("9876").is_empty());
+        assert_eq!(names("pin"), vec!["mail"]);
+        assert_eq!(names(""), vec!["bank", "mail"]);
+    }
+}
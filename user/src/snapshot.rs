@@ -0,0 +1,311 @@
+use std::{fs::{create_dir_all, read_dir, remove_file, File}, io::Write, path::{Path, PathBuf}};
+
+use crate::api::{self, APError};
+use crate::audit;
+use crate::config;
+use crate::lock;
+use crate::spec::{base_path, load, save_file, snapshots_path, write_atomic, AuditOp, Encryptor, EncryptorType, Serializable, SnapshotType};
+
+/*
+ * Before a change is saved the service's file is copied into a snapshot, so a bad
+ * `set-kv --reset` can be taken back. Each service has a directory of its own under
+ * snapshots, named the way the service's file is, holding a snapshot per change named
+ * by when it was taken. Only the newest `snapshot-keep` of them are kept.
+ *
+ * Everything done under one vault lock is one operation, so undoing a bulk change
+ * puts back every service it touched. Undo removes the snapshots it puts back, so
+ * running it again goes further back. Snapshots are local to this copy of the vault,
+ * git and backups leave them out.
+ */
+
+fn entry_dir(key: &[u8], name: &str) -> PathBuf {
+    snapshots_path(base_path()).join(EncryptorType::filename(key, name))
+}
+
+/// Snapshot files in dir, newest first. Names are zero padded times so they sort.
+fn files(dir: &Path) -> Result<Vec<PathBuf>, APError> {
+    let mut files = vec![];
+    if dir.is_dir() {
+        for entry in read_dir(dir)? {
+            files.push(entry?.path());
+        }
+    }
+    files.sort();
+    files.reverse();
+    Ok(files)
+}
+
+/// Every service's snapshot files, newest first
+fn all_files() -> Result<Vec<Vec<PathBuf>>, APError> {
+    let dir = snapshots_path(base_path());
+    let mut all = vec![];
+    if dir.exists() {
+        for entry in read_dir(dir)? {
+            all.push(files(&entry?.path())?);
+        }
+    }
+    Ok(all)
+}
+
+fn load_file(path: &Path, key: &[u8]) -> Result<SnapshotType, APError> {
+    load::<SnapshotType, EncryptorType>(&mut File::open(path)?, key)
+}
+
+/// Keep what names are now before op changes them. The caller holds the exclusive lock.
+pub(crate) fn take<S: AsRef<str>>(key: &[u8], op: AuditOp, names: &[S]) -> Result<(), APError> {
+    let keep = config::current().snapshot_keep;
+    if keep == 0 {
+        return Ok(());
+    }
+    let batch = lock::operation().unwrap_or_else(rand::random);
+    for name in names.iter().map(|n| n.as_ref()) {
+        let path = EncryptorType::full_path(key, name);
+        let entry = match path.exists() {
+            true => Some(std::fs::read(path)?),
+            false => None
+        };
+        let snapshot = SnapshotType::new(name, op, batch, entry);
+        let dir = entry_dir(key, name);
+        create_dir_all(&dir)?;
+        save_file(dir.join(format!("{:020}", snapshot.time_ns())), key, &snapshot)?;
+        for old in files(&dir)?.iter().skip(keep) {
+            remove_file(old)?;
+        }
+    }
+    Ok(())
+}
+
+/// Put name back the way snapshot has it
+fn apply(key: &[u8], pass: &str, snapshot: &SnapshotType) -> Result<(), APError> {
+    let name = snapshot.name();
+    let path = EncryptorType::full_path(key, name);
+    match snapshot.get_entry() {
+        // The change created it
+        None => {
+            if path.exists() {
+                remove_file(path)?;
+            }
+        }
+        Some(data) => {
+            // Brought back from the trash if it's still there, along with its attachments
+            if snapshot.get_op() == AuditOp::Delete && !path.exists() {
+                match api::restore(name, pass) {
+                    Err(APError::NotExist(_)) => {}
+                    res => return res
+                }
+            }
+            write_atomic(&path, |file| Ok(file.write_all(data)?))?;
+        }
+    }
+    audit::record(key, AuditOp::Revert, name);
+    Ok(())
+}
+
+/// Snapshots of name or every service, newest first
+pub fn list(pass: &str, name: Option<&str>) -> Result<Vec<SnapshotType>, APError> {
+    let _lock = lock::shared()?;
+    let key = api::load_id(pass)?.key();
+    let dirs = match name {
+        Some(name) => vec![files(&entry_dir(&key, name))?],
+        None => all_files()?
+    };
+    let mut snapshots = vec![];
+    for path in dirs.iter().flatten() {
+        snapshots.push(load_file(path, &key)?);
+    }
+    snapshots.sort_by_key(|s| std::cmp::Reverse(s.time_ns()));
+    Ok(snapshots)
+}
+
+/// Take back the last operation that changed any services. Returns the snapshots that
+/// were put back, empty if there was nothing to undo.
+pub fn undo(pass: &str) -> Result<Vec<SnapshotType>, APError> {
+    let _lock = lock::exclusive()?;
+    let key = api::load_id(pass)?.key();
+    let dirs = all_files()?;
+    let newest = match dirs.iter().filter_map(|files| files.first()).max_by_key(|path| path.file_name()) {
+        Some(newest) => load_file(newest, &key)?,
+        None => return Ok(vec![])
+    };
+
+    let mut undone = vec![];
+    for files in dirs.iter() {
+        // A service changed more than once by the operation goes back to before the first
+        let mut found = vec![];
+        for path in files {
+            let snapshot = load_file(path, &key)?;
+            if snapshot.get_batch() != newest.get_batch() {
+                break;
+            }
+            found.push((snapshot, path));
+        }
+        if let Some((snapshot, _)) = found.last() {
+            apply(&key, pass, snapshot)?;
+        }
+        for (_, path) in found.iter() {
+            remove_file(path)?;
+        }
+        undone.extend(found.pop().map(|(snapshot, _)| snapshot));
+    }
+    undone.sort_by(|s1, s2| s1.name().cmp(s2.name()));
+    Ok(undone)
+}
+
+/// Split NAME@TIME as `SnapshotType::id` writes it
+pub fn parse_id(id: &str) -> Option<(&str, u64)> {
+    let (name, time) = id.rsplit_once('@')?;
+    Some((name, time.parse().ok()?))
+}
+
+/// Put name back the way it was in the newest snapshot taken at time. What it is now
+/// is kept as a snapshot first, so this can be undone too.
+pub fn restore(pass: &str, name: &str, time: u64) -> Result<(), APError> {
+    let _lock = lock::exclusive()?;
+    let key = api::load_id(pass)?.key();
+    let mut found = None;
+    for path in files(&entry_dir(&key, name))? {
+        let snapshot = load_file(&path, &key)?;
+        if snapshot.time() == time {
+            found = Some(snapshot);
+            break;
+        }
+    }
+    let snapshot = found.ok_or_else(|| APError::NotExist(format!("{}@{}", name, time)))?;
+    take(&key, AuditOp::Revert, &[name])?;
+    apply(&key, pass, &snapshot)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::TextMode;
+    use crate::spec::Visibility;
+    use crate::testvault::{TestVault, PASS};
+
+    fn new_service(name: &str, kvs: &[(&str, &str)]) {
+        api::new(name, PASS, &TextMode::NoWhiteSpace, 16, kvs, &[], None).unwrap();
+    }
+
+    fn kvs(name: &str) -> Vec<(String, String)> {
+        api::get_all(name, PASS).unwrap().get_kvs().iter().map(|(k, v, _)| (k.clone(), v.clone())).collect()
+    }
+
+    fn undone(pass: &str) -> Vec<(String, AuditOp)> {
+        undo(pass).unwrap().iter().map(|s| (s.name().to_owned(), s.get_op())).collect()
+    }
+
+    #[test]
+    fn test_parse_id() {
+        assert_eq!(parse_id("mail@1700000000"), Some(("mail", 1700000000)));
+        // Only the last @ splits, names can have them
+        assert_eq!(parse_id("me@example.com@5"), Some(("me@example.com", 5)));
+        assert_eq!(parse_id("mail"), None);
+        assert_eq!(parse_id("mail@soon"), None);
+    }
+
+    #[test]
+    fn test_undo() {
+        let _vault = TestVault::new("undo");
+        assert!(undo(PASS).unwrap().is_empty());
+        new_service("mail", &[("user", "me"), ("pin", "1234")]);
+        api::set_kvs("mail", PASS, &[("user", "you")], Visibility::Plain, true, None).unwrap();
+        assert_eq!(kvs("mail"), vec![("user".to_owned(), "you".to_owned())]);
+
+        assert_eq!(undone(PASS), vec![("mail".to_owned(), AuditOp::SetKvs)]);
+        assert_eq!(kvs("mail"), vec![("pin".to_owned(), "1234".to_owned()), ("user".to_owned(), "me".to_owned())]);
+        // The snapshot it put back is gone, so the next undo takes back the New
+        assert_eq!(undone(PASS), vec![("mail".to_owned(), AuditOp::New)]);
+        assert!(!api::exists(PASS, "mail").unwrap());
+        assert!(undo(PASS).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_undo_batch() {
+        let _vault = TestVault::new("undo-batch");
+        new_service("mail", &[]);
+        new_service("bank", &[]);
+        new_service("shop", &[]);
+        api::set_tags("shop", PASS, &["web"], false, None).unwrap();
+
+        // Everything under the one lock is undone together, mail going back to before
+        // the first of its two changes
+        let names = ["mail", "bank", "mail"];
+        let results = api::bulk(PASS, &names, &api::BulkAction::SetTags(vec!["money".to_owned()], true)).unwrap();
+        assert!(results.iter().all(|(_, res)| res.is_ok()));
+        assert_eq!(undone(PASS), vec![("bank".to_owned(), AuditOp::SetTags), ("mail".to_owned(), AuditOp::SetTags)]);
+        for name in ["mail", "bank"].iter() {
+            assert!(api::get_all(name, PASS).unwrap().get_tags().is_empty());
+        }
+        // An earlier operation is left alone until it's undone on its own
+        assert_eq!(api::get_all("shop", PASS).unwrap().get_tags(), ["web"]);
+        assert_eq!(undone(PASS), vec![("shop".to_owned(), AuditOp::SetTags)]);
+        assert!(api::get_all("shop", PASS).unwrap().get_tags().is_empty());
+    }
+
+    #[test]
+    fn test_undo_delete() {
+        let _vault = TestVault::new("undo-delete");
+        new_service("mail", &[("user", "me")]);
+        api::attach("mail", PASS, "note.txt", &mut "attached".as_bytes()).unwrap();
+        api::delete("mail", PASS).unwrap();
+        assert!(!api::exists(PASS, "mail").unwrap());
+
+        assert_eq!(undone(PASS), vec![("mail".to_owned(), AuditOp::Delete)]);
+        assert_eq!(kvs("mail"), vec![("user".to_owned(), "me".to_owned())]);
+        // Restored from the trash rather than just rewritten, so the attachment came too
+        assert!(api::list_trash(PASS).unwrap().is_empty());
+        let mut data = vec![];
+        api::get_attachment("mail", PASS, "note.txt", &mut data).unwrap();
+        assert_eq!(data, b"attached");
+    }
+
+    #[test]
+    fn test_retention() {
+        let _vault = TestVault::new("snapshot-keep");
+        config::set(config::Scope::Vault, config::SNAPSHOT_KEEP, "2").unwrap();
+        new_service("mail", &[]);
+        for n in 0..4 {
+            let n = n.to_string();
+            api::set_kvs("mail", PASS, &[("n", &n)], Visibility::Plain, true, None).unwrap();
+        }
+        let kept = list(PASS, Some("mail")).unwrap();
+        config::set(config::Scope::Vault, config::SNAPSHOT_KEEP, "0").unwrap();
+        api::set_kvs("mail", PASS, &[("n", "4")], Visibility::Plain, true, None).unwrap();
+        let off = list(PASS, Some("mail")).unwrap();
+        // Put back for the other tests, the config is read once per process
+        config::set(config::Scope::Vault, config::SNAPSHOT_KEEP, &config::Config::default().snapshot_keep.to_string()).unwrap();
+
+        // The newest two, from before the last two changes
+        assert_eq!(kept.len(), 2);
+        assert!(kept[0].time_ns() > kept[1].time_ns());
+        assert!(kept.iter().all(|s| s.get_op() == AuditOp::SetKvs));
+        // Nothing new is taken with it off
+        assert_eq!(off.len(), 2);
+        assert_eq!(undone(PASS), vec![("mail".to_owned(), AuditOp::SetKvs)]);
+        assert_eq!(kvs("mail"), vec![("n".to_owned(), "2".to_owned())]);
+    }
+
+    #[test]
+    fn test_restore() {
+        let _vault = TestVault::new("snapshot-restore");
+        new_service("mail", &[("user", "first")]);
+        std::thread::sleep(std::time::Duration::from_millis(1100));
+        api::set_kvs("mail", PASS, &[("user", "second")], Visibility::Plain, true, None).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(1100));
+        api::set_kvs("mail", PASS, &[("user", "third")], Visibility::Plain, true, None).unwrap();
+
+        let snapshots = list(PASS, Some("mail")).unwrap();
+        assert_eq!(snapshots.len(), 3);
+        // Back to how it was before the first set-kv, picked out by its id
+        let id = snapshots[1].id();
+        let (name, time) = parse_id(&id).unwrap();
+        restore(PASS, name, time).unwrap();
+        assert_eq!(kvs("mail"), vec![("user".to_owned(), "first".to_owned())]);
+        assert!(matches!(restore(PASS, "mail", 1), Err(APError::NotExist(_))));
+        assert!(matches!(restore(PASS, "bank", time), Err(APError::NotExist(_))));
+
+        // The restore is a change like any other and can be undone
+        assert_eq!(undone(PASS), vec![("mail".to_owned(), AuditOp::Revert)]);
+        assert_eq!(kvs("mail"), vec![("user".to_owned(), "third".to_owned())]);
+    }
+}
@@ -0,0 +1,100 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use super::{Encryptor, EncryptorType, Serializable, SpecType, ATTACHMENT_MAGIC};
+
+/// Metadata for a file attached to a service. The file contents are not part
+/// of this struct, they follow it on disk as a chunked stream (see `stream`).
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct AttachmentV1 {
+    pub(super) magic: u32,
+    pub(super) service: String,
+    pub(super) name: String,
+    pub(super) size: u64,
+    pub(super) create_time: u64
+}
+
+impl AttachmentV1 {
+    pub fn new(service: &str, name: &str, size: u64) -> Self {
+        Self {
+            magic: ATTACHMENT_MAGIC,
+            service: service.to_owned(),
+            name: name.to_owned(),
+            size,
+            create_time: super::now()
+        }
+    }
+
+    /// Name used to derive the on disk filename. Service and attachment names
+    /// come from user strings which can't contain a nul, so this can never
+    /// collide with a service's filename.
+    pub fn storage_name(service: &str, name: &str) -> String {
+        format!("{}\0{}", service, name)
+    }
+
+    /// The on disk filename starts with the service's, so a service's attachments
+    /// can be found without decrypting anyone else's
+    pub fn filename(key: &[u8], service: &str, name: &str) -> String {
+        format!("{}.{}", EncryptorType::filename(key, service), EncryptorType::filename(key, &Self::storage_name(service, name)))
+    }
+
+    pub fn get_service(&self) -> &str {
+        &self.service
+    }
+
+    pub fn get_size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn created(&self) -> String {
+        super::timestamp_as_string(self.create_time)
+    }
+
+    pub fn create_time(&self) -> u64 {
+        self.create_time
+    }
+
+    pub fn version() -> u16 {
+        1
+    }
+
+    pub fn spec_type() -> SpecType {
+        SpecType::Attachment
+    }
+}
+
+impl Serializable for AttachmentV1 {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn to_binary(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    fn from_binary(bin: &[u8]) -> Option<Self> {
+        match bincode::deserialize(bin) {
+            Ok(entry) => Some(entry),
+            Err(_) => None
+        }
+    }
+
+    fn sanity_check(&self) -> bool {
+        self.magic == ATTACHMENT_MAGIC
+    }
+
+    fn version(&self) -> u16 {
+        Self::version()
+    }
+
+    fn spec_type(&self) -> SpecType {
+        Self::spec_type()
+    }
+}
+
+impl fmt::Display for AttachmentV1 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&format!("{} ({} bytes, added {})", self.name, self.size, self.created()))
+    }
+}
@@ -0,0 +1,93 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use super::{AuditOp, Client, Serializable, SpecType, AUDIT_MAGIC};
+
+/// One call recorded in the audit log. prev is the hash of the record before it
+/// as it was written to disk, which is what chains the log together.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct AuditV1 {
+    pub(super) magic: u32,
+    pub(super) time: u64,
+    pub(super) name: String,
+    pub(super) op: AuditOp,
+    pub(super) client: Client,
+    pub(super) prev: [u8; 32]
+}
+
+impl AuditV1 {
+    pub fn new(name: &str, op: AuditOp, client: Client, prev: [u8; 32]) -> Self {
+        Self {
+            magic: AUDIT_MAGIC,
+            time: super::now(),
+            name: name.to_owned(),
+            op,
+            client,
+            prev
+        }
+    }
+
+    pub fn get_op(&self) -> AuditOp {
+        self.op
+    }
+
+    pub fn get_client(&self) -> Client {
+        self.client
+    }
+
+    pub fn get_prev(&self) -> &[u8; 32] {
+        &self.prev
+    }
+
+    pub fn time(&self) -> u64 {
+        self.time
+    }
+
+    pub fn when(&self) -> String {
+        super::timestamp_as_string(self.time)
+    }
+
+    pub fn version() -> u16 {
+        1
+    }
+
+    pub fn spec_type() -> SpecType {
+        SpecType::Audit
+    }
+}
+
+impl Serializable for AuditV1 {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn to_binary(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    fn from_binary(bin: &[u8]) -> Option<Self> {
+        bincode::deserialize(bin).ok()
+    }
+
+    fn sanity_check(&self) -> bool {
+        self.magic == AUDIT_MAGIC
+    }
+
+    fn version(&self) -> u16 {
+        Self::version()
+    }
+
+    fn spec_type(&self) -> SpecType {
+        Self::spec_type()
+    }
+}
+
+impl fmt::Display for AuditV1 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.name.is_empty() {
+            true => write!(f, "{} {} {}", self.when(), self.client, self.op),
+            false => write!(f, "{} {} {} {}", self.when(), self.client, self.op, self.name)
+        }
+    }
+}
@@ -33,13 +33,16 @@ impl Encryptor for Encrypt {
     }
 
     fn decrypt<T: super::Serializable>(&self, key: &[u8]) -> Option<T> {
+        let plaintext = self.decrypt_bytes(key)?;
+        T::from_binary(&plaintext)
+    }
+
+    fn decrypt_bytes(&self, key: &[u8]) -> Option<Vec<u8>> {
         let key = Key::<Aes256Gcm>::from_slice(key);
         let cipher = Aes256Gcm::new(&key);
 
         let nonce = Nonce::from_slice(&self.nonce);
-        let plaintext = cipher.decrypt(&nonce, self.ciphertext.as_ref()).ok()?;
-        
-        T::from_binary(&plaintext)
+        cipher.decrypt(&nonce, self.ciphertext.as_ref()).ok()
     }
 
     fn genkey(pass: &str) -> APKey {
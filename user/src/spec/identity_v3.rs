@@ -0,0 +1,131 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use super::{APKey, Serializable, SpecType, IDENTITY_MAGIC};
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct IdentityV3 {
+    pub(super) magic: u32,
+    pub(super) name: String,
+    pub(super) key: APKey,
+    pub(super) kv: Vec<(String, String)>,
+    pub(super) create_time: u64,
+    pub(super) modify_time: u64,
+    pub(super) tag_max_age: Vec<(String, u32)>
+}
+
+impl IdentityV3 {
+    pub fn new<T: AsRef<str>>(name: &str, key: &APKey, kvs: &[(T, T)]) -> Self {
+        let now = super::now();
+        let mut kv = vec![];
+        for (key, val) in kvs {
+            kv.push((key.as_ref().to_owned(), val.as_ref().to_owned()));
+        }
+        kv.sort();
+        Self {
+            magic: IDENTITY_MAGIC,
+            name: name.to_owned(),
+            key: key.to_owned(),
+            kv,
+            create_time: now,
+            modify_time: now,
+            tag_max_age: vec![]
+        }
+    }
+
+    pub fn key(&self) -> APKey {
+        self.key.clone()
+    }
+
+    pub fn get_kvs(&self) -> &[(String, String)] {
+        &self.kv
+    }
+
+    pub fn set_kvs(&mut self, kvs: &[(&str, &str)], reset: bool) {
+        if reset {
+            self.kv.clear();
+        }
+        for (key, value) in kvs.iter() {
+            self.kv.push((key.to_string(), value.to_string()));
+        }
+        self.kv.sort();
+        self.modify_time = super::now();
+    }
+
+    /// Maximum password age in days for services with each tag
+    pub fn get_tag_max_ages(&self) -> &[(String, u32)] {
+        &self.tag_max_age
+    }
+
+    pub fn set_tag_max_age(&mut self, tag: &str, days: Option<u32>) {
+        self.tag_max_age.retain(|(t, _)| t != tag);
+        if let Some(days) = days {
+            self.tag_max_age.push((tag.to_owned(), days));
+        }
+        self.tag_max_age.sort();
+        self.modify_time = super::now();
+    }
+
+    pub fn created(&self) -> String {
+        super::timestamp_as_string(self.create_time)
+    }
+
+    pub fn modified(&self) -> String {
+        super::timestamp_as_string(self.modify_time)
+    }
+
+    pub fn version() -> u16 {
+        3
+    }
+
+    pub fn spec_type() -> SpecType {
+        SpecType::Identity
+    }
+}
+
+impl Serializable for IdentityV3 {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn to_binary(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    fn from_binary(bin: &[u8]) -> Option<Self> {
+        match bincode::deserialize(bin) {
+            Ok(entry) => Some(entry),
+            Err(_) => None
+        }
+    }
+
+    fn sanity_check(&self) -> bool {
+        self.magic == IDENTITY_MAGIC
+    }
+
+    fn version(&self) -> u16 {
+        Self::version()
+    }
+
+    fn spec_type(&self) -> SpecType {
+        Self::spec_type()
+    }
+}
+
+impl fmt::Display for IdentityV3 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut kvs = String::new();
+        for (key, value) in self.kv.iter() {
+            kvs = format!("{}  {}: {}\n", kvs, key, value);
+        }
+        let created = format!("Created: {}", self.created());
+        let modified = format!("Modified: {}", self.modified());
+        let mut policies = String::new();
+        for (tag, days) in self.tag_max_age.iter() {
+            policies = format!("{}  {}: {} days\n", policies, tag, days);
+        }
+
+        f.write_str(&format!("Name: {}\n{}\n{}\nKey value pairs:\n{}Max password age by tag:\n{}", self.name, created, modified, kvs, policies))
+    }
+}
\ No newline at end of file
@@ -0,0 +1,189 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use super::{APKey, Serializable, SpecType, Visibility, IDENTITY_MAGIC};
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct IdentityV4 {
+    pub(super) magic: u32,
+    pub(super) name: String,
+    pub(super) key: APKey,
+    pub(super) kv: Vec<(String, String, Visibility)>,
+    pub(super) create_time: u64,
+    pub(super) modify_time: u64,
+    pub(super) tag_max_age: Vec<(String, u32)>
+}
+
+impl IdentityV4 {
+    pub fn new<T: AsRef<str>>(name: &str, key: &APKey, kvs: &[(T, T)]) -> Self {
+        let now = super::now();
+        let mut kv = vec![];
+        for (key, val) in kvs {
+            kv.push((key.as_ref().to_owned(), val.as_ref().to_owned(), Visibility::Plain));
+        }
+        kv.sort();
+        Self {
+            magic: IDENTITY_MAGIC,
+            name: name.to_owned(),
+            key: key.to_owned(),
+            kv,
+            create_time: now,
+            modify_time: now,
+            tag_max_age: vec![]
+        }
+    }
+
+    pub fn key(&self) -> APKey {
+        self.key.clone()
+    }
+
+    pub fn get_kvs(&self) -> &[(String, String, Visibility)] {
+        &self.kv
+    }
+
+    /// Value and visibility of the first kv with this key
+    pub fn get_kv(&self, key: &str) -> Option<(&str, Visibility)> {
+        self.kv.iter()
+            .find(|(k, _, _)| k == key)
+            .map(|(_, v, visibility)| (v.as_str(), *visibility))
+    }
+
+    pub fn set_kvs(&mut self, kvs: &[(&str, &str)], visibility: Visibility, reset: bool) {
+        if reset {
+            self.kv.clear();
+        }
+        for (key, value) in kvs.iter() {
+            self.kv.push((key.to_string(), value.to_string(), visibility));
+        }
+        self.kv.sort();
+        self.modify_time = super::now();
+    }
+
+    /// Returns false if no kv has this key
+    pub fn set_kv_visibility(&mut self, key: &str, visibility: Visibility) -> bool {
+        let mut found = false;
+        for (k, _, v) in self.kv.iter_mut() {
+            if k == key {
+                *v = visibility;
+                found = true;
+            }
+        }
+        self.kv.sort();
+        self.modify_time = super::now();
+        found
+    }
+
+    pub fn remove_kvs<S: AsRef<str>>(&mut self, keys: &[S]) {
+        self.kv.retain(|(k, _, _)| !keys.iter().any(|key| key.as_ref() == k));
+        self.modify_time = super::now();
+    }
+
+    /// Maximum password age in days for services with each tag
+    pub fn get_tag_max_ages(&self) -> &[(String, u32)] {
+        &self.tag_max_age
+    }
+
+    pub fn set_tag_max_age(&mut self, tag: &str, days: Option<u32>) {
+        self.tag_max_age.retain(|(t, _)| t != tag);
+        if let Some(days) = days {
+            self.tag_max_age.push((tag.to_owned(), days));
+        }
+        self.tag_max_age.sort();
+        self.modify_time = super::now();
+    }
+
+    /// Move the max age policies of from onto to, or drop them if to is None. When
+    /// more than one applies to the same tag the strictest is kept. False if none
+    /// of from had a policy.
+    pub fn replace_tag_max_ages<S: AsRef<str>>(&mut self, from: &[S], to: Option<&str>) -> bool {
+        let moved: Vec<u32> = self.tag_max_age.iter()
+            .filter(|(t, _)| from.iter().any(|f| f.as_ref() == t))
+            .map(|(_, days)| *days)
+            .collect();
+        if moved.is_empty() {
+            return false;
+        }
+        self.tag_max_age.retain(|(t, _)| !from.iter().any(|f| f.as_ref() == t));
+        if let Some(to) = to {
+            let existing = self.tag_max_age.iter().find(|(t, _)| t == to).map(|(_, days)| *days);
+            let days = moved.into_iter().chain(existing).min();
+            self.set_tag_max_age(to, days);
+        }
+        self.modify_time = super::now();
+        true
+    }
+
+    /// Human readable form, concealed values are only shown if reveal is set and
+    /// copy only values are never shown.
+    pub fn format(&self, reveal: bool) -> String {
+        let kvs = super::format_kvs(&self.kv, reveal);
+        let created = format!("Created: {}", self.created());
+        let modified = format!("Modified: {}", self.modified());
+        let mut policies = String::new();
+        for (tag, days) in self.tag_max_age.iter() {
+            policies = format!("{}  {}: {} days\n", policies, tag, days);
+        }
+
+        format!("Name: {}\n{}\n{}\nKey value pairs:\n{}Max password age by tag:\n{}", self.name, created, modified, kvs, policies)
+    }
+
+    pub fn created(&self) -> String {
+        super::timestamp_as_string(self.create_time)
+    }
+
+    pub fn modified(&self) -> String {
+        super::timestamp_as_string(self.modify_time)
+    }
+
+    pub fn create_time(&self) -> u64 {
+        self.create_time
+    }
+
+    pub fn modify_time(&self) -> u64 {
+        self.modify_time
+    }
+
+    pub fn version() -> u16 {
+        4
+    }
+
+    pub fn spec_type() -> SpecType {
+        SpecType::Identity
+    }
+}
+
+impl Serializable for IdentityV4 {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn to_binary(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    fn from_binary(bin: &[u8]) -> Option<Self> {
+        match bincode::deserialize(bin) {
+            Ok(entry) => Some(entry),
+            Err(_) => None
+        }
+    }
+
+    fn sanity_check(&self) -> bool {
+        self.magic == IDENTITY_MAGIC
+    }
+
+    fn version(&self) -> u16 {
+        Self::version()
+    }
+
+    fn spec_type(&self) -> SpecType {
+        Self::spec_type()
+    }
+}
+
+impl fmt::Display for IdentityV4 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.format(false))
+    }
+}
@@ -0,0 +1,124 @@
+use crate::api::APError;
+
+use super::{
+    identity_v1::IdentityV1, identity_v2::IdentityV2, identity_v3::IdentityV3, identity_v4::IdentityV4,
+    service_v1::ServiceEntryV1, service_v2::ServiceEntryV2, service_v3::ServiceEntryV3, service_v4::ServiceEntryV4,
+    attachment_v1::AttachmentV1, trash_v1::TrashV1, audit_v1::AuditV1, snapshot_v1::SnapshotV1, Serializable, SpecType
+};
+
+/*
+ * Every spec version ap has ever written, and how to get from each one to the next.
+ * Upgrades work on decrypted plaintext so a file can be read at any old version and
+ * brought up to date in memory, without rewriting it. Adding a version means adding
+ * its struct with a From impl for the previous one and a line here.
+ */
+
+type Upgrade = fn(&[u8]) -> Option<Vec<u8>>;
+
+struct SpecVersion {
+    spec_type: SpecType,
+    version: u16,
+    sane: fn(&[u8]) -> bool,
+    /// Plaintext of this version to the next one, None for the current version
+    upgrade: Option<Upgrade>
+}
+
+fn sane<T: Serializable>(plaintext: &[u8]) -> bool {
+    T::from_binary(plaintext)
+        .map(|t| t.sanity_check())
+        .unwrap_or(false)
+}
+
+fn upgrade<O: Serializable, N: Serializable + From<O>>(plaintext: &[u8]) -> Option<Vec<u8>> {
+    O::from_binary(plaintext).map(|old| N::from(old).to_binary())
+}
+
+const REGISTRY: &[SpecVersion] = &[
+    SpecVersion { spec_type: SpecType::Service, version: 1, sane: sane::<ServiceEntryV1>, upgrade: Some(upgrade::<ServiceEntryV1, ServiceEntryV2>) },
+    SpecVersion { spec_type: SpecType::Service, version: 2, sane: sane::<ServiceEntryV2>, upgrade: Some(upgrade::<ServiceEntryV2, ServiceEntryV3>) },
+    SpecVersion { spec_type: SpecType::Service, version: 3, sane: sane::<ServiceEntryV3>, upgrade: Some(upgrade::<ServiceEntryV3, ServiceEntryV4>) },
+    SpecVersion { spec_type: SpecType::Service, version: 4, sane: sane::<ServiceEntryV4>, upgrade: None },
+    SpecVersion { spec_type: SpecType::Identity, version: 1, sane: sane::<IdentityV1>, upgrade: Some(upgrade::<IdentityV1, IdentityV2>) },
+    SpecVersion { spec_type: SpecType::Identity, version: 2, sane: sane::<IdentityV2>, upgrade: Some(upgrade::<IdentityV2, IdentityV3>) },
+    SpecVersion { spec_type: SpecType::Identity, version: 3, sane: sane::<IdentityV3>, upgrade: Some(upgrade::<IdentityV3, IdentityV4>) },
+    SpecVersion { spec_type: SpecType::Identity, version: 4, sane: sane::<IdentityV4>, upgrade: None },
+    SpecVersion { spec_type: SpecType::Attachment, version: 1, sane: sane::<AttachmentV1>, upgrade: None },
+    SpecVersion { spec_type: SpecType::Trash, version: 1, sane: sane::<TrashV1>, upgrade: None },
+    SpecVersion { spec_type: SpecType::Audit, version: 1, sane: sane::<AuditV1>, upgrade: None },
+    SpecVersion { spec_type: SpecType::Snapshot, version: 1, sane: sane::<SnapshotV1>, upgrade: None },
+];
+
+fn find(spec_type: SpecType, version: u16) -> Option<&'static SpecVersion> {
+    REGISTRY.iter().find(|s| s.spec_type == spec_type && s.version == version)
+}
+
+/// The version new files of this spec type are written at
+pub fn current_version(spec_type: SpecType) -> u16 {
+    REGISTRY.iter()
+        .filter(|s| s.spec_type == spec_type)
+        .map(|s| s.version)
+        .max()
+        .unwrap()
+}
+
+/// Whether plaintext deserializes as this spec version, None if there's no such version
+pub fn is_sane(spec_type: SpecType, version: u16, plaintext: &[u8]) -> Option<bool> {
+    find(spec_type, version).map(|s| (s.sane)(plaintext))
+}
+
+/// Bring plaintext written at version up to target by chaining every upgrade in between.
+pub fn upgrade_plaintext(spec_type: SpecType, version: u16, target: u16, plaintext: Vec<u8>) -> Result<Vec<u8>, APError> {
+    let mut version = version;
+    let mut plaintext = plaintext;
+    while version != target {
+        let upgrade = find(spec_type, version)
+            .and_then(|s| s.upgrade)
+            .ok_or(APError::VersionTooOld)?;
+        plaintext = upgrade(&plaintext).ok_or(APError::CorruptCiphertext)?;
+        version += 1;
+    }
+    Ok(plaintext)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::TextMode;
+    use crate::spec::{decode, encode, Encryptor, EncryptorType, IdentityType, ServiceType, AttachmentType, TrashType, AuditType, SnapshotType, Visibility};
+
+    #[test]
+    fn test_registry_chains() {
+        for spec_type in [SpecType::Service, SpecType::Identity, SpecType::Attachment, SpecType::Trash, SpecType::Audit, SpecType::Snapshot] {
+            let current = current_version(spec_type);
+            // Every version from 1 up is there and only the current one has nowhere to go
+            for version in 1..=current {
+                let spec = find(spec_type, version).unwrap();
+                assert_eq!(spec.upgrade.is_none(), version == current);
+            }
+        }
+        assert_eq!(current_version(SpecType::Service), ServiceType::version());
+        assert_eq!(current_version(SpecType::Identity), IdentityType::version());
+        assert_eq!(current_version(SpecType::Attachment), AttachmentType::version());
+        assert_eq!(current_version(SpecType::Trash), TrashType::version());
+        assert_eq!(current_version(SpecType::Audit), AuditType::version());
+        assert_eq!(current_version(SpecType::Snapshot), SnapshotType::version());
+    }
+
+    #[test]
+    fn test_kvs_become_plain() {
+        let key = EncryptorType::genkey("migrate");
+        let v3 = ServiceEntryV3::new("mail", "pass", 1, &[("user", "me"), ("pin", "1234")], &["web"], 16, &TextMode::AlphaNumeric);
+        // Read from a file written at v3, the way an old vault is
+        let service = decode::<ServiceType, EncryptorType>(&encode(&key, &v3).unwrap(), &key).unwrap();
+        assert_eq!(service.get_kvs(), [("pin".to_owned(), "1234".to_owned(), Visibility::Plain),
+                                       ("user".to_owned(), "me".to_owned(), Visibility::Plain)]);
+        assert_eq!(service.get_tags(), ["web"]);
+        assert_eq!(service.get_pass(false), Some("pass"));
+
+        let v3 = IdentityV3::new("me", &key, &[("email", "me@example.com")]);
+        let plaintext = upgrade_plaintext(SpecType::Identity, 3, 4, v3.to_binary()).unwrap();
+        let id = IdentityV4::from_binary(&plaintext).unwrap();
+        assert_eq!(id.get_kvs(), [("email".to_owned(), "me@example.com".to_owned(), Visibility::Plain)]);
+        assert_eq!(id.key(), key);
+    }
+}
@@ -1,26 +1,58 @@
-use std::{fs::{read_dir, File}, io::{Read, Write}, path::{Path, PathBuf}};
+use std::{fmt, fs::{read_dir, remove_file, rename, File}, io::{Read, Seek, SeekFrom, Write}, path::{Path, PathBuf}, sync::Mutex, time::{Duration, Instant}};
 
+use clipboard::ClipboardProvider;
+use clipboard::osx_clipboard::OSXClipboardContext;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use time::{format_description, OffsetDateTime, UtcOffset};
 
 use crate::api::APError;
 
 pub mod service_v1;
 pub mod service_v2;
+pub mod service_v3;
+pub mod service_v4;
 pub mod identity_v1;
 pub mod identity_v2;
+pub mod identity_v3;
+pub mod identity_v4;
+pub mod attachment_v1;
+pub mod trash_v1;
+pub mod audit_v1;
+pub mod snapshot_v1;
+pub mod migration;
 pub mod encryptor;
+pub mod stream;
 
 pub const PASS_PATH: &'static str = ".pass";
 pub const PASS_BASE_ENVVAR: &'static str = "AP_BASEDIR";
 const IDENTITY_MAGIC: u32 = 0xfedb1234;
 const SERVICE_MAGIC: u32 = 0x83596235;
-const IDENTITY_FNAME: &str = ".apid";
-
-pub const VERSION: u32 = 2;
+const ATTACHMENT_MAGIC: u32 = 0x4a7c91d2;
+const TRASH_MAGIC: u32 = 0x7e3a55c1;
+const AUDIT_MAGIC: u32 = 0x3c0d9e17;
+const SNAPSHOT_MAGIC: u32 = 0x51a9f06e;
+pub(crate) const IDENTITY_FNAME: &str = ".apid";
+const LOCK_FNAME: &str = ".lock";
+const CONFIG_FNAME: &str = ".apconf";
+const LEGACY_DIR: &str = "legacy";
+const QUARANTINE_DIR: &str = "quarantine";
+const BACKUP_DIR: &str = "backup";
+const HOOKS_DIR: &str = "hooks";
+const AUDIT_DIR: &str = "audit";
+const SNAPSHOTS_DIR: &str = "snapshots";
+const TRASH_DIR: &str = "trash";
+const TRASH_INFO_FNAME: &str = ".trashinfo";
+pub(crate) const TMP_PREFIX: &str = ".tmp-";
+
+pub const VERSION: u32 = 4;
 pub type EncryptorType = crate::spec::encryptor::Encrypt;
-pub type IdentityType = identity_v2::IdentityV2;
-pub type ServiceType = service_v2::ServiceEntryV2;
+pub type IdentityType = identity_v4::IdentityV4;
+pub type ServiceType = service_v4::ServiceEntryV4;
+pub type AttachmentType = attachment_v1::AttachmentV1;
+pub type TrashType = trash_v1::TrashV1;
+pub type AuditType = audit_v1::AuditV1;
+pub type SnapshotType = snapshot_v1::SnapshotV1;
 
 pub fn base_path() -> PathBuf {
     if let Ok(basepath) = std::env::var(PASS_BASE_ENVVAR) {
@@ -33,10 +65,226 @@ pub fn identity_path<P: AsRef<Path>>(basedir: P) -> PathBuf {
     Path::join(basedir.as_ref(), IDENTITY_FNAME)
 }
 
+pub fn lock_path<P: AsRef<Path>>(basedir: P) -> PathBuf {
+    Path::join(basedir.as_ref(), LOCK_FNAME)
+}
+
+/// The vault's settings, see `config`
+pub fn config_path<P: AsRef<Path>>(basedir: P) -> PathBuf {
+    Path::join(basedir.as_ref(), CONFIG_FNAME)
+}
+
+/// Where `upgrade_encryptor` keeps entries it hasn't finished moving to the new encryptor
+pub fn legacy_path<P: AsRef<Path>>(basedir: P) -> PathBuf {
+    Path::join(basedir.as_ref(), LEGACY_DIR)
+}
+
+pub fn quarantine_path<P: AsRef<Path>>(basedir: P) -> PathBuf {
+    Path::join(basedir.as_ref(), QUARANTINE_DIR)
+}
+
+/// Where `migrate` copies files before rewriting them
+pub fn backup_path<P: AsRef<Path>>(basedir: P) -> PathBuf {
+    Path::join(basedir.as_ref(), BACKUP_DIR)
+}
+
+/// Scripts run before and after changes, see `hooks`
+pub fn hooks_path<P: AsRef<Path>>(basedir: P) -> PathBuf {
+    Path::join(basedir.as_ref(), HOOKS_DIR)
+}
+
+/// Where the audit log and its rotated copies are kept, see `audit`
+pub fn audit_path<P: AsRef<Path>>(basedir: P) -> PathBuf {
+    Path::join(basedir.as_ref(), AUDIT_DIR)
+}
+
+/// Where earlier versions of services are kept, see `snapshot`
+pub fn snapshots_path<P: AsRef<Path>>(basedir: P) -> PathBuf {
+    Path::join(basedir.as_ref(), SNAPSHOTS_DIR)
+}
+
+pub fn trash_path<P: AsRef<Path>>(basedir: P) -> PathBuf {
+    Path::join(basedir.as_ref(), TRASH_DIR)
+}
+
+/// Where the record for the deletion is kept inside its trash directory
+pub fn trash_info_path<P: AsRef<Path>>(trashdir: P) -> PathBuf {
+    Path::join(trashdir.as_ref(), TRASH_INFO_FNAME)
+}
+
+/// Days deleted services are kept in the trash, 0 keeps them forever
+pub fn trash_retention() -> u64 {
+    crate::config::current().trash_retention_days
+}
+
 #[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq)]
 pub enum SpecType {
     Service,
-    Identity
+    Identity,
+    Attachment,
+    Trash,
+    Audit,
+    Snapshot
+}
+
+/// How a key value pair is shown to the user
+#[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Visibility {
+    /// Always shown
+    Plain,
+    /// Masked until the user asks to reveal it
+    Concealed,
+    /// Never shown, only copied to the clipboard
+    CopyOnly
+}
+
+impl Visibility {
+    /// The visibility after this one, for cycling through them in the GUI
+    pub fn next(self) -> Self {
+        match self {
+            Self::Plain => Self::Concealed,
+            Self::Concealed => Self::CopyOnly,
+            Self::CopyOnly => Self::Plain
+        }
+    }
+}
+
+impl fmt::Display for Visibility {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Plain => "plain",
+            Self::Concealed => "concealed",
+            Self::CopyOnly => "copy-only"
+        })
+    }
+}
+
+/// Calls recorded in the audit log
+#[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AuditOp {
+    Get,
+    GetAll,
+    New,
+    Upgrade,
+    SetKvs,
+    SetKvVisibility,
+    RemoveKvs,
+    SetTags,
+    SetMaxAge,
+    Delete,
+    Restore,
+    /// First record of a log after the previous one was rotated out
+    Rotate,
+    /// Brought in from outside the vault, like a backup
+    Import,
+    /// Put back as it was before an earlier change
+    Revert,
+    /// Written out of the vault, password and all
+    Export
+}
+
+impl fmt::Display for AuditOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Get => "get",
+            Self::GetAll => "get-all",
+            Self::New => "new",
+            Self::Upgrade => "upgrade",
+            Self::SetKvs => "set-kv",
+            Self::SetKvVisibility => "set-kv-visibility",
+            Self::RemoveKvs => "remove-kv",
+            Self::SetTags => "set-tags",
+            Self::SetMaxAge => "set-max-age",
+            Self::Delete => "delete",
+            Self::Restore => "restore",
+            Self::Rotate => "rotate",
+            Self::Import => "import",
+            Self::Revert => "revert",
+            Self::Export => "export"
+        })
+    }
+}
+
+/// What made a call, recorded in the audit log
+#[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Client {
+    Apcli,
+    Ap,
+    /// Anything else using the crate
+    Library
+}
+
+impl fmt::Display for Client {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Apcli => "apcli",
+            Self::Ap => "ap",
+            Self::Library => "library"
+        })
+    }
+}
+
+pub const MASK: &str = "********";
+
+fn format_kvs(kvs: &[(String, String, Visibility)], reveal: bool) -> String {
+    let mut out = String::new();
+    for (key, value, visibility) in kvs.iter() {
+        let value = match visibility {
+            Visibility::Plain => value.as_str(),
+            Visibility::Concealed if reveal => value.as_str(),
+            Visibility::Concealed => MASK,
+            Visibility::CopyOnly => "<copy only>"
+        };
+        out = format!("{}  {}: {}\n", out, key, value);
+    }
+    out
+}
+
+/// Digest of the last value copied and when it's due to be cleared
+static PENDING_CLEAR: Mutex<Option<(String, Instant)>> = Mutex::new(None);
+
+/// Copied values are cleared after the configured delay, unless something else has
+/// been copied by then
+pub fn copy_to_clipboard(value: &str) {
+    let mut clipboard = OSXClipboardContext::new().unwrap();
+    clipboard.set_contents(value.to_string()).unwrap();
+
+    let secs = crate::config::current().clipboard_clear;
+    if secs > 0 {
+        let digest = clipboard_digest(value);
+        *PENDING_CLEAR.lock().unwrap() = Some((digest.clone(), Instant::now() + Duration::from_secs(secs)));
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_secs(secs));
+            clear_clipboard_if(&digest);
+            let mut pending = PENDING_CLEAR.lock().unwrap();
+            if pending.as_ref().map(|(d, _)| *d == digest).unwrap_or(false) {
+                *pending = None;
+            }
+        });
+    }
+}
+
+/// Identifies a copied value without keeping it around
+pub fn clipboard_digest(value: &str) -> String {
+    format!("{:x}", Sha256::digest(value.as_bytes()))
+}
+
+/// Empty the clipboard if it still holds the value with this digest
+pub fn clear_clipboard_if(digest: &str) {
+    if let Ok(mut clipboard) = OSXClipboardContext::new() {
+        if clipboard.get_contents().map(|c| clipboard_digest(&c) == digest).unwrap_or(false) {
+            let _ = clipboard.set_contents(String::new());
+        }
+    }
+}
+
+/// The clear still to come from the last copy, as the digest and seconds left. A
+/// process exiting before then has to hand it to something that will stay around.
+pub fn pending_clipboard_clear() -> Option<(String, u64)> {
+    PENDING_CLEAR.lock().unwrap().as_ref()
+        .map(|(digest, at)| (digest.clone(), at.saturating_duration_since(Instant::now())))
+        .filter(|(_, left)| !left.is_zero())
+        .map(|(digest, left)| (digest, left.as_secs().max(1)))
 }
 
 pub trait Serializable: Sized {
@@ -60,6 +308,9 @@ pub trait Encryptor: Serialize + for <'a> Deserialize<'a> {
 
     fn decrypt<T: Serializable>(&self, key: &[u8]) -> Option<T>;
 
+    /// Decrypt without deserializing, None if the key doesn't authenticate
+    fn decrypt_bytes(&self, key: &[u8]) -> Option<Vec<u8>>;
+
     fn encrypt_version() -> u16;
 
     fn genkey(pass: &str) -> APKey;
@@ -71,11 +322,11 @@ pub trait Encryptor: Serialize + for <'a> Deserialize<'a> {
     }
 }
 
-fn now() -> u64 {
+pub(crate) fn now() -> u64 {
     OffsetDateTime::now_utc().unix_timestamp() as u64
 }
 
-fn timestamp_as_string(ts: u64) -> String {
+pub(crate) fn timestamp_as_string(ts: u64) -> String {
     let dtutc = OffsetDateTime::from_unix_timestamp(ts as i64)
         .unwrap();
     let utc = match UtcOffset::current_local_offset() {
@@ -87,8 +338,12 @@ fn timestamp_as_string(ts: u64) -> String {
             true
         }
     };
-    let mut dtstr = dtutc.format(&format_description::parse("[year]-[month]-[day] [hour]:[minute]:[second]").unwrap())
+    let date_format = crate::config::current().date_format;
+    let format = format_description::parse_borrowed::<2>(&date_format)
+        .or_else(|_| format_description::parse_borrowed::<2>(crate::config::DEFAULT_DATE_FORMAT))
         .unwrap();
+    let mut dtstr = dtutc.format(&format)
+        .unwrap_or_else(|_| ts.to_string());
     if utc {
         dtstr = format!("{} UTC", dtstr);
     }
@@ -102,7 +357,7 @@ pub struct Header {
     pub encrypt_version: u16
 }
 
-const HEADER_SIZE: usize = 8;
+pub(crate) const HEADER_SIZE: usize = 8;
 
 impl Header {
     fn create<T: Serializable, E: Encryptor>(entry: &T) -> Self {
@@ -114,35 +369,117 @@ impl Header {
     }
 }
 
-pub fn save<T: Serializable>(file: &mut File, key: &[u8], service: &T) -> Result<(), APError> {
-    assert!(file.metadata()?.len() == 0);
+/// Contents of a vault file: the header followed by the encrypted service
+pub fn encode<T: Serializable>(key: &[u8], service: &T) -> Result<Vec<u8>, APError> {
     let encrypted = EncryptorType::encrypt(key, service);
     let header = Header::create::<T, EncryptorType>(service);
-    let headerdata = bincode::serialize(&header)?;
-    assert!(headerdata.len() == HEADER_SIZE);
-    let data = bincode::serialize(&encrypted)?;
-    file.write_all(&headerdata).unwrap();
-    file.write_all(&data).unwrap();
+    let mut data = bincode::serialize(&header)?;
+    assert!(data.len() == HEADER_SIZE);
+    data.extend(bincode::serialize(&encrypted)?);
+    Ok(data)
+}
+
+pub fn save<T: Serializable>(file: &mut File, key: &[u8], service: &T) -> Result<(), APError> {
+    assert!(file.metadata()?.len() == 0);
+    file.write_all(&encode(key, service)?)?;
     Ok(())
 }
 
-pub fn load_header(file: &mut File) -> Result<Header, APError> {
-    let mut data = [0u8; 8];
-    file.read_exact(&mut data).unwrap();
-    let header = bincode::deserialize::<Header>(&data[0..HEADER_SIZE])?;
-    Ok(header)
+/// Save to path through `write_atomic`, replacing whatever was there.
+pub fn save_file<T: Serializable, P: AsRef<Path>>(path: P, key: &[u8], service: &T) -> Result<(), APError> {
+    write_atomic(path, |file| save(file, key, service))
+}
+
+/*
+ * Every vault file is written to a hidden temp file in the same directory, synced,
+ * renamed over the destination and then the directory is synced. A crash or a full
+ * disk at any point leaves either the old file or the new one, never a partial one.
+ */
+pub fn write_atomic<P, F, R>(path: P, write: F) -> Result<R, APError>
+where
+    P: AsRef<Path>,
+    F: FnOnce(&mut File) -> Result<R, APError>
+{
+    let path = path.as_ref();
+    let dir = match path.parent() {
+        Some(p) if p != Path::new("") => p,
+        _ => Path::new(".")
+    };
+    let fname = path.file_name()
+        .ok_or(std::io::Error::from(std::io::ErrorKind::InvalidInput))?;
+    let tmppath = dir.join(format!("{}{}.{:08x}", TMP_PREFIX, fname.to_string_lossy(), rand::random::<u32>()));
+
+    let res = File::options()
+        .write(true)
+        .create_new(true)
+        .open(&tmppath)
+        .map_err(APError::from)
+        .and_then(|mut file| {
+            let res = write(&mut file)?;
+            file.sync_all()?;
+            Ok(res)
+        })
+        .and_then(|res| {
+            rename(&tmppath, path)?;
+            Ok(res)
+        });
+    if res.is_err() {
+        let _ = remove_file(&tmppath);
+    }
+    let res = res?;
+    sync_dir(dir)?;
+    Ok(res)
+}
+
+#[cfg(unix)]
+pub(crate) fn sync_dir(dir: &Path) -> Result<(), APError> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn sync_dir(_dir: &Path) -> Result<(), APError> {
+    // Directories can't be opened for syncing here, the rename is as good as it gets
+    Ok(())
+}
+
+pub fn load_header<R: Read>(file: &mut R) -> Result<Header, APError> {
+    let mut data = [0u8; HEADER_SIZE];
+    if let Err(e) = file.read_exact(&mut data) {
+        return match e.kind() {
+            std::io::ErrorKind::UnexpectedEof => Err(APError::CorruptHeader),
+            _ => Err(e.into())
+        };
+    }
+    bincode::deserialize::<Header>(&data).map_err(|_| APError::CorruptHeader)
+}
+
+pub fn decode_header(data: &[u8]) -> Result<Header, APError> {
+    if data.len() < HEADER_SIZE {
+        return Err(APError::CorruptHeader);
+    }
+    bincode::deserialize::<Header>(&data[0..HEADER_SIZE]).map_err(|_| APError::CorruptHeader)
 }
 
 pub fn load<T: Serializable, E: Encryptor>(file: &mut File, key: &[u8]) -> Result<T, APError> {
     let mut data = vec![];
-    file.read_to_end(&mut data).unwrap();
-    let header = bincode::deserialize::<Header>(&data[0..HEADER_SIZE])?;
+    file.read_to_end(&mut data)?;
+    decode::<T, E>(&data, key)
+}
+
+/// Reverse of `encode`
+pub fn decode<T: Serializable, E: Encryptor>(data: &[u8], key: &[u8]) -> Result<T, APError> {
+    let header = decode_header(data)?;
     if header.encrypt_version != E::encrypt_version() {
         return Err(APError::WrongEncryptVersion(E::encrypt_version(), header.encrypt_version));
     }
 
+    // Older spec versions are brought up to date in memory, the file is left alone
     let encoder = bincode::deserialize::<EncryptorType>(&data[HEADER_SIZE..data.len()])?;
-    match encoder.decrypt::<T>(key) {
+    let plaintext = encoder.decrypt_bytes(key).ok_or(APError::Decryption)?;
+    let current = migration::current_version(header.spec_type);
+    let plaintext = migration::upgrade_plaintext(header.spec_type, header.spec_version, current, plaintext)?;
+    match T::from_binary(&plaintext) {
         Some(entry) => {
             match entry.sanity_check() {
                 true => Ok(entry),
@@ -153,9 +490,82 @@ pub fn load<T: Serializable, E: Encryptor>(file: &mut File, key: &[u8]) -> Resul
     }
 }
 
-pub fn list<P: AsRef<Path>>(basedir: P, by_spec: Option<SpecType>, by_version: Option<u16>) -> Result<Vec<PathBuf>, APError> {
+/*
+ * Attachments don't fit in memory the way entries do, so they get their own layout:
+ * header, the contents encrypted with `stream`, the encrypted AttachmentType and
+ * finally the u64 LE size of that encrypted metadata. Metadata goes last since the
+ * content size is only known once the stream has been written.
+ */
+pub fn save_attachment<R: Read>(file: &mut File, key: &[u8], service: &str, name: &str, reader: &mut R) -> Result<AttachmentType, APError> {
+    assert!(file.metadata()?.len() == 0);
+    let header = Header {
+        spec_type: AttachmentType::spec_type(),
+        spec_version: AttachmentType::version(),
+        encrypt_version: EncryptorType::encrypt_version()
+    };
+    let headerdata = bincode::serialize(&header)?;
+    assert!(headerdata.len() == HEADER_SIZE);
+    file.write_all(&headerdata)?;
+
+    let size = stream::encrypt_stream(key, reader, file)?;
+    let attachment = AttachmentType::new(service, name, size);
+    let data = bincode::serialize(&EncryptorType::encrypt(key, &attachment))?;
+    file.write_all(&data)?;
+    file.write_all(&(data.len() as u64).to_le_bytes())?;
+    Ok(attachment)
+}
+
+/// Load the metadata of an attachment, returning it with the size of the encrypted contents.
+fn load_attachment_int<R: Read + Seek>(file: &mut R, key: &[u8]) -> Result<(AttachmentType, u64), APError> {
+    let filelen = file.seek(SeekFrom::End(0))?;
+    file.seek(SeekFrom::Start(0))?;
+    let header = load_header(file)?;
+    if header.spec_type != AttachmentType::spec_type() {
+        return Err(APError::WrongSpecType(AttachmentType::spec_type(), header.spec_type));
+    }
+    if header.encrypt_version != EncryptorType::encrypt_version() {
+        return Err(APError::WrongEncryptVersion(EncryptorType::encrypt_version(), header.encrypt_version));
+    }
+
+    let mut lenbuf = [0u8; 8];
+    file.seek(SeekFrom::End(-8))?;
+    file.read_exact(&mut lenbuf)?;
+    let metalen = u64::from_le_bytes(lenbuf);
+    let streamlen = filelen.checked_sub(HEADER_SIZE as u64 + 8 + metalen)
+        .ok_or(APError::Decryption)?;
+
+    let mut data = vec![0u8; metalen as usize];
+    file.seek(SeekFrom::Start(HEADER_SIZE as u64 + streamlen))?;
+    file.read_exact(&mut data)?;
+    let encoder = bincode::deserialize::<EncryptorType>(&data)?;
+    let plaintext = encoder.decrypt_bytes(key).ok_or(APError::Decryption)?;
+    match AttachmentType::from_binary(&plaintext) {
+        Some(attachment) => match attachment.sanity_check() {
+            true => Ok((attachment, streamlen)),
+            false => Err(APError::PasswordIncorrect)
+        },
+        None => Err(APError::CorruptCiphertext)
+    }
+}
+
+pub fn load_attachment<R: Read + Seek>(file: &mut R, key: &[u8]) -> Result<AttachmentType, APError> {
+    load_attachment_int(file, key).map(|(attachment, _)| attachment)
+}
+
+/// Decrypt the contents of an attachment into writer. Returns the number of bytes written.
+pub fn load_attachment_data<R: Read + Seek, W: Write>(file: &mut R, key: &[u8], writer: &mut W) -> Result<u64, APError> {
+    let (attachment, streamlen) = load_attachment_int(file, key)?;
+    file.seek(SeekFrom::Start(HEADER_SIZE as u64))?;
+    let size = stream::decrypt_stream(key, &mut file.take(streamlen), writer)?;
+    if size != attachment.get_size() {
+        return Err(APError::Decryption);
+    }
+    Ok(size)
+}
+
+/// Headers of the entry files in basedir, None for a file whose header can't be read
+pub fn scan<P: AsRef<Path>>(basedir: P) -> Result<Vec<(PathBuf, Option<Header>)>, APError> {
     let dir = basedir.as_ref();
-    let mut data = [0u8; HEADER_SIZE];
     let mut entries = vec![];
 
     if !dir.exists() {
@@ -166,27 +576,115 @@ pub fn list<P: AsRef<Path>>(basedir: P, by_spec: Option<SpecType>, by_version: O
         if filename.file_type()?.is_dir() {
             continue;
         }
-        if filename.path().starts_with(".") {
+        // Skips the identity and any temp files left by `write_atomic`
+        if filename.file_name().to_string_lossy().starts_with(".") {
             continue;
         }
         let mut file = File::open(filename.path())?;
-        file.read_exact(&mut data)?;
-        let header = bincode::deserialize::<Header>(&data[0..HEADER_SIZE])?;
-        if let Some(spec) = by_spec {
-            if spec != header.spec_type {
-                continue;
-            }
-        }
-        if let Some(version) = by_version {
-            if version != header.spec_version {
-                continue;
-            }
-        }
-        entries.push(filename.path());
+        let header = match load_header(&mut file) {
+            Ok(header) => Some(header),
+            Err(APError::CorruptHeader) => None,
+            Err(e) => return Err(e)
+        };
+        entries.push((filename.path(), header));
     }
     Ok(entries)
 }
 
+/// Entry files in basedir, skipping any with an unreadable header
+pub fn list<P: AsRef<Path>>(basedir: P, by_spec: Option<SpecType>, by_version: Option<u16>) -> Result<Vec<PathBuf>, APError> {
+    Ok(scan(basedir)?
+        .into_iter()
+        .filter_map(|(path, header)| header.map(|h| (path, h)))
+        .filter(|(_, header)| by_spec.map(|s| s == header.spec_type).unwrap_or(true))
+        .filter(|(_, header)| by_version.map(|v| v == header.spec_version).unwrap_or(true))
+        .map(|(path, _)| path)
+        .collect())
+}
+
+/// What `check_file` found wrong with a file, if anything
+#[derive(Debug, Clone, PartialEq)]
+pub enum FileStatus {
+    Valid(SpecType, u16),
+    /// Well formed but doesn't decrypt with this key
+    WrongKey(SpecType, u16),
+    /// Too short, or a spec type or version ap doesn't know
+    CorruptHeader,
+    /// The header is fine but the encrypted part is damaged
+    CorruptCiphertext(SpecType, u16),
+    /// Left behind by an interrupted encryptor upgrade
+    Legacy,
+    /// Not something ap writes, like leftover temp files
+    Unexpected
+}
+
+impl FileStatus {
+    pub fn is_valid(&self) -> bool {
+        matches!(self, Self::Valid(_, _))
+    }
+}
+
+impl fmt::Display for FileStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Valid(spec, version) => write!(f, "ok ({:?} v{})", spec, version),
+            Self::WrongKey(spec, version) => write!(f, "doesn't decrypt with this password ({:?} v{})", spec, version),
+            Self::CorruptHeader => write!(f, "corrupt header"),
+            Self::CorruptCiphertext(spec, version) => write!(f, "corrupt ciphertext ({:?} v{})", spec, version),
+            Self::Legacy => write!(f, "leftover from an interrupted encryptor upgrade"),
+            Self::Unexpected => write!(f, "unexpected file")
+        }
+    }
+}
+
+fn check_attachment<R: Read + Seek>(file: &mut R, key: &[u8], version: u16) -> FileStatus {
+    if version != AttachmentType::version() {
+        return FileStatus::CorruptHeader;
+    }
+    match load_attachment_int(file, key) {
+        Ok(_) => {}
+        Err(APError::Decryption) => return FileStatus::WrongKey(SpecType::Attachment, version),
+        Err(_) => return FileStatus::CorruptCiphertext(SpecType::Attachment, version)
+    }
+    match load_attachment_data(file, key, &mut std::io::sink()) {
+        Ok(_) => FileStatus::Valid(SpecType::Attachment, version),
+        Err(_) => FileStatus::CorruptCiphertext(SpecType::Attachment, version)
+    }
+}
+
+/// Work out whether a file is readable with key without changing it. Only fails
+/// on io errors, anything wrong with the file itself is in the returned status.
+pub fn check_file<R: Read + Seek>(file: &mut R, key: &[u8]) -> Result<FileStatus, APError> {
+    file.seek(SeekFrom::Start(0))?;
+    let header = match load_header(file) {
+        Ok(header) => header,
+        Err(APError::CorruptHeader) => return Ok(FileStatus::CorruptHeader),
+        Err(e) => return Err(e)
+    };
+    let (spec, version) = (header.spec_type, header.spec_version);
+    if header.encrypt_version != EncryptorType::encrypt_version() {
+        return Ok(FileStatus::CorruptHeader);
+    }
+    if spec == SpecType::Attachment {
+        return Ok(check_attachment(file, key, version));
+    }
+
+    let mut data = vec![];
+    file.read_to_end(&mut data)?;
+    let encoder = match bincode::deserialize::<EncryptorType>(&data) {
+        Ok(encoder) => encoder,
+        Err(_) => return Ok(FileStatus::CorruptCiphertext(spec, version))
+    };
+    Ok(match encoder.decrypt_bytes(key) {
+        None => FileStatus::WrongKey(spec, version),
+        Some(plaintext) => match migration::is_sane(spec, version, &plaintext) {
+            Some(true) => FileStatus::Valid(spec, version),
+            Some(false) => FileStatus::CorruptCiphertext(spec, version),
+            None => FileStatus::CorruptHeader
+        }
+    })
+}
+
 impl From<self::identity_v1::IdentityV1> for self::identity_v2::IdentityV2 {
     fn from(value: self::identity_v1::IdentityV1) -> Self {
         let mut kv = vec![];
@@ -225,4 +723,147 @@ impl From<self::service_v1::ServiceEntryV1> for self::service_v2::ServiceEntryV2
             modify_time: value.modify_time,
         }
     }
+}
+
+impl From<self::identity_v2::IdentityV2> for self::identity_v3::IdentityV3 {
+    fn from(value: self::identity_v2::IdentityV2) -> Self {
+        Self {
+            magic: value.magic,
+            name: value.name,
+            key: value.key,
+            kv: value.kv,
+            create_time: value.create_time,
+            modify_time: value.modify_time,
+            tag_max_age: Vec::new(),
+        }
+    }
+}
+
+impl From<self::service_v2::ServiceEntryV2> for self::service_v3::ServiceEntryV3 {
+    fn from(value: self::service_v2::ServiceEntryV2) -> Self {
+        Self {
+            magic: value.magic,
+            name: value.name,
+            pass: value.pass,
+            nonce: value.nonce,
+            kv: value.kv,
+            tags: value.tags,
+            len: value.len,
+            text_mode: value.text_mode,
+            create_time: value.create_time,
+            modify_time: value.modify_time,
+            // V2 didn't track password changes separately, modify time is the best guess
+            pass_time: value.modify_time,
+            max_age: None,
+        }
+    }
+}
+
+impl From<self::identity_v3::IdentityV3> for self::identity_v4::IdentityV4 {
+    fn from(value: self::identity_v3::IdentityV3) -> Self {
+        Self {
+            magic: value.magic,
+            name: value.name,
+            key: value.key,
+            kv: value.kv.into_iter().map(|(k, v)| (k, v, Visibility::Plain)).collect(),
+            create_time: value.create_time,
+            modify_time: value.modify_time,
+            tag_max_age: value.tag_max_age,
+        }
+    }
+}
+
+impl From<self::service_v3::ServiceEntryV3> for self::service_v4::ServiceEntryV4 {
+    fn from(value: self::service_v3::ServiceEntryV3) -> Self {
+        Self {
+            magic: value.magic,
+            name: value.name,
+            pass: value.pass,
+            nonce: value.nonce,
+            kv: value.kv.into_iter().map(|(k, v)| (k, v, Visibility::Plain)).collect(),
+            tags: value.tags,
+            len: value.len,
+            text_mode: value.text_mode,
+            create_time: value.create_time,
+            modify_time: value.modify_time,
+            pass_time: value.pass_time,
+            max_age: value.max_age,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_masking() {
+        let kvs = [("user", "me"), ("pin", "1234"), ("seed", "words")];
+        let mut service = ServiceType::new("mail", "pass", 0, &kvs, &[], 16, &crate::hash::TextMode::AlphaNumeric);
+        let mut id = IdentityType::new("me", &[7; 32], &kvs);
+        for (key, visibility) in [("pin", Visibility::Concealed), ("seed", Visibility::CopyOnly)] {
+            service.set_kv_visibility(key, visibility);
+            id.set_kv_visibility(key, visibility);
+        }
+
+        for (shown, revealed) in [(service.to_string(), service.format(true)), (id.to_string(), id.format(true))] {
+            assert!(shown.contains("  user: me\n") && revealed.contains("  user: me\n"));
+            assert!(shown.contains(&format!("  pin: {}\n", MASK)));
+            assert!(revealed.contains("  pin: 1234\n"));
+            // Copy only values aren't shown even when revealing
+            assert!(shown.contains("  seed: <copy only>\n") && revealed.contains("  seed: <copy only>\n"));
+            assert!(!shown.contains("1234") && !shown.contains("words") && !revealed.contains("words"));
+        }
+    }
+
+    #[test]
+    fn test_write_atomic() {
+        let dir = std::env::temp_dir().join(format!("ap-atomic-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("entry");
+
+        write_atomic(&path, |f| Ok(f.write_all(b"old")?)).unwrap();
+
+        // A failed write leaves the old contents and no temp file behind
+        let res = write_atomic(&path, |f| {
+            f.write_all(b"partial")?;
+            Err::<(), _>(APError::Decryption)
+        });
+        assert!(res.is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), b"old");
+        assert_eq!(read_dir(&dir).unwrap().count(), 1);
+
+        write_atomic(&path, |f| Ok(f.write_all(b"new")?)).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"new");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_check_file() {
+        let dir = std::env::temp_dir().join(format!("ap-check-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("entry");
+        let key = EncryptorType::genkey("pass");
+        let entry = ServiceType::new("svc", "pw", 0, &[("k", "v")], &["tag"], 16, &crate::hash::TextMode::NoWhiteSpace);
+        save_file(&path, &key, &entry).unwrap();
+        let check = || check_file(&mut File::open(&path).unwrap(), &key).unwrap();
+
+        assert_eq!(check(), FileStatus::Valid(SpecType::Service, ServiceType::version()));
+        let wrongkey = EncryptorType::genkey("other");
+        assert_eq!(check_file(&mut File::open(&path).unwrap(), &wrongkey).unwrap(),
+                   FileStatus::WrongKey(SpecType::Service, ServiceType::version()));
+
+        let len = std::fs::metadata(&path).unwrap().len();
+        File::options().write(true).open(&path).unwrap().set_len(len - 4).unwrap();
+        assert_eq!(check(), FileStatus::CorruptCiphertext(SpecType::Service, ServiceType::version()));
+        // Listing skips it rather than failing
+        assert!(list(&dir, None, None).unwrap().len() == 1);
+
+        File::options().write(true).open(&path).unwrap().set_len(3).unwrap();
+        assert_eq!(check(), FileStatus::CorruptHeader);
+        assert!(list(&dir, None, None).unwrap().is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
\ No newline at end of file
@@ -0,0 +1,104 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use super::{AuditOp, Serializable, SpecType, SNAPSHOT_MAGIC};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+/// A service as it was before a change. entry is the file it was saved in, still
+/// encrypted, or None if the change created the service. Snapshots taken by the
+/// same operation share a batch.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SnapshotV1 {
+    pub(super) magic: u32,
+    pub(super) name: String,
+    pub(super) time_ns: u64,
+    pub(super) batch: u64,
+    pub(super) op: AuditOp,
+    pub(super) entry: Option<Vec<u8>>
+}
+
+impl SnapshotV1 {
+    pub fn new(name: &str, op: AuditOp, batch: u64, entry: Option<Vec<u8>>) -> Self {
+        Self {
+            magic: SNAPSHOT_MAGIC,
+            name: name.to_owned(),
+            time_ns: OffsetDateTime::now_utc().unix_timestamp_nanos() as u64,
+            batch,
+            op,
+            entry
+        }
+    }
+
+    pub fn get_op(&self) -> AuditOp {
+        self.op
+    }
+
+    pub fn get_batch(&self) -> u64 {
+        self.batch
+    }
+
+    pub fn get_entry(&self) -> Option<&[u8]> {
+        self.entry.as_deref()
+    }
+
+    /// Orders snapshots more finely than time
+    pub fn time_ns(&self) -> u64 {
+        self.time_ns
+    }
+
+    pub fn time(&self) -> u64 {
+        self.time_ns / NANOS_PER_SECOND
+    }
+
+    pub fn when(&self) -> String {
+        super::timestamp_as_string(self.time())
+    }
+
+    /// How it's picked out on the command line, NAME@TIME
+    pub fn id(&self) -> String {
+        format!("{}@{}", self.name, self.time())
+    }
+
+    pub fn version() -> u16 {
+        1
+    }
+
+    pub fn spec_type() -> SpecType {
+        SpecType::Snapshot
+    }
+}
+
+impl Serializable for SnapshotV1 {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn to_binary(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    fn from_binary(bin: &[u8]) -> Option<Self> {
+        bincode::deserialize(bin).ok()
+    }
+
+    fn sanity_check(&self) -> bool {
+        self.magic == SNAPSHOT_MAGIC
+    }
+
+    fn version(&self) -> u16 {
+        Self::version()
+    }
+
+    fn spec_type(&self) -> SpecType {
+        Self::spec_type()
+    }
+}
+
+impl fmt::Display for SnapshotV1 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} before {}", self.id(), self.when(), self.op)
+    }
+}
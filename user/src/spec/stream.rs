@@ -0,0 +1,167 @@
+use std::io::{ErrorKind, Read, Write};
+
+use aes_gcm::{
+    aead::{Aead, KeyInit},
+    Aes256Gcm, Key, Nonce
+};
+
+use crate::api::APError;
+
+/// Plaintext bytes per encrypted chunk.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+const PREFIX_SIZE: usize = 7;
+
+/*
+ * Chunked AEAD in the style of the STREAM construction. Every chunk is sealed
+ * on its own with nonce = random prefix (7) || chunk counter (4, BE) || last flag (1),
+ * so chunks can't be reordered, dropped or truncated without failing decryption.
+ *
+ * Layout: prefix, then for each chunk a u32 LE ciphertext length followed by
+ * the ciphertext. There is always at least one (possibly empty) final chunk.
+ */
+
+fn chunk_nonce(prefix: &[u8; PREFIX_SIZE], counter: u32, last: bool) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[0..PREFIX_SIZE].copy_from_slice(prefix);
+    nonce[PREFIX_SIZE..PREFIX_SIZE + 4].copy_from_slice(&counter.to_be_bytes());
+    nonce[11] = last as u8;
+    nonce
+}
+
+/// Fill as much of buf as the reader allows, returning how much was read.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<usize, APError> {
+    let mut total = 0;
+    while total < buf.len() {
+        match reader.read(&mut buf[total..]) {
+            Ok(0) => break,
+            Ok(n) => total += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into())
+        }
+    }
+    Ok(total)
+}
+
+/// Encrypt everything from reader into writer. Returns the plaintext size.
+pub fn encrypt_stream<R: Read, W: Write>(key: &[u8], reader: &mut R, writer: &mut W) -> Result<u64, APError> {
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+    let prefix: [u8; PREFIX_SIZE] = rand::random();
+    writer.write_all(&prefix)?;
+
+    let mut current = vec![0u8; CHUNK_SIZE];
+    let mut next = vec![0u8; CHUNK_SIZE];
+    let mut current_len = read_full(reader, &mut current)?;
+    let mut counter: u32 = 0;
+    let mut total = 0u64;
+    loop {
+        // Read ahead so we know whether the current chunk is the last one
+        let next_len = if current_len == CHUNK_SIZE {
+            read_full(reader, &mut next)?
+        } else {
+            0
+        };
+        let last = next_len == 0;
+        let nonce = chunk_nonce(&prefix, counter, last);
+        let ciphertext = cipher.encrypt(Nonce::from_slice(&nonce), &current[0..current_len])
+            .map_err(|_| APError::Decryption)?;
+        writer.write_all(&(ciphertext.len() as u32).to_le_bytes())?;
+        writer.write_all(&ciphertext)?;
+        total += current_len as u64;
+        if last {
+            break;
+        }
+        std::mem::swap(&mut current, &mut next);
+        current_len = next_len;
+        counter = counter.checked_add(1).ok_or(APError::Decryption)?;
+    }
+    writer.flush()?;
+    Ok(total)
+}
+
+fn read_chunk<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>, APError> {
+    let mut lenbuf = [0u8; 4];
+    match read_full(reader, &mut lenbuf)? {
+        0 => return Ok(None),
+        4 => {},
+        _ => return Err(APError::Decryption)
+    }
+    let len = u32::from_le_bytes(lenbuf) as usize;
+    // A chunk is never bigger than a full plaintext chunk plus the tag
+    if len > CHUNK_SIZE + 16 {
+        return Err(APError::Decryption);
+    }
+    let mut chunk = vec![0u8; len];
+    if read_full(reader, &mut chunk)? != len {
+        return Err(APError::Decryption);
+    }
+    Ok(Some(chunk))
+}
+
+/// Decrypt a stream written by `encrypt_stream` into writer. Returns the plaintext size.
+pub fn decrypt_stream<R: Read, W: Write>(key: &[u8], reader: &mut R, writer: &mut W) -> Result<u64, APError> {
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+    let mut prefix = [0u8; PREFIX_SIZE];
+    if read_full(reader, &mut prefix)? != PREFIX_SIZE {
+        return Err(APError::Decryption);
+    }
+
+    let mut counter: u32 = 0;
+    let mut total = 0u64;
+    let mut current = read_chunk(reader)?.ok_or(APError::Decryption)?;
+    loop {
+        let next = read_chunk(reader)?;
+        let last = next.is_none();
+        let nonce = chunk_nonce(&prefix, counter, last);
+        let plaintext = cipher.decrypt(Nonce::from_slice(&nonce), current.as_slice())
+            .map_err(|_| APError::Decryption)?;
+        writer.write_all(&plaintext)?;
+        total += plaintext.len() as u64;
+        match next {
+            Some(chunk) => current = chunk,
+            None => break
+        }
+        counter = counter.checked_add(1).ok_or(APError::Decryption)?;
+    }
+    writer.flush()?;
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(size: usize) {
+        let key = [7u8; 32];
+        let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+        let mut encrypted = vec![];
+        assert_eq!(encrypt_stream(&key, &mut data.as_slice(), &mut encrypted).unwrap(), size as u64);
+        let mut decrypted = vec![];
+        assert_eq!(decrypt_stream(&key, &mut encrypted.as_slice(), &mut decrypted).unwrap(), size as u64);
+        assert_eq!(data, decrypted);
+    }
+
+    #[test]
+    fn test_stream_roundtrip() {
+        roundtrip(0);
+        roundtrip(10);
+        roundtrip(CHUNK_SIZE);
+        roundtrip(CHUNK_SIZE * 2 + 5);
+    }
+
+    #[test]
+    fn test_stream_truncated() {
+        let key = [7u8; 32];
+        let data = vec![1u8; CHUNK_SIZE * 2];
+        let mut encrypted = vec![];
+        encrypt_stream(&key, &mut data.as_slice(), &mut encrypted).unwrap();
+
+        // Dropping the final chunk must not decrypt as a shorter file
+        let truncated = &encrypted[0..PREFIX_SIZE + 4 + CHUNK_SIZE + 16];
+        let mut out = vec![];
+        assert!(decrypt_stream(&key, &mut &truncated[..], &mut out).is_err());
+
+        let wrongkey = [8u8; 32];
+        let mut out = vec![];
+        assert!(decrypt_stream(&wrongkey, &mut encrypted.as_slice(), &mut out).is_err());
+    }
+}
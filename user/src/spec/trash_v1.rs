@@ -0,0 +1,95 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use super::{Serializable, SpecType, TRASH_MAGIC};
+
+/// Record of a deleted service. The service and its attachments are moved
+/// unchanged into the same trash directory as this record.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct TrashV1 {
+    pub(super) magic: u32,
+    pub(super) name: String,
+    pub(super) attachments: Vec<String>,
+    pub(super) delete_time: u64
+}
+
+impl TrashV1 {
+    pub fn new(name: &str, attachments: &[&str]) -> Self {
+        Self {
+            magic: TRASH_MAGIC,
+            name: name.to_owned(),
+            attachments: attachments.iter().map(|a| (*a).to_owned()).collect(),
+            delete_time: super::now()
+        }
+    }
+
+    /// Name used to derive the trash directory, unique per deletion of a service
+    pub fn storage_name(&self) -> String {
+        format!("{}\0trash\0{}", self.name, self.delete_time)
+    }
+
+    pub fn get_attachments(&self) -> &[String] {
+        &self.attachments
+    }
+
+    pub fn delete_time(&self) -> u64 {
+        self.delete_time
+    }
+
+    /// Seconds since the service was deleted
+    pub fn age(&self) -> u64 {
+        super::now().saturating_sub(self.delete_time)
+    }
+
+    pub fn deleted(&self) -> String {
+        super::timestamp_as_string(self.delete_time)
+    }
+
+    pub fn version() -> u16 {
+        1
+    }
+
+    pub fn spec_type() -> SpecType {
+        SpecType::Trash
+    }
+}
+
+impl Serializable for TrashV1 {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn to_binary(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    fn from_binary(bin: &[u8]) -> Option<Self> {
+        match bincode::deserialize(bin) {
+            Ok(entry) => Some(entry),
+            Err(_) => None
+        }
+    }
+
+    fn sanity_check(&self) -> bool {
+        self.magic == TRASH_MAGIC
+    }
+
+    fn version(&self) -> u16 {
+        Self::version()
+    }
+
+    fn spec_type(&self) -> SpecType {
+        Self::spec_type()
+    }
+}
+
+impl fmt::Display for TrashV1 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&format!("{} (deleted {})", self.name, self.deleted()))?;
+        if !self.attachments.is_empty() {
+            f.write_str(&format!(", attachments: {}", self.attachments.join(", ")))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,84 @@
+use std::{collections::BTreeMap, fs::File, io::{Read, Write}, path::{Path, PathBuf}};
+
+use serde::{Deserialize, Serialize};
+
+use crate::api::APError;
+use crate::spec::write_atomic;
+
+use super::{Op, Storage};
+
+const CONTAINER_MAGIC: u32 = 0x5a1c0e7b;
+const CONTAINER_VERSION: u16 = 1;
+
+#[derive(Deserialize, Serialize)]
+struct Container {
+    magic: u32,
+    version: u16,
+    records: BTreeMap<String, Vec<u8>>
+}
+
+/*
+ * A whole vault in one file, for syncing or carrying around a single file instead of
+ * a directory. Records are already encrypted so the container itself is plain bincode.
+ * It's read once when opened and every batch rewrites it through `write_atomic`, which
+ * makes batches atomic for free.
+ */
+#[derive(Debug)]
+pub struct ContainerStorage {
+    path: PathBuf,
+    records: BTreeMap<String, Vec<u8>>
+}
+
+impl ContainerStorage {
+    /// Open the container at path, which starts out empty if there's no file yet
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, APError> {
+        let path = path.as_ref().to_owned();
+        if !path.exists() {
+            return Ok(Self { path, records: BTreeMap::new() });
+        }
+        let mut data = vec![];
+        File::open(&path)?.read_to_end(&mut data)?;
+        let container = bincode::deserialize::<Container>(&data)
+            .map_err(|_| APError::CorruptHeader)?;
+        if container.magic != CONTAINER_MAGIC {
+            return Err(APError::CorruptHeader);
+        }
+        if container.version != CONTAINER_VERSION {
+            return Err(APError::VersionTooOld);
+        }
+        Ok(Self { path, records: container.records })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Storage for ContainerStorage {
+    fn get(&self, name: &str) -> Result<Option<Vec<u8>>, APError> {
+        Ok(self.records.get(name).cloned())
+    }
+
+    fn list(&self) -> Result<Vec<String>, APError> {
+        Ok(self.records.keys().cloned().collect())
+    }
+
+    fn batch(&mut self, ops: Vec<Op>) -> Result<(), APError> {
+        let mut records = self.records.clone();
+        for op in ops {
+            match op {
+                Op::Put(name, data) => records.insert(name, data),
+                Op::Delete(name) => records.remove(&name)
+            };
+        }
+        let container = Container {
+            magic: CONTAINER_MAGIC,
+            version: CONTAINER_VERSION,
+            records
+        };
+        let data = bincode::serialize(&container)?;
+        write_atomic(&self.path, |file| Ok(file.write_all(&data)?))?;
+        self.records = container.records;
+        Ok(())
+    }
+}
@@ -0,0 +1,179 @@
+use std::{fs::{create_dir_all, read_dir, remove_file, rename, File}, io::{Read, Write}, path::{Path, PathBuf}};
+
+use crate::api::APError;
+use crate::spec::{config_path, lock_path, sync_dir, TMP_PREFIX};
+
+use super::{Op, Storage};
+
+/*
+ * One file per record directly under a directory, the layout vaults have always had.
+ * Subdirectories like the trash and the lock file aren't records and aren't listed.
+ */
+#[derive(Debug, Clone)]
+pub struct DirStorage {
+    dir: PathBuf
+}
+
+impl DirStorage {
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        Self { dir: dir.as_ref().to_owned() }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Path of a record, refusing names that would escape the directory
+    pub fn path(&self, name: &str) -> Result<PathBuf, APError> {
+        if name.is_empty() || name.contains('/') || name.contains('\\') || name == "." || name == ".." {
+            return Err(std::io::Error::from(std::io::ErrorKind::InvalidInput).into());
+        }
+        Ok(self.dir.join(name))
+    }
+
+    fn temp_path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}{}.{:08x}", TMP_PREFIX, name, rand::random::<u32>()))
+    }
+
+    fn stage(&self, name: &str, data: &[u8]) -> Result<PathBuf, APError> {
+        let tmppath = self.temp_path(name);
+        let res = File::options()
+            .write(true)
+            .create_new(true)
+            .open(&tmppath)
+            .and_then(|mut file| {
+                file.write_all(data)?;
+                file.sync_all()
+            });
+        if let Err(e) = res {
+            let _ = remove_file(&tmppath);
+            return Err(e.into());
+        }
+        Ok(tmppath)
+    }
+
+    /// Move the record at path out of the way so it can be put back, None if there's none
+    fn set_aside(&self, name: &str, path: &Path) -> Result<Option<PathBuf>, APError> {
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let aside = self.temp_path(name);
+        rename(path, &aside)?;
+        Ok(Some(aside))
+    }
+
+    /// Apply each op, recording what was at its path before so it can be rolled back
+    fn apply(&self,
+             ops: Vec<Op>,
+             staged: &mut std::vec::IntoIter<PathBuf>,
+             applied: &mut Vec<(PathBuf, Option<PathBuf>)>) -> Result<(), APError> {
+        for op in ops {
+            match op {
+                Op::Put(name, _) => {
+                    let tmppath = staged.next().unwrap();
+                    let dest = self.dir.join(&name);
+                    let aside = self.set_aside(&name, &dest).inspect_err(|_| {
+                        let _ = remove_file(&tmppath);
+                    })?;
+                    if let Err(e) = rename(&tmppath, &dest) {
+                        let _ = remove_file(&tmppath);
+                        applied.extend(aside.map(|aside| (dest, Some(aside))));
+                        return Err(e.into());
+                    }
+                    applied.push((dest, aside));
+                }
+                Op::Delete(name) => {
+                    let path = self.dir.join(&name);
+                    match self.set_aside(&name, &path)? {
+                        Some(aside) => applied.push((path, Some(aside))),
+                        // Something that isn't a record is in the way
+                        None if path.exists() => return Err(std::io::Error::from(std::io::ErrorKind::InvalidInput).into()),
+                        None => {}
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Storage for DirStorage {
+    fn get(&self, name: &str) -> Result<Option<Vec<u8>>, APError> {
+        let path = self.path(name)?;
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let mut data = vec![];
+        File::open(path)?.read_to_end(&mut data)?;
+        Ok(Some(data))
+    }
+
+    fn list(&self) -> Result<Vec<String>, APError> {
+        let mut names = vec![];
+        if !self.dir.exists() {
+            return Ok(names);
+        }
+        let lockfile = lock_path(&self.dir);
+        let configfile = config_path(&self.dir);
+        for entry in read_dir(&self.dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !entry.file_type()?.is_file() || entry.path() == lockfile || entry.path() == configfile || name.starts_with(TMP_PREFIX) {
+                continue;
+            }
+            names.push(name);
+        }
+        Ok(names)
+    }
+
+    /// Every put is written to a temp file first, so a failure before they're renamed
+    /// into place leaves the directory as it was. Records a put replaces or a delete
+    /// removes are set aside until the whole batch is in, and a failure part way puts
+    /// them back. A crash part way can still leave part of the batch applied, each
+    /// record on its own is never partial.
+    fn batch(&mut self, ops: Vec<Op>) -> Result<(), APError> {
+        for op in ops.iter() {
+            match op {
+                Op::Put(name, _) | Op::Delete(name) => self.path(name)?
+            };
+        }
+        create_dir_all(&self.dir)?;
+        let mut staged = vec![];
+        for op in ops.iter() {
+            if let Op::Put(name, data) = op {
+                match self.stage(name, data) {
+                    Ok(tmppath) => staged.push(tmppath),
+                    Err(e) => {
+                        for tmppath in staged {
+                            let _ = remove_file(tmppath);
+                        }
+                        return Err(e);
+                    }
+                }
+            }
+        }
+
+        let mut staged = staged.into_iter();
+        let mut applied = vec![];
+        if let Err(e) = self.apply(ops, &mut staged, &mut applied) {
+            for tmppath in staged {
+                let _ = remove_file(tmppath);
+            }
+            // Newest first, so a record touched twice ends up as it was to begin with
+            for (path, aside) in applied.into_iter().rev() {
+                let _ = match aside {
+                    Some(aside) => rename(aside, &path),
+                    None => remove_file(&path)
+                };
+            }
+            return Err(e);
+        }
+        // The batch is in, a set aside record left behind is only a stray temp file
+        for (_, aside) in applied {
+            if let Some(aside) = aside {
+                let _ = remove_file(aside);
+            }
+        }
+        sync_dir(&self.dir)
+    }
+}
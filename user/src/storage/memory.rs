@@ -0,0 +1,37 @@
+use std::collections::BTreeMap;
+
+use crate::api::APError;
+
+use super::{Op, Storage};
+
+/// Keeps everything in memory, for tests and scratch vaults
+#[derive(Debug, Clone, Default)]
+pub struct MemStorage {
+    records: BTreeMap<String, Vec<u8>>
+}
+
+impl MemStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Storage for MemStorage {
+    fn get(&self, name: &str) -> Result<Option<Vec<u8>>, APError> {
+        Ok(self.records.get(name).cloned())
+    }
+
+    fn list(&self) -> Result<Vec<String>, APError> {
+        Ok(self.records.keys().cloned().collect())
+    }
+
+    fn batch(&mut self, ops: Vec<Op>) -> Result<(), APError> {
+        for op in ops {
+            match op {
+                Op::Put(name, data) => self.records.insert(name, data),
+                Op::Delete(name) => self.records.remove(&name)
+            };
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,136 @@
+use crate::api::APError;
+
+pub mod dir;
+pub mod memory;
+pub mod container;
+
+pub use dir::DirStorage;
+pub use memory::MemStorage;
+pub use container::ContainerStorage;
+
+/*
+ * Where a vault keeps its encrypted records. Records are opaque blobs under flat names,
+ * the hashed filenames from `Encryptor::filename` plus the identity, so a backend never
+ * sees anything that isn't already encrypted.
+ */
+
+#[derive(Debug, Clone)]
+pub enum Op {
+    Put(String, Vec<u8>),
+    Delete(String)
+}
+
+pub trait Storage {
+    /// None if there's no record called name
+    fn get(&self, name: &str) -> Result<Option<Vec<u8>>, APError>;
+
+    /// Names of every record, in no particular order
+    fn list(&self) -> Result<Vec<String>, APError>;
+
+    /// Apply every op or none of them, a failure part way leaves the records as they
+    /// were. Deleting a record that isn't there is fine.
+    fn batch(&mut self, ops: Vec<Op>) -> Result<(), APError>;
+
+    fn exists(&self, name: &str) -> Result<bool, APError> {
+        Ok(self.get(name)?.is_some())
+    }
+
+    fn put(&mut self, name: &str, data: Vec<u8>) -> Result<(), APError> {
+        self.batch(vec![Op::Put(name.to_owned(), data)])
+    }
+
+    fn delete(&mut self, name: &str) -> Result<(), APError> {
+        self.batch(vec![Op::Delete(name.to_owned())])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("ap-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn check_storage<S: Storage>(storage: &mut S) {
+        assert!(storage.list().unwrap().is_empty());
+        assert_eq!(storage.get("a").unwrap(), None);
+
+        storage.put("a", vec![1, 2, 3]).unwrap();
+        storage.put("b", vec![4]).unwrap();
+        storage.put("a", vec![5]).unwrap();
+        assert_eq!(storage.get("a").unwrap(), Some(vec![5]));
+        let mut names = storage.list().unwrap();
+        names.sort();
+        assert_eq!(names, vec!["a", "b"]);
+
+        storage.batch(vec![
+            Op::Delete("a".to_owned()),
+            Op::Put("c".to_owned(), vec![6]),
+            Op::Delete("missing".to_owned())
+        ]).unwrap();
+        assert!(!storage.exists("a").unwrap());
+        assert_eq!(storage.get("c").unwrap(), Some(vec![6]));
+
+        storage.delete("b").unwrap();
+        storage.delete("c").unwrap();
+        assert!(storage.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_memory_storage() {
+        check_storage(&mut MemStorage::new());
+    }
+
+    #[test]
+    fn test_dir_storage() {
+        let dir = temp_dir("dir-storage");
+        check_storage(&mut DirStorage::new(&dir));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_dir_storage_failed_batch() {
+        let dir = temp_dir("dir-storage-failed");
+        let mut storage = DirStorage::new(&dir);
+        storage.put("a", vec![1]).unwrap();
+        storage.put("b", vec![2]).unwrap();
+        // A directory where a record goes makes the last put fail after the rest are in
+        std::fs::create_dir_all(dir.join("d").join("in the way")).unwrap();
+        assert!(storage.batch(vec![
+            Op::Put("a".to_owned(), vec![3]),
+            Op::Delete("b".to_owned()),
+            Op::Put("c".to_owned(), vec![4]),
+            Op::Put("a".to_owned(), vec![5]),
+            Op::Put("d".to_owned(), vec![6])
+        ]).is_err());
+
+        assert_eq!(storage.get("a").unwrap(), Some(vec![1]));
+        assert_eq!(storage.get("b").unwrap(), Some(vec![2]));
+        assert!(!storage.exists("c").unwrap());
+        let mut files: Vec<String> = std::fs::read_dir(&dir).unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        files.sort();
+        assert_eq!(files, vec!["a", "b", "d"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_container_storage() {
+        let dir = temp_dir("container-storage");
+        let path = dir.join("vault.apc");
+        check_storage(&mut ContainerStorage::open(&path).unwrap());
+
+        // Everything is in the one file and survives reopening
+        let mut storage = ContainerStorage::open(&path).unwrap();
+        storage.put("a", vec![7]).unwrap();
+        let storage = ContainerStorage::open(&path).unwrap();
+        assert_eq!(storage.get("a").unwrap(), Some(vec![7]));
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
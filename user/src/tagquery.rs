@@ -0,0 +1,360 @@
+use std::fmt;
+
+use thiserror::Error;
+
+use crate::bitmap::Bitmap;
+use crate::tagtree;
+
+/*
+ * Boolean queries over tags, like `work and not legacy` or `(aws or gcp) and prod`.
+ * `not` binds tightest, then `and`, then `or`. Keywords are case insensitive and a tag
+ * that clashes with one, or has spaces or parens in it, can be written in double quotes.
+ * An empty query matches everything. A tag matches services tagged with anything
+ * under it in the hierarchy too, so `work` takes in `work/aws`.
+ */
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum TagQueryError {
+    #[error("Unexpected '{0}' in tag query")]
+    Unexpected(String),
+    #[error("Tag query ended early")]
+    UnexpectedEnd,
+    #[error("Missing closing quote in tag query")]
+    UnterminatedQuote
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr<T> {
+    All,
+    Tag(T),
+    Not(Box<Expr<T>>),
+    And(Box<Expr<T>>, Box<Expr<T>>),
+    Or(Box<Expr<T>>, Box<Expr<T>>)
+}
+
+/// A query as written, with tags by name
+pub type TagQuery = Expr<String>;
+
+/// A query with each tag replaced by the indices in a `Bitmap` of the tags under it
+pub type CompiledQuery = Expr<Vec<usize>>;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    And,
+    Or,
+    Not,
+    Tag(String)
+}
+
+fn tokenize(query: &str) -> Result<Vec<Token>, TagQueryError> {
+    let mut tokens = vec![];
+    let mut chars = query.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '(' => tokens.push(Token::Open),
+            ')' => tokens.push(Token::Close),
+            '"' => {
+                let mut tag = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some(c) => tag.push(c),
+                        None => return Err(TagQueryError::UnterminatedQuote)
+                    }
+                }
+                tokens.push(Token::Tag(tag));
+            }
+            c => {
+                let mut word = c.to_string();
+                while let Some(c) = chars.peek() {
+                    if c.is_whitespace() || *c == '(' || *c == ')' || *c == '"' {
+                        break;
+                    }
+                    word.push(*c);
+                    chars.next();
+                }
+                tokens.push(match word.to_lowercase().as_str() {
+                    "and" => Token::And,
+                    "or" => Token::Or,
+                    "not" => Token::Not,
+                    _ => Token::Tag(word)
+                });
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn or(&mut self) -> Result<TagQuery, TagQueryError> {
+        let mut expr = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<TagQuery, TagQueryError> {
+        let mut expr = self.unary()?;
+        while self.peek() == Some(&Token::And) {
+            self.next();
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<TagQuery, TagQueryError> {
+        match self.next() {
+            Some(Token::Not) => Ok(Expr::Not(Box::new(self.unary()?))),
+            Some(Token::Tag(tag)) => Ok(Expr::Tag(tag)),
+            Some(Token::Open) => {
+                let expr = self.or()?;
+                match self.next() {
+                    Some(Token::Close) => Ok(expr),
+                    Some(token) => Err(unexpected(&token)),
+                    None => Err(TagQueryError::UnexpectedEnd)
+                }
+            }
+            Some(token) => Err(unexpected(&token)),
+            None => Err(TagQueryError::UnexpectedEnd)
+        }
+    }
+}
+
+fn unexpected(token: &Token) -> TagQueryError {
+    TagQueryError::Unexpected(match token {
+        Token::Open => "(".to_owned(),
+        Token::Close => ")".to_owned(),
+        Token::And => "and".to_owned(),
+        Token::Or => "or".to_owned(),
+        Token::Not => "not".to_owned(),
+        Token::Tag(tag) => tag.clone()
+    })
+}
+
+impl TagQuery {
+    pub fn parse(query: &str) -> Result<Self, TagQueryError> {
+        let tokens = tokenize(query)?;
+        if tokens.is_empty() {
+            return Ok(Expr::All);
+        }
+        let mut parser = Parser { tokens, pos: 0 };
+        let expr = parser.or()?;
+        match parser.next() {
+            None => Ok(expr),
+            Some(token) => Err(unexpected(&token))
+        }
+    }
+
+    fn fold<S: AsRef<str>>(tags: &[S], join: fn(Box<TagQuery>, Box<TagQuery>) -> TagQuery) -> Self {
+        tags.iter()
+            .map(|t| Expr::Tag(t.as_ref().to_owned()))
+            .reduce(|q1, q2| join(Box::new(q1), Box::new(q2)))
+            .unwrap_or(Expr::All)
+    }
+
+    /// Services with every one of tags, everything if there are none
+    pub fn all_of<S: AsRef<str>>(tags: &[S]) -> Self {
+        Self::fold(tags, Expr::And)
+    }
+
+    /// Services with any of tags, everything if there are none
+    pub fn any_of<S: AsRef<str>>(tags: &[S]) -> Self {
+        Self::fold(tags, Expr::Or)
+    }
+
+    pub fn matches<S: AsRef<str>>(&self, tags: &[S]) -> bool {
+        match self {
+            Expr::All => true,
+            Expr::Tag(tag) => tags.iter().any(|t| tagtree::within(t.as_ref(), tag)),
+            Expr::Not(q) => !q.matches(tags),
+            Expr::And(q1, q2) => q1.matches(tags) && q2.matches(tags),
+            Expr::Or(q1, q2) => q1.matches(tags) || q2.matches(tags)
+        }
+    }
+
+    /// Resolve tags to bitmap indices once, so checking each service is just bit tests
+    pub fn compile<F: Fn(&str) -> Vec<usize>>(&self, index: &F) -> CompiledQuery {
+        match self {
+            Expr::All => Expr::All,
+            Expr::Tag(tag) => Expr::Tag(index(tag)),
+            Expr::Not(q) => Expr::Not(Box::new(q.compile(index))),
+            Expr::And(q1, q2) => Expr::And(Box::new(q1.compile(index)), Box::new(q2.compile(index))),
+            Expr::Or(q1, q2) => Expr::Or(Box::new(q1.compile(index)), Box::new(q2.compile(index)))
+        }
+    }
+}
+
+impl CompiledQuery {
+    pub fn matches_bitmap(&self, bmp: &Bitmap) -> bool {
+        match self {
+            Expr::All => true,
+            Expr::Tag(idxs) => idxs.iter().any(|idx| bmp.check_set(*idx)),
+            Expr::Not(q) => !q.matches_bitmap(bmp),
+            Expr::And(q1, q2) => q1.matches_bitmap(bmp) && q2.matches_bitmap(bmp),
+            Expr::Or(q1, q2) => q1.matches_bitmap(bmp) || q2.matches_bitmap(bmp)
+        }
+    }
+}
+
+/// Every tag in a set of services, each with a position in their tag bitmaps
+#[derive(Debug, Clone, Default)]
+pub struct TagIndex {
+    tags: Vec<String>
+}
+
+impl TagIndex {
+    pub fn new<S: AsRef<str>, I: IntoIterator<Item=S>>(tags: I) -> Self {
+        let mut tags: Vec<String> = tags.into_iter().map(|t| t.as_ref().to_owned()).collect();
+        tags.sort();
+        tags.dedup();
+        Self { tags }
+    }
+
+    /// Sorted and without duplicates, a tag's position here is its bit
+    pub fn tags(&self) -> &[String] {
+        &self.tags
+    }
+
+    pub fn index(&self, tag: &str) -> Option<usize> {
+        self.tags.binary_search_by(|t| t.as_str().cmp(tag)).ok()
+    }
+
+    pub fn bitmap<S: AsRef<str>>(&self, tags: &[S]) -> Bitmap {
+        let mut bmp = Bitmap::new(self.tags.len());
+        for idx in tags.iter().filter_map(|t| self.index(t.as_ref())) {
+            bmp.set(idx);
+        }
+        bmp
+    }
+
+    /// Indices of tag and every tag under it
+    pub fn within(&self, tag: &str) -> Vec<usize> {
+        self.tags.iter()
+            .enumerate()
+            .filter_map(|(idx, t)| tagtree::within(t, tag).then_some(idx))
+            .collect()
+    }
+
+    pub fn compile(&self, query: &TagQuery) -> CompiledQuery {
+        query.compile(&|tag| self.within(tag))
+    }
+}
+
+fn write_tag(f: &mut fmt::Formatter, tag: &str) -> fmt::Result {
+    let plain = !tag.is_empty()
+        && !tag.chars().any(|c| c.is_whitespace() || c == '(' || c == ')' || c == '"')
+        && !["and", "or", "not"].contains(&tag.to_lowercase().as_str());
+    match plain {
+        true => f.write_str(tag),
+        false => write!(f, "\"{}\"", tag)
+    }
+}
+
+/// Parses back to the same query, with parens only where they're needed
+impl fmt::Display for TagQuery {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expr::All => Ok(()),
+            Expr::Tag(tag) => write_tag(f, tag),
+            Expr::Not(q) => match **q {
+                Expr::And(_, _) | Expr::Or(_, _) => write!(f, "not ({})", q),
+                _ => write!(f, "not {}", q)
+            },
+            Expr::And(q1, q2) => {
+                for (idx, q) in [q1, q2].iter().enumerate() {
+                    if idx > 0 {
+                        f.write_str(" and ")?;
+                    }
+                    match ***q {
+                        // The right hand side of `and` is a unary in the grammar
+                        Expr::Or(_, _) => write!(f, "({})", q)?,
+                        Expr::And(_, _) if idx == 1 => write!(f, "({})", q)?,
+                        _ => write!(f, "{}", q)?
+                    }
+                }
+                Ok(())
+            }
+            Expr::Or(q1, q2) => match **q2 {
+                Expr::Or(_, _) => write!(f, "{} or ({})", q1, q2),
+                _ => write!(f, "{} or {}", q1, q2)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let tag = |t: &str| Box::new(Expr::Tag(t.to_owned()));
+        assert_eq!(TagQuery::parse("  ").unwrap(), Expr::All);
+        assert_eq!(TagQuery::parse("work AND not legacy").unwrap(),
+                   Expr::And(tag("work"), Box::new(Expr::Not(tag("legacy")))));
+        assert_eq!(TagQuery::parse("(aws or gcp) and prod").unwrap(),
+                   Expr::And(Box::new(Expr::Or(tag("aws"), tag("gcp"))), tag("prod")));
+        assert_eq!(TagQuery::parse("a or b and c").unwrap(),
+                   Expr::Or(tag("a"), Box::new(Expr::And(tag("b"), tag("c")))));
+        assert_eq!(TagQuery::parse("\"and\" or \"my tag\"").unwrap(), Expr::Or(tag("and"), tag("my tag")));
+
+        assert_eq!(TagQuery::parse("work legacy"), Err(TagQueryError::Unexpected("legacy".to_owned())));
+        assert_eq!(TagQuery::parse("(work"), Err(TagQueryError::UnexpectedEnd));
+        assert_eq!(TagQuery::parse("work and"), Err(TagQueryError::UnexpectedEnd));
+        assert_eq!(TagQuery::parse("\"work"), Err(TagQueryError::UnterminatedQuote));
+
+        for query in ["not (a or b) and c", "a and (b and c)", "(a or b) and not \"c d\"", "a or (b or c)"] {
+            let parsed = TagQuery::parse(query).unwrap();
+            assert_eq!(parsed.to_string(), query);
+            assert_eq!(TagQuery::parse(&parsed.to_string()).unwrap(), parsed);
+        }
+    }
+
+    #[test]
+    fn test_matches() {
+        let tags = ["work", "aws"];
+        let query = TagQuery::parse("(aws or gcp) and not legacy").unwrap();
+        assert!(query.matches(&tags));
+        assert!(!query.matches(&["aws", "legacy"]));
+        assert!(TagQuery::all_of::<&str>(&[]).matches::<&str>(&[]));
+        assert!(!TagQuery::all_of(&["work", "gcp"]).matches(&tags));
+        assert!(TagQuery::any_of(&["work", "gcp"]).matches(&tags));
+
+        // Same answers from the bitmap, gcp and legacy aren't in the index at all
+        let index = TagIndex::new(["work", "aws", "work"]);
+        assert_eq!(index.tags(), ["aws", "work"]);
+        let mut bmp = index.bitmap(&["aws"]);
+        assert!(index.compile(&query).matches_bitmap(&bmp));
+        assert!(!index.compile(&TagQuery::parse("work or legacy").unwrap()).matches_bitmap(&bmp));
+        bmp.set(1);
+        assert!(index.compile(&TagQuery::parse("work and not gcp").unwrap()).matches_bitmap(&bmp));
+
+        // Tags take in everything under them
+        let index = TagIndex::new(["work/aws", "work/db", "workshop"]);
+        let query = index.compile(&TagQuery::parse("work and not work/db").unwrap());
+        assert!(query.matches_bitmap(&index.bitmap(&["work/aws"])));
+        assert!(!query.matches_bitmap(&index.bitmap(&["work/aws", "work/db"])));
+        assert!(!query.matches_bitmap(&index.bitmap(&["workshop"])));
+        assert!(TagQuery::parse("work").unwrap().matches(&["work/aws"]));
+    }
+}
@@ -0,0 +1,111 @@
+/*
+ * Tags with `/` in them form a hierarchy, `work/aws` and `work/db` are both under
+ * `work`. Filtering on a tag takes in everything under it, and the tree gives the
+ * folders along with how many services are in each.
+ */
+
+pub const SEPARATOR: char = '/';
+
+/// Whether tag is ancestor or somewhere under it
+pub fn within(tag: &str, ancestor: &str) -> bool {
+    tag.strip_prefix(ancestor)
+        .map(|rest| rest.is_empty() || rest.starts_with(SEPARATOR))
+        .unwrap_or(false)
+}
+
+/// The tag itself and each folder above it, `a/b/c` gives `a`, `a/b` and `a/b/c`
+pub fn ancestors(tag: &str) -> impl Iterator<Item=&str> {
+    tag.match_indices(SEPARATOR)
+        .map(move |(idx, _)| &tag[..idx])
+        .chain(std::iter::once(tag))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TagNode {
+    /// Last part of the path
+    pub name: String,
+    /// Full tag, what filters use
+    pub path: String,
+    /// Services tagged with this or anything under it
+    pub count: usize,
+    pub children: Vec<TagNode>
+}
+
+impl TagNode {
+    fn new(path: &str) -> Self {
+        let name = path.rsplit(SEPARATOR).next().unwrap_or(path).to_owned();
+        Self { name, path: path.to_owned(), count: 0, children: vec![] }
+    }
+
+    fn child(&mut self, path: &str) -> &mut TagNode {
+        let idx = match self.children.iter().position(|c| c.path == path) {
+            Some(idx) => idx,
+            None => {
+                self.children.push(TagNode::new(path));
+                self.children.len() - 1
+            }
+        };
+        &mut self.children[idx]
+    }
+
+    fn sort(&mut self) {
+        self.children.sort_by(|c1, c2| c1.name.cmp(&c2.name));
+        for child in self.children.iter_mut() {
+            child.sort();
+        }
+    }
+
+    /// This node and everything under it, parents before their children
+    pub fn iter(&self) -> Box<dyn Iterator<Item=&TagNode> + '_> {
+        Box::new(std::iter::once(self).chain(self.children.iter().flat_map(|c| c.iter())))
+    }
+}
+
+/// The folders at the top of the hierarchy, given each service's tags
+pub fn build<'a, I: IntoIterator<Item=&'a [String]>>(services: I) -> Vec<TagNode> {
+    let mut root = TagNode::new("");
+    for tags in services {
+        // Each service counts once per folder, however many of its tags are in there
+        let mut paths: Vec<&str> = tags.iter().flat_map(|t| ancestors(t)).collect();
+        paths.sort();
+        paths.dedup();
+        for path in paths {
+            let mut node = &mut root;
+            for ancestor in ancestors(path) {
+                node = node.child(ancestor);
+            }
+            node.count += 1;
+        }
+    }
+    root.sort();
+    root.children
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tag_tree() {
+        assert!(within("work/aws", "work"));
+        assert!(within("work", "work"));
+        assert!(!within("workshop", "work"));
+        assert!(!within("work", "work/aws"));
+        assert_eq!(ancestors("a/b/c").collect::<Vec<&str>>(), vec!["a", "a/b", "a/b/c"]);
+
+        let services = vec![
+            vec!["work/aws".to_owned(), "work/db".to_owned()],
+            vec!["work".to_owned()],
+            vec!["home".to_owned(), "work/aws/prod".to_owned()]
+        ];
+        let tree = build(services.iter().map(|t| t.as_slice()));
+        let flat: Vec<(&str, usize)> = tree.iter()
+            .flat_map(|node| node.iter())
+            .map(|node| (node.path.as_str(), node.count))
+            .collect();
+        assert_eq!(flat, vec![
+            ("home", 1), ("work", 3), ("work/aws", 2), ("work/aws/prod", 1), ("work/db", 1)
+        ]);
+        assert_eq!(tree[1].children[0].name, "aws");
+    }
+}
@@ -0,0 +1,55 @@
+use std::{path::{Path, PathBuf}, sync::{Mutex, MutexGuard}};
+
+use crate::api;
+use crate::spec::PASS_BASE_ENVVAR;
+
+/*
+ * A vault of its own for tests that go through the api. Where the vault is comes from
+ * the environment, which every test in the process shares, so only one of these is
+ * open at a time and the others wait their turn.
+ */
+
+pub const PASS: &str = "test-pass";
+
+static OPEN: Mutex<()> = Mutex::new(());
+
+pub struct TestVault {
+    pub dir: PathBuf,
+    _open: MutexGuard<'static, ()>
+}
+
+/// A directory for a test vault, emptied
+pub fn dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("ap-vault-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
+
+/// Point the api at dir, only while a `TestVault` is open
+pub fn use_dir(dir: &Path) {
+    std::env::set_var(PASS_BASE_ENVVAR, dir);
+}
+
+impl TestVault {
+    /// Nothing there yet, not even the directory
+    pub fn empty(name: &str) -> Self {
+        // A test failing while it had the vault open doesn't stop the rest
+        let open = OPEN.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = dir(name);
+        use_dir(&dir);
+        Self { dir, _open: open }
+    }
+
+    /// A vault with an identity opened by `PASS`
+    pub fn new(name: &str) -> Self {
+        let vault = Self::empty(name);
+        api::init::<&str>("me", PASS, &[]).unwrap();
+        vault
+    }
+}
+
+impl Drop for TestVault {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
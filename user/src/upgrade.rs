@@ -1,92 +1,490 @@
-use std::{fs::File, io::Seek, path::PathBuf};
+use std::{fs::{copy, create_dir_all, read_dir, remove_dir, remove_file, rename, File}, io::{Read, Write}, path::{Path, PathBuf}};
 
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-use crate::{api::{self, APError}, spec::{base_path, identity_v1::IdentityV1, identity_v2::IdentityV2, load, load_header, save, service_v1::ServiceEntryV1, service_v2::ServiceEntryV2, Encryptor, EncryptorType, Serializable, SpecType}};
+use crate::{api::{self, APError}, lock, spec::{backup_path, base_path, identity_path, legacy_path, list, load, load_header, migration, now, save_file, scan, trash_info_path, trash_path, write_atomic, APKey, Encryptor, EncryptorType, IdentityType, Serializable, ServiceType, SpecType, TrashType}};
 
 
+/// The encryptor files are being moved off of and the one replacing it. Update these
+/// when adding an encryptor, the upgrader binary always goes from one to the other.
+pub type OldEncryptor = crate::spec::encryptor::Encrypt;
+pub type NewEncryptor = EncryptorType;
+
+const JOURNAL_FNAME: &str = ".journal";
+
 #[derive(Error, Debug)]
 pub enum APUpgradeError {
     #[error("Internal AP error: {0}")]
     APError(#[from] APError),
-    #[error("In Progress")]
-    InProgress
+    #[error("An upgrade is already in progress, resume or roll it back")]
+    InProgress,
+    #[error("No upgrade in progress")]
+    NotInProgress,
+    #[error("The upgrade is already cleaning up, it can only be resumed")]
+    Committed,
+    #[error("{0} doesn't read back the same after re-encrypting")]
+    Verify(String)
+}
+
+impl From<std::io::Error> for APUpgradeError {
+    fn from(e: std::io::Error) -> Self {
+        Self::APError(e.into())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+enum MoveState {
+    Planned,
+    /// The old file is in the legacy directory
+    Moved,
+    /// The new file is written and reads back the same as the old one
+    Written
+}
+
+/// One file being re-encrypted. Only hashed filenames go in the journal, never names.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Move {
+    spec_type: SpecType,
+    old: String,
+    new: String,
+    state: MoveState
+}
+
+/*
+ * Record of an encryptor upgrade kept in the legacy directory. Every file is moved into
+ * legacy, re-encrypted into the vault and checked before the journal moves on. Nothing
+ * in legacy is deleted until every file is written, so until then the whole upgrade can
+ * be rolled back. Once cleanup starts the journal is committed and can only go forward.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Journal {
+    from: u16,
+    to: u16,
+    moves: Vec<Move>,
+    committed: bool
+}
+
+/// How far along an encryptor upgrade is
+#[derive(Debug, Clone)]
+pub struct UpgradeStatus {
+    pub from: u16,
+    pub to: u16,
+    pub total: usize,
+    pub moved: usize,
+    pub written: usize,
+    pub committed: bool
 }
 
-fn try_open<O: Encryptor, T: Serializable>(objname: &str, key: &[u8]) -> bool {
-    let path = O::full_path(key, objname);
-    File::open(&path).ok().map(|mut f| {
-        load::<T, O>(&mut f, &key).ok()
-    }).flatten().is_some()
+fn journal_path(basedir: &Path) -> PathBuf {
+    legacy_path(basedir).join(JOURNAL_FNAME)
 }
 
-pub fn upgrade_encryptor<O: Encryptor, N: Encryptor, T: Serializable>(pass: &str) -> Result<(), APUpgradeError> {
-    let legacy_dir = base_path().join("legacy");
-    std::fs::create_dir_all(&legacy_dir).unwrap();
-    let inprogress = legacy_dir.read_dir().unwrap().next().is_some();
-    if inprogress {
+fn load_journal(basedir: &Path) -> Result<Option<Journal>, APError> {
+    let path = journal_path(basedir);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let mut data = vec![];
+    File::open(path)?.read_to_end(&mut data)?;
+    Ok(Some(bincode::deserialize(&data)?))
+}
+
+fn save_journal(basedir: &Path, journal: &Journal) -> Result<(), APError> {
+    let data = bincode::serialize(journal)?;
+    write_atomic(journal_path(basedir), |file| Ok(file.write_all(&data)?))
+}
+
+fn load_key<E: Encryptor>(path: &Path, pass: &str) -> Result<APKey, APError> {
+    let id = load::<IdentityType, E>(&mut File::open(path)?, &E::genkey(pass))?;
+    Ok(id.key())
+}
+
+/// The identity moves last, so wherever it is in the journal says which copy holds the key
+fn vault_key<O: Encryptor, N: Encryptor>(basedir: &Path, journal: &Journal, pass: &str) -> Result<APKey, APError> {
+    let id = journal.moves.iter().find(|m| m.spec_type == SpecType::Identity);
+    match id {
+        Some(m) if m.state == MoveState::Written => load_key::<N>(&basedir.join(&m.new), pass),
+        Some(m) if legacy_path(basedir).join(&m.old).exists() => load_key::<O>(&legacy_path(basedir).join(&m.old), pass),
+        _ => load_key::<O>(&identity_path(basedir), pass)
+    }
+}
+
+fn plan<O: Encryptor, N: Encryptor>(basedir: &Path, pass: &str) -> Result<Journal, APError> {
+    let key = load_key::<O>(&identity_path(basedir), pass)?;
+    let mut moves = vec![];
+    for path in list(basedir, Some(SpecType::Service), None)? {
+        // Anything on another encryptor already is left where it is
+        if load_header(&mut File::open(&path)?)?.encrypt_version != O::encrypt_version() {
+            continue;
+        }
+        let entry = load::<ServiceType, O>(&mut File::open(&path)?, &key)?;
+        moves.push(Move {
+            spec_type: SpecType::Service,
+            old: path.file_name().unwrap().to_string_lossy().into_owned(),
+            new: N::filename(&key, entry.name()),
+            state: MoveState::Planned
+        });
+    }
+    let id = identity_path(basedir).file_name().unwrap().to_string_lossy().into_owned();
+    moves.push(Move {
+        spec_type: SpecType::Identity,
+        old: id.clone(),
+        new: id,
+        state: MoveState::Planned
+    });
+    Ok(Journal {
+        from: O::encrypt_version(),
+        to: N::encrypt_version(),
+        moves,
+        committed: false
+    })
+}
+
+/// Re-encrypt oldpath into newpath and check it reads back the same, returns its name
+fn reencrypt<T: Serializable, O: Encryptor, N: Encryptor>(oldpath: &Path, newpath: &Path, oldkey: &[u8], newkey: &[u8]) -> Result<String, APUpgradeError> {
+    let entry = load::<T, O>(&mut File::open(oldpath)?, oldkey)?;
+    save_file(newpath, newkey, &entry)?;
+    let check = load::<T, N>(&mut File::open(newpath)?, newkey)?;
+    if check.to_binary() != entry.to_binary() {
+        return Err(APUpgradeError::Verify(entry.name().to_owned()));
+    }
+    Ok(entry.name().to_owned())
+}
+
+/// Take move i one state further along, saving the journal after
+fn step<O: Encryptor, N: Encryptor>(basedir: &Path, journal: &mut Journal, i: usize, key: &[u8], pass: &str) -> Result<(), APUpgradeError> {
+    let m = journal.moves[i].clone();
+    let oldpath = legacy_path(basedir).join(&m.old);
+    let newpath = basedir.join(&m.new);
+    match m.state {
+        MoveState::Planned => {
+            // Already there if the journal didn't get saved after the last rename
+            if !oldpath.exists() {
+                rename(basedir.join(&m.old), &oldpath)?;
+            }
+            journal.moves[i].state = MoveState::Moved;
+        }
+        MoveState::Moved => {
+            let name = match m.spec_type {
+                SpecType::Identity => reencrypt::<IdentityType, O, N>(&oldpath, &newpath, &O::genkey(pass), &N::genkey(pass))?,
+                _ => reencrypt::<ServiceType, O, N>(&oldpath, &newpath, key, key)?
+            };
+            journal.moves[i].state = MoveState::Written;
+            println!("[{}/{}] Upgraded {}", i + 1, journal.moves.len(), name);
+        }
+        MoveState::Written => return Ok(())
+    }
+    Ok(save_journal(basedir, journal)?)
+}
+
+fn run<O: Encryptor, N: Encryptor>(basedir: &Path, journal: &mut Journal, pass: &str) -> Result<(), APUpgradeError> {
+    let legacy_dir = legacy_path(basedir);
+    let total = journal.moves.len();
+    if !journal.committed {
+        let key = vault_key::<O, N>(basedir, journal, pass)?;
+        for i in 0..total {
+            while journal.moves[i].state != MoveState::Written {
+                step::<O, N>(basedir, journal, i, &key, pass)?;
+            }
+        }
+        journal.committed = true;
+        save_journal(basedir, journal)?;
+    }
+
+    for m in &journal.moves {
+        let oldpath = legacy_dir.join(&m.old);
+        if oldpath.exists() {
+            remove_file(oldpath)?;
+        }
+    }
+    remove_file(journal_path(basedir))?;
+    remove_dir(&legacy_dir)?;
+    println!("Upgraded {} files from encryptor v{} to v{}", total, journal.from, journal.to);
+    Ok(())
+}
+
+/// Start moving every service and the identity from encryptor O to N. Attachments
+/// and the trash aren't covered, there has only ever been the one encryptor for them.
+pub fn upgrade_encryptor<O: Encryptor, N: Encryptor>(pass: &str) -> Result<(), APUpgradeError> {
+    let _lock = lock::exclusive()?;
+    let basedir = base_path();
+    let legacy_dir = legacy_path(&basedir);
+    if legacy_dir.exists() && legacy_dir.read_dir()?.next().is_some() {
         return Err(APUpgradeError::InProgress);
     }
+    let mut journal = plan::<O, N>(&basedir, pass)?;
+    create_dir_all(&legacy_dir)?;
+    save_journal(&basedir, &journal)?;
+    run::<O, N>(&basedir, &mut journal, pass)
+}
+
+/// Carry on an interrupted upgrade from its journal
+pub fn resume_encryptor<O: Encryptor, N: Encryptor>(pass: &str) -> Result<(), APUpgradeError> {
+    let _lock = lock::exclusive()?;
+    let basedir = base_path();
+    let mut journal = load_journal(&basedir)?.ok_or(APUpgradeError::NotInProgress)?;
+    run::<O, N>(&basedir, &mut journal, pass)
+}
 
-    for objname in &api::list(pass, &[])? {
-        if try_open::<N, T>(objname, &N::genkey(pass)) {
-            println!("Skipping {}", objname);
+/// Put every file the journal moved back the way it was before the upgrade started
+pub fn rollback_encryptor() -> Result<(), APUpgradeError> {
+    let _lock = lock::exclusive()?;
+    let basedir = base_path();
+    let legacy_dir = legacy_path(&basedir);
+    let journal = load_journal(&basedir)?.ok_or(APUpgradeError::NotInProgress)?;
+    if journal.committed {
+        return Err(APUpgradeError::Committed);
+    }
+    for m in journal.moves.iter().rev() {
+        let oldpath = legacy_dir.join(&m.old);
+        if !oldpath.exists() {
             continue;
         }
-        let newkey = N::genkey(pass);
-        let oldkey = O::genkey(pass);
-        let newfilename = N::filename(&newkey, objname);
-        let oldfilename = O::filename(&oldkey, objname);
-        let newobjpath = base_path().join(&newfilename);
-        let oldobjpath = legacy_dir.clone().join(&oldfilename);
-        std::fs::rename(&base_path().join(oldfilename), &oldobjpath).unwrap();
-
-        let mut oldfile = File::open(&oldobjpath).unwrap();
-        let entry = load::<T, O>(&mut oldfile, &oldkey)?;
-        let mut newfile = File::create(&newobjpath).unwrap();
-        save::<T>(&mut newfile, &newkey, &entry)?;
-        std::fs::remove_file(&oldobjpath).unwrap();
-        println!("Saved entry {}", entry.name());
-    }
-
-    let done = legacy_dir.read_dir().unwrap().next().is_none();
-    if done {
-        std::fs::remove_dir(&legacy_dir).unwrap();
+        let newpath = basedir.join(&m.new);
+        if m.state != MoveState::Planned && newpath.exists() {
+            remove_file(&newpath)?;
+        }
+        rename(&oldpath, basedir.join(&m.old))?;
     }
+    remove_file(journal_path(&basedir))?;
+    remove_dir(&legacy_dir)?;
+    println!("Rolled back {} files to encryptor v{}", journal.moves.len(), journal.from);
     Ok(())
 }
 
-fn upgrade_spec<E: Encryptor, O: Serializable, N: Serializable + From<O>>(file: &mut File, key: &[u8]) -> Result<(), APError> {
-    let old = load::<O, E>(file, key)?;
-    let new = N::from(old);
-    file.set_len(0)?;
-    file.seek(std::io::SeekFrom::Start(0))?;
-    save(file, key, &new)?;
+/// None when no upgrade is in progress
+pub fn upgrade_status() -> Result<Option<UpgradeStatus>, APUpgradeError> {
+    let _lock = lock::shared()?;
+    let journal = match load_journal(&base_path())? {
+        Some(journal) => journal,
+        None => return Ok(None)
+    };
+    let count = |state| journal.moves.iter().filter(|m| m.state == state).count();
+    Ok(Some(UpgradeStatus {
+        from: journal.from,
+        to: journal.to,
+        total: journal.moves.len(),
+        moved: count(MoveState::Moved),
+        written: count(MoveState::Written),
+        committed: journal.committed
+    }))
+}
+
+/// A file written at an older spec version than ap writes now
+#[derive(Debug, Clone)]
+pub struct Migration {
+    pub path: PathBuf,
+    pub spec_type: SpecType,
+    pub from: u16,
+    pub to: u16
+}
+
+pub struct MigrationReport {
+    pub files: Vec<Migration>,
+    /// Copies of the files taken before they were rewritten, None on a dry run
+    /// or when there was nothing to rewrite
+    pub backup: Option<PathBuf>
+}
+
+fn stale_file(path: PathBuf) -> Result<Option<Migration>, APError> {
+    let header = match load_header(&mut File::open(&path)?) {
+        Ok(header) => header,
+        // Unreadable files are for fsck to sort out
+        Err(APError::CorruptHeader) => return Ok(None),
+        Err(e) => return Err(e)
+    };
+    // Attachments are streamed rather than loaded whole, they'll need their own
+    // path through here once there's a second version of them
+    if header.spec_type == SpecType::Attachment {
+        return Ok(None);
+    }
+    let current = migration::current_version(header.spec_type);
+    if header.spec_version >= current {
+        return Ok(None);
+    }
+    Ok(Some(Migration {
+        path,
+        spec_type: header.spec_type,
+        from: header.spec_version,
+        to: current
+    }))
+}
+
+/// Every file in the vault behind the current spec version, including the trash
+fn stale_files(basedir: &Path) -> Result<Vec<Migration>, APError> {
+    let mut paths = vec![identity_path(basedir)];
+    paths.extend(scan(basedir)?.into_iter().map(|(path, _)| path));
+    let trash = trash_path(basedir);
+    if trash.exists() {
+        for dir in read_dir(&trash)? {
+            let dir = dir?.path();
+            if !dir.is_dir() {
+                continue;
+            }
+            paths.push(trash_info_path(&dir));
+            paths.extend(scan(&dir)?.into_iter().map(|(path, _)| path));
+        }
+    }
+
+    let mut stale = vec![];
+    for path in paths.into_iter().filter(|p| p.exists()) {
+        if let Some(m) = stale_file(path)? {
+            stale.push(m);
+        }
+    }
+    stale.sort_by(|m1, m2| m1.path.cmp(&m2.path));
+    Ok(stale)
+}
+
+fn migrate_file<T: Serializable>(path: &Path, key: &[u8], write: bool) -> Result<(), APError> {
+    let entry = load::<T, EncryptorType>(&mut File::open(path)?, key)?;
+    if write {
+        save_file(path, key, &entry)?;
+    }
     Ok(())
 }
 
-pub fn check_upgrade<E: Encryptor>(filename: &PathBuf, key: &[u8]) -> Result<(), APError> {
-    
-    let header = {
-        let mut file = File::open(&filename)?;
-        load_header(&mut file)?
+fn migrate_one(m: &Migration, pass: &str, key: &[u8], write: bool) -> Result<(), APError> {
+    match m.spec_type {
+        SpecType::Service => migrate_file::<ServiceType>(&m.path, key, write),
+        SpecType::Identity => migrate_file::<IdentityType>(&m.path, &EncryptorType::genkey(pass), write),
+        SpecType::Trash => migrate_file::<TrashType>(&m.path, key, write),
+        SpecType::Attachment => unreachable!("Attachments aren't migrated"),
+        SpecType::Audit => unreachable!("Audit logs aren't migrated"),
+        SpecType::Snapshot => unreachable!("Snapshots aren't migrated")
+    }
+}
+
+/// Rewrite every file in the vault that's behind the current spec version. Reads
+/// upgrade old files in memory so this is never required, it just saves doing it
+/// every time. With dry_run nothing is written and the report is what would be.
+///
+/// Every file is upgraded in memory before anything is written, then the ones that
+/// will change are copied under the backup directory.
+pub fn migrate(pass: &str, dry_run: bool) -> Result<MigrationReport, APUpgradeError> {
+    let _lock = match dry_run {
+        true => lock::shared()?,
+        false => lock::exclusive()?
     };
+    let key = api::load_id(pass)?.key();
+    let basedir = base_path();
+    let files = stale_files(&basedir)?;
+    for m in &files {
+        migrate_one(m, pass, &key, false)?;
+    }
+    if dry_run || files.is_empty() {
+        return Ok(MigrationReport { files, backup: None });
+    }
 
-    let mut file = File::options()
-        .read(true)
-        .write(true)
-        .open(&filename)?;
+    let backup = backup_path(&basedir).join(format!("migrate-{}", now()));
+    for m in &files {
+        let dest = backup.join(m.path.strip_prefix(&basedir).unwrap_or(&m.path));
+        create_dir_all(dest.parent().unwrap_or(&backup)).map_err(APError::from)?;
+        copy(&m.path, &dest).map_err(APError::from)?;
+    }
+    for m in &files {
+        migrate_one(m, pass, &key, true)?;
+    }
+    Ok(MigrationReport { files, backup: Some(backup) })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::hash::TextMode;
+    use crate::testvault::{TestVault, PASS};
+
+    type Enc = EncryptorType;
+
+    const NAMES: [&str; 2] = ["mail", "bank"];
+
+    /// The vault's own files and what's in them, leaving out the lock and directories
+    fn files(basedir: &Path) -> BTreeMap<String, Vec<u8>> {
+        let mut files = BTreeMap::new();
+        for entry in read_dir(basedir).unwrap() {
+            let path = entry.unwrap().path();
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            if path.is_file() && !name.starts_with('.') {
+                files.insert(name, std::fs::read(&path).unwrap());
+            }
+        }
+        files
+    }
+
+    fn entries() -> Vec<Vec<u8>> {
+        NAMES.iter().map(|name| api::get_all(name, PASS).unwrap().to_binary()).collect()
+    }
 
-    match header.spec_type {
-        SpecType::Service => match header.spec_version {
-            1 => upgrade_spec::<EncryptorType, ServiceEntryV1, ServiceEntryV2>(&mut file, key),
-            2 => Ok(()),
-            _ => Err(APError::VersionTooOld)
+    /// Begin an upgrade the way `upgrade_encryptor` does and take it `steps` moves along
+    /// before it's interrupted. With torn, the next file is renamed into legacy without
+    /// the journal being saved after.
+    fn interrupt(basedir: &Path, steps: usize, torn: bool) {
+        let mut journal = plan::<Enc, Enc>(basedir, PASS).unwrap();
+        create_dir_all(legacy_path(basedir)).unwrap();
+        save_journal(basedir, &journal).unwrap();
+        let key = vault_key::<Enc, Enc>(basedir, &journal, PASS).unwrap();
+        for n in 0..steps {
+            step::<Enc, Enc>(basedir, &mut journal, n / 2, &key, PASS).unwrap();
         }
-        SpecType::Identity => match header.spec_version {
-            1 => upgrade_spec::<EncryptorType, IdentityV1, IdentityV2>(&mut file, key),
-            2 => Ok(()),
-            _ => Err(APError::VersionTooOld)
+        if torn {
+            let m = &journal.moves[steps / 2];
+            rename(basedir.join(&m.old), legacy_path(basedir).join(&m.old)).unwrap();
         }
     }
-}
\ No newline at end of file
+
+    fn check(steps: usize, torn: bool) {
+        let vault = TestVault::new("upgrade");
+        for name in NAMES.iter() {
+            api::new(name, PASS, &TextMode::NoWhiteSpace, 16, &[("user", *name)], &[], None).unwrap();
+        }
+        let before = files(&vault.dir);
+        let expected = entries();
+
+        interrupt(&vault.dir, steps, torn);
+        rollback_encryptor().unwrap();
+        assert!(!legacy_path(&vault.dir).exists());
+        assert_eq!(files(&vault.dir), before, "rollback after {} steps", steps);
+        assert_eq!(entries(), expected);
+
+        interrupt(&vault.dir, steps, torn);
+        resume_encryptor::<Enc, Enc>(PASS).unwrap();
+        assert!(!legacy_path(&vault.dir).exists());
+        assert!(files(&vault.dir).keys().eq(before.keys()), "resume after {} steps", steps);
+        assert_eq!(api::list(PASS, &[]).unwrap().len(), NAMES.len());
+        assert_eq!(entries(), expected);
+        assert!(matches!(upgrade_status(), Ok(None)));
+    }
+
+    #[test]
+    fn test_interrupted() {
+        // Two states for each service and the identity
+        let total = (NAMES.len() + 1) * 2;
+        for steps in 0..=total {
+            check(steps, false);
+            if steps % 2 == 0 && steps < total {
+                check(steps, true);
+            }
+        }
+    }
+
+    #[test]
+    fn test_committed() {
+        let vault = TestVault::new("upgrade-committed");
+        api::new::<&str>("mail", PASS, &TextMode::NoWhiteSpace, 16, &[], &[], None).unwrap();
+        let total = 2 * 2;
+        interrupt(&vault.dir, total, false);
+        let mut journal = load_journal(&vault.dir).unwrap().unwrap();
+        journal.committed = true;
+        save_journal(&vault.dir, &journal).unwrap();
+
+        assert!(matches!(rollback_encryptor(), Err(APUpgradeError::Committed)));
+        assert!(matches!(upgrade_encryptor::<Enc, Enc>(PASS), Err(APUpgradeError::InProgress)));
+        resume_encryptor::<Enc, Enc>(PASS).unwrap();
+        assert!(!legacy_path(&vault.dir).exists());
+        api::get_all("mail", PASS).unwrap();
+        assert!(matches!(resume_encryptor::<Enc, Enc>(PASS), Err(APUpgradeError::NotInProgress)));
+    }
+}
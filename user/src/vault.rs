@@ -0,0 +1,157 @@
+use crate::api::APError;
+use crate::spec::{decode, decode_header, encode, Encryptor, EncryptorType, IdentityType, Serializable, ServiceType, SpecType, IDENTITY_FNAME};
+use crate::storage::{Op, Storage};
+
+/*
+ * The records of a vault on top of any `Storage`: the identity, encrypted with the
+ * password, and the services, encrypted with the identity's key under hashed names.
+ * Locking, attachments and the trash still work on the vault directory directly.
+ */
+pub struct Vault<S: Storage> {
+    storage: S
+}
+
+impl<S: Storage> Vault<S> {
+    pub fn new(storage: S) -> Self {
+        Self { storage }
+    }
+
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    pub fn into_storage(self) -> S {
+        self.storage
+    }
+
+    pub fn is_inited(&self) -> Result<bool, APError> {
+        self.storage.exists(IDENTITY_FNAME)
+    }
+
+    pub fn load_id(&self, pass: &str) -> Result<IdentityType, APError> {
+        let data = self.storage.get(IDENTITY_FNAME)?.ok_or(APError::NotInited)?;
+        let header = decode_header(&data)?;
+        if header.spec_type != SpecType::Identity {
+            return Err(APError::WrongSpecType(SpecType::Identity, header.spec_type));
+        }
+        decode::<IdentityType, EncryptorType>(&data, &EncryptorType::genkey(pass))
+    }
+
+    pub fn save_id(&mut self, pass: &str, id: &IdentityType) -> Result<(), APError> {
+        self.storage.put(IDENTITY_FNAME, encode(&EncryptorType::genkey(pass), id)?)
+    }
+
+    pub fn exists(&self, key: &[u8], name: &str) -> Result<bool, APError> {
+        self.storage.exists(&EncryptorType::filename(key, name))
+    }
+
+    pub fn load_service(&self, key: &[u8], name: &str) -> Result<ServiceType, APError> {
+        let data = self.storage.get(&EncryptorType::filename(key, name))?
+            .ok_or_else(|| APError::NotExist(name.to_owned()))?;
+        let header = decode_header(&data)?;
+        if header.spec_type != SpecType::Service {
+            return Err(APError::WrongSpecType(SpecType::Service, header.spec_type));
+        }
+        decode::<ServiceType, EncryptorType>(&data, key)
+    }
+
+    pub fn save_service(&mut self, key: &[u8], entry: &ServiceType) -> Result<(), APError> {
+        self.save_services(key, std::slice::from_ref(entry))
+    }
+
+    /// Save all of entries or none of them
+    pub fn save_services(&mut self, key: &[u8], entries: &[ServiceType]) -> Result<(), APError> {
+        let mut ops = vec![];
+        for entry in entries {
+            ops.push(Op::Put(EncryptorType::filename(key, entry.name()), encode(key, entry)?));
+        }
+        self.storage.batch(ops)
+    }
+
+    /// Save entries along with the identity in the same batch, so a change that
+    /// touches both lands all at once
+    pub fn save_id_and_services(&mut self,
+                                pass: &str,
+                                id: &IdentityType,
+                                entries: &[ServiceType]) -> Result<(), APError> {
+        let key = id.key();
+        let mut ops = vec![Op::Put(IDENTITY_FNAME.to_owned(), encode(&EncryptorType::genkey(pass), id)?)];
+        for entry in entries {
+            ops.push(Op::Put(EncryptorType::filename(&key, entry.name()), encode(&key, entry)?));
+        }
+        self.storage.batch(ops)
+    }
+
+    pub fn delete_service(&mut self, key: &[u8], name: &str) -> Result<(), APError> {
+        self.storage.delete(&EncryptorType::filename(key, name))
+    }
+
+    /// Every service that can be read, sorted by name, along with the names of the
+    /// records that couldn't be
+    pub fn list_services(&self, key: &[u8]) -> Result<(Vec<ServiceType>, Vec<String>), APError> {
+        let mut services: Vec<ServiceType> = vec![];
+        let mut corrupt = vec![];
+        for name in self.storage.list()? {
+            if name == IDENTITY_FNAME {
+                continue;
+            }
+            let data = match self.storage.get(&name)? {
+                Some(data) => data,
+                None => continue
+            };
+            match decode_header(&data) {
+                Ok(header) if header.spec_type != SpecType::Service => continue,
+                Ok(_) => {}
+                Err(_) => {
+                    corrupt.push(name);
+                    continue;
+                }
+            }
+            match decode::<ServiceType, EncryptorType>(&data, key) {
+                Ok(entry) => services.push(entry),
+                Err(APError::Io(e)) => return Err(e.into()),
+                Err(_) => corrupt.push(name)
+            }
+        }
+        services.sort_by(|s1, s2| s1.name().cmp(s2.name()));
+        corrupt.sort();
+        Ok((services, corrupt))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::TextMode;
+    use crate::storage::MemStorage;
+
+    #[test]
+    fn test_vault_memory() {
+        let mut vault = Vault::new(MemStorage::new());
+        assert!(!vault.is_inited().unwrap());
+        assert!(matches!(vault.load_id("pw"), Err(APError::NotInited)));
+
+        let key = EncryptorType::genkey("pw");
+        vault.save_id("pw", &IdentityType::new::<&str>("me", &key, &[])).unwrap();
+        assert!(vault.load_id("other").is_err());
+        let key = vault.load_id("pw").unwrap().key();
+
+        let services: Vec<ServiceType> = ["b", "a"].iter()
+            .map(|name| ServiceType::new::<&str>(name, "secret", 0, &[], &[], 16, &TextMode::AlphaNumeric))
+            .collect();
+        vault.save_services(&key, &services).unwrap();
+        assert!(vault.exists(&key, "a").unwrap());
+        assert_eq!(vault.load_service(&key, "b").unwrap().get_pass(false), Some("secret"));
+
+        let mut storage = vault.into_storage();
+        storage.put("garbage", vec![1, 2, 3]).unwrap();
+        let mut vault = Vault::new(storage);
+        let (services, corrupt) = vault.list_services(&key).unwrap();
+        let names: Vec<&str> = services.iter().map(|s| s.name()).collect();
+        assert_eq!(names, vec!["a", "b"]);
+        assert_eq!(corrupt, vec!["garbage"]);
+
+        vault.delete_service(&key, "a").unwrap();
+        assert!(matches!(vault.load_service(&key, "a"), Err(APError::NotExist(_))));
+    }
+}
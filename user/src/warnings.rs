@@ -0,0 +1,19 @@
+use std::sync::Mutex;
+
+/*
+ * Things that went wrong after a change was already made, so the call that made it
+ * still succeeds: a trash purge, an audit record, a post hook or a git commit that
+ * failed. Config lines that were skipped go here too. They wait here until a front
+ * end takes them to show.
+ */
+
+static PENDING: Mutex<Vec<String>> = Mutex::new(vec![]);
+
+pub(crate) fn push(warning: String) {
+    PENDING.lock().unwrap_or_else(|e| e.into_inner()).push(warning);
+}
+
+/// Warnings since the last call, oldest first
+pub fn take() -> Vec<String> {
+    std::mem::take(&mut *PENDING.lock().unwrap_or_else(|e| e.into_inner()))
+}
@@ -0,0 +1,193 @@
+#!/usr/bin/env python3
+"""
+Writes the sample KDBX 4 databases the kdbx tests read, independently of the Rust
+code so they check it against the format rather than against itself. Needs the
+cryptography package. Both databases have the password `autopass`.
+
+  sample-aes-argon2id.kdbx  AES-256, Argon2id, gzip
+  sample-chacha-aeskdf.kdbx ChaCha20, AES-KDF, uncompressed
+
+This script and the reader were written from the same reading of the spec, so a
+mistake in that reading would pass. A database saved by KeePassXC or KeePass itself
+is still wanted next to these.
+"""
+
+import base64
+import gzip
+import hashlib
+import hmac
+import os
+import struct
+from xml.sax.saxutils import escape
+
+from cryptography.hazmat.primitives import padding
+from cryptography.hazmat.primitives.ciphers import Cipher, algorithms, modes
+from cryptography.hazmat.primitives.kdf.argon2 import Argon2id
+
+PASSWORD = b"autopass"
+AES256 = bytes.fromhex("31c1f2e6bf714350be5805216afc5aff")
+CHACHA20 = bytes.fromhex("d6038a2b8b6f4cb5a524339a31dbb59a")
+KDF_AES = bytes.fromhex("c9d9f39a628a4460bf740d08c18a4fea")
+KDF_ARGON2ID = bytes.fromhex("9e298b1956db4773b23dfc3ec6f0a1e6")
+EPOCH_OFFSET = 62135596800
+
+
+def variant_dict(items):
+    out = struct.pack("<H", 0x0100)
+    for key, kind, value in items:
+        key = key.encode()
+        if kind == "u32":
+            data, tag = struct.pack("<I", value), 0x04
+        elif kind == "u64":
+            data, tag = struct.pack("<Q", value), 0x05
+        else:
+            data, tag = value, 0x42
+        out += struct.pack("<BI", tag, len(key)) + key + struct.pack("<I", len(data)) + data
+    return out + b"\x00"
+
+
+def field(fid, data):
+    return struct.pack("<BI", fid, len(data)) + data
+
+
+def kdbx_time(ts):
+    return base64.b64encode(struct.pack("<q", ts + EPOCH_OFFSET)).decode()
+
+
+class Inner:
+    """ChaCha20 inner stream, one keystream across every protected value in order"""
+
+    def __init__(self, key):
+        digest = hashlib.sha512(key).digest()
+        nonce = b"\x00" * 4 + digest[32:44]
+        self.enc = Cipher(algorithms.ChaCha20(digest[:32], nonce), None).encryptor()
+
+    def protect(self, value):
+        return base64.b64encode(self.enc.update(value.encode())).decode()
+
+
+def entry_xml(inner, uuid, fields, tags="", binaries=(), history=(), times=(1700000000, 1700003600)):
+    xml = "<Entry><UUID>%s</UUID>" % base64.b64encode(uuid).decode()
+    if tags:
+        xml += "<Tags>%s</Tags>" % escape(tags)
+    xml += "<Times><CreationTime>%s</CreationTime><LastModificationTime>%s</LastModificationTime></Times>" % (
+        kdbx_time(times[0]), kdbx_time(times[1]))
+    for key, value, protected in fields:
+        if protected:
+            xml += '<String><Key>%s</Key><Value Protected="True">%s</Value></String>' % (escape(key), inner.protect(value))
+        else:
+            xml += "<String><Key>%s</Key><Value>%s</Value></String>" % (escape(key), escape(value))
+    for key, ref in binaries:
+        xml += '<Binary><Key>%s</Key><Value Ref="%d"/></Binary>' % (escape(key), ref)
+    if history:
+        # Rendered here, after the fields, so protected values take keystream in document order
+        xml += "<History>" + "".join(entry_xml(inner, os.urandom(16), old) for old in history) + "</History>"
+    return xml + "</Entry>"
+
+
+def database_xml(inner):
+    recycle = os.urandom(16)
+    # Built in the order they appear in the document, the order the inner stream goes in
+    bank = entry_xml(inner, os.urandom(16), [
+        ("Title", "Bank", False),
+        ("UserName", "me", False),
+        ("Password", "bank-pass", True),
+    ])
+    nameless = entry_xml(inner, os.urandom(16), [("Password", "no-name", True)])
+    aws = entry_xml(inner, os.urandom(16), [
+        ("Title", "AWS", False),
+        ("UserName", "root", False),
+        ("Password", "aws-pass", True),
+        ("URL", "https://aws.amazon.com", False),
+        ("Notes", "first line\nsecond line", False),
+        ("Account", "1234", False),
+        ("otp", "otpauth://totp/aws?secret=JBSWY3DPEHPK3PXP", True),
+    ], tags="cloud;billing", binaries=[("key.pem", 0)], history=[[("Title", "AWS", False), ("Password", "old-aws", True)]])
+    mail = entry_xml(inner, os.urandom(16), [
+        ("Title", "", False),
+        ("UserName", "me@example.com", False),
+        ("Password", "mail & <pass>", True),
+        ("URL", "https://www.mail.example.com/login", False),
+    ])
+    gone = entry_xml(inner, os.urandom(16), [("Title", "Old", False), ("Password", "old-pass", True)])
+
+    def group(name, body, uuid=None):
+        uuid = uuid or os.urandom(16)
+        return "<Group><UUID>%s</UUID><Name>%s</Name>%s</Group>" % (base64.b64encode(uuid).decode(), escape(name), body)
+
+    root = group("Sample", bank + nameless +
+                 group("Work", group("Cloud", aws)) +
+                 group("Personal", mail) +
+                 group("Recycle Bin", gone, recycle))
+    return ('<?xml version="1.0" encoding="utf-8" standalone="yes"?>\n'
+            "<KeePassFile><Meta><Generator>make_kdbx.py</Generator><DatabaseName>Sample</DatabaseName>"
+            "<RecycleBinEnabled>True</RecycleBinEnabled><RecycleBinUUID>%s</RecycleBinUUID></Meta>"
+            "<Root>%s<DeletedObjects/></Root></KeePassFile>" % (base64.b64encode(recycle).decode(), root))
+
+
+def aes_kdf(composite, seed, rounds):
+    enc = Cipher(algorithms.AES(seed), modes.ECB()).encryptor()
+    key = composite
+    for _ in range(rounds):
+        key = enc.update(key)
+    return hashlib.sha256(key).digest()
+
+
+def write(path, cipher, kdf, compress):
+    seed = os.urandom(32)
+    composite = hashlib.sha256(hashlib.sha256(PASSWORD).digest()).digest()
+    if kdf == "argon2id":
+        salt = os.urandom(32)
+        params = [("$UUID", "bytes", KDF_ARGON2ID), ("S", "bytes", salt), ("P", "u32", 1),
+                  ("M", "u64", 64 * 1024), ("I", "u64", 2), ("V", "u32", 0x13)]
+        transformed = Argon2id(salt=salt, length=32, iterations=2, lanes=1, memory_cost=64).derive(composite)
+    else:
+        kseed = os.urandom(32)
+        params = [("$UUID", "bytes", KDF_AES), ("R", "u64", 1000), ("S", "bytes", kseed)]
+        transformed = aes_kdf(composite, kseed, 1000)
+    iv = os.urandom(16 if cipher == "aes" else 12)
+
+    header = struct.pack("<IIHH", 0x9AA2D903, 0xB54BFB67, 0, 4)
+    header += field(2, AES256 if cipher == "aes" else CHACHA20)
+    header += field(3, struct.pack("<I", 1 if compress else 0))
+    header += field(4, seed)
+    header += field(7, iv)
+    header += field(11, variant_dict(params))
+    header += field(0, b"\r\n\r\n")
+
+    inner_key = os.urandom(64)
+    attachment = b"-----BEGIN KEY-----\x00\x01binary\xff-----END KEY-----\n"
+    payload = field(1, struct.pack("<I", 3)) + field(2, inner_key) + field(3, b"\x01" + attachment) + field(0, b"")
+    payload += database_xml(Inner(inner_key)).encode()
+    if compress:
+        payload = gzip.compress(payload)
+
+    master = hashlib.sha256(seed + transformed).digest()
+    if cipher == "aes":
+        padder = padding.PKCS7(128).padder()
+        enc = Cipher(algorithms.AES(master), modes.CBC(iv)).encryptor()
+        payload = enc.update(padder.update(payload) + padder.finalize()) + enc.finalize()
+    else:
+        enc = Cipher(algorithms.ChaCha20(master, b"\x00" * 4 + iv), None).encryptor()
+        payload = enc.update(payload)
+
+    hmac_base = hashlib.sha512(seed + transformed + b"\x01").digest()
+
+    def block_mac(index, data):
+        key = hashlib.sha512(struct.pack("<Q", index) + hmac_base).digest()
+        return hmac.new(key, struct.pack("<Q", index) + struct.pack("<I", len(data)) + data, hashlib.sha256).digest()
+
+    out = header + hashlib.sha256(header).digest()
+    out += hmac.new(hashlib.sha512(struct.pack("<Q", 2**64 - 1) + hmac_base).digest(), header, hashlib.sha256).digest()
+    # Small blocks so reading has to join several
+    blocks = [payload[i:i + 512] for i in range(0, len(payload), 512)] + [b""]
+    for index, data in enumerate(blocks):
+        out += block_mac(index, data) + struct.pack("<I", len(data)) + data
+    with open(path, "wb") as f:
+        f.write(out)
+
+
+if __name__ == "__main__":
+    here = os.path.dirname(os.path.abspath(__file__))
+    write(os.path.join(here, "sample-aes-argon2id.kdbx"), "aes", "argon2id", True)
+    write(os.path.join(here, "sample-chacha-aeskdf.kdbx"), "chacha", "aes", False)